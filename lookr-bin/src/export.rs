@@ -0,0 +1,192 @@
+//! Writing results to a file as CSV or TSV, for spreadsheets and scripts, or
+//! as protobuf messages for programs reading a lot of them.
+
+use prost::Message;
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+/// How the fields of each row are separated.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Format {
+    Csv,
+    Tsv,
+}
+
+impl Format {
+    pub(crate) fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "csv" => Some(Format::Csv),
+            "tsv" => Some(Format::Tsv),
+            _ => None,
+        }
+    }
+
+    fn separator(self) -> char {
+        match self {
+            Format::Csv => ',',
+            Format::Tsv => '\t',
+        }
+    }
+}
+
+/// Write the header and rows to a new file at `path`, replacing any there.
+pub(crate) fn export(
+    path: &Path,
+    format: Format,
+    header: &[&str],
+    rows: &[Vec<String>],
+) -> io::Result<()> {
+    let mut out = BufWriter::new(File::create(path)?);
+    write_table(&mut out, format, header, rows)?;
+    out.flush()
+}
+
+/// Write the messages to a new file at `path`, or to stdout without one,
+/// each preceded by its length as a varint. That's protobuf's own delimited
+/// format, which its libraries read with `parseDelimitedFrom` and the like.
+pub(crate) fn export_messages<M: Message>(path: Option<&Path>, messages: &[M]) -> io::Result<()> {
+    match path {
+        Some(path) => {
+            let mut out = BufWriter::new(File::create(path)?);
+            write_messages(&mut out, messages)?;
+            out.flush()
+        }
+        None => {
+            let stdout = io::stdout();
+            let mut out = BufWriter::new(stdout.lock());
+            write_messages(&mut out, messages)?;
+            out.flush()
+        }
+    }
+}
+
+fn write_messages<M: Message>(out: &mut impl Write, messages: &[M]) -> io::Result<()> {
+    let mut buf = Vec::new();
+    for message in messages {
+        buf.clear();
+        // Only fails if the buffer is too small, and it grows.
+        message.encode_length_delimited(&mut buf).unwrap();
+        out.write_all(&buf)?;
+    }
+    Ok(())
+}
+
+fn write_table(
+    out: &mut impl Write,
+    format: Format,
+    header: &[&str],
+    rows: &[Vec<String>],
+) -> io::Result<()> {
+    write_row(out, format, header.iter().copied())?;
+    for row in rows {
+        write_row(out, format, row.iter().map(String::as_str))?;
+    }
+    Ok(())
+}
+
+fn write_row<'a>(
+    out: &mut impl Write,
+    format: Format,
+    fields: impl Iterator<Item = &'a str>,
+) -> io::Result<()> {
+    let separator = format.separator().to_string();
+    let fields: Vec<Cow<str>> = fields.map(|f| quote(f, format.separator())).collect();
+    writeln!(out, "{}", fields.join(&separator))
+}
+
+/// The field in quotes, with its quotes doubled, if it has anything in it
+/// that would otherwise end it, as RFC 4180 has it. The same goes for TSV.
+fn quote(field: &str, separator: char) -> Cow<'_, str> {
+    if field.contains([separator, '"', '\n', '\r']) {
+        Cow::Owned(format!("\"{}\"", field.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(field)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use lookr_client::SearchResult;
+
+    /// Splits RFC 4180 text back into rows of fields.
+    fn parse(text: &str, separator: char) -> Vec<Vec<String>> {
+        let mut rows = Vec::new();
+        let mut row = Vec::new();
+        let mut field = String::new();
+        let mut quoted = false;
+        let mut chars = text.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '"' if quoted && chars.peek() == Some(&'"') => {
+                    chars.next();
+                    field.push('"');
+                }
+                '"' => quoted = !quoted,
+                c if c == separator && !quoted => row.push(std::mem::take(&mut field)),
+                '\n' if !quoted => {
+                    row.push(std::mem::take(&mut field));
+                    rows.push(std::mem::take(&mut row));
+                }
+                c => field.push(c),
+            }
+        }
+        rows
+    }
+
+    #[test]
+    fn test_write_table() {
+        let rows = vec![
+            vec!["/src/main.rs".to_string()],
+            vec!["/docs/a, b.txt".to_string()],
+            vec!["/docs/\"quoted\"\tand\nbroken".to_string()],
+        ];
+        for format in &[Format::Csv, Format::Tsv] {
+            let mut out = Vec::new();
+            write_table(&mut out, *format, &["path"], &rows).unwrap();
+            let text = String::from_utf8(out).unwrap();
+
+            let mut expected = vec![vec!["path".to_string()]];
+            expected.extend(rows.iter().cloned());
+            assert_eq!(parse(&text, format.separator()), expected);
+        }
+
+        let mut out = Vec::new();
+        let rows = vec![vec!["host:1".to_string(), "/a, b".to_string()]];
+        write_table(&mut out, Format::Csv, &["host", "path"], &rows).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "host,path\nhost:1,\"/a, b\"\n"
+        );
+    }
+
+    #[test]
+    fn test_write_messages() {
+        let hits = vec![
+            SearchResult {
+                path: "/src/main.rs".to_string(),
+                score: 1.5,
+                metadata: vec![("mtime".to_string(), "1".to_string())]
+                    .into_iter()
+                    .collect(),
+                highlighted: String::new(),
+            },
+            SearchResult::default(),
+            SearchResult {
+                path: "/docs/a, b.txt".to_string(),
+                ..Default::default()
+            },
+        ];
+        let mut out = Vec::new();
+        write_messages(&mut out, &hits).unwrap();
+
+        let mut buf = &out[..];
+        let mut read = Vec::new();
+        while !buf.is_empty() {
+            read.push(SearchResult::decode_length_delimited(&mut buf).unwrap());
+        }
+        assert_eq!(read, hits);
+    }
+}
@@ -1,14 +1,413 @@
-use clap::{App, AppSettings, Arg};
-use lookrd::proto::rpc::lookr_client::LookrClient;
-use lookrd::proto::rpc::QueryReq;
+mod export;
+mod open;
+mod paths;
+
+use crate::export::Format;
+use crate::paths::PathStyle;
+use clap::{value_t, App, AppSettings, Arg, ArgMatches, Shell};
+use lookr_client::{
+    ErrorCode, FieldBoosts, FindDuplicateContentReq, FindDuplicateNamesReq, LookrClient, QueryReq,
+    ReindexOutcome, SortBy, DEFAULT_SERVER, VERSION,
+};
+use rustyline::error::ReadlineError;
+use rustyline::DefaultEditor;
+use std::collections::HashMap;
+use std::env;
 use std::error;
-use tonic::Request;
+use std::io::{self, IsTerminal};
+use std::path::Path;
+use std::process;
+
+/// How many times an interactive session tries to reconnect to a daemon
+/// that's gone away, over about six seconds, before giving up on a query.
+const RECONNECT_ATTEMPTS: u32 = 6;
 
-static DEFAULT_SERVER: &str = "[::1]:50051";
+/// What goes around matches with `--highlight` on a terminal: bold red, then
+/// back to normal.
+const HIGHLIGHT_START: &str = "\x1b[1;31m";
+const HIGHLIGHT_END: &str = "\x1b[0m";
 
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn error::Error>> {
-    let matches = App::new(env!("CARGO_PKG_NAME"))
+async fn main() {
+    // Printed as the message rather than the Debug form main would use.
+    if let Err(e) = run().await {
+        eprintln!("Error: {}", e);
+        process::exit(1);
+    }
+}
+
+async fn run() -> Result<(), Box<dyn error::Error>> {
+    let addr_help = format!(
+        "Override the default server to connect to: {}. Given more than once, queries every server and merges the results.",
+        DEFAULT_SERVER
+    );
+    let matches = app(&addr_help).get_matches();
+    if matches.is_present("completions") {
+        let shell = value_t!(matches, "completions", Shell).unwrap_or_else(|e| e.exit());
+        app(&addr_help).gen_completions_to(env!("CARGO_PKG_NAME"), shell, &mut io::stdout());
+        return Ok(());
+    }
+
+    let addrs: Vec<&str> = match matches.values_of("addr") {
+        Some(addrs) => addrs.collect(),
+        None => vec![DEFAULT_SERVER],
+    };
+    if addrs.len() > 1 {
+        let single = [
+            "stats",
+            "roots",
+            "compact",
+            "health",
+            "snapshot",
+            "recent",
+            "inspect",
+            "find-marker",
+            "pause",
+            "resume",
+            "reindex",
+            "reindex-file",
+            "verify",
+            "duplicates",
+            "duplicate-content",
+            "dirs",
+            "open",
+            "highlight",
+        ];
+        if let Some(arg) = single.iter().find(|a| matches.is_present(a)) {
+            eprintln!("--{} can only be used with one --addr", arg);
+            process::exit(1);
+        }
+    }
+
+    let mut daemons = Daemons::default();
+    for (addr, client) in addrs.iter().zip(lookr_client::connect_all(&addrs).await) {
+        match client {
+            Ok(c) => {
+                check_version(&c).await;
+                let mut c = authenticate(c).await;
+                // A session outlives restarts of the daemon.
+                if matches.is_present("interactive") {
+                    c = c.with_reconnect(RECONNECT_ATTEMPTS);
+                }
+                daemons.hosts.push(addr.to_string());
+                daemons.clients.push(c);
+            }
+            Err(e @ lookr_client::Error::InvalidAddress(_)) => {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
+            Err(e) if addrs.len() == 1 => return Err(e.into()),
+            // Search the daemons that are up.
+            Err(e) => eprintln!("Warning: skipping {}: {}", addr, e),
+        }
+    }
+    if daemons.clients.is_empty() {
+        eprintln!("Could not connect to any of the daemons");
+        process::exit(1);
+    }
+    let client = &daemons.clients[0];
+
+    if matches.is_present("stats") {
+        let stats = client.stats().await?;
+        println!("Documents: {}", stats.num_docs);
+        if let Some(e) = &stats.walk_errors {
+            println!(
+                "Walk errors: {} permission denied, {} not found, {} filesystem loops, {} other",
+                e.permission_denied, e.not_found, e.loops, e.other
+            );
+        }
+        return Ok(());
+    }
+
+    if matches.is_present("roots") {
+        for root in &client.list_roots().await? {
+            if root.last_indexed == 0 {
+                println!("{}\t{} docs\tindexing", root.path, root.doc_count);
+            } else {
+                println!(
+                    "{}\t{} docs\tindexed at {}",
+                    root.path, root.doc_count, root.last_indexed
+                );
+            }
+        }
+        return Ok(());
+    }
+
+    if matches.is_present("compact") {
+        let resp = client.compact().await.map_err(explain)?;
+        println!(
+            "Compacted index from {} to {} segments",
+            resp.segments_before, resp.segments_after
+        );
+        return Ok(());
+    }
+
+    if let Some(dir) = matches.value_of("snapshot") {
+        // The daemon's working directory isn't ours.
+        let dir = env::current_dir()?.join(dir);
+        let path = client
+            .snapshot(&dir.to_string_lossy())
+            .await
+            .map_err(explain)?;
+        println!("Wrote snapshot to {}", path);
+        return Ok(());
+    }
+
+    if matches.is_present("health") {
+        let health = client.health_check().await?;
+        println!("Healthy: {}", health.healthy);
+        if health.watcher_stopped {
+            println!("The watcher has stopped, restart the daemon to pick up changes again");
+        }
+        println!(
+            "Watch queue full {} times, {} changes dropped",
+            health.queue_full, health.dropped_events
+        );
+        for root in &health.dirty_roots {
+            println!("Waiting to walk again: {}", root);
+        }
+        for root in &health.unwatched_roots {
+            println!("Not watched for changes: {}", root);
+        }
+        if health.paused {
+            println!("Indexing is paused, lookr --resume to catch up");
+        }
+        if !health.ready {
+            println!("Still indexing the index paths, queries may not find everything yet");
+        }
+        return Ok(());
+    }
+
+    if matches.is_present("pause") {
+        if client.pause().await?.was_paused {
+            println!("Indexing was already paused");
+        } else {
+            println!("Indexing paused, lookr --resume to catch up");
+        }
+        return Ok(());
+    }
+
+    if matches.is_present("resume") {
+        if client.resume().await?.was_paused {
+            println!("Indexing resumed");
+        } else {
+            println!("Indexing wasn't paused");
+        }
+        return Ok(());
+    }
+
+    if matches.is_present("reindex") {
+        for root in client.reindex("").await?.roots {
+            println!("Waiting to walk again: {}", root);
+        }
+        return Ok(());
+    }
+
+    if let Some(path) = matches.value_of("reindex-file") {
+        // The daemon's working directory isn't ours.
+        let path = paths::normalize(&env::current_dir()?.join(path));
+        let path = path.to_string_lossy();
+        let resp = client.reindex_file(&path).await.map_err(explain)?;
+        match resp.outcome() {
+            ReindexOutcome::Added => println!("Added to the index: {}", path),
+            ReindexOutcome::Updated => println!("Updated: {}", path),
+            ReindexOutcome::Removed => println!("Removed from the index: {}", path),
+        }
+        return Ok(());
+    }
+
+    if matches.is_present("verify") {
+        let resp = client.verify().await.map_err(explain)?;
+        println!("Indexed: {}, found: {}", resp.indexed, resp.found);
+        println!("Indexed but gone: {}", resp.missing);
+        for path in &resp.missing_sample {
+            println!("  {}", path);
+        }
+        println!("Found but not indexed: {}", resp.unindexed);
+        for path in &resp.unindexed_sample {
+            println!("  {}", path);
+        }
+        println!("Indexed more than once: {}", resp.duplicates);
+        if resp.missing + resp.unindexed + resp.duplicates > 0 {
+            println!("The index has drifted, lookr --reindex to catch up");
+        }
+        return Ok(());
+    }
+
+    if matches.is_present("duplicates") {
+        let mut req = FindDuplicateNamesReq {
+            kind: matches.value_of("kind").unwrap_or_default().to_string(),
+            ..Default::default()
+        };
+        loop {
+            let resp = client
+                .find_duplicate_names(req.clone())
+                .await
+                .map_err(explain)?;
+            for group in &resp.groups {
+                println!("{} ({})", group.name, group.count);
+                for path in &group.paths {
+                    println!("  {}", path);
+                }
+                let more = group.count - group.paths.len() as u64;
+                if more > 0 {
+                    println!("  ... and {} more", more);
+                }
+            }
+            if resp.next.is_empty() {
+                return Ok(());
+            }
+            req.after = resp.next;
+        }
+    }
+
+    if matches.is_present("duplicate-content") {
+        let mut req = FindDuplicateContentReq::default();
+        loop {
+            let resp = client
+                .find_duplicate_content(req.clone())
+                .await
+                .map_err(explain)?;
+            for group in &resp.groups {
+                println!("{} ({})", group.hash, group.count);
+                for path in &group.paths {
+                    println!("  {}", path);
+                }
+                let more = group.count - group.paths.len() as u64;
+                if more > 0 {
+                    println!("  ... and {} more", more);
+                }
+            }
+            if resp.next.is_empty() {
+                return Ok(());
+            }
+            req.after = resp.next;
+        }
+    }
+
+    if matches.is_present("recent") {
+        let resp = client.recent_queries(0).await?;
+        if !resp.enabled {
+            eprintln!("The daemon isn't keeping queries, set query_history in its config");
+            process::exit(1);
+        }
+        println!("Recent queries:");
+        for q in &resp.recent {
+            println!("{}\t{} hits\t{}", q.timestamp, q.total_hits, q.query);
+        }
+        println!("Most frequent:");
+        for q in &resp.top {
+            println!("{}\t{}", q.count, q.query);
+        }
+        return Ok(());
+    }
+
+    if let Some(path) = matches.value_of("inspect") {
+        // The daemon's working directory isn't ours.
+        let path = paths::normalize(&env::current_dir()?.join(path));
+        let path = path.to_string_lossy();
+        let doc = client.get_doc(&path).await.map_err(explain)?;
+        if !doc.found {
+            eprintln!("{} isn't indexed", path);
+            process::exit(1);
+        }
+        for field in &doc.fields {
+            println!("{}: {}", field.name, field.value);
+        }
+        return Ok(());
+    }
+
+    if let Some(marker) = matches.value_of("find-marker") {
+        let start = paths::normalize(&env::current_dir()?);
+        let resp = client
+            .find_marker(&start.to_string_lossy(), marker)
+            .await
+            .map_err(explain)?;
+        if !resp.found {
+            eprintln!("No {} indexed in {} or above it", marker, start.display());
+            process::exit(1);
+        }
+        println!("{}", resp.dir);
+        return Ok(());
+    }
+
+    if matches.is_present("interactive") {
+        return interactive(&daemons, &matches).await;
+    }
+
+    let query = matches.value_of("QUERY").unwrap();
+    if matches.is_present("open") {
+        return query_and_open(client, query, &matches).await;
+    }
+    let printed = query_and_print(&daemons, query, &matches).await?;
+    // As grep does, so scripts can tell whether anything matched.
+    process::exit(exit_code(printed));
+}
+
+/// Open the one result in `$EDITOR`, or the one chosen from a list when
+/// there are several.
+async fn query_and_open(
+    client: &LookrClient,
+    query: &str,
+    matches: &ArgMatches<'_>,
+) -> Result<(), Box<dyn error::Error>> {
+    let mut req = query_req(query, matches)?;
+    if req.count == 0 {
+        req.count = open::CHOICES;
+    }
+    let resp = client.query(req).await.map_err(explain)?;
+    let paths: Vec<String> = resp.hits.into_iter().map(|h| h.path).collect();
+    if paths.is_empty() {
+        eprintln!("Nothing matched {:?}", query);
+        process::exit(1);
+    }
+    let editor = match open::editor(env::var_os("EDITOR")) {
+        Some(e) => e,
+        None => {
+            for path in &paths {
+                println!("{}", path);
+            }
+            eprintln!("Set $EDITOR to open results with, e.g. EDITOR=vim");
+            process::exit(1);
+        }
+    };
+
+    let path = if paths.len() == 1 {
+        &paths[0]
+    } else {
+        let style = path_style(matches)?;
+        let choices: Vec<String> = paths.iter().map(|p| style.format(p)).collect();
+        if resp.truncated {
+            print_truncated(resp.total_hits, paths.len());
+        }
+        let stdin = io::stdin();
+        match open::choose(&choices, &mut stdin.lock(), &mut io::stderr())? {
+            Some(i) => &paths[i],
+            None => return Ok(()),
+        }
+    };
+    let status =
+        open::open(&editor, path).map_err(|e| format!("Could not run {}: {}", editor[0], e))?;
+    if !status.success() {
+        process::exit(status.code().unwrap_or(1));
+    }
+    Ok(())
+}
+
+/// 1 if nothing was printed, otherwise 0.
+fn exit_code(printed: usize) -> i32 {
+    if printed == 0 {
+        1
+    } else {
+        0
+    }
+}
+
+/// The command line, also used to generate the shell completions. These are
+/// static, so they can't offer paths from the index; that would need the
+/// daemon to answer completion requests.
+fn app(addr_help: &str) -> App<'static, '_> {
+    App::new(env!("CARGO_PKG_NAME"))
         .setting(AppSettings::ColoredHelp)
         .version(env!("CARGO_PKG_VERSION"))
         .author(env!("CARGO_PKG_AUTHORS"))
@@ -16,43 +415,855 @@ async fn main() -> Result<(), Box<dyn error::Error>> {
         .arg(
             Arg::with_name("QUERY")
                 .help("The query to run against the index.")
-                .required(true)
+                .required_unless_one(&["stats", "roots", "compact", "health", "snapshot", "recent", "inspect", "find-marker", "pause", "resume", "reindex", "reindex-file", "verify", "duplicates", "duplicate-content", "interactive", "completions"])
                 .index(1),
         )
+        .arg(
+            Arg::with_name("stats")
+                .long("stats")
+                .help("Print stats about the daemon's index instead of querying it.")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("interactive")
+                .short("i")
+                .long("interactive")
+                .help("Read queries from a prompt, running each one as it is entered.")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("name")
+                .short("n")
+                .long("name")
+                .help("Only match the query against file names, not their directories.")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("tag")
+                .short("t")
+                .long("tag")
+                .help("Only match files with this tag.")
+                .takes_value(true)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("kind")
+                .short("k")
+                .long("kind")
+                .help("Only match entries of this kind.")
+                .takes_value(true)
+                .possible_values(&["file", "dir", "symlink", "other"])
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("group")
+                .short("g")
+                .long("group")
+                .value_name("NAME")
+                .help("Only match files with an extension in this group from the daemon's extension_groups.")
+                .takes_value(true)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("ext")
+                .long("ext")
+                .value_name("EXT,...")
+                .help("Only match files with one of these extensions, e.g. --ext rs,toml.")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .use_delimiter(true)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("not_ext")
+                .long("not-ext")
+                .value_name("EXT,...")
+                .help("Leave out files with any of these extensions, e.g. --not-ext lock.")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .use_delimiter(true)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("substring")
+                .short("s")
+                .long("substring")
+                .help("Match the query anywhere in file names, if the daemon indexes substrings.")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("within")
+                .long("in")
+                .value_name("DIR")
+                .help("Only match entries under this directory.")
+                .takes_value(true)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("depth")
+                .long("depth")
+                .value_name("N")
+                .help("Only match entries N levels below their index path, e.g. --depth 1 for those directly in it.")
+                .takes_value(true)
+                .validator(positive_depth)
+                .conflicts_with("max_depth")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("max_depth")
+                .long("max-depth")
+                .value_name("N")
+                .help("Only match entries at most N levels below their index path.")
+                .takes_value(true)
+                .validator(positive_depth)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("wait")
+                .long("wait")
+                .help("If lookrd is still indexing after starting, wait for it to finish rather than answer from what it's indexed so far.")
+                .takes_value(false)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("same_file_as")
+                .long("same-file-as")
+                .value_name("PATH")
+                .help("Only match the other hard links to this file, e.g. --same-file-as big.iso \"\". Needs index_inodes in lookrd's config.")
+                .takes_value(true)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("keep_stop_words")
+                .long("keep-stop-words")
+                .help("Match the stop words in lookrd's config, rather than leaving them out of the query.")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("mine")
+                .long("mine")
+                .help("Only match files you own. Needs index_owners in lookrd's config.")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("xattr")
+                .long("xattr")
+                .value_name("NAME[=VALUE]")
+                .help("Only match files with this extended attribute, e.g. --xattr user.project=lookr. Needs the attribute in index_xattrs in lookrd's config.")
+                .takes_value(true)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("boost")
+                .long("boost")
+                .value_name("FIELD=FACTOR")
+                .help("Rank matches in path, filename or ext this many times higher, e.g. --boost filename=3. Boosting filename or ext also matches against them.")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .validator(|v| boosts(&[v.as_str()]).map(|_| ()))
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("first_line")
+                .long("first-line")
+                .alias("interpreter")
+                .help("Only match files whose first line matches this query, e.g. --interpreter bash.")
+                .takes_value(true)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("sort")
+                .long("sort")
+                .help("The order to print results in, shallowest paths first for depth.")
+                .takes_value(true)
+                .possible_values(&["relevance", "depth", "alpha"])
+                .default_value("relevance"),
+        )
+        .arg(
+            Arg::with_name("scores")
+                .long("scores")
+                .help("Print how well each result matched before it. Ignored with more than one server, as their scores can't be compared.")
+                .conflicts_with("dirs")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("quiet")
+                .short("q")
+                .long("quiet")
+                .help("Print nothing but the results, leaving out how many more there are.")
+                .conflicts_with_all(&["verbose", "scores"])
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("verbose")
+                .short("v")
+                .long("verbose")
+                .help("Print each result's score before it, and what else the daemon keeps about it, like its mtime, after it. Ignored with more than one server.")
+                .conflicts_with("dirs")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("open")
+                .short("o")
+                .long("open")
+                .help("Open the result in $EDITOR, choosing from a numbered list if there are several, instead of printing them.")
+                .conflicts_with_all(&["dirs", "output", "null", "interactive"])
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("null")
+                .short("0")
+                .long("null")
+                .help("End each result with a NUL rather than a newline, for xargs -0.")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("output")
+                .long("output")
+                .value_name("FILE")
+                .help("Write the results to this file, with a header, instead of printing them.")
+                .takes_value(true)
+                .conflicts_with("null"),
+        )
+        .arg(
+            Arg::with_name("format")
+                .long("format")
+                .help(
+                    "The format of the --output file, or proto for each result as a \
+                     length-delimited protobuf SearchResult, on stdout without --output.",
+                )
+                .takes_value(true)
+                .possible_values(&["csv", "tsv", "proto"])
+                .requires_ifs(&[("csv", "output"), ("tsv", "output")]),
+        )
+        .arg(
+            Arg::with_name("dirs")
+                .long("dirs")
+                .help("Print the number of matches in each directory instead of the matches.")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("relative_to")
+                .long("relative-to")
+                .value_name("DIR")
+                .help("Print paths relative to this directory, e.g. --relative-to .")
+                .takes_value(true)
+                .conflicts_with("tilde")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("tilde")
+                .long("tilde")
+                .help("Print paths under the home directory starting with ~.")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("highlight")
+                .long("highlight")
+                .help(
+                    "Mark where the query matched in each path, in bold red on a terminal \
+                     and between ** otherwise.",
+                )
+                .conflicts_with_all(&["relative_to", "tilde", "dirs"])
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("compact")
+                .long("compact")
+                .help("Merge the daemon's index segments instead of querying it.")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("health")
+                .long("health")
+                .help("Check the daemon is keeping up with filesystem changes instead of querying it.")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("recent")
+                .long("recent")
+                .help("List the queries the daemon ran recently, and the most frequent, instead of querying it.")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("pause")
+                .long("pause")
+                .help("Have the daemon hold on to changes rather than indexing them, until --resume.")
+                .conflicts_with("resume")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("resume")
+                .long("resume")
+                .help("Have the daemon index the changes held since --pause, and carry on.")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("verify")
+                .long("verify")
+                .help("Have the daemon compare what it has indexed with what's on disk, without changing anything.")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("duplicate-content")
+                .long("duplicate-content")
+                .help("List the files with the same contents, grouped by their hash, instead of querying. Needs content_hash_max_size in the daemon's config.")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("duplicates")
+                .long("duplicates")
+                .help("List the names shared by more than one indexed entry, with their paths, instead of querying. Honours --kind.")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("reindex")
+                .long("reindex")
+                .help("Have the daemon walk all of its index paths again, for changes it missed.")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("reindex-file")
+                .long("reindex-file")
+                .value_name("PATH")
+                .help("Have the daemon index a path again, or remove it if it's gone, for a change it missed.")
+                .takes_value(true)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("inspect")
+                .long("inspect")
+                .value_name("PATH")
+                .help("Print what the daemon has stored in its index for a path instead of querying it.")
+                .takes_value(true)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("find-marker")
+                .long("find-marker")
+                .value_name("NAME")
+                .help("Print the nearest directory at or above this one with NAME indexed in it, like Cargo.toml or .git, instead of querying.")
+                .takes_value(true)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("snapshot")
+                .long("snapshot")
+                .value_name("DIR")
+                .help("Have the daemon copy its index to a new directory instead of querying it.")
+                .takes_value(true)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("roots")
+                .long("roots")
+                .help("List the paths the daemon is indexing instead of querying it.")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("limit")
+                .short("l")
+                .long("limit")
+                .help("The most results to print.")
+                .takes_value(true)
+                .validator(|v| {
+                    v.parse::<i32>()
+                        .ok()
+                        .filter(|l| *l > 0)
+                        .map(|_| ())
+                        .ok_or_else(|| "must be a positive number".to_string())
+                })
+                .required(false),
+        )
         .arg(
             Arg::with_name("addr")
                 .short("a")
                 .long("addr")
-                .help(
-                    format!(
-                        "Override the default server to connect to: {}",
-                        DEFAULT_SERVER
-                    )
-                    .as_str(),
-                )
+                .help(addr_help)
                 .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
                 .required(false)
                 .global(true),
         )
-        .get_matches();
+        .arg(
+            Arg::with_name("completions")
+                .long("generate-completions")
+                .value_name("SHELL")
+                .help("Print the completion script for this shell and exit.")
+                .possible_values(&Shell::variants())
+                .hidden(true),
+        )
+}
 
-    let query = matches.value_of("QUERY").unwrap();
+/// The daemons that could be connected to, and the addresses they were
+/// given as.
+#[derive(Default)]
+struct Daemons {
+    hosts: Vec<String>,
+    clients: Vec<LookrClient>,
+}
+
+/// Warn if the daemon may not understand this client. Anything else going
+/// wrong is left to the request that follows to report.
+async fn check_version(client: &LookrClient) {
+    match client.version().await {
+        Ok(version) if !lookr_client::compatible(&version) => eprintln!(
+            "Warning: lookrd is version {} but lookr is version {}, requests may fail.",
+            version, VERSION
+        ),
+        Err(lookr_client::Error::Rpc(status))
+            if status.code() == lookr_client::Code::Unimplemented =>
+        {
+            eprintln!(
+                "Warning: lookrd is older than lookr {}, requests may fail.",
+                VERSION
+            )
+        }
+        _ => (),
+    }
+}
 
-    let server = matches.value_of("addr").unwrap_or(DEFAULT_SERVER);
-    let mut client = LookrClient::connect(format!("http://{}", server)).await?;
+/// Prove who we are to a daemon serving several users. The client is used
+/// as it is if that fails, leaving the requests to report why.
+async fn authenticate(client: LookrClient) -> LookrClient {
+    let user = match env::var("USER").or_else(|_| env::var("LOGNAME")) {
+        Ok(user) => user,
+        Err(_) => return client,
+    };
+    match client.authenticate(&user).await {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("Warning: could not authenticate as {}: {}", user, e);
+            client
+        }
+    }
+}
 
-    let req = Request::new(QueryReq {
+/// Prompt for queries until EOF, running each against the same connection.
+async fn interactive(
+    daemons: &Daemons,
+    matches: &ArgMatches<'_>,
+) -> Result<(), Box<dyn error::Error>> {
+    let mut rl = DefaultEditor::new()?;
+    loop {
+        match rl.readline("lookr> ") {
+            Ok(line) => {
+                let query = line.trim();
+                if query.is_empty() {
+                    continue;
+                }
+                rl.add_history_entry(query)?;
+                if let Err(e) = query_and_print(daemons, query, matches).await {
+                    eprintln!("Error: {}", e);
+                }
+            }
+            // Ctrl-C discards the current line, Ctrl-D exits.
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => return Ok(()),
+            Err(e) => return Err(Box::new(e)),
+        }
+    }
+}
+
+/// The query request the flags ask for.
+fn query_req(query: &str, matches: &ArgMatches<'_>) -> io::Result<QueryReq> {
+    let directories = matches.is_present("dirs");
+    let verbose = matches.is_present("verbose");
+    let scores = verbose || matches.is_present("scores");
+    // Checked by the argument's validator.
+    let limit = matches
+        .value_of("limit")
+        .map_or(0, |l| l.parse::<i32>().unwrap());
+    let sort_by = match matches.value_of("sort") {
+        Some("depth") => SortBy::PathDepth,
+        Some("alpha") => SortBy::Alphabetical,
+        _ => SortBy::Relevance,
+    };
+    // The daemon's working directory isn't ours.
+    let absolute = |arg| -> io::Result<String> {
+        match matches.value_of(arg) {
+            Some(path) => Ok(paths::normalize(&env::current_dir()?.join(path))
+                .to_string_lossy()
+                .to_string()),
+            None => Ok(String::new()),
+        }
+    };
+    // Checked by the arguments' validators.
+    let depth = |arg| {
+        matches
+            .value_of(arg)
+            .map_or(0, |d| d.parse::<u32>().unwrap())
+    };
+    let (min_depth, max_depth) = match depth("depth") {
+        0 => (0, depth("max_depth")),
+        exactly => (exactly, exactly),
+    };
+    let values = |arg| -> Vec<String> {
+        matches
+            .values_of(arg)
+            .map(|v| v.map(str::to_string).collect())
+            .unwrap_or_default()
+    };
+    let highlight = matches.is_present("highlight");
+    let (highlight_start, highlight_end) = if highlight && io::stdout().is_terminal() {
+        (HIGHLIGHT_START.to_string(), HIGHLIGHT_END.to_string())
+    } else {
+        // The daemon's own markers.
+        (String::new(), String::new())
+    };
+    Ok(QueryReq {
         secret: String::new(),
         query: query.to_string(),
-        count: 0,
+        count: limit,
         offset: 0,
-    });
+        directories,
+        filename_only: matches.is_present("name"),
+        tag: matches.value_of("tag").unwrap_or_default().to_string(),
+        kind: matches.value_of("kind").unwrap_or_default().to_string(),
+        sort_by: sort_by as i32,
+        first_line: matches
+            .value_of("first_line")
+            .unwrap_or_default()
+            .to_string(),
+        within: absolute("within")?,
+        substring: matches.is_present("substring"),
+        // Checked by the argument's validator.
+        boosts: matches
+            .values_of("boost")
+            .map(|v| boosts(&v.collect::<Vec<_>>()).unwrap()),
+        same_file_as: absolute("same_file_as")?,
+        owned_by_me: matches.is_present("mine"),
+        keep_stop_words: matches.is_present("keep_stop_words"),
+        ext_group: matches.value_of("group").unwrap_or_default().to_string(),
+        xattr: matches.value_of("xattr").unwrap_or_default().to_string(),
+        extensions: values("ext"),
+        exclude_extensions: values("not_ext"),
+        highlight,
+        highlight_start,
+        highlight_end,
+        min_depth,
+        max_depth,
+        wait_for_ready: matches.is_present("wait"),
+        // Scores are only shown if asked for.
+        fields: if verbose {
+            vec!["score".to_string(), "mtime".to_string()]
+        } else if scores {
+            vec![]
+        } else {
+            vec!["path".to_string()]
+        },
+    })
+}
 
-    let resp = client.query(req).await?;
+/// Returns the number of results printed.
+async fn query_and_print(
+    daemons: &Daemons,
+    query: &str,
+    matches: &ArgMatches<'_>,
+) -> Result<usize, Box<dyn error::Error>> {
+    let directories = matches.is_present("dirs");
+    let quiet = matches.is_present("quiet");
+    let verbose = matches.is_present("verbose");
+    let scores = verbose || matches.is_present("scores");
+    let req = query_req(query, matches)?;
 
-    for r in &resp.get_ref().results {
-        println!("{}", r);
+    let style = path_style(matches)?;
+    let output = match matches.value_of("output") {
+        path if matches.value_of("format") == Some("proto") => Output::Proto(path.map(Path::new)),
+        // Checked by the argument's possible values.
+        Some(path) => Output::Export(
+            Path::new(path),
+            Format::from_name(matches.value_of("format").unwrap_or("csv")).unwrap(),
+        ),
+        None if matches.is_present("null") => Output::Print('\0'),
+        None => Output::Print('\n'),
+    };
+    if daemons.clients.len() > 1 {
+        return query_all_and_print(daemons, &req, &style, &output, quiet).await;
+    }
+    let resp = daemons.clients[0].query(req).await.map_err(explain)?;
+    if resp.warming_up && !quiet {
+        print_warming_up("lookrd");
     }
 
-    Ok(())
+    if directories {
+        let mut dirs = resp.directories;
+        dirs.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.path.cmp(&b.path)));
+        if let Output::Proto(path) = output {
+            for d in &mut dirs {
+                d.path = style.format(&d.path);
+            }
+            export::export_messages(path, &dirs)?;
+            return Ok(dirs.len());
+        }
+        let rows = dirs
+            .iter()
+            .map(|d| vec![d.count.to_string(), style.format(&d.path)])
+            .collect();
+        output.write(&["count", "path"], rows)?;
+        // Only the directories are wanted by whatever reads NULs.
+        if let (Output::Print('\n'), false) = (&output, quiet) {
+            let total: u64 = dirs.iter().map(|d| d.count).sum();
+            println!("{} matches across {} directories", total, dirs.len());
+        }
+        return Ok(dirs.len());
+    }
+
+    if let Output::Proto(path) = output {
+        let mut hits = resp.hits;
+        for h in &mut hits {
+            h.path = style.format(&h.path);
+        }
+        export::export_messages(path, &hits)?;
+        if resp.truncated && !quiet {
+            print_truncated(resp.total_hits, hits.len());
+        }
+        return Ok(hits.len());
+    }
+
+    let mut header = vec!["path"];
+    if scores {
+        header.insert(0, "score");
+    }
+    if verbose {
+        header.push("metadata");
+    }
+    let rows = resp
+        .hits
+        .iter()
+        .map(|h| {
+            let mut row = Vec::with_capacity(header.len());
+            if scores {
+                row.push(format!("{:.3}", h.score));
+            }
+            if h.highlighted.is_empty() {
+                row.push(style.format(&h.path));
+            } else {
+                // Always absolute, as the other path styles conflict with it.
+                row.push(h.highlighted.clone());
+            }
+            if verbose {
+                row.push(metadata(&h.metadata));
+            }
+            row
+        })
+        .collect();
+    output.write(&header, rows)?;
+    if resp.truncated && !quiet {
+        print_truncated(resp.total_hits, resp.hits.len());
+    }
+
+    Ok(resp.hits.len())
+}
+
+/// The metadata as `key=value` pairs, in order of key.
+fn metadata(metadata: &HashMap<String, String>) -> String {
+    let mut pairs: Vec<String> = metadata
+        .iter()
+        .map(|(key, value)| format!("{}={}", key, value))
+        .collect();
+    pairs.sort();
+    pairs.join(" ")
+}
+
+/// Run the query against every daemon, printing the merged results with the
+/// address of the daemon each came from.
+async fn query_all_and_print(
+    daemons: &Daemons,
+    req: &QueryReq,
+    style: &PathStyle,
+    output: &Output<'_>,
+    quiet: bool,
+) -> Result<usize, Box<dyn error::Error>> {
+    let mut hosts = Vec::new();
+    let mut responses = Vec::new();
+    let results = lookr_client::query_all(&daemons.clients, req).await;
+    for (host, result) in daemons.hosts.iter().zip(results) {
+        match result {
+            Ok(resp) => {
+                if resp.warming_up && !quiet {
+                    print_warming_up(host);
+                }
+                hosts.push(host);
+                responses.push(resp);
+            }
+            Err(e) => eprintln!("Warning: {} failed: {}", host, explain(e)),
+        }
+    }
+    if responses.is_empty() {
+        return Err("None of the daemons could run the query".into());
+    }
+
+    let limit = if req.count > 0 {
+        Some(req.count as usize)
+    } else {
+        None
+    };
+    let merged = lookr_client::merge_results(&responses, limit);
+    let rows = merged
+        .iter()
+        .map(|(i, path)| vec![hosts[*i].to_string(), style.format(path)])
+        .collect();
+    output.write(&["host", "path"], rows)?;
+    // The daemons' matches that weren't merged count too.
+    if !quiet {
+        let total_hits: u64 = responses.iter().map(|r| r.total_hits).sum();
+        print_truncated(total_hits, merged.len());
+    }
+    Ok(merged.len())
+}
+
+/// Where the results go.
+enum Output<'a> {
+    /// To stdout, each row's fields separated by tabs, and the row followed
+    /// by this.
+    Print(char),
+    /// To a new file, with a header row.
+    Export(&'a Path, Format),
+    /// As length-delimited protobuf messages, to a new file or stdout.
+    /// Only the results of a single daemon can be written this way.
+    Proto(Option<&'a Path>),
+}
+
+impl Output<'_> {
+    fn write(&self, header: &[&str], rows: Vec<Vec<String>>) -> io::Result<()> {
+        match self {
+            Output::Print(end) => {
+                for row in rows {
+                    print!("{}{}", row.join("\t"), end);
+                }
+                Ok(())
+            }
+            Output::Export(path, format) => export::export(path, *format, header, &rows),
+            Output::Proto(_) => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "--format proto can only be used with one --addr",
+            )),
+        }
+    }
+}
+
+/// Say the daemon's first walk isn't done, so there may be matches it
+/// hasn't found yet.
+fn print_warming_up(daemon: &str) {
+    eprintln!(
+        "{} is still indexing, there may be more matches (--wait to wait for it)",
+        daemon
+    );
+}
+
+/// Say how many matches weren't printed, on stderr so it doesn't end up
+/// with the results.
+fn print_truncated(total_hits: u64, printed: usize) {
+    let more = total_hits.saturating_sub(printed as u64);
+    if more > 0 {
+        eprintln!("... ({} more results, refine your query)", more);
+    }
+}
+
+/// The boosts given as `field=factor`.
+fn boosts(values: &[&str]) -> Result<FieldBoosts, String> {
+    let mut boosts = FieldBoosts::default();
+    for value in values {
+        let (field, factor) = value
+            .split_once('=')
+            .ok_or_else(|| format!("expected FIELD=FACTOR, got {:?}", value))?;
+        let factor = factor
+            .parse::<f32>()
+            .ok()
+            .filter(|f| f.is_finite() && *f > 0.0)
+            .ok_or_else(|| format!("{:?} is not a positive number", factor))?;
+        match field {
+            "path" => boosts.path = factor,
+            "filename" => boosts.filename = factor,
+            "ext" => boosts.ext = factor,
+            _ => {
+                return Err(format!(
+                    "unknown field {:?}, expected path, filename or ext",
+                    field
+                ))
+            }
+        }
+    }
+    Ok(boosts)
+}
+
+/// Checks a depth is a whole number of levels, at least 1. The index paths
+/// themselves, at depth 0, are listed by --roots.
+fn positive_depth(value: String) -> Result<(), String> {
+    value
+        .parse::<u32>()
+        .ok()
+        .filter(|d| *d > 0)
+        .map(|_| ())
+        .ok_or_else(|| "must be a positive number".to_string())
+}
+
+/// Adds what can be done about a failure to the daemon's message, going by
+/// its error code.
+fn explain(e: lookr_client::Error) -> Box<dyn error::Error> {
+    let hint = match e.code() {
+        Some(ErrorCode::QueryParse) => {
+            "Check the query, quoting terms with punctuation in them, e.g. \"my-file\"."
+        }
+        Some(ErrorCode::Timeout) => "Try a more specific query, or a smaller --limit.",
+        Some(ErrorCode::IndexerBusy) => "Try again shortly.",
+        Some(ErrorCode::IndexUnavailable) | Some(ErrorCode::IndexerStopped) => {
+            "Check the lookrd logs, it may need restarting."
+        }
+        Some(ErrorCode::Unauthenticated) => {
+            "lookrd serves several users, check it keeps an index for $USER."
+        }
+        _ => return e.into(),
+    };
+    format!("{}\n{}", e, hint).into()
+}
+
+fn path_style(matches: &ArgMatches<'_>) -> Result<PathStyle, Box<dyn error::Error>> {
+    if let Some(dir) = matches.value_of("relative_to") {
+        // The daemon's paths are absolute, so the base has to be too.
+        return Ok(PathStyle::RelativeTo(env::current_dir()?.join(dir)));
+    }
+    if matches.is_present("tilde") {
+        if let Some(home) = env::var_os("HOME") {
+            return Ok(PathStyle::Tilde(home.into()));
+        }
+    }
+    Ok(PathStyle::Absolute)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_exit_code() {
+        assert_eq!(exit_code(0), 1);
+        assert_eq!(exit_code(1), 0);
+        assert_eq!(exit_code(100), 0);
+    }
+
+    #[test]
+    fn test_metadata() {
+        let mut m = HashMap::new();
+        assert_eq!(metadata(&m), "");
+        m.insert("size".to_string(), "10".to_string());
+        m.insert("mtime".to_string(), "1600000000".to_string());
+        assert_eq!(metadata(&m), "mtime=1600000000 size=10");
+    }
+
+    #[test]
+    fn test_completions() {
+        let mut script = Vec::new();
+        app("").gen_completions_to(env!("CARGO_PKG_NAME"), Shell::Bash, &mut script);
+        let script = String::from_utf8(script).unwrap();
+        assert!(script.contains("_lookr()"));
+        for flag in ["--max-depth", "--highlight", "--format"] {
+            assert!(script.contains(flag), "{}", flag);
+        }
+    }
 }
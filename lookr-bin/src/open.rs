@@ -0,0 +1,100 @@
+//! Opening a result in the user's editor, chosen from a numbered list when
+//! the query matched more than one.
+
+use std::ffi::OsString;
+use std::io::{self, BufRead, Write};
+use std::process::{Command, ExitStatus};
+
+/// The most results listed to choose from, unless `--limit` says otherwise.
+pub(crate) const CHOICES: i32 = 20;
+
+/// The editor command in `$EDITOR`, split into the program and its
+/// arguments, like `code --wait`. `None` if it's unset or blank.
+pub(crate) fn editor(var: Option<OsString>) -> Option<Vec<String>> {
+    let var = var?;
+    let words: Vec<String> = var
+        .to_string_lossy()
+        .split_whitespace()
+        .map(str::to_string)
+        .collect();
+    if words.is_empty() {
+        None
+    } else {
+        Some(words)
+    }
+}
+
+/// List the results, numbered from 1, and ask which to open until given a
+/// number in range. Returns its index, or `None` if nothing was entered.
+pub(crate) fn choose(
+    choices: &[String],
+    input: &mut impl BufRead,
+    out: &mut impl Write,
+) -> io::Result<Option<usize>> {
+    for (i, choice) in choices.iter().enumerate() {
+        writeln!(out, "{:>3}) {}", i + 1, choice)?;
+    }
+    loop {
+        write!(out, "Open which? [1-{}, Enter to cancel] ", choices.len())?;
+        out.flush()?;
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim();
+        if line.is_empty() {
+            return Ok(None);
+        }
+        match line.parse::<usize>() {
+            Ok(n) if (1..=choices.len()).contains(&n) => return Ok(Some(n - 1)),
+            _ => writeln!(out, "Enter a number from 1 to {}", choices.len())?,
+        }
+    }
+}
+
+/// Run the editor on the path, waiting for it to exit.
+pub(crate) fn open(editor: &[String], path: &str) -> io::Result<ExitStatus> {
+    Command::new(&editor[0])
+        .args(&editor[1..])
+        .arg(path)
+        .status()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_editor() {
+        assert_eq!(editor(None), None);
+        assert_eq!(editor(Some(" ".into())), None);
+        assert_eq!(editor(Some("vim".into())), Some(vec!["vim".to_string()]));
+        assert_eq!(
+            editor(Some("code  --wait".into())),
+            Some(vec!["code".to_string(), "--wait".to_string()])
+        );
+    }
+
+    #[test]
+    fn test_choose() {
+        let choices = vec!["/a.rs".to_string(), "/b.rs".to_string()];
+        let choose = |input: &str| {
+            let mut out = Vec::new();
+            let chosen = choose(&choices, &mut Cursor::new(input), &mut out).unwrap();
+            (chosen, String::from_utf8(out).unwrap())
+        };
+
+        let (chosen, out) = choose("2\n");
+        assert_eq!(chosen, Some(1));
+        assert!(out.starts_with("  1) /a.rs\n  2) /b.rs\n"));
+
+        // Asks again until it gets a number in range.
+        let (chosen, out) = choose("x\n0\n3\n1\n");
+        assert_eq!(chosen, Some(0));
+        assert_eq!(out.matches("Enter a number from 1 to 2").count(), 3);
+
+        assert_eq!(choose("\n").0, None);
+        assert_eq!(choose("").0, None);
+    }
+}
@@ -0,0 +1,108 @@
+//! Shortening the absolute paths lookrd returns for printing.
+
+use std::path::{Component, Path, PathBuf};
+
+/// How result paths are printed.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum PathStyle {
+    /// As the daemon returned them.
+    Absolute,
+    /// Relative to this absolute directory, with `..` to reach paths outside
+    /// it.
+    RelativeTo(PathBuf),
+    /// With the home directory, given here, replaced by `~`.
+    Tilde(PathBuf),
+}
+
+impl PathStyle {
+    pub(crate) fn format(&self, path: &str) -> String {
+        match self {
+            PathStyle::Absolute => path.to_string(),
+            PathStyle::RelativeTo(base) => relative_to(Path::new(path), base)
+                .to_string_lossy()
+                .to_string(),
+            PathStyle::Tilde(home) => tilde(Path::new(path), home),
+        }
+    }
+}
+
+/// Removes `.` and `..` from a path without touching the filesystem, so a
+/// base given as `../foo` compares with the paths in the index.
+pub(crate) fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => (),
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            c => normalized.push(c),
+        }
+    }
+    normalized
+}
+
+/// The path from `base` to `path`, both absolute. If they don't share a root,
+/// `path` is returned as it is.
+fn relative_to(path: &Path, base: &Path) -> PathBuf {
+    let path = normalize(path);
+    let base = normalize(base);
+    let mut path_components = path.components().peekable();
+    let mut base_components = base.components().peekable();
+    if path_components.peek() != base_components.peek() {
+        return path;
+    }
+    while path_components.peek().is_some() && path_components.peek() == base_components.peek() {
+        path_components.next();
+        base_components.next();
+    }
+
+    let mut relative: PathBuf = base_components.map(|_| Component::ParentDir).collect();
+    relative.extend(path_components);
+    if relative.as_os_str().is_empty() {
+        relative.push(Component::CurDir);
+    }
+    relative
+}
+
+/// The path with a leading `home` replaced by `~`.
+fn tilde(path: &Path, home: &Path) -> String {
+    // The root as home would turn every path into ~.
+    if home.parent().is_none() {
+        return path.to_string_lossy().to_string();
+    }
+    match path.strip_prefix(home) {
+        Ok(rest) if rest.as_os_str().is_empty() => "~".to_string(),
+        Ok(rest) => format!("~/{}", rest.to_string_lossy()),
+        Err(_) => path.to_string_lossy().to_string(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_relative_to() {
+        let relative = |path, base| PathStyle::RelativeTo(PathBuf::from(base)).format(path);
+        assert_eq!(relative("/home/me/src/a.rs", "/home/me"), "src/a.rs");
+        assert_eq!(relative("/home/me/src/a.rs", "/home/me/"), "src/a.rs");
+        assert_eq!(relative("/home/me", "/home/me"), ".");
+        assert_eq!(relative("/home/me/a", "/home/me/src/lookr"), "../../a");
+        assert_eq!(relative("/etc/hosts", "/home/me"), "../../etc/hosts");
+        // Only whole components match.
+        assert_eq!(relative("/home/meg/a", "/home/me"), "../meg/a");
+        assert_eq!(relative("/home/me/a", "/home/me/src/../"), "a");
+        assert_eq!(relative("/a", "/"), "a");
+    }
+
+    #[test]
+    fn test_tilde() {
+        let tilde = |path, home| PathStyle::Tilde(PathBuf::from(home)).format(path);
+        assert_eq!(tilde("/home/me/src/a.rs", "/home/me"), "~/src/a.rs");
+        assert_eq!(tilde("/home/me", "/home/me/"), "~");
+        assert_eq!(tilde("/home/meg/a", "/home/me"), "/home/meg/a");
+        assert_eq!(tilde("/etc/hosts", "/home/me"), "/etc/hosts");
+        assert_eq!(tilde("/etc/hosts", "/"), "/etc/hosts");
+    }
+}
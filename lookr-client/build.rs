@@ -0,0 +1,6 @@
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // The proto lives in the daemon's crate, outside this one.
+    println!("cargo:rerun-if-changed=../lookr-daemon/proto/rpc.proto");
+    tonic_build::compile_protos("../lookr-daemon/proto/rpc.proto")?;
+    Ok(())
+}
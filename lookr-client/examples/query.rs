@@ -0,0 +1,29 @@
+//! Prints every path matching the query given as the first argument, reading
+//! the results a page at a time.
+//!
+//!     cargo run -p lookr-client --example query -- report
+
+use futures::TryStreamExt;
+use lookr_client::{LookrClient, QueryReq, DEFAULT_SERVER};
+use std::env;
+use std::time::Duration;
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let query = env::args().nth(1).unwrap_or_default();
+    let client = LookrClient::builder(DEFAULT_SERVER)
+        .timeout(Duration::from_secs(5))
+        .page_size(100)
+        .connect()
+        .await?;
+
+    let req = QueryReq {
+        query,
+        ..Default::default()
+    };
+    let mut results = client.query_stream(req);
+    while let Some(path) = results.try_next().await? {
+        println!("{}", path);
+    }
+    Ok(())
+}
@@ -0,0 +1,18 @@
+//! Prints the number of documents indexed under each of the daemon's roots,
+//! sharing one connection between concurrent requests.
+//!
+//!     cargo run -p lookr-client --example stats
+
+use lookr_client::{LookrClient, DEFAULT_SERVER};
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let client = LookrClient::connect(DEFAULT_SERVER).await?;
+
+    let (stats, roots) = futures::try_join!(client.stats(), client.list_roots())?;
+    println!("{} documents", stats.num_docs);
+    for root in roots {
+        println!("{}\t{}", root.doc_count, root.path);
+    }
+    Ok(())
+}
@@ -0,0 +1,147 @@
+//! A cache of query responses, for programs that send the same query again
+//! and again, like a prompt that queries on every keystroke.
+
+use crate::proto::rpc::{QueryReq, QueryResp};
+use prost::Message;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Responses kept for `ttl` after they were fetched. Past `capacity`, the
+/// least recently used is dropped to make room.
+#[derive(Debug)]
+pub(crate) struct QueryCache {
+    ttl: Duration,
+    capacity: usize,
+    entries: Mutex<Entries>,
+}
+
+#[derive(Debug, Default)]
+struct Entries {
+    responses: HashMap<Vec<u8>, Entry>,
+    /// Counts lookups, to tell which entry was used least recently.
+    clock: u64,
+}
+
+#[derive(Debug)]
+struct Entry {
+    fetched: Instant,
+    used: u64,
+    resp: QueryResp,
+}
+
+impl QueryCache {
+    pub(crate) fn new(ttl: Duration, capacity: usize) -> Self {
+        QueryCache {
+            ttl,
+            capacity: capacity.max(1),
+            entries: Mutex::new(Entries::default()),
+        }
+    }
+
+    /// The response to the same request sent with the same secret, if it was
+    /// fetched within the TTL.
+    pub(crate) fn get(&self, secret: Option<&str>, req: &QueryReq) -> Option<QueryResp> {
+        let key = key(secret, req);
+        let mut entries = self.entries.lock().unwrap();
+        entries.clock += 1;
+        let clock = entries.clock;
+        match entries.responses.get_mut(&key) {
+            Some(entry) if entry.fetched.elapsed() < self.ttl => {
+                entry.used = clock;
+                Some(entry.resp.clone())
+            }
+            Some(_) => {
+                entries.responses.remove(&key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    pub(crate) fn insert(&self, secret: Option<&str>, req: &QueryReq, resp: &QueryResp) {
+        let key = key(secret, req);
+        let mut entries = self.entries.lock().unwrap();
+        if !entries.responses.contains_key(&key) && entries.responses.len() >= self.capacity {
+            let ttl = self.ttl;
+            entries.responses.retain(|_, e| e.fetched.elapsed() < ttl);
+            if entries.responses.len() >= self.capacity {
+                let oldest = entries
+                    .responses
+                    .iter()
+                    .min_by_key(|(_, e)| e.used)
+                    .map(|(k, _)| k.clone());
+                if let Some(oldest) = oldest {
+                    entries.responses.remove(&oldest);
+                }
+            }
+        }
+        entries.clock += 1;
+        let used = entries.clock;
+        entries.responses.insert(
+            key,
+            Entry {
+                fetched: Instant::now(),
+                used,
+                resp: resp.clone(),
+            },
+        );
+    }
+
+    pub(crate) fn clear(&self) {
+        self.entries.lock().unwrap().responses.clear();
+    }
+}
+
+/// The request as sent, along with the secret, so clients authenticated as
+/// different users never share responses.
+fn key(secret: Option<&str>, req: &QueryReq) -> Vec<u8> {
+    let secret = secret.unwrap_or_default();
+    let mut key = Vec::with_capacity(secret.len() + 1 + req.encoded_len());
+    key.extend_from_slice(secret.as_bytes());
+    // Secrets are text, so this can't be part of one.
+    key.push(0);
+    // Only fails if the buffer is too small, and it grows.
+    req.encode(&mut key).unwrap();
+    key
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_query_cache() {
+        let req = |query: &str| QueryReq {
+            query: query.to_string(),
+            ..Default::default()
+        };
+        let resp = |path: &str| QueryResp {
+            results: vec![path.to_string()],
+            ..Default::default()
+        };
+        let cache = QueryCache::new(Duration::from_secs(60), 2);
+
+        cache.insert(None, &req("a"), &resp("/a"));
+        cache.insert(None, &req("b"), &resp("/b"));
+        assert_eq!(cache.get(None, &req("a")), Some(resp("/a")));
+        // Another user's secret, or another count, is another request.
+        assert_eq!(cache.get(Some("secret"), &req("a")), None);
+        let mut counted = req("a");
+        counted.count = 5;
+        assert_eq!(cache.get(None, &counted), None);
+
+        // b was used least recently, so makes way for c.
+        cache.insert(None, &req("c"), &resp("/c"));
+        assert_eq!(cache.get(None, &req("b")), None);
+        assert_eq!(cache.get(None, &req("a")), Some(resp("/a")));
+        assert_eq!(cache.get(None, &req("c")), Some(resp("/c")));
+
+        cache.clear();
+        assert_eq!(cache.get(None, &req("a")), None);
+
+        let expired = QueryCache::new(Duration::from_millis(0), 2);
+        expired.insert(None, &req("a"), &resp("/a"));
+        assert_eq!(expired.get(None, &req("a")), None);
+    }
+}
@@ -0,0 +1,68 @@
+use crate::proto::rpc::{ErrorCode, ErrorDetails};
+use prost::Message;
+use std::error;
+use std::fmt;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The address to connect to was not a host:port.
+    InvalidAddress(String),
+    /// The daemon could not be connected to.
+    Connect(tonic::transport::Error),
+    /// The daemon returned an error for the request.
+    Rpc(tonic::Status),
+    /// The user's secret could not be read from this file.
+    Secret(String, std::io::Error),
+    /// The connection to the daemon was lost, and reconnecting failed with
+    /// this on the last attempt.
+    Disconnected(tonic::Status),
+}
+
+impl Error {
+    /// What went wrong, if the daemon said. Daemons from before codes were
+    /// sent, and failures that never reached the daemon, have none.
+    pub fn code(&self) -> Option<ErrorCode> {
+        match self {
+            Error::Rpc(status) if !status.details().is_empty() => {
+                let details = ErrorDetails::decode(status.details()).ok()?;
+                ErrorCode::from_i32(details.code)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::InvalidAddress(addr) => write!(
+                f,
+                "Invalid address {:?}, expected host:port, e.g. {}",
+                addr,
+                crate::DEFAULT_SERVER
+            ),
+            Error::Connect(e) => write!(f, "Could not connect to lookrd: {}", e),
+            Error::Rpc(status) => write!(f, "{}", status.message()),
+            Error::Secret(path, e) => write!(f, "Could not read the secret in {}: {}", path, e),
+            Error::Disconnected(status) => write!(
+                f,
+                "Lost the connection to lookrd, and could not reconnect: {}",
+                status.message()
+            ),
+        }
+    }
+}
+
+impl From<tonic::transport::Error> for Error {
+    fn from(e: tonic::transport::Error) -> Self {
+        Error::Connect(e)
+    }
+}
+
+impl From<tonic::Status> for Error {
+    fn from(status: tonic::Status) -> Self {
+        Error::Rpc(status)
+    }
+}
@@ -0,0 +1,1033 @@
+//! A client library for the lookr daemon, for running lookr queries from
+//! other programs.
+//!
+//! A `LookrClient` holds a single connection to the daemon, which is shared
+//! by its clones and by concurrent requests.
+//!
+//! ```no_run
+//! # async fn run() -> Result<(), lookr_client::Error> {
+//! let client = lookr_client::LookrClient::connect("[::1]:50051").await?;
+//! for path in client.query_str("report").await? {
+//!     println!("{}", path);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use cache::QueryCache;
+use futures::future::join_all;
+use futures::stream::{self, Stream, StreamExt, TryStreamExt};
+use std::sync::Arc;
+use std::time::Duration;
+use tonic::transport::{Channel, Endpoint};
+use tonic::{Request, Status};
+
+mod cache;
+mod error;
+
+pub use error::Error;
+pub use proto::rpc::{
+    BatchQueryReq, BatchQueryResp, CompactResp, DirectoryCount, DocField, DuplicateContent,
+    DuplicateName, ErrorCode, FieldBoosts, FindDuplicateContentReq, FindDuplicateContentResp,
+    FindDuplicateNamesReq, FindDuplicateNamesResp, FindMarkerResp, GetDocResp, HealthCheckResp,
+    ListRootsResp, PauseResp, QueryCount, QueryReq, QueryResp, RecentQueriesResp, RecentQuery,
+    ReindexFileResp, ReindexOutcome, ReindexResp, ResumeResp, Root, SearchResult, SortBy,
+    StatsResp, VerifyResp, WalkErrors,
+};
+pub use tonic::Code;
+
+/// The generated protobuf messages and gRPC client for the daemon.
+pub mod proto {
+    pub mod rpc {
+        tonic::include_proto!("rpc");
+    }
+}
+
+use proto::rpc::lookr_client::LookrClient as RpcClient;
+use proto::rpc::{
+    CompactReq, FindMarkerReq, GetDocReq, HealthCheckReq, ListRootsReq, PauseReq, RecentQueriesReq,
+    ReindexFileReq, ReindexReq, ResumeReq, SecretPathReq, SnapshotReq, StatsReq, VerifyReq,
+    VersionReq,
+};
+
+/// The address the daemon listens on by default.
+pub static DEFAULT_SERVER: &str = "[::1]:50051";
+
+/// This client's version, which a daemon with a different major version may
+/// not understand.
+pub static VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// The request metadata a daemon serving several users reads the secret
+/// from.
+static SECRET_HEADER: &str = "lookr-secret";
+
+/// The number of results fetched at a time by `query_stream`.
+const DEFAULT_PAGE_SIZE: i32 = 500;
+
+/// How long to wait before the first attempt to reconnect, doubling after
+/// each that fails, up to the most.
+const RECONNECT_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_RECONNECT_BACKOFF: Duration = Duration::from_secs(5);
+
+/// A connection to the daemon. Cloning it is cheap, and the clones share the
+/// connection.
+#[derive(Clone, Debug)]
+pub struct LookrClient {
+    inner: RpcClient<Channel>,
+    page_size: i32,
+    /// Sent with every request to a daemon serving several users.
+    secret: Option<String>,
+    /// Shared by the clones, if queries are cached.
+    cache: Option<Arc<QueryCache>>,
+    /// How many times to try reconnecting when the connection is lost.
+    reconnect_attempts: Option<u32>,
+}
+
+/// Options for connecting to the daemon, from `LookrClient::builder`.
+#[derive(Clone, Debug)]
+pub struct ClientBuilder {
+    addr: String,
+    timeout: Option<Duration>,
+    page_size: i32,
+    /// How long responses are cached for, and how many.
+    cache: Option<(Duration, usize)>,
+    #[cfg(feature = "tls")]
+    tls: Option<tonic::transport::ClientTlsConfig>,
+}
+
+impl ClientBuilder {
+    fn new(addr: &str) -> Self {
+        ClientBuilder {
+            addr: addr.to_string(),
+            timeout: None,
+            page_size: DEFAULT_PAGE_SIZE,
+            cache: None,
+            #[cfg(feature = "tls")]
+            tls: None,
+        }
+    }
+
+    /// Fail any request that takes longer than this.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// How many results `query_stream` fetches in each request.
+    pub fn page_size(mut self, page_size: usize) -> Self {
+        self.page_size = page_size.clamp(1, i32::MAX as usize) as i32;
+        self
+    }
+
+    /// Answer a query sent again within `ttl` from the response to the first,
+    /// without asking the daemon, keeping up to `capacity` responses. Changes
+    /// to the index in that time are missed, so keep the TTL short.
+    pub fn cache(mut self, ttl: Duration, capacity: usize) -> Self {
+        self.cache = Some((ttl, capacity));
+        self
+    }
+
+    /// Connect over TLS, checking the server's certificate was signed by
+    /// this PEM encoded CA and is for `domain`.
+    #[cfg(feature = "tls")]
+    pub fn tls(mut self, ca_certificate: &[u8], domain: &str) -> Self {
+        use tonic::transport::{Certificate, ClientTlsConfig};
+
+        let config = ClientTlsConfig::new()
+            .ca_certificate(Certificate::from_pem(ca_certificate))
+            .domain_name(domain);
+        self.tls = Some(config);
+        self
+    }
+
+    /// Checks the address is given as host:port, returning the endpoint to
+    /// connect to.
+    fn endpoint(&self) -> Result<Endpoint, Error> {
+        let invalid = || Error::InvalidAddress(self.addr.clone());
+        let mut parts = self.addr.rsplitn(2, ':');
+        let port = parts.next().unwrap_or_default();
+        let host = parts.next().unwrap_or_default();
+        if host.is_empty() || port.parse::<u16>().is_err() {
+            return Err(invalid());
+        }
+
+        #[cfg(feature = "tls")]
+        let scheme = if self.tls.is_some() { "https" } else { "http" };
+        #[cfg(not(feature = "tls"))]
+        let scheme = "http";
+        let mut endpoint =
+            Endpoint::from_shared(format!("{}://{}", scheme, self.addr)).map_err(|_| invalid())?;
+        if let Some(timeout) = self.timeout {
+            endpoint = endpoint.timeout(timeout);
+        }
+        #[cfg(feature = "tls")]
+        {
+            if let Some(tls) = &self.tls {
+                endpoint = endpoint.tls_config(tls.clone());
+            }
+        }
+        Ok(endpoint)
+    }
+
+    /// Check the options and connect to the daemon.
+    pub async fn connect(self) -> Result<LookrClient, Error> {
+        let channel = self.endpoint()?.connect().await?;
+        Ok(LookrClient {
+            inner: RpcClient::new(channel),
+            page_size: self.page_size,
+            secret: None,
+            cache: self
+                .cache
+                .map(|(ttl, capacity)| Arc::new(QueryCache::new(ttl, capacity))),
+            reconnect_attempts: None,
+        })
+    }
+}
+
+impl LookrClient {
+    /// Connect to the daemon at `addr`, given as host:port.
+    pub async fn connect(addr: &str) -> Result<Self, Error> {
+        Self::builder(addr).connect().await
+    }
+
+    /// Options for connecting to the daemon at `addr`, given as host:port.
+    pub fn builder(addr: &str) -> ClientBuilder {
+        ClientBuilder::new(addr)
+    }
+
+    /// Prove to a daemon serving several users that this is `user`, by
+    /// reading their secret from the file the daemon keeps it in, which only
+    /// they can read. A daemon serving one index needs no secret, and the
+    /// client is returned as it is.
+    pub async fn authenticate(&self, user: &str) -> Result<Self, Error> {
+        let path = match self.get_secret_path(user).await {
+            Ok(path) => path,
+            // Older daemons serve one index.
+            Err(Error::Rpc(status)) if status.code() == Code::Unimplemented => String::new(),
+            Err(e) => return Err(e),
+        };
+        if path.is_empty() {
+            return Ok(self.clone());
+        }
+        let secret = std::fs::read_to_string(&path).map_err(|e| Error::Secret(path.clone(), e))?;
+        Ok(self.clone().with_secret(secret.trim()))
+    }
+
+    /// Send `secret` with every request, for when it's known already.
+    pub fn with_secret(mut self, secret: &str) -> Self {
+        self.secret = Some(secret.to_string());
+        self
+    }
+
+    /// When a query finds the connection to the daemon lost, as it is while
+    /// the daemon restarts, try up to `attempts` times to reconnect, waiting
+    /// twice as long before each, and then send the query again.
+    pub fn with_reconnect(mut self, attempts: u32) -> Self {
+        self.reconnect_attempts = Some(attempts);
+        self
+    }
+
+    /// The file holding `user`'s secret, or an empty string if the daemon
+    /// doesn't need one.
+    pub async fn get_secret_path(&self, user: &str) -> Result<String, Error> {
+        let req = self.request(SecretPathReq {
+            user: user.to_string(),
+        });
+        let resp = self.inner.clone().get_secret_path(req).await?;
+        Ok(resp.into_inner().path)
+    }
+
+    /// A request carrying the secret, if there is one.
+    fn request<T>(&self, message: T) -> Request<T> {
+        let mut req = Request::new(message);
+        if let Some(secret) = &self.secret {
+            if let Ok(value) = secret.parse() {
+                req.metadata_mut().insert(SECRET_HEADER, value);
+            }
+        }
+        req
+    }
+
+    /// Run a query, returning the matching paths.
+    pub async fn query_str(&self, query: &str) -> Result<Vec<String>, Error> {
+        let req = QueryReq {
+            query: query.to_string(),
+            ..Default::default()
+        };
+        Ok(self.query(req).await?.results)
+    }
+
+    /// Run a query. The response's `hits` are filled in from its paths if
+    /// the daemon is too old to send them.
+    pub async fn query(&self, req: QueryReq) -> Result<QueryResp, Error> {
+        let secret = self.secret.as_deref();
+        if let Some(resp) = self.cache.as_ref().and_then(|c| c.get(secret, &req)) {
+            return Ok(resp);
+        }
+        let cache_req = self.cache.as_ref().map(|_| req.clone());
+        let mut resp = self.send_query(req).await?;
+        if resp.hits.is_empty() {
+            resp.hits = resp
+                .results
+                .iter()
+                .map(|path| SearchResult {
+                    path: path.clone(),
+                    ..Default::default()
+                })
+                .collect();
+        }
+        if let (Some(cache), Some(req)) = (&self.cache, cache_req) {
+            cache.insert(secret, &req, &resp);
+        }
+        Ok(resp)
+    }
+
+    /// Send the query to the daemon, reconnecting and sending it again if the
+    /// connection was lost and the client is set to.
+    async fn send_query(&self, req: QueryReq) -> Result<QueryResp, Error> {
+        let retry = self.reconnect_attempts.map(|_| req.clone());
+        let status = match self.inner.clone().query(self.request(req)).await {
+            Ok(resp) => return Ok(resp.into_inner()),
+            Err(status) => status,
+        };
+        match (self.reconnect_attempts, retry) {
+            (Some(attempts), Some(req)) if connection_lost(&status) => {
+                self.reconnect(attempts, status).await?;
+                let resp = self.inner.clone().query(self.request(req)).await?;
+                Ok(resp.into_inner())
+            }
+            _ => Err(status.into()),
+        }
+    }
+
+    /// Wait until the daemon can be reached again, backing off between
+    /// attempts. The channel connects again by itself on the next request,
+    /// so each attempt asks for the daemon's version.
+    async fn reconnect(&self, attempts: u32, mut status: Status) -> Result<(), Error> {
+        let mut backoff = RECONNECT_BACKOFF;
+        for _ in 0..attempts {
+            tokio::time::delay_for(backoff).await;
+            match self
+                .inner
+                .clone()
+                .version(self.request(VersionReq {}))
+                .await
+            {
+                Err(e) if connection_lost(&e) => status = e,
+                // Any answer at all, even from a daemon too old to know its
+                // version, means it's back.
+                _ => return Ok(()),
+            }
+            backoff = (backoff * 2).min(MAX_RECONNECT_BACKOFF);
+        }
+        Err(Error::Disconnected(status))
+    }
+
+    /// Forget the cached responses, so the next queries go to the daemon.
+    /// Shared with the clones.
+    pub fn clear_cache(&self) {
+        if let Some(cache) = &self.cache {
+            cache.clear();
+        }
+    }
+
+    /// Run a query, fetching the results a page at a time as the stream is
+    /// read. Starts from the request's offset, and stops after its count if
+    /// one is given, otherwise carries on until every match is returned.
+    pub fn query_stream(&self, req: QueryReq) -> impl Stream<Item = Result<String, Error>> {
+        let client = self.clone();
+        let remaining = if req.count > 0 { Some(req.count) } else { None };
+        let pages = stream::try_unfold(
+            (client, req, remaining, false),
+            |(client, mut req, remaining, done)| async move {
+                if done || remaining == Some(0) {
+                    return Ok::<_, Error>(None);
+                }
+                let page_size = remaining.map_or(client.page_size, |r| r.min(client.page_size));
+                req.count = page_size;
+                let results = client.query(req.clone()).await?.results;
+
+                let fetched = results.len() as i32;
+                req.offset += fetched;
+                let remaining = remaining.map(|r| r - fetched);
+                // A short page means there are no more matches.
+                let done = fetched < page_size;
+                Ok(Some((results, (client, req, remaining, done))))
+            },
+        );
+        pages
+            .map_ok(|results| stream::iter(results.into_iter().map(Ok)))
+            .try_flatten()
+            .boxed()
+    }
+
+    /// Run several queries against the same view of the index.
+    pub async fn batch_query(&self, queries: Vec<QueryReq>) -> Result<Vec<QueryResp>, Error> {
+        let req = self.request(BatchQueryReq { queries });
+        let resp = self.inner.clone().batch_query(req).await?;
+        Ok(resp.into_inner().responses)
+    }
+
+    pub async fn stats(&self) -> Result<StatsResp, Error> {
+        let resp = self.inner.clone().stats(self.request(StatsReq {})).await?;
+        Ok(resp.into_inner())
+    }
+
+    pub async fn list_roots(&self) -> Result<Vec<Root>, Error> {
+        let req = self.request(ListRootsReq {});
+        let resp = self.inner.clone().list_roots(req).await?;
+        Ok(resp.into_inner().roots)
+    }
+
+    pub async fn compact(&self) -> Result<CompactResp, Error> {
+        let resp = self
+            .inner
+            .clone()
+            .compact(self.request(CompactReq {}))
+            .await?;
+        Ok(resp.into_inner())
+    }
+
+    /// Have the daemon copy its index to `dest_dir`, an absolute path on the
+    /// daemon's host, returning where it was written.
+    pub async fn snapshot(&self, dest_dir: &str) -> Result<String, Error> {
+        let req = self.request(SnapshotReq {
+            dest_dir: dest_dir.to_string(),
+        });
+        let resp = self.inner.clone().snapshot(req).await?;
+        Ok(resp.into_inner().path)
+    }
+
+    pub async fn health_check(&self) -> Result<HealthCheckResp, Error> {
+        let req = self.request(HealthCheckReq {});
+        let resp = self.inner.clone().health_check(req).await?;
+        Ok(resp.into_inner())
+    }
+
+    /// The daemon's version. Daemons from before the version was reported
+    /// return an `Unimplemented` status.
+    pub async fn version(&self) -> Result<String, Error> {
+        let req = self.request(VersionReq {});
+        let resp = self.inner.clone().version(req).await?;
+        Ok(resp.into_inner().version)
+    }
+
+    /// The queries the daemon ran most recently, and the `top` most frequent
+    /// of them, or the daemon's default number if `top` is 0.
+    pub async fn recent_queries(&self, top: u32) -> Result<RecentQueriesResp, Error> {
+        let req = self.request(RecentQueriesReq { top });
+        let resp = self.inner.clone().recent_queries(req).await?;
+        Ok(resp.into_inner())
+    }
+
+    /// What the daemon has stored for `path`, an absolute path on its host.
+    pub async fn get_doc(&self, path: &str) -> Result<GetDocResp, Error> {
+        let req = self.request(GetDocReq {
+            path: path.to_string(),
+        });
+        let resp = self.inner.clone().get_doc(req).await?;
+        Ok(resp.into_inner())
+    }
+
+    /// The deepest directory at or above `start_path`, an absolute path on
+    /// the daemon's host, that has `marker` indexed in it.
+    pub async fn find_marker(
+        &self,
+        start_path: &str,
+        marker: &str,
+    ) -> Result<FindMarkerResp, Error> {
+        let req = self.request(FindMarkerReq {
+            start_path: start_path.to_string(),
+            marker: marker.to_string(),
+        });
+        let resp = self.inner.clone().find_marker(req).await?;
+        Ok(resp.into_inner())
+    }
+
+    /// Have the daemon hold on to changes rather than indexing them, until
+    /// `resume` is called.
+    pub async fn pause(&self) -> Result<PauseResp, Error> {
+        let resp = self.inner.clone().pause(self.request(PauseReq {})).await?;
+        Ok(resp.into_inner())
+    }
+
+    pub async fn resume(&self) -> Result<ResumeResp, Error> {
+        let resp = self
+            .inner
+            .clone()
+            .resume(self.request(ResumeReq {}))
+            .await?;
+        Ok(resp.into_inner())
+    }
+
+    /// Have the daemon walk `root` again, or all of its roots if it's empty,
+    /// once it's caught up on changes.
+    pub async fn reindex(&self, root: &str) -> Result<ReindexResp, Error> {
+        let req = self.request(ReindexReq {
+            root: root.to_string(),
+        });
+        let resp = self.inner.clone().reindex(req).await?;
+        Ok(resp.into_inner())
+    }
+
+    /// Have the daemon index `path`, an absolute path under one of its index
+    /// paths, again, or remove it if it's gone.
+    pub async fn reindex_file(&self, path: &str) -> Result<ReindexFileResp, Error> {
+        let req = self.request(ReindexFileReq {
+            path: path.to_string(),
+        });
+        let resp = self.inner.clone().reindex_file(req).await?;
+        Ok(resp.into_inner())
+    }
+
+    /// Have the daemon compare what's indexed with what's on disk, without
+    /// changing anything.
+    pub async fn verify(&self) -> Result<VerifyResp, Error> {
+        let resp = self
+            .inner
+            .clone()
+            .verify(self.request(VerifyReq {}))
+            .await?;
+        Ok(resp.into_inner())
+    }
+
+    /// A page of the names shared by more than one indexed entry, with the
+    /// entries' paths.
+    pub async fn find_duplicate_names(
+        &self,
+        req: FindDuplicateNamesReq,
+    ) -> Result<FindDuplicateNamesResp, Error> {
+        let req = self.request(req);
+        let resp = self.inner.clone().find_duplicate_names(req).await?;
+        Ok(resp.into_inner())
+    }
+
+    /// A page of the files sharing the same contents, grouped by their hash,
+    /// from a daemon indexing hashes.
+    pub async fn find_duplicate_content(
+        &self,
+        req: FindDuplicateContentReq,
+    ) -> Result<FindDuplicateContentResp, Error> {
+        let req = self.request(req);
+        let resp = self.inner.clone().find_duplicate_content(req).await?;
+        Ok(resp.into_inner())
+    }
+}
+
+/// Whether the request failed for want of a connection to the daemon,
+/// rather than the daemon failing it, which always sends details.
+fn connection_lost(status: &Status) -> bool {
+    status.details().is_empty()
+        && match status.code() {
+            Code::Unavailable => true,
+            Code::Unknown => status.message().starts_with("transport error"),
+            _ => false,
+        }
+}
+
+/// Connect to each of several daemons at once, returning the clients or
+/// errors in the order of `addrs`.
+pub async fn connect_all<S: AsRef<str>>(addrs: &[S]) -> Vec<Result<LookrClient, Error>> {
+    join_all(addrs.iter().map(|a| LookrClient::connect(a.as_ref()))).await
+}
+
+/// Run the same query against several daemons at once, returning each one's
+/// response or error in the order of `clients`.
+pub async fn query_all(clients: &[LookrClient], req: &QueryReq) -> Vec<Result<QueryResp, Error>> {
+    join_all(clients.iter().map(|c| c.query(req.clone()))).await
+}
+
+/// Merge the results of the same query from several daemons into one list of
+/// up to `limit` paths, each paired with the index of the response it came
+/// from. Scores from different indexes can't be compared, so the daemons take
+/// turns, each giving its best match not yet taken.
+pub fn merge_results(responses: &[QueryResp], limit: Option<usize>) -> Vec<(usize, &str)> {
+    let mut iters: Vec<_> = responses.iter().map(|r| r.results.iter()).collect();
+    let mut merged = Vec::new();
+    loop {
+        let mut taken = false;
+        for (i, iter) in iters.iter_mut().enumerate() {
+            if limit.is_some_and(|l| merged.len() >= l) {
+                return merged;
+            }
+            if let Some(path) = iter.next() {
+                merged.push((i, path.as_str()));
+                taken = true;
+            }
+        }
+        if !taken {
+            return merged;
+        }
+    }
+}
+
+/// Whether a daemon of `version` should understand this client, which is the
+/// case if their major versions match. A version that can't be read is
+/// assumed not to.
+pub fn compatible(version: &str) -> bool {
+    let major = |v: &str| v.split('.').next().and_then(|m| m.parse::<u64>().ok());
+    major(version).is_some() && major(version) == major(VERSION)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use proto::rpc::lookr_server::{Lookr, LookrServer};
+    use proto::rpc::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use tokio::sync::oneshot;
+    use tokio::task::JoinHandle;
+    use tonic::transport::Server;
+    use tonic::{Response, Status};
+
+    /// Serves queries from a fixed list of paths, counting the requests.
+    struct TestServer {
+        paths: Vec<String>,
+        queries: Arc<AtomicUsize>,
+    }
+
+    #[tonic::async_trait]
+    impl Lookr for TestServer {
+        async fn query(&self, req: Request<QueryReq>) -> Result<Response<QueryResp>, Status> {
+            self.queries.fetch_add(1, Ordering::SeqCst);
+            let req = req.into_inner();
+            let results = self
+                .paths
+                .iter()
+                .filter(|p| p.contains(&req.query))
+                .skip(req.offset as usize)
+                .take(req.count as usize)
+                .cloned()
+                .collect();
+            Ok(Response::new(QueryResp {
+                results,
+                ..Default::default()
+            }))
+        }
+
+        async fn batch_query(
+            &self,
+            _req: Request<BatchQueryReq>,
+        ) -> Result<Response<BatchQueryResp>, Status> {
+            // Fails as the daemon does, with the code in the details.
+            let details = ErrorDetails {
+                code: ErrorCode::QueryParse as i32,
+            };
+            let mut buf = Vec::new();
+            prost::Message::encode(&details, &mut buf).unwrap();
+            Err(Status::with_details(
+                tonic::Code::InvalidArgument,
+                "Could not parse query",
+                buf.into(),
+            ))
+        }
+
+        /// Each user's secret is kept in the file named by their user name.
+        async fn get_secret_path(
+            &self,
+            req: Request<SecretPathReq>,
+        ) -> Result<Response<SecretPathResp>, Status> {
+            Ok(Response::new(SecretPathResp {
+                path: req.into_inner().user,
+            }))
+        }
+
+        /// Only answers requests with the test secret.
+        async fn stats(&self, req: Request<StatsReq>) -> Result<Response<StatsResp>, Status> {
+            match req.metadata().get(SECRET_HEADER) {
+                Some(secret) if secret == "s3cret" => Ok(Response::new(StatsResp::default())),
+                _ => Err(Status::unimplemented("")),
+            }
+        }
+
+        async fn list_roots(
+            &self,
+            _req: Request<ListRootsReq>,
+        ) -> Result<Response<ListRootsResp>, Status> {
+            Err(Status::unimplemented(""))
+        }
+
+        async fn compact(
+            &self,
+            _req: Request<CompactReq>,
+        ) -> Result<Response<CompactResp>, Status> {
+            Err(Status::unimplemented(""))
+        }
+
+        async fn snapshot(
+            &self,
+            _req: Request<SnapshotReq>,
+        ) -> Result<Response<SnapshotResp>, Status> {
+            Err(Status::unimplemented(""))
+        }
+
+        async fn health_check(
+            &self,
+            _req: Request<HealthCheckReq>,
+        ) -> Result<Response<HealthCheckResp>, Status> {
+            Err(Status::unimplemented(""))
+        }
+
+        async fn version(
+            &self,
+            _req: Request<VersionReq>,
+        ) -> Result<Response<VersionResp>, Status> {
+            Ok(Response::new(VersionResp {
+                version: "0.1.9".to_string(),
+            }))
+        }
+
+        async fn recent_queries(
+            &self,
+            _req: Request<RecentQueriesReq>,
+        ) -> Result<Response<RecentQueriesResp>, Status> {
+            Err(Status::unimplemented(""))
+        }
+
+        async fn get_doc(&self, _req: Request<GetDocReq>) -> Result<Response<GetDocResp>, Status> {
+            Err(Status::unimplemented(""))
+        }
+
+        async fn pause(&self, _req: Request<PauseReq>) -> Result<Response<PauseResp>, Status> {
+            Err(Status::unimplemented(""))
+        }
+
+        async fn resume(&self, _req: Request<ResumeReq>) -> Result<Response<ResumeResp>, Status> {
+            Err(Status::unimplemented(""))
+        }
+
+        async fn reindex(
+            &self,
+            _req: Request<ReindexReq>,
+        ) -> Result<Response<ReindexResp>, Status> {
+            Err(Status::unimplemented(""))
+        }
+
+        async fn verify(&self, _req: Request<VerifyReq>) -> Result<Response<VerifyResp>, Status> {
+            Err(Status::unimplemented(""))
+        }
+
+        async fn reindex_file(
+            &self,
+            _req: Request<ReindexFileReq>,
+        ) -> Result<Response<ReindexFileResp>, Status> {
+            Err(Status::unimplemented(""))
+        }
+
+        async fn find_duplicate_names(
+            &self,
+            _req: Request<FindDuplicateNamesReq>,
+        ) -> Result<Response<FindDuplicateNamesResp>, Status> {
+            Err(Status::unimplemented(""))
+        }
+
+        async fn find_duplicate_content(
+            &self,
+            _req: Request<FindDuplicateContentReq>,
+        ) -> Result<Response<FindDuplicateContentResp>, Status> {
+            Err(Status::unimplemented(""))
+        }
+
+        async fn find_marker(
+            &self,
+            _req: Request<FindMarkerReq>,
+        ) -> Result<Response<FindMarkerResp>, Status> {
+            Err(Status::unimplemented(""))
+        }
+
+        type ReplicateStream = futures::stream::Empty<Result<ReplicationBatch, Status>>;
+
+        async fn replicate(
+            &self,
+            _req: Request<ReplicateReq>,
+        ) -> Result<Response<Self::ReplicateStream>, Status> {
+            Err(Status::unimplemented(""))
+        }
+    }
+
+    /// Start a server for the paths, returning its address and the count of
+    /// queries it has served.
+    async fn serve(paths: &[&str]) -> (String, Arc<AtomicUsize>) {
+        let queries = Arc::new(AtomicUsize::new(0));
+        let server = TestServer {
+            paths: paths.iter().map(|p| p.to_string()).collect(),
+            queries: queries.clone(),
+        };
+        let mut listener =
+            tokio::net::TcpListener::bind(std::net::SocketAddr::from(([127, 0, 0, 1], 0)))
+                .await
+                .unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            Server::builder()
+                .add_service(LookrServer::new(server))
+                .serve_with_incoming(listener.incoming())
+                .await
+                .unwrap();
+        });
+        (addr, queries)
+    }
+
+    /// Start a server for the paths on `port`, or any port if it's 0,
+    /// returning its port and a way to stop it.
+    async fn serve_on(port: u16, paths: &[&str]) -> (u16, impl FnOnce() -> JoinHandle<()>) {
+        let server = TestServer {
+            paths: paths.iter().map(|p| p.to_string()).collect(),
+            queries: Arc::new(AtomicUsize::new(0)),
+        };
+        let mut listener =
+            tokio::net::TcpListener::bind(std::net::SocketAddr::from(([127, 0, 0, 1], port)))
+                .await
+                .unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (stop, stopped) = oneshot::channel::<()>();
+        let handle = tokio::spawn(async move {
+            Server::builder()
+                .add_service(LookrServer::new(server))
+                .serve_with_incoming_shutdown(listener.incoming(), async {
+                    let _ = stopped.await;
+                })
+                .await
+                .unwrap();
+        });
+        let stop = move || {
+            let _ = stop.send(());
+            handle
+        };
+        (port, stop)
+    }
+
+    #[test]
+    fn test_endpoint() {
+        let endpoint = |addr| LookrClient::builder(addr).endpoint();
+        assert!(endpoint("[::1]:50051").is_ok());
+        assert!(endpoint("localhost:50051").is_ok());
+        assert!(endpoint("127.0.0.1:1").is_ok());
+
+        assert!(endpoint("localhost").is_err());
+        assert!(endpoint(":50051").is_err());
+        assert!(endpoint("localhost:http").is_err());
+        assert!(endpoint("localhost:70000").is_err());
+        assert!(endpoint("bad host:50051").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_query_stream() {
+        let paths = ["/a/1", "/a/2", "/a/3", "/a/4", "/a/5", "/a/6", "/a/7"];
+        let (addr, queries) = serve(&paths).await;
+        let client = LookrClient::builder(&addr)
+            .page_size(3)
+            .connect()
+            .await
+            .unwrap();
+
+        let all: Vec<String> = client
+            .query_stream(QueryReq {
+                query: "/a".to_string(),
+                ..Default::default()
+            })
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(all, paths);
+        assert_eq!(queries.swap(0, Ordering::SeqCst), 3);
+
+        // A clone shares the connection, and pages stop at the count.
+        let some: Vec<String> = client
+            .clone()
+            .query_stream(QueryReq {
+                query: "/a".to_string(),
+                count: 5,
+                offset: 1,
+                ..Default::default()
+            })
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(some, &paths[1..6]);
+        assert_eq!(queries.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_cached_query() {
+        let (addr, queries) = serve(&["/a/1", "/a/2", "/b/1"]).await;
+        let client = LookrClient::builder(&addr)
+            .cache(Duration::from_secs(60), 10)
+            .connect()
+            .await
+            .unwrap();
+        let query = |client: &LookrClient, query: &str| {
+            let client = client.clone();
+            let req = QueryReq {
+                query: query.to_string(),
+                count: 10,
+                ..Default::default()
+            };
+            async move { client.query(req).await.unwrap().results }
+        };
+
+        assert_eq!(query(&client, "/a").await, ["/a/1", "/a/2"]);
+        assert_eq!(query(&client, "/a").await, ["/a/1", "/a/2"]);
+        assert_eq!(queries.load(Ordering::SeqCst), 1);
+        assert_eq!(query(&client, "/b").await, ["/b/1"]);
+        assert_eq!(queries.load(Ordering::SeqCst), 2);
+
+        // The clones share the cache, and clearing it.
+        client.clone().clear_cache();
+        assert_eq!(query(&client, "/a").await, ["/a/1", "/a/2"]);
+        assert_eq!(queries.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_reconnect() {
+        let (port, stop) = serve_on(0, &["/a/1"]).await;
+        let addr = format!("127.0.0.1:{}", port);
+        let client = LookrClient::connect(&addr).await.unwrap();
+        let query = |client: &LookrClient| {
+            let client = client.clone();
+            let req = QueryReq {
+                query: "/a".to_string(),
+                count: 10,
+                ..Default::default()
+            };
+            async move { client.query(req).await.map(|r| r.results) }
+        };
+        assert_eq!(query(&client).await.unwrap(), ["/a/1"]);
+        stop().await.unwrap();
+
+        // Without reconnecting, the query fails as soon as it's sent.
+        match query(&client).await {
+            Err(Error::Rpc(status)) => assert!(connection_lost(&status)),
+            r => panic!("Expected the connection to be lost, got {:?}", r),
+        }
+        match query(&client.clone().with_reconnect(1)).await {
+            Err(e @ Error::Disconnected(_)) => {
+                assert!(e.to_string().starts_with("Lost the connection to lookrd"))
+            }
+            r => panic!("Expected to fail to reconnect, got {:?}", r),
+        }
+
+        // The daemon comes back while the client backs off.
+        let restart = tokio::spawn(async move {
+            tokio::time::delay_for(Duration::from_millis(200)).await;
+            serve_on(port, &["/a/2"]).await.1
+        });
+        let client = client.with_reconnect(10);
+        assert_eq!(query(&client).await.unwrap(), ["/a/2"]);
+        restart.await.unwrap()().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_hits_from_results() {
+        let (addr, _) = serve(&["/a/1", "/a/2"]).await;
+        let client = LookrClient::connect(&addr).await.unwrap();
+        let resp = client
+            .query(QueryReq {
+                query: "/a".to_string(),
+                count: 10,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        let paths: Vec<&str> = resp.hits.iter().map(|h| h.path.as_str()).collect();
+        assert_eq!(paths, resp.results);
+        assert_eq!(paths, ["/a/1", "/a/2"]);
+    }
+
+    #[test]
+    fn test_compatible() {
+        let major = VERSION.split('.').next().unwrap();
+        assert!(compatible(VERSION));
+        assert!(compatible(&format!("{}.99.0", major)));
+        assert!(!compatible("99.0.0"));
+        assert!(!compatible(""));
+        assert!(!compatible("x.1.1"));
+    }
+
+    #[tokio::test]
+    async fn test_version() {
+        let (addr, _) = serve(&[]).await;
+        let client = LookrClient::connect(&addr).await.unwrap();
+        assert_eq!(client.version().await.unwrap(), "0.1.9");
+    }
+
+    #[test]
+    fn test_merge_results() {
+        let resp = |results: &[&str]| QueryResp {
+            results: results.iter().map(|r| r.to_string()).collect(),
+            ..Default::default()
+        };
+        let responses = [resp(&["/a/1", "/a/2", "/a/3"]), resp(&[]), resp(&["/c/1"])];
+
+        assert_eq!(
+            merge_results(&responses, None),
+            vec![(0, "/a/1"), (2, "/c/1"), (0, "/a/2"), (0, "/a/3")]
+        );
+        assert_eq!(
+            merge_results(&responses, Some(3)),
+            vec![(0, "/a/1"), (2, "/c/1"), (0, "/a/2")]
+        );
+        assert!(merge_results(&responses, Some(0)).is_empty());
+        assert!(merge_results(&[], None).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_query_all() {
+        let (first, _) = serve(&["/first/report"]).await;
+        let (second, _) = serve(&["/second/report", "/second/other"]).await;
+        // Nothing listens on port 1.
+        let clients = connect_all(&[first.as_str(), "127.0.0.1:1", second.as_str()]).await;
+        assert!(clients[1].is_err());
+        let clients: Vec<LookrClient> = clients.into_iter().filter_map(Result::ok).collect();
+
+        let req = QueryReq {
+            query: "report".to_string(),
+            count: 10,
+            ..Default::default()
+        };
+        let responses: Vec<QueryResp> = query_all(&clients, &req)
+            .await
+            .into_iter()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(
+            merge_results(&responses, None),
+            vec![(0, "/first/report"), (1, "/second/report")]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_error_code() {
+        let (addr, _) = serve(&[]).await;
+        let client = LookrClient::connect(&addr).await.unwrap();
+
+        let e = client.batch_query(Vec::new()).await.unwrap_err();
+        assert_eq!(e.code(), Some(ErrorCode::QueryParse));
+        // No details were sent.
+        let e = client.stats().await.unwrap_err();
+        assert_eq!(e.code(), None);
+    }
+
+    #[tokio::test]
+    async fn test_authenticate() {
+        let (addr, _) = serve(&[]).await;
+        let client = LookrClient::connect(&addr).await.unwrap();
+
+        // No secret needed.
+        let same = client.authenticate("").await.unwrap();
+        assert_eq!(same.secret, None);
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("secret");
+        std::fs::write(&path, "s3cret\n").unwrap();
+        assert!(client.stats().await.is_err());
+        let authenticated = client.authenticate(path.to_str().unwrap()).await.unwrap();
+        assert!(authenticated.stats().await.is_ok());
+
+        match client.authenticate("/no/such/secret").await {
+            Err(Error::Secret(path, _)) => assert_eq!(path, "/no/such/secret"),
+            r => panic!("Expected a Secret error, got {:?}", r.map(|_| ())),
+        }
+    }
+}
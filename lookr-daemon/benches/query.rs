@@ -0,0 +1,74 @@
+//! Query latency over an in-RAM index of synthetic paths.
+//!
+//!     cargo bench -p lookrd --features bench --bench query
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use lookrd::config::LookrdConfig;
+use lookrd::proto::rpc::lookr_server::Lookr;
+use lookrd::proto::rpc::{QueryReq, SortBy};
+use lookrd::rpc::LookrService;
+use lookrd::testing;
+use tokio::runtime::{Builder, Runtime};
+use tonic::Request;
+
+/// The number of paths indexed.
+const DOCS: usize = 100_000;
+
+/// The queries run, with the share of the index each matches.
+const QUERIES: [(&str, &str); 4] = [
+    ("one", "file12345"),
+    ("hundredth", "module42"),
+    ("tenth", "project3"),
+    ("fifth", "rs"),
+];
+
+fn run(rt: &mut Runtime, service: &LookrService, req: &QueryReq) {
+    rt.block_on(service.query(Request::new(req.clone())))
+        .unwrap();
+}
+
+fn query_latency(c: &mut Criterion) {
+    let paths = testing::synthetic_paths(DOCS);
+    let service = testing::service(&paths, &LookrdConfig::default());
+    let mut rt = Builder::new()
+        .basic_scheduler()
+        .enable_all()
+        .build()
+        .unwrap();
+
+    let mut group = c.benchmark_group("query");
+    for &(share, query) in &QUERIES {
+        let req = QueryReq {
+            query: query.to_string(),
+            ..Default::default()
+        };
+        group.bench_with_input(BenchmarkId::new("relevance", share), &req, |b, req| {
+            b.iter(|| run(&mut rt, &service, req))
+        });
+
+        // Every match is collected to be sorted.
+        let sorted = QueryReq {
+            sort_by: SortBy::Alphabetical as i32,
+            ..req.clone()
+        };
+        group.bench_with_input(
+            BenchmarkId::new("alphabetical", share),
+            &sorted,
+            |b, req| b.iter(|| run(&mut rt, &service, req)),
+        );
+
+        let directories = QueryReq {
+            directories: true,
+            ..req.clone()
+        };
+        group.bench_with_input(
+            BenchmarkId::new("directories", share),
+            &directories,
+            |b, req| b.iter(|| run(&mut rt, &service, req)),
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, query_latency);
+criterion_main!(benches);
@@ -0,0 +1,438 @@
+//! Listing what's in zip and tar archives, so the files in them can be
+//! indexed as if they were below the archive. An entry `inner/file.rs` of
+//! `/real/path/archive.zip` is indexed as `/real/path/archive.zip!/inner/file.rs`.
+//!
+//! Only the names are read, from the zip's central directory or the tar's
+//! headers, so nothing is decompressed. Compressed tars (`.tar.gz` and the
+//! like) and zip64 archives aren't listed.
+
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+
+/// Separates an archive's path from the path of an entry in it.
+pub(crate) const SEPARATOR: &str = "!/";
+
+/// The size of a tar header, and of the blocks entries are padded to.
+const TAR_BLOCK: u64 = 512;
+
+/// The size of the zip end of central directory record, without its comment.
+const ZIP_EOCD_LEN: usize = 22;
+const ZIP_EOCD_SIGNATURE: &[u8] = b"PK\x05\x06";
+const ZIP_ENTRY_SIGNATURE: &[u8] = b"PK\x01\x02";
+/// The size of a central directory entry, without its name, extra field and
+/// comment.
+const ZIP_ENTRY_LEN: usize = 46;
+
+/// An entry in an archive.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Entry {
+    /// The path within the archive, without leading or trailing slashes.
+    pub name: String,
+    /// One of `file_kind`'s names.
+    pub kind: &'static str,
+}
+
+/// Whether `p` is named like an archive that can be listed.
+pub(crate) fn is_archive(p: &Path) -> bool {
+    archive_type(p).is_some()
+}
+
+/// The path indexed for `name` in the archive at `archive`.
+pub(crate) fn virtual_path(archive: &str, name: &str) -> String {
+    format!("{}{}{}", archive, SEPARATOR, name)
+}
+
+/// The archive a virtual path is in, if it is one.
+pub(crate) fn archive_of(path: &str) -> Option<&str> {
+    path.split_once(SEPARATOR).map(|(archive, _)| archive)
+}
+
+/// List the entries of the archive at `p`.
+pub(crate) fn entries(p: &Path) -> io::Result<Vec<Entry>> {
+    let file = BufReader::new(File::open(p)?);
+    match archive_type(p) {
+        Some(ArchiveType::Zip) => zip_entries(file),
+        Some(ArchiveType::Tar) => tar_entries(file),
+        None => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Not a zip or tar archive",
+        )),
+    }
+}
+
+enum ArchiveType {
+    Zip,
+    Tar,
+}
+
+fn archive_type(p: &Path) -> Option<ArchiveType> {
+    let ext = p.extension()?.to_string_lossy().to_lowercase();
+    match ext.as_str() {
+        "zip" => Some(ArchiveType::Zip),
+        "tar" => Some(ArchiveType::Tar),
+        _ => None,
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// The entry for a name as the archive has it, or `None` for one that names
+/// the archive's root.
+fn entry(name: &str, kind: &'static str) -> Option<Entry> {
+    let name = name.trim_start_matches("./").trim_matches('/');
+    if name.is_empty() || name == "." {
+        return None;
+    }
+    Some(Entry {
+        name: name.to_string(),
+        kind,
+    })
+}
+
+fn u16_at(buf: &[u8], at: usize) -> usize {
+    u16::from_le_bytes([buf[at], buf[at + 1]]) as usize
+}
+
+fn u32_at(buf: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([buf[at], buf[at + 1], buf[at + 2], buf[at + 3]])
+}
+
+/// The entries named in the zip's central directory, found through the
+/// record at its end.
+fn zip_entries(mut r: impl Read + Seek) -> io::Result<Vec<Entry>> {
+    let len = r.seek(SeekFrom::End(0))?;
+    // The record ends the file, but for a comment of up to 64KiB.
+    let tail_len = len.min((ZIP_EOCD_LEN + u16::MAX as usize) as u64);
+    r.seek(SeekFrom::Start(len - tail_len))?;
+    let mut tail = vec![0; tail_len as usize];
+    r.read_exact(&mut tail)?;
+    let eocd = tail
+        .windows(ZIP_EOCD_SIGNATURE.len())
+        .rposition(|w| w == ZIP_EOCD_SIGNATURE)
+        .filter(|at| at + ZIP_EOCD_LEN <= tail.len())
+        .ok_or_else(|| invalid("No zip end of central directory record"))?;
+    let eocd = &tail[eocd..];
+    let count = u16_at(eocd, 10);
+    let cd_len = u32_at(eocd, 12);
+    let cd_offset = u32_at(eocd, 16);
+    if count == u16::MAX as usize || cd_offset == u32::MAX {
+        return Err(invalid("Zip64 archives aren't supported"));
+    }
+    if cd_offset as u64 + cd_len as u64 > len {
+        return Err(invalid("The zip central directory is past the end"));
+    }
+
+    r.seek(SeekFrom::Start(cd_offset as u64))?;
+    let mut cd = vec![0; cd_len as usize];
+    r.read_exact(&mut cd)?;
+    let mut entries = Vec::with_capacity(count);
+    let mut at = 0;
+    while at + ZIP_ENTRY_LEN <= cd.len() && &cd[at..at + 4] == ZIP_ENTRY_SIGNATURE {
+        let host = cd[at + 5];
+        let name_len = u16_at(&cd, at + 28);
+        let extra_len = u16_at(&cd, at + 30);
+        let comment_len = u16_at(&cd, at + 32);
+        let mode = u32_at(&cd, at + 38) >> 16;
+        let name_start = at + ZIP_ENTRY_LEN;
+        let name = cd
+            .get(name_start..name_start + name_len)
+            .ok_or_else(|| invalid("A zip entry's name is past the end"))?;
+        let name = String::from_utf8_lossy(name);
+        // Unix mode bits are only there if the archive was made on Unix.
+        let kind = if name.ends_with('/') {
+            "dir"
+        } else if host == 3 && mode & 0o170000 == 0o120000 {
+            "symlink"
+        } else {
+            "file"
+        };
+        entries.extend(entry(&name, kind));
+        at = name_start + name_len + extra_len + comment_len;
+    }
+    Ok(entries)
+}
+
+/// The entries named in the tar's headers, skipping over their contents.
+/// Understands ustar, GNU long names and pax paths.
+fn tar_entries(mut r: impl Read + Seek) -> io::Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    // From a GNU long name or pax header, for the entry after it.
+    let mut next_name = None;
+    let mut header = [0; TAR_BLOCK as usize];
+    // Sizes are checked against what's left, so a forged one is an error.
+    let start = r.stream_position()?;
+    let len = r.seek(SeekFrom::End(0))?;
+    r.seek(SeekFrom::Start(start))?;
+    loop {
+        match r.read_exact(&mut header) {
+            Ok(()) => (),
+            // Some writers leave off the zero blocks at the end.
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof && next_name.is_none() => break,
+            Err(e) => return Err(e),
+        }
+        if header.iter().all(|b| *b == 0) {
+            break;
+        }
+        if tar_octal(&header[148..156]) != Some(tar_checksum(&header)) {
+            return Err(invalid("A tar header's checksum doesn't match"));
+        }
+        let size = tar_size(&header[124..136]).ok_or_else(|| invalid("Bad tar entry size"))?;
+        if size > len.saturating_sub(r.stream_position()?) {
+            return Err(invalid(
+                "A tar entry is bigger than what's left of the archive",
+            ));
+        }
+        let padded = size
+            .div_ceil(TAR_BLOCK)
+            .checked_mul(TAR_BLOCK)
+            .ok_or_else(|| invalid("Bad tar entry size"))?;
+        match header[156] {
+            b'L' => {
+                next_name = Some(tar_string(&read_data(&mut r, size, padded)?));
+                continue;
+            }
+            b'x' => {
+                let data = read_data(&mut r, size, padded)?;
+                next_name = pax_path(&data).or(next_name);
+                continue;
+            }
+            _ => (),
+        }
+        let kind = match header[156] {
+            b'0' | b'\0' | b'1' | b'7' => "file",
+            b'2' => "symlink",
+            b'5' => "dir",
+            b'g' => {
+                r.seek(SeekFrom::Current(padded as i64))?;
+                continue;
+            }
+            _ => "other",
+        };
+        let name = match next_name.take() {
+            Some(name) => name,
+            None => {
+                let name = tar_string(&header[0..100]);
+                let prefix = tar_string(&header[345..500]);
+                if &header[257..262] == b"ustar" && !prefix.is_empty() {
+                    format!("{}/{}", prefix, name)
+                } else {
+                    name
+                }
+            }
+        };
+        entries.extend(entry(&name, kind));
+        r.seek(SeekFrom::Current(padded as i64))?;
+    }
+    Ok(entries)
+}
+
+/// The entry's contents, read as far as they go rather than set aside in
+/// advance, and the reader moved past their padding.
+fn read_data(r: &mut (impl Read + Seek), size: u64, padded: u64) -> io::Result<Vec<u8>> {
+    let mut data = Vec::new();
+    r.take(size).read_to_end(&mut data)?;
+    if (data.len() as u64) < size {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    r.seek(SeekFrom::Current((padded - size) as i64))?;
+    Ok(data)
+}
+
+/// A NUL terminated string field.
+fn tar_string(field: &[u8]) -> String {
+    let end = field.iter().position(|b| *b == 0).unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..end]).to_string()
+}
+
+/// An octal number field, padded with spaces or NULs.
+fn tar_octal(field: &[u8]) -> Option<u64> {
+    let digits = tar_string(field);
+    let digits = digits.trim_matches(|c| c == ' ' || c == '\0');
+    if digits.is_empty() {
+        return Some(0);
+    }
+    u64::from_str_radix(digits, 8).ok()
+}
+
+/// The size field, which GNU tar writes in base 256 when it's too big for
+/// octal.
+fn tar_size(field: &[u8]) -> Option<u64> {
+    if field[0] & 0x80 == 0 {
+        return tar_octal(field);
+    }
+    let mut size: u64 = (field[0] & 0x7f) as u64;
+    for b in &field[1..] {
+        size = size.checked_mul(256)?.checked_add(*b as u64)?;
+    }
+    Some(size)
+}
+
+/// The sum of the header's bytes, with the checksum field counted as spaces.
+fn tar_checksum(header: &[u8]) -> u64 {
+    header
+        .iter()
+        .enumerate()
+        .map(|(i, b)| if (148..156).contains(&i) { b' ' } else { *b })
+        .map(u64::from)
+        .sum()
+}
+
+/// The path in a pax extended header, made of records like `17 path=a/b.rs\n`.
+fn pax_path(data: &[u8]) -> Option<String> {
+    let mut rest = data;
+    while !rest.is_empty() {
+        let space = rest.iter().position(|b| *b == b' ')?;
+        let len: usize = std::str::from_utf8(&rest[..space]).ok()?.parse().ok()?;
+        let record = rest.get(space + 1..len)?;
+        if let Some(path) = record.strip_prefix(b"path=") {
+            let path = path.strip_suffix(b"\n").unwrap_or(path);
+            return Some(String::from_utf8_lossy(path).to_string());
+        }
+        rest = &rest[len..];
+    }
+    None
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use std::io::Cursor;
+
+    fn names(entries: &[Entry]) -> Vec<(&str, &str)> {
+        entries.iter().map(|e| (e.name.as_str(), e.kind)).collect()
+    }
+
+    /// A zip with the entries stored rather than compressed, so it can be
+    /// written by hand.
+    fn zip(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut cd = Vec::new();
+        for (name, data) in files {
+            let offset = out.len() as u32;
+            let local = |out: &mut Vec<u8>| {
+                out.extend_from_slice(&[0; 14]);
+                out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+                out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+                out.extend_from_slice(&(name.len() as u16).to_le_bytes());
+                out.extend_from_slice(&0u16.to_le_bytes());
+            };
+            out.extend_from_slice(b"PK\x03\x04");
+            local(&mut out);
+            out.extend_from_slice(name.as_bytes());
+            out.extend_from_slice(data);
+
+            cd.extend_from_slice(ZIP_ENTRY_SIGNATURE);
+            cd.extend_from_slice(&[0; 24]);
+            cd.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            cd.extend_from_slice(&[0; 12]);
+            cd.extend_from_slice(&offset.to_le_bytes());
+            cd.extend_from_slice(name.as_bytes());
+        }
+        let cd_offset = out.len() as u32;
+        out.extend_from_slice(&cd);
+        out.extend_from_slice(ZIP_EOCD_SIGNATURE);
+        out.extend_from_slice(&[0; 4]);
+        out.extend_from_slice(&(files.len() as u16).to_le_bytes());
+        out.extend_from_slice(&(files.len() as u16).to_le_bytes());
+        out.extend_from_slice(&(cd.len() as u32).to_le_bytes());
+        out.extend_from_slice(&cd_offset.to_le_bytes());
+        out.extend_from_slice(b"\x05\x00hello");
+        out
+    }
+
+    fn tar_header(name: &str, size: usize, kind: u8) -> Vec<u8> {
+        let mut header = vec![0; TAR_BLOCK as usize];
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        header[124..135].copy_from_slice(format!("{:011o}", size).as_bytes());
+        header[156] = kind;
+        header[257..263].copy_from_slice(b"ustar\0");
+        let checksum = tar_checksum(&header);
+        header[148..155].copy_from_slice(format!("{:06o}\0", checksum).as_bytes());
+        header
+    }
+
+    /// A tar of the entries, each a name, type flag and contents.
+    pub(crate) fn tar(files: &[(&str, u8, &[u8])]) -> Vec<u8> {
+        let mut out = Vec::new();
+        for (name, kind, data) in files {
+            out.extend(tar_header(name, data.len(), *kind));
+            out.extend_from_slice(data);
+            let padding = (TAR_BLOCK as usize - data.len() % TAR_BLOCK as usize) % 512;
+            out.extend(vec![0; padding]);
+        }
+        out.extend(vec![0; 2 * TAR_BLOCK as usize]);
+        out
+    }
+
+    #[test]
+    fn test_archive_entries() {
+        let zipped = zip(&[
+            ("src/", b""),
+            ("src/main.rs", b"fn main() {}"),
+            ("./README.md", b"# Readme"),
+        ]);
+        let entries = zip_entries(Cursor::new(zipped)).unwrap();
+        assert_eq!(
+            names(&entries),
+            [
+                ("src", "dir"),
+                ("src/main.rs", "file"),
+                ("README.md", "file")
+            ]
+        );
+        assert!(zip_entries(Cursor::new(b"not a zip".to_vec())).is_err());
+
+        let long_name = format!("{}/deep.rs", "d".repeat(120));
+        let pax_name = "pax/named.rs";
+        // The length counts itself, two digits here.
+        let pax_record = format!(" path={}\n", pax_name);
+        let pax_record = format!("{}{}", pax_record.len() + 2, pax_record);
+        let tarred = tar(&[
+            ("src/", b'5', b""),
+            ("src/lib.rs", b'0', &[b'x'; 600]),
+            ("././@LongLink", b'L', format!("{}\0", long_name).as_bytes()),
+            ("truncated", b'0', b""),
+            ("PaxHeaders/named.rs", b'x', pax_record.as_bytes()),
+            ("named.rs", b'0', b"pax"),
+            ("link", b'2', b""),
+        ]);
+        let entries = tar_entries(Cursor::new(tarred.clone())).unwrap();
+        assert_eq!(
+            names(&entries),
+            [
+                ("src", "dir"),
+                ("src/lib.rs", "file"),
+                (long_name.as_str(), "file"),
+                (pax_name, "file"),
+                ("link", "symlink"),
+            ]
+        );
+        let mut corrupt = tarred;
+        corrupt[0] = b'x';
+        assert!(tar_entries(Cursor::new(corrupt)).is_err());
+
+        // A size far past the end, in base 256, is an error rather than
+        // something to read or skip.
+        for kind in [b'0', b'L', b'x', b'g'] {
+            let mut forged = tar_header("huge", 0, kind);
+            forged[124..136].copy_from_slice(&[
+                0x80, 0, 0, 0, 0x7f, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+            ]);
+            let checksum = tar_checksum(&forged);
+            forged[148..155].copy_from_slice(format!("{:06o}\0", checksum).as_bytes());
+            forged.extend(vec![0; 2 * TAR_BLOCK as usize]);
+            let e = tar_entries(Cursor::new(forged)).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        }
+
+        assert_eq!(
+            virtual_path("/a/b.zip", "src/main.rs"),
+            "/a/b.zip!/src/main.rs"
+        );
+        assert_eq!(archive_of("/a/b.zip!/src/main.rs"), Some("/a/b.zip"));
+        assert_eq!(archive_of("/a/b.zip"), None);
+    }
+}
@@ -0,0 +1,279 @@
+//! An audit trail of the queries run, appended to a file as JSON lines.
+
+use serde::Serialize;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// The number of entries waiting to be written before more are dropped.
+const AUDIT_QUEUE: usize = 1024;
+
+/// How long to carry on appending to a full log after failing to rotate it,
+/// before trying again.
+const ROTATE_RETRY: Duration = Duration::from_secs(60);
+
+/// A query as it goes in the audit log.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub(crate) struct AuditEntry {
+    /// Seconds since the Unix epoch.
+    pub timestamp: u64,
+    /// The user the query was authenticated as, if the daemon has users.
+    pub user: Option<String>,
+    /// The address of the client that sent the query.
+    pub addr: Option<String>,
+    pub query: String,
+    pub total_hits: u64,
+    /// The number of results sent back, at most `total_hits`.
+    pub returned: u64,
+}
+
+impl AuditEntry {
+    pub(crate) fn now(
+        user: Option<String>,
+        addr: Option<String>,
+        query: &str,
+        total_hits: u64,
+        returned: u64,
+    ) -> Self {
+        AuditEntry {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+            user,
+            addr,
+            query: query.to_string(),
+            total_hits,
+            returned,
+        }
+    }
+}
+
+/// Appends entries to the log on a thread of its own, so a slow disk never
+/// holds up a query. Once the file reaches `max_bytes` it's moved to
+/// `<path>.1`, replacing the one before, and a new one is started, or if
+/// that fails, appended to until `ROTATE_RETRY` has passed. The entries
+/// still queued are written when this is dropped.
+#[derive(Debug)]
+pub struct AuditLog {
+    tx: Option<SyncSender<AuditEntry>>,
+    writer: Option<JoinHandle<()>>,
+}
+
+impl AuditLog {
+    /// Start appending to the file at `path`, creating it if needed.
+    pub fn open(path: &Path, max_bytes: u64) -> io::Result<Self> {
+        // Opened here, so a bad path is reported when the daemon starts.
+        let file = open_append(path)?;
+        let size = file.metadata()?.len();
+        let (tx, rx) = mpsc::sync_channel(AUDIT_QUEUE);
+        let writer = Writer {
+            path: path.to_path_buf(),
+            max_bytes,
+            file,
+            size,
+            retry_rotate: None,
+        };
+        let writer = thread::Builder::new()
+            .name("audit".to_string())
+            .spawn(move || writer.run(rx))?;
+        Ok(AuditLog {
+            tx: Some(tx),
+            writer: Some(writer),
+        })
+    }
+
+    /// Queue the entry to be written. If the writer has fallen too far
+    /// behind, it's dropped rather than making the query wait.
+    pub(crate) fn record(&self, entry: AuditEntry) {
+        if let Some(tx) = &self.tx {
+            match tx.try_send(entry) {
+                Ok(()) => (),
+                Err(TrySendError::Full(entry)) => {
+                    warn!(
+                        "Audit log is behind, dropped the entry for {:?}",
+                        entry.query
+                    )
+                }
+                Err(TrySendError::Disconnected(_)) => (),
+            }
+        }
+    }
+}
+
+impl Drop for AuditLog {
+    fn drop(&mut self) {
+        // Closing the channel lets the writer finish what's queued and stop.
+        self.tx.take();
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+struct Writer {
+    path: PathBuf,
+    max_bytes: u64,
+    file: File,
+    size: u64,
+    /// After failing to rotate, when to try again.
+    retry_rotate: Option<Instant>,
+}
+
+impl Writer {
+    fn run(mut self, rx: Receiver<AuditEntry>) {
+        for entry in rx {
+            self.write(&entry, Instant::now());
+        }
+    }
+
+    fn write(&mut self, entry: &AuditEntry, now: Instant) {
+        let mut line = match serde_json::to_vec(entry) {
+            Ok(l) => l,
+            Err(e) => {
+                error!("Could not serialize the audit entry: {}", e);
+                return;
+            }
+        };
+        line.push(b'\n');
+        let full = self.size > 0 && self.size + line.len() as u64 > self.max_bytes;
+        if full && self.retry_rotate.is_none_or(|at| now >= at) {
+            match self.rotate() {
+                Ok(()) => self.retry_rotate = None,
+                Err(e) => {
+                    error!(
+                        "Could not rotate the audit log {:?}, trying again in {}s: {}",
+                        self.path,
+                        ROTATE_RETRY.as_secs(),
+                        e
+                    );
+                    self.retry_rotate = Some(now + ROTATE_RETRY);
+                }
+            }
+        }
+        // Written whole, so concurrent readers never see half a line.
+        match self.file.write_all(&line) {
+            Ok(()) => self.size += line.len() as u64,
+            Err(e) => error!("Could not write to the audit log {:?}: {}", self.path, e),
+        }
+    }
+
+    /// Move the full log aside and start a new one.
+    fn rotate(&mut self) -> io::Result<()> {
+        fs::rename(&self.path, rotated_path(&self.path))?;
+        self.file = open_append(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+/// Where the previous log is kept, `<path>.1`.
+fn rotated_path(path: &Path) -> PathBuf {
+    let mut rotated = path.as_os_str().to_owned();
+    rotated.push(".1");
+    PathBuf::from(rotated)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn read_entries(path: &Path) -> Vec<serde_json::Value> {
+        fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_audit_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.log");
+        let entry = |query| {
+            AuditEntry::now(
+                Some("me".to_string()),
+                Some("127.0.0.1:1234".to_string()),
+                query,
+                3,
+                2,
+            )
+        };
+
+        let log = AuditLog::open(&path, 1024).unwrap();
+        log.record(entry("one"));
+        log.record(entry("two"));
+        drop(log);
+        let entries = read_entries(&path);
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0]["query"], "one");
+        assert_eq!(entries[0]["user"], "me");
+        assert_eq!(entries[0]["addr"], "127.0.0.1:1234");
+        assert_eq!(entries[0]["total_hits"], 3);
+        assert_eq!(entries[0]["returned"], 2);
+        assert!(entries[0]["timestamp"].as_u64().unwrap() > 0);
+
+        // Appended to, and moved aside once the next entry won't fit. The
+        // queries are the same length, so every line is too.
+        let line_len = fs::metadata(&path).unwrap().len() / 2;
+        let log = AuditLog::open(&path, line_len * 3).unwrap();
+        log.record(entry("six"));
+        log.record(entry("ten"));
+        drop(log);
+        let old: Vec<_> = read_entries(&rotated_path(&path))
+            .into_iter()
+            .map(|e| e["query"].clone())
+            .collect();
+        assert_eq!(old, vec!["one", "two", "six"]);
+        let entries = read_entries(&path);
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0]["query"], "ten");
+    }
+
+    #[test]
+    fn test_rotate_retry() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.log");
+        let entry = |query| AuditEntry::now(None, None, query, 0, 0);
+        let mut writer = Writer {
+            path: path.clone(),
+            max_bytes: 1,
+            file: open_append(&path).unwrap(),
+            size: 0,
+            retry_rotate: None,
+        };
+        let start = Instant::now();
+        let queries = |path: &Path| -> Vec<_> {
+            read_entries(path)
+                .into_iter()
+                .map(|e| e["query"].clone())
+                .collect()
+        };
+
+        // A directory in the way can't be replaced, so the log can't be
+        // rotated, and carries on growing.
+        let rotated = rotated_path(&path);
+        fs::create_dir(&rotated).unwrap();
+        fs::write(rotated.join("in-the-way"), "").unwrap();
+        writer.write(&entry("a"), start);
+        writer.write(&entry("b"), start);
+        assert_eq!(queries(&path), vec!["a", "b"]);
+        assert_eq!(writer.retry_rotate, Some(start + ROTATE_RETRY));
+
+        // Not tried again until it's time, even if it would work.
+        fs::remove_dir_all(&rotated).unwrap();
+        writer.write(&entry("c"), start + ROTATE_RETRY / 2);
+        assert_eq!(queries(&path), vec!["a", "b", "c"]);
+        writer.write(&entry("d"), start + ROTATE_RETRY);
+        assert_eq!(queries(&rotated), vec!["a", "b", "c"]);
+        assert_eq!(queries(&path), vec!["d"]);
+        assert_eq!(writer.retry_rotate, None);
+    }
+}
@@ -0,0 +1,1185 @@
+//! Configuration for the daemon.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::env;
+use std::ffi::OsString;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufReader};
+use std::mem;
+use std::ops::RangeInclusive;
+use std::path::{Component, Path, PathBuf};
+
+/// Environment variable naming the config file to use.
+pub static CONFIG_ENV: &str = "LOOKRD_CONFIG";
+/// The config file under the home directory, used if no other is found.
+static DEFAULT_CONFIG: &str = ".lookrd";
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct LookrdConfig {
+    /// The paths that will be indexed by the indexer. Defaults to none, so
+    /// leaving it out is reported by `validate` rather than serde.
+    #[serde(default)]
+    pub index_paths: Vec<String>,
+    /// The location this data will be written to.
+    #[serde(default)]
+    pub data_dir: String,
+    /// With `multi_user`, the users to index for, separated by commas. All
+    /// login users if not given.
+    pub users: Option<String>,
+    /// Once the initial walk is complete, read through the term dictionaries
+    /// so the first query doesn't pay to load them.
+    #[serde(default)]
+    pub prewarm: bool,
+    /// Paths under the index paths that should not be indexed, the whole
+    /// subtree below each of these is skipped.
+    #[serde(default)]
+    pub exclude_paths: Vec<String>,
+    /// Require every term in a query to match, rather than any of them. With
+    /// this set `foo bar` only matches paths containing both `foo` and `bar`.
+    #[serde(default)]
+    pub default_and: bool,
+    /// Words dropped from queries, like `src` or `test`, that match so much
+    /// of the index they don't tell results apart. Applied as queries are
+    /// parsed, so they can be changed without reindexing.
+    #[serde(default)]
+    pub stop_words: Vec<String>,
+    /// How matches are ranked.
+    #[serde(default)]
+    pub scoring: Scoring,
+    /// With BM25 scoring, how quickly repeats of a term in a path stop
+    /// adding to its score, from 0 for at once. Defaults to 1.2.
+    #[serde(default)]
+    pub bm25_k1: Option<f32>,
+    /// With BM25 scoring, how much a path's length counts against it, from 0
+    /// for not at all to 1 for in proportion. Defaults to 0.75.
+    #[serde(default)]
+    pub bm25_b: Option<f32>,
+    /// Favour recently modified matches: one modified just now scores this
+    /// much more again, halving with each week since. Off unless set.
+    #[serde(default)]
+    pub recency_weight: Option<f32>,
+    /// Merge the index down to a single segment whenever it has more than
+    /// this many segments.
+    #[serde(default)]
+    pub compact_segments: Option<usize>,
+    /// Commit after every change from the watcher, rather than after a
+    /// second without changes or every 1000 of them, so no change is lost
+    /// if the daemon dies. Slow where files change often.
+    #[serde(default)]
+    pub durable: bool,
+    /// Once the index holds more than this many entries, evict the least
+    /// recently modified, so it only keeps the most recent files.
+    #[serde(default)]
+    pub max_docs: Option<u64>,
+    /// Read tags for each file from its `<file>.lookr.json` sidecar.
+    #[serde(default)]
+    pub tags: bool,
+    /// How many watcher events can be queued for the indexer, defaults to
+    /// `DEFAULT_WATCH_QUEUE`.
+    #[serde(default)]
+    pub watch_queue: Option<usize>,
+    /// What the watcher does when the queue is full.
+    #[serde(default)]
+    pub watch_overflow: OverflowPolicy,
+    /// Index the first line of files up to this many bytes in size, to find
+    /// scripts by their shebang or documents by their title.
+    #[serde(default)]
+    pub first_line_max_size: Option<u64>,
+    /// How much of the start of a file is checked for NUL bytes and invalid
+    /// UTF-8 before its first line is indexed, defaults to
+    /// `DEFAULT_BINARY_SNIFF_BYTES`. Files that fail the check are binary,
+    /// and only their paths are indexed.
+    #[serde(default)]
+    pub binary_sniff_bytes: Option<u64>,
+    /// Index the entries of zip and tar archives as virtual paths below
+    /// them, like `/src/code.zip!/lib/main.rs`.
+    #[serde(default)]
+    pub archives: bool,
+    /// The largest archive whose entries are indexed, defaults to
+    /// `DEFAULT_ARCHIVE_MAX_BYTES`.
+    #[serde(default)]
+    pub archive_max_bytes: Option<u64>,
+    /// Give up on a query that takes longer than this, defaults to
+    /// `DEFAULT_QUERY_TIMEOUT_MS`.
+    #[serde(default)]
+    pub query_timeout_ms: Option<u64>,
+    /// How long a query with `wait_for_ready` waits for the first walk of
+    /// the index paths, defaults to `DEFAULT_MAX_READY_WAIT_MS`. It's then
+    /// answered from what's been indexed so far.
+    #[serde(default)]
+    pub max_ready_wait_ms: Option<u64>,
+    /// How many queries can run at once, across every client and user,
+    /// defaults to `DEFAULT_MAX_CONCURRENT_QUERIES`. More are turned away.
+    #[serde(default)]
+    pub max_concurrent_queries: Option<usize>,
+    /// How many queries a second each client address can send, defaults to
+    /// `DEFAULT_CLIENT_QUERIES_PER_SEC`. More are turned away.
+    #[serde(default)]
+    pub client_queries_per_sec: Option<u32>,
+    /// Fold accented characters in paths and queries to ASCII, so `cafe`
+    /// matches `café`.
+    #[serde(default)]
+    pub ascii_fold: bool,
+    /// Also index the words of camelCase filenames, so `getUserName.rs`
+    /// matches `user`.
+    #[serde(default)]
+    pub split_words: bool,
+    /// Only index files with one of these extensions. Directories are
+    /// always indexed.
+    #[serde(default)]
+    pub index_extensions: Option<Vec<String>>,
+    /// Never index files with one of these extensions, even if they are in
+    /// `index_extensions`.
+    #[serde(default)]
+    pub exclude_extensions: Option<Vec<String>>,
+    /// The nice value to run the indexer at, higher being lower priority.
+    /// Left as the daemon's own if 0.
+    #[serde(default)]
+    pub index_nice: i32,
+    /// Pause for this long after every 100 documents added by a walk,
+    /// to leave CPU for everything else.
+    #[serde(default)]
+    pub index_throttle_ms: Option<u64>,
+    /// The memory the index writer buffers documents in before writing them
+    /// out as a segment, shared between its threads, defaults to
+    /// `DEFAULT_WRITER_MEMORY_BYTES`.
+    #[serde(default)]
+    pub writer_memory_bytes: Option<usize>,
+    /// How many threads the index writer indexes documents on, defaults to
+    /// `DEFAULT_WRITER_THREADS`.
+    #[serde(default)]
+    pub writer_threads: Option<usize>,
+    /// Once the socket is bound, switch the daemon to this user, so it can
+    /// be started as root without staying root.
+    #[serde(default)]
+    pub run_as_user: Option<String>,
+    /// Index each entry under its real path, so a file reachable through
+    /// symlinks is only indexed once, under whichever path was seen first.
+    #[serde(default)]
+    pub canonical_ids: bool,
+    /// Index `\` in paths as `/`, so paths from a Windows volume are queried
+    /// like any other. Results still show the paths as they were found.
+    #[serde(default)]
+    pub normalize_separators: bool,
+    /// How the index paths are watched for changes.
+    #[serde(default)]
+    pub watch_mode: WatchMode,
+    /// How long the native watcher waits for a path to settle before
+    /// reporting its changes, defaults to `DEFAULT_WATCH_DEBOUNCE_MS`.
+    #[serde(default)]
+    pub watch_debounce_ms: Option<u64>,
+    /// Index paths to poll for changes whatever the `watch_mode`, for network
+    /// filesystems mounted alongside local ones.
+    #[serde(default)]
+    pub poll_paths: Vec<String>,
+    /// How often polled paths are scanned, defaults to
+    /// `DEFAULT_POLL_INTERVAL_SECS`.
+    #[serde(default)]
+    pub poll_interval_secs: Option<u64>,
+    /// Keep this many of the most recent queries, for `RecentQueries`. Off
+    /// unless set, as queries can say a lot about who ran them.
+    #[serde(default)]
+    pub query_history: Option<usize>,
+    /// Keep the responses to this many distinct queries, answering repeats
+    /// of them from memory until the index next changes.
+    #[serde(default)]
+    pub query_cache_size: Option<usize>,
+    /// Remember this many distinct queries that found nothing, answering
+    /// them, and queries that only add words to them, without searching
+    /// until the index next changes.
+    #[serde(default)]
+    pub negative_cache_size: Option<usize>,
+    /// Keep the index in `<data_dir>/index` rather than in memory, so it's
+    /// there to query from the moment the daemon restarts.
+    #[serde(default)]
+    pub persist_index: bool,
+    /// Start watching a persisted index straight away, rather than walking
+    /// the index paths first. Changes made while the daemon wasn't running
+    /// are missed until the roots are reindexed.
+    #[serde(default)]
+    pub skip_initial_walk: bool,
+    /// Also index filenames as runs of characters, so queries with
+    /// `substring` set match any part of a name.
+    #[serde(default)]
+    pub substring_index: bool,
+    /// Keep a separate index for each user, of only what they can see, and
+    /// answer each user's queries from theirs.
+    #[serde(default)]
+    pub multi_user: bool,
+    /// Also index each entry's inode and device, so queries can find the
+    /// hard links to a file. Unix only.
+    #[serde(default)]
+    pub index_inodes: bool,
+    /// Also index the uid owning each entry, so queries can find only the
+    /// user's own files. Unix only.
+    #[serde(default)]
+    pub index_owners: bool,
+    /// Index a hash of the contents of files up to this many bytes in size,
+    /// so files with the same contents can be found. Off unless set.
+    #[serde(default)]
+    pub content_hash_max_size: Option<u64>,
+    /// Also index the extended attributes whose names start with one of
+    /// these, like `user.`, so queries can find the files that have them.
+    /// Linux and macOS only.
+    #[serde(default)]
+    pub index_xattrs: Vec<String>,
+    /// Answer `Replicate`, streaming the index to standby daemons.
+    #[serde(default)]
+    pub serve_replication: bool,
+    /// Run as a standby of the daemon at this URL, like
+    /// `http://primary:8901`, keeping a copy of its index rather than
+    /// walking and watching `index_paths`.
+    #[serde(default)]
+    pub replicate_from: Option<String>,
+    /// Append every query, with who ran it and from where, to this file as
+    /// JSON lines. Off unless set.
+    #[serde(default)]
+    pub audit_log: Option<String>,
+    /// The size the audit log can reach before it's moved to `<file>.1`,
+    /// defaults to `DEFAULT_AUDIT_LOG_MAX_BYTES`.
+    #[serde(default)]
+    pub audit_log_max_bytes: Option<u64>,
+    /// Named lists of extensions, like `images` for `png`, `jpg` and `gif`,
+    /// so queries can match any of them by the group's name. A table in
+    /// TOML, so kept with `tokenizers` after the plain values.
+    #[serde(default)]
+    pub extension_groups: BTreeMap<String, Vec<String>>,
+    /// How often to walk each of these index paths again from scratch, in
+    /// seconds, to catch changes the watcher missed. Only those listed are.
+    /// Also a table in TOML.
+    #[serde(default)]
+    pub rewalk_interval_secs: BTreeMap<String, u64>,
+    /// How each text field is split into terms, for indexing and querying.
+    /// Kept last, as TOML needs tables after plain values.
+    #[serde(default)]
+    pub tokenizers: FieldTokenizers,
+}
+
+/// The number of watcher events queued for the indexer if not configured.
+pub(crate) const DEFAULT_WATCH_QUEUE: usize = 10_000;
+
+/// How long a query can run for if not configured.
+pub(crate) const DEFAULT_QUERY_TIMEOUT_MS: u64 = 10_000;
+
+/// How long a query can wait for the first walk if not configured.
+pub(crate) const DEFAULT_MAX_READY_WAIT_MS: u64 = 30_000;
+
+/// How many queries can run at once if not configured.
+pub(crate) const DEFAULT_MAX_CONCURRENT_QUERIES: usize = 64;
+
+/// How many queries a second a client can send if not configured.
+pub(crate) const DEFAULT_CLIENT_QUERIES_PER_SEC: u32 = 100;
+
+/// The size the audit log is rotated at if not configured.
+pub const DEFAULT_AUDIT_LOG_MAX_BYTES: u64 = 64 * 1024 * 1024;
+
+/// How much of a file is checked for being binary if not configured.
+pub(crate) const DEFAULT_BINARY_SNIFF_BYTES: u64 = 8 * 1024;
+
+/// The largest archive listed if not configured.
+pub(crate) const DEFAULT_ARCHIVE_MAX_BYTES: u64 = 64 * 1024 * 1024;
+
+/// How often polled paths are scanned if not configured.
+pub(crate) const DEFAULT_POLL_INTERVAL_SECS: u64 = 30;
+
+/// How long the native watcher waits for changes to settle if not
+/// configured.
+pub(crate) const DEFAULT_WATCH_DEBOUNCE_MS: u64 = 1000;
+
+/// The index writer's memory if not configured.
+pub(crate) const DEFAULT_WRITER_MEMORY_BYTES: usize = 50_000_000;
+
+/// The index writer's threads if not configured.
+pub(crate) const DEFAULT_WRITER_THREADS: usize = 1;
+
+/// The memory tantivy accepts for each of the index writer's threads.
+const WRITER_THREAD_MEMORY_RANGE: RangeInclusive<usize> =
+    3_000_000..=(u32::MAX as usize - 1_000_001);
+
+/// The debounce allowed. Any shorter and a file being written is reported
+/// once for every write; any longer and changes take minutes to show up.
+const WATCH_DEBOUNCE_RANGE_MS: RangeInclusive<u64> = 10..=60_000;
+
+/// How changes to the index paths are noticed.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WatchMode {
+    /// Poll the paths on network filesystems, like NFS, SMB and FUSE mounts,
+    /// and use notifications for the rest. Only Linux can tell which
+    /// filesystem a path is on; elsewhere, and when it can't be told,
+    /// notifications are used.
+    #[default]
+    Auto,
+    /// Use the platform's notifications (inotify, FSEvents, ...).
+    Native,
+    /// Scan the paths every `poll_interval_secs` and compare modification
+    /// times. Works where notifications don't, such as NFS and SMB mounts
+    /// changed by other machines, but costs a stat of every file per scan.
+    Poll,
+}
+
+/// The tokenizer for each of the text fields.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct FieldTokenizers {
+    #[serde(default)]
+    pub path: TokenizerKind,
+    #[serde(default)]
+    pub filename: TokenizerKind,
+    #[serde(default)]
+    pub first_line: TokenizerKind,
+}
+
+/// The ways a field can be split into terms.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TokenizerKind {
+    /// Words, split on whitespace and punctuation.
+    #[default]
+    Default,
+    /// The whole value as one term, so only the exact value matches.
+    Raw,
+    /// Every run of 2 or 3 characters, so part of a word matches it.
+    Ngram,
+    /// Split on whitespace only, keeping punctuation in the terms.
+    Whitespace,
+}
+
+/// The ways matches can be ranked.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scoring {
+    /// BM25, tuned with `bm25_k1` and `bm25_b`.
+    #[default]
+    Bm25,
+    /// Classic TF-IDF, where repeats of a term keep adding to the score and
+    /// longer paths count for less.
+    Tfidf,
+}
+
+/// What to do with a watcher event when the indexer's queue is full.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OverflowPolicy {
+    /// Wait for the indexer to make room, holding up the watcher.
+    #[default]
+    Block,
+    /// Drop the event and mark its root dirty, so the root is walked again
+    /// once the indexer has caught up.
+    Drop,
+}
+
+/// Reads the config, in a format chosen by the file's extension: `.toml`,
+/// `.yaml` or `.yml`, and JSON for `.json` or no extension.
+pub fn read_config(cfg: &Path) -> io::Result<LookrdConfig> {
+    let ext = cfg.extension().map(|e| e.to_string_lossy().to_lowercase());
+    let mut config: LookrdConfig = match ext.as_deref() {
+        None | Some("json") => {
+            let reader = BufReader::new(File::open(cfg)?);
+            serde_json::from_reader(reader)?
+        }
+        Some("toml") => {
+            let contents = fs::read_to_string(cfg)?;
+            toml::from_str(&contents).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?
+        }
+        Some("yaml") | Some("yml") => {
+            let reader = BufReader::new(File::open(cfg)?);
+            serde_yaml::from_reader(reader)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?
+        }
+        Some(ext) => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Unknown config format {:?} for {:?}, expected .json, .toml or .yaml",
+                    ext, cfg
+                ),
+            ))
+        }
+    };
+    // Relative to the config, rather than wherever the daemon was started.
+    let dir = env::current_dir()?.join(cfg.parent().unwrap_or_else(|| Path::new("")));
+    config.resolve_paths(&dir);
+    let problems = config.validate();
+    for p in problems.iter().filter(|p| p.warning) {
+        warn!("{:?}: {}", cfg, p);
+    }
+    let errors: Vec<String> = problems
+        .iter()
+        .filter(|p| !p.warning)
+        .map(|p| format!("  {}", p))
+        .collect();
+    if !errors.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{:?} has problems:\n{}", cfg, errors.join("\n")),
+        ));
+    }
+    Ok(config)
+}
+
+/// Something wrong with a config value, or that looks like a mistake.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConfigProblem {
+    /// The field the value is in.
+    pub field: &'static str,
+    pub message: String,
+    /// The daemon can start anyway, but probably shouldn't.
+    pub warning: bool,
+}
+
+impl ConfigProblem {
+    fn error(field: &'static str, message: impl Into<String>) -> Self {
+        ConfigProblem {
+            field,
+            message: message.into(),
+            warning: false,
+        }
+    }
+
+    fn warning(field: &'static str, message: impl Into<String>) -> Self {
+        ConfigProblem {
+            field,
+            message: message.into(),
+            warning: true,
+        }
+    }
+}
+
+impl fmt::Display for ConfigProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.field, self.message)
+    }
+}
+
+impl LookrdConfig {
+    /// Make the relative paths in the config relative to `dir`, the
+    /// directory the config was read from.
+    pub fn resolve_paths(&mut self, dir: &Path) {
+        let resolve_all = |paths: &mut Vec<String>| {
+            for p in paths.iter_mut() {
+                *p = resolve_path(dir, p);
+            }
+        };
+        resolve_all(&mut self.index_paths);
+        resolve_all(&mut self.exclude_paths);
+        resolve_all(&mut self.poll_paths);
+        self.rewalk_interval_secs = mem::take(&mut self.rewalk_interval_secs)
+            .into_iter()
+            .map(|(p, secs)| (resolve_path(dir, &p), secs))
+            .collect();
+        // Left empty to be reported as missing.
+        if !self.data_dir.is_empty() {
+            self.data_dir = resolve_path(dir, &self.data_dir);
+        }
+        if let Some(audit_log) = &mut self.audit_log {
+            *audit_log = resolve_path(dir, audit_log);
+        }
+    }
+
+    /// A hash of the settings that change how an entry is indexed, so an
+    /// index built with others can be told apart and rebuilt, rather than
+    /// keep what's unchanged indexed the old way. Those that only change
+    /// which entries are indexed, or how queries are answered, are left
+    /// out.
+    pub fn index_fingerprint(&self) -> String {
+        let settings = serde_json::json!({
+            "tags": self.tags,
+            "first_line_max_size": self.first_line_max_size,
+            "binary_sniff_bytes": self.binary_sniff_bytes,
+            "archives": self.archives,
+            "archive_max_bytes": self.archive_max_bytes,
+            "ascii_fold": self.ascii_fold,
+            "split_words": self.split_words,
+            "canonical_ids": self.canonical_ids,
+            "normalize_separators": self.normalize_separators,
+            "substring_index": self.substring_index,
+            "index_inodes": self.index_inodes,
+            "index_owners": self.index_owners,
+            "content_hash_max_size": self.content_hash_max_size,
+            "index_xattrs": self.index_xattrs,
+            "tokenizers": self.tokenizers,
+        });
+        blake2b_simd::Params::new()
+            .hash_length(16)
+            .hash(settings.to_string().as_bytes())
+            .to_hex()
+            .to_string()
+    }
+
+    /// Every problem with the values that parse but make no sense, or look
+    /// like mistakes, rather than just the first.
+    pub fn validate(&self) -> Vec<ConfigProblem> {
+        self.problems(dirs::home_dir().as_deref())
+    }
+
+    fn problems(&self, home: Option<&Path>) -> Vec<ConfigProblem> {
+        let mut problems = Vec::new();
+        if self.index_paths.is_empty() && self.replicate_from.is_none() {
+            problems.push(ConfigProblem::error(
+                "index_paths",
+                "there are no directories to index, list at least one",
+            ));
+        }
+        let roots: Vec<&Path> = self.index_paths.iter().map(Path::new).collect();
+        for (i, root) in roots.iter().enumerate() {
+            let path = &self.index_paths[i];
+            // Path compares by component, so a trailing slash doesn't count.
+            if let Some(other) = roots[..i].iter().find(|r| *r == root) {
+                problems.push(ConfigProblem::error(
+                    "index_paths",
+                    format!("{} is listed twice, as {}", path, other.display()),
+                ));
+            } else if let Some(above) = roots.iter().find(|r| *r != root && root.starts_with(r)) {
+                problems.push(ConfigProblem::error(
+                    "index_paths",
+                    format!(
+                        "{} is inside {}, so would be indexed twice",
+                        path,
+                        above.display()
+                    ),
+                ));
+            }
+            if root.parent().is_none() && root.has_root() {
+                problems.push(ConfigProblem::warning(
+                    "index_paths",
+                    format!(
+                        "{} indexes the whole filesystem, including /proc and /sys",
+                        path
+                    ),
+                ));
+            } else if home == Some(*root) {
+                problems.push(ConfigProblem::warning(
+                    "index_paths",
+                    format!(
+                        "{} is the home directory, caches and all, consider exclude_paths",
+                        path
+                    ),
+                ));
+            }
+        }
+        if let Err(e) = writable_dir(Path::new(&self.data_dir)) {
+            problems.push(ConfigProblem::error("data_dir", e));
+        }
+        for (path, secs) in &self.rewalk_interval_secs {
+            if !roots.contains(&Path::new(path)) {
+                problems.push(ConfigProblem::error(
+                    "rewalk_interval_secs",
+                    format!("{} isn't one of the index_paths", path),
+                ));
+            }
+            if *secs == 0 {
+                problems.push(ConfigProblem::error(
+                    "rewalk_interval_secs",
+                    format!("the interval for {} must be at least a second", path),
+                ));
+            }
+        }
+        if let Some(ms) = self.watch_debounce_ms {
+            if !WATCH_DEBOUNCE_RANGE_MS.contains(&ms) {
+                problems.push(ConfigProblem::error(
+                    "watch_debounce_ms",
+                    format!(
+                        "must be from {} to {}, not {}",
+                        WATCH_DEBOUNCE_RANGE_MS.start(),
+                        WATCH_DEBOUNCE_RANGE_MS.end(),
+                        ms
+                    ),
+                ));
+            }
+        }
+        let writer_threads = self.writer_threads.unwrap_or(DEFAULT_WRITER_THREADS);
+        let memory = self
+            .writer_memory_bytes
+            .unwrap_or(DEFAULT_WRITER_MEMORY_BYTES);
+        match memory.checked_div(writer_threads) {
+            None => problems.push(ConfigProblem::error("writer_threads", "must be at least 1")),
+            Some(per_thread) if !WRITER_THREAD_MEMORY_RANGE.contains(&per_thread) => {
+                problems.push(ConfigProblem::error(
+                    "writer_memory_bytes",
+                    format!(
+                        "must be from {} to {} for each of the {} writer threads, not {}",
+                        WRITER_THREAD_MEMORY_RANGE.start(),
+                        WRITER_THREAD_MEMORY_RANGE.end(),
+                        writer_threads,
+                        memory
+                    ),
+                ));
+            }
+            Some(_) => (),
+        }
+        if self.skip_initial_walk && !self.persist_index {
+            problems.push(ConfigProblem::error(
+                "skip_initial_walk",
+                "needs persist_index, as there's nothing to watch without a walk",
+            ));
+        }
+        if self.max_docs == Some(0) {
+            problems.push(ConfigProblem::error("max_docs", "must be at least 1"));
+        }
+        if self.max_concurrent_queries == Some(0) {
+            problems.push(ConfigProblem::error(
+                "max_concurrent_queries",
+                "must be at least 1",
+            ));
+        }
+        if self.client_queries_per_sec == Some(0) {
+            problems.push(ConfigProblem::error(
+                "client_queries_per_sec",
+                "must be at least 1",
+            ));
+        }
+        if self.query_cache_size == Some(0) {
+            problems.push(ConfigProblem::error(
+                "query_cache_size",
+                "must be at least 1, or left out for no cache",
+            ));
+        }
+        if self.negative_cache_size == Some(0) {
+            problems.push(ConfigProblem::error(
+                "negative_cache_size",
+                "must be at least 1, or left out for no cache",
+            ));
+        }
+        if self.scoring != Scoring::Bm25 && (self.bm25_k1.is_some() || self.bm25_b.is_some()) {
+            problems.push(ConfigProblem::error(
+                "scoring",
+                "bm25_k1 and bm25_b only apply to bm25 scoring",
+            ));
+        }
+        if let Some(k1) = self.bm25_k1 {
+            if !(k1.is_finite() && k1 >= 0.0) {
+                problems.push(ConfigProblem::error(
+                    "bm25_k1",
+                    format!("must be 0 or more, not {}", k1),
+                ));
+            }
+        }
+        if let Some(b) = self.bm25_b {
+            if !(0.0..=1.0).contains(&b) {
+                problems.push(ConfigProblem::error(
+                    "bm25_b",
+                    format!("must be from 0 to 1, not {}", b),
+                ));
+            }
+        }
+        if let Some(primary) = &self.replicate_from {
+            if !primary.starts_with("http://") && !primary.starts_with("https://") {
+                problems.push(ConfigProblem::error(
+                    "replicate_from",
+                    format!("{} must be a URL, like http://primary:8901", primary),
+                ));
+            }
+            if self.multi_user {
+                problems.push(ConfigProblem::error(
+                    "replicate_from",
+                    "can't be used with multi_user, which has an index for each user",
+                ));
+            }
+        }
+        if self.serve_replication && self.multi_user {
+            problems.push(ConfigProblem::error(
+                "serve_replication",
+                "can't be used with multi_user, which has an index for each user",
+            ));
+        }
+        if let Some(weight) = self.recency_weight {
+            if !(weight.is_finite() && weight >= 0.0) {
+                problems.push(ConfigProblem::error(
+                    "recency_weight",
+                    format!("must be 0 or more, not {}", weight),
+                ));
+            }
+        }
+        for (name, extensions) in &self.extension_groups {
+            let valid = |c: char| c.is_ascii_alphanumeric() || c == '_' || c == '-';
+            if name.is_empty() || !name.chars().all(valid) {
+                problems.push(ConfigProblem::error(
+                    "extension_groups",
+                    format!("{:?} must be named with letters, digits, _ and -", name),
+                ));
+            }
+            if extensions
+                .iter()
+                .all(|e| e.trim_start_matches('.').is_empty())
+            {
+                problems.push(ConfigProblem::error(
+                    "extension_groups",
+                    format!("{} has no extensions", name),
+                ));
+            }
+        }
+        problems
+    }
+}
+
+/// `path` joined onto `dir` if it's relative, without any `.` or `..`, so it
+/// compares with the paths the indexer finds. Absolute and empty paths are
+/// left as they are.
+fn resolve_path(dir: &Path, path: &str) -> String {
+    if path.is_empty() || Path::new(path).is_absolute() {
+        return path.to_string();
+    }
+    let mut resolved = PathBuf::new();
+    for c in dir.join(path).components() {
+        match c {
+            Component::CurDir => (),
+            Component::ParentDir => {
+                resolved.pop();
+            }
+            c => resolved.push(c),
+        }
+    }
+    resolved.to_string_lossy().into_owned()
+}
+
+/// Whether the daemon can write to `dir`, creating it if it has to. Without
+/// writing anything, so only the permission bits are checked.
+fn writable_dir(dir: &Path) -> Result<(), String> {
+    if dir.as_os_str().is_empty() {
+        return Err("must be given, the directory the daemon writes its data to".to_string());
+    }
+    // The nearest that exists, which the rest are created in.
+    let existing = match dir.ancestors().find(|d| d.exists()) {
+        Some(d) => d,
+        None => return Ok(()),
+    };
+    if !existing.is_dir() {
+        return Err(format!("{} is not a directory", existing.display()));
+    }
+    #[cfg(unix)]
+    let writable = nix::unistd::access(existing, nix::unistd::AccessFlags::W_OK).is_ok();
+    #[cfg(not(unix))]
+    let writable = fs::metadata(existing).map_or(false, |m| !m.permissions().readonly());
+    if writable {
+        Ok(())
+    } else {
+        Err(format!("{} can't be written to", existing.display()))
+    }
+}
+
+/// Finds the config file to load. In order of precedence: the `--config`
+/// flag, `$LOOKRD_CONFIG`, `$XDG_CONFIG_HOME/lookr/config.json` if it
+/// exists, and finally `~/.lookrd`. `XDG_CONFIG_HOME` defaults to
+/// `~/.config`.
+pub fn config_path(
+    flag: Option<&str>,
+    env: Option<OsString>,
+    xdg_config_home: Option<OsString>,
+    home: Option<PathBuf>,
+) -> Option<PathBuf> {
+    if let Some(f) = flag {
+        return Some(PathBuf::from(f));
+    }
+    if let Some(e) = env.filter(|e| !e.is_empty()) {
+        return Some(PathBuf::from(e));
+    }
+
+    let xdg_config_home = match xdg_config_home.filter(|x| !x.is_empty()) {
+        Some(x) => Some(PathBuf::from(x)),
+        None => home.as_ref().map(|h| h.join(".config")),
+    };
+    if let Some(xdg) = xdg_config_home {
+        let xdg = xdg.join("lookr").join("config.json");
+        if xdg.is_file() {
+            return Some(xdg);
+        }
+    }
+
+    home.map(|h| h.join(DEFAULT_CONFIG))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_config_path() {
+        let home = tempfile::tempdir().unwrap();
+        let home = home.path().to_path_buf();
+        let xdg = home.join(".config");
+        let xdg_config = xdg.join("lookr").join("config.json");
+        let default = home.join(DEFAULT_CONFIG);
+
+        let path = |flag, env: Option<&str>, xdg: Option<&Path>| {
+            config_path(
+                flag,
+                env.map(OsString::from),
+                xdg.map(|x| x.as_os_str().to_owned()),
+                Some(home.clone()),
+            )
+            .unwrap()
+        };
+
+        // Without an XDG config file, fall through to the home dir.
+        assert_eq!(path(None, None, Some(&xdg)), default);
+        assert_eq!(path(None, None, None), default);
+
+        fs::create_dir_all(xdg_config.parent().unwrap()).unwrap();
+        fs::write(&xdg_config, "{}").unwrap();
+        assert_eq!(path(None, None, Some(&xdg)), xdg_config);
+        assert_eq!(path(None, Some(""), None), xdg_config);
+        assert_eq!(
+            path(None, Some("/env.json"), Some(&xdg)),
+            Path::new("/env.json")
+        );
+        assert_eq!(
+            path(Some("/flag.json"), Some("/env.json"), Some(&xdg)),
+            Path::new("/flag.json")
+        );
+
+        assert_eq!(config_path(None, None, None, None), None);
+    }
+
+    #[test]
+    fn test_read_config_formats() {
+        let config = LookrdConfig {
+            index_paths: vec!["/home/me/src".to_string(), "/srv/a b".to_string()],
+            data_dir: "/var/lib/lookrd".to_string(),
+            exclude_paths: vec!["/home/me/src/target".to_string()],
+            prewarm: true,
+            compact_segments: Some(10),
+            watch_overflow: OverflowPolicy::Drop,
+            watch_mode: WatchMode::Poll,
+            tokenizers: FieldTokenizers {
+                filename: TokenizerKind::Ngram,
+                ..Default::default()
+            },
+            poll_paths: vec!["/srv/a b".to_string()],
+            extension_groups: vec![("code".to_string(), vec!["rs".to_string()])]
+                .into_iter()
+                .collect(),
+            rewalk_interval_secs: vec![("/srv/a b".to_string(), 3600)].into_iter().collect(),
+            ..Default::default()
+        };
+        let dir = tempfile::tempdir().unwrap();
+
+        let formats: Vec<(&str, String)> = vec![
+            ("json", serde_json::to_string(&config).unwrap()),
+            ("toml", toml::to_string(&config).unwrap()),
+            ("yaml", serde_yaml::to_string(&config).unwrap()),
+            ("yml", serde_yaml::to_string(&config).unwrap()),
+        ];
+        for (ext, contents) in formats {
+            let path = dir.path().join(format!("config.{}", ext));
+            fs::write(&path, contents).unwrap();
+            assert_eq!(read_config(&path).unwrap(), config, "format: {}", ext);
+        }
+
+        let path = dir.path().join("config.ini");
+        fs::write(&path, "").unwrap();
+        let err = read_config(&path).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_relative_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        let etc = dir.path().join("etc");
+        fs::create_dir(&etc).unwrap();
+        let path = etc.join("lookrd.json");
+        fs::write(
+            &path,
+            r#"{
+                "index_paths": ["../src", "./docs", "/srv"],
+                "exclude_paths": ["../src/target"],
+                "rewalk_interval_secs": {"./docs": 3600},
+                "data_dir": "data"
+            }"#,
+        )
+        .unwrap();
+
+        // Relative to the config's directory, not the daemon's.
+        let config = read_config(&path).unwrap();
+        let under = |p: &str| dir.path().join(p).to_string_lossy().to_string();
+        assert_eq!(
+            config.index_paths,
+            vec![under("src"), under("etc/docs"), "/srv".to_string()]
+        );
+        assert_eq!(config.exclude_paths, vec![under("src/target")]);
+        assert_eq!(
+            config.rewalk_interval_secs.keys().collect::<Vec<_>>(),
+            vec![&under("etc/docs")]
+        );
+        assert_eq!(config.data_dir, under("etc/data"));
+    }
+
+    #[test]
+    fn test_unknown_tokenizer() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.json");
+        let config = |tokenizers: &str| {
+            format!(
+                r#"{{"index_paths": ["/srv"], "data_dir": "/tmp", "tokenizers": {}}}"#,
+                tokenizers
+            )
+        };
+
+        fs::write(&path, config(r#"{"filename": "ngram"}"#)).unwrap();
+        assert_eq!(
+            read_config(&path).unwrap().tokenizers.filename,
+            TokenizerKind::Ngram
+        );
+        fs::write(&path, config(r#"{"filename": "stemmed"}"#)).unwrap();
+        assert!(read_config(&path).is_err());
+        fs::write(&path, config(r#"{"content": "ngram"}"#)).unwrap();
+        assert!(read_config(&path).is_err());
+    }
+
+    #[test]
+    fn test_watch_debounce_range() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.json");
+        let config = |ms: u64| {
+            format!(
+                r#"{{"index_paths": ["/srv"], "data_dir": "/tmp", "watch_debounce_ms": {}}}"#,
+                ms
+            )
+        };
+
+        fs::write(&path, config(250)).unwrap();
+        assert_eq!(read_config(&path).unwrap().watch_debounce_ms, Some(250));
+        for ms in [0, 9, 60_001] {
+            fs::write(&path, config(ms)).unwrap();
+            let e = read_config(&path).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn test_scoring_params() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.json");
+        let config = |params: &str| {
+            format!(
+                r#"{{"index_paths": ["/srv"], "data_dir": "/tmp", {}}}"#,
+                params
+            )
+        };
+
+        fs::write(&path, config(r#""bm25_k1": 2.0, "bm25_b": 0"#)).unwrap();
+        let read = read_config(&path).unwrap();
+        assert_eq!(read.scoring, Scoring::Bm25);
+        assert_eq!((read.bm25_k1, read.bm25_b), (Some(2.0), Some(0.0)));
+        fs::write(&path, config(r#""scoring": "tfidf""#)).unwrap();
+        assert_eq!(read_config(&path).unwrap().scoring, Scoring::Tfidf);
+        fs::write(&path, config(r#""recency_weight": 0.5"#)).unwrap();
+        assert_eq!(read_config(&path).unwrap().recency_weight, Some(0.5));
+
+        for params in [
+            r#""bm25_k1": -1"#,
+            r#""recency_weight": -0.5"#,
+            r#""bm25_b": 1.5"#,
+            r#""scoring": "tfidf", "bm25_b": 0.5"#,
+        ] {
+            fs::write(&path, config(params)).unwrap();
+            let e = read_config(&path).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn test_extension_groups() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.json");
+        let config = |groups: &str| {
+            format!(
+                r#"{{"index_paths": ["/srv"], "data_dir": "/tmp", "extension_groups": {}}}"#,
+                groups
+            )
+        };
+
+        fs::write(&path, config(r#"{"source-code": ["rs", ".py"]}"#)).unwrap();
+        assert_eq!(
+            read_config(&path).unwrap().extension_groups["source-code"],
+            vec!["rs", ".py"]
+        );
+        for groups in [
+            r#"{"": ["rs"]}"#,
+            r#"{"source code": ["rs"]}"#,
+            r#"{"code": []}"#,
+            r#"{"code": ["."]}"#,
+        ] {
+            fs::write(&path, config(groups)).unwrap();
+            let e = read_config(&path).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn test_index_fingerprint() {
+        let config = LookrdConfig::default();
+        assert_eq!(
+            config.index_fingerprint(),
+            LookrdConfig::default().index_fingerprint()
+        );
+        // Changed by what changes how entries are indexed.
+        for changed in [
+            LookrdConfig {
+                substring_index: true,
+                ..Default::default()
+            },
+            LookrdConfig {
+                first_line_max_size: Some(256),
+                ..Default::default()
+            },
+            LookrdConfig {
+                content_hash_max_size: Some(1024),
+                ..Default::default()
+            },
+            LookrdConfig {
+                tokenizers: FieldTokenizers {
+                    path: TokenizerKind::Whitespace,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        ] {
+            assert_ne!(changed.index_fingerprint(), config.index_fingerprint());
+        }
+        // But not by what's only used answering queries.
+        let querying = LookrdConfig {
+            default_and: true,
+            query_timeout_ms: Some(5),
+            ..Default::default()
+        };
+        assert_eq!(querying.index_fingerprint(), config.index_fingerprint());
+    }
+
+    #[test]
+    fn test_validate() {
+        let data = tempfile::tempdir().unwrap();
+        let data_dir = data.path().to_string_lossy().to_string();
+        let home = Path::new("/home/me");
+        let problems = |index_paths: &[&str], data_dir: &str| {
+            let config = LookrdConfig {
+                index_paths: index_paths.iter().map(|p| p.to_string()).collect(),
+                data_dir: data_dir.to_string(),
+                ..Default::default()
+            };
+            config.problems(Some(home))
+        };
+        let fields = |problems: Vec<ConfigProblem>| {
+            problems
+                .into_iter()
+                .map(|p| (p.field, p.warning))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(problems(&["/srv", "/home/me/src"], &data_dir), vec![]);
+        // Not made yet, but its parent can be written to.
+        let unmade = data.path().join("a/b").to_string_lossy().to_string();
+        assert_eq!(problems(&["/srv"], &unmade), vec![]);
+
+        assert_eq!(
+            fields(problems(&[], &data_dir)),
+            vec![("index_paths", false)]
+        );
+        assert_eq!(fields(problems(&["/srv"], "")), vec![("data_dir", false)]);
+        let file = data.path().join("file");
+        fs::write(&file, "").unwrap();
+        let under_file = file.join("data").to_string_lossy().to_string();
+        assert_eq!(
+            fields(problems(&["/srv"], &under_file)),
+            vec![("data_dir", false)]
+        );
+
+        let twice = problems(&["/srv", "/srv/"], &data_dir);
+        assert_eq!(twice.len(), 1);
+        assert_eq!(twice[0].message, "/srv/ is listed twice, as /srv");
+        let nested = problems(&["/srv/a", "/srv"], &data_dir);
+        assert_eq!(nested.len(), 1);
+        assert_eq!(
+            nested[0].to_string(),
+            "index_paths: /srv/a is inside /srv, so would be indexed twice"
+        );
+        // Not nested, just sharing a prefix.
+        assert_eq!(problems(&["/srv/a", "/srv/ab"], &data_dir), vec![]);
+
+        assert_eq!(
+            fields(problems(&["/"], &data_dir)),
+            vec![("index_paths", true)]
+        );
+        assert_eq!(
+            fields(problems(&["/home/me/"], &data_dir)),
+            vec![("index_paths", true)]
+        );
+
+        // A standby has nothing of its own to index.
+        let standby = |primary: &str, multi_user: bool| {
+            let config = LookrdConfig {
+                data_dir: data_dir.clone(),
+                replicate_from: Some(primary.to_string()),
+                multi_user,
+                ..Default::default()
+            };
+            fields(config.problems(None))
+        };
+        assert_eq!(standby("http://primary:8901", false), vec![]);
+        assert_eq!(
+            standby("primary:8901", true),
+            vec![("replicate_from", false), ("replicate_from", false)]
+        );
+
+        // Each of the writer's threads needs at least 3MB.
+        let writer = |memory: usize, threads: usize| {
+            let config = LookrdConfig {
+                index_paths: vec!["/srv".to_string()],
+                data_dir: data_dir.clone(),
+                writer_memory_bytes: Some(memory),
+                writer_threads: Some(threads),
+                ..Default::default()
+            };
+            fields(config.problems(None))
+        };
+        assert_eq!(writer(3_000_000, 1), vec![]);
+        assert_eq!(writer(400_000_000, 8), vec![]);
+        assert_eq!(writer(2_999_999, 1), vec![("writer_memory_bytes", false)]);
+        assert_eq!(writer(8_000_000, 4), vec![("writer_memory_bytes", false)]);
+        assert_eq!(
+            writer(u32::MAX as usize, 1),
+            vec![("writer_memory_bytes", false)]
+        );
+        assert_eq!(writer(50_000_000, 0), vec![("writer_threads", false)]);
+
+        // Only the index paths are walked again, at most once a second.
+        let rewalk = |path: &str, secs: u64| {
+            let config = LookrdConfig {
+                index_paths: vec!["/srv".to_string()],
+                data_dir: data_dir.clone(),
+                rewalk_interval_secs: vec![(path.to_string(), secs)].into_iter().collect(),
+                ..Default::default()
+            };
+            fields(config.problems(None))
+        };
+        assert_eq!(rewalk("/srv/", 3600), vec![]);
+        assert_eq!(rewalk("/srv", 0), vec![("rewalk_interval_secs", false)]);
+        assert_eq!(
+            rewalk("/srv/a", 3600),
+            vec![("rewalk_interval_secs", false)]
+        );
+        // Which is as much as tantivy takes.
+        let index = tantivy::Index::create_in_ram(crate::indexer::build_schema());
+        assert!(index.writer_with_num_threads(2, 6_000_000).is_ok());
+
+        // Every problem is reported, not just the first.
+        let config = LookrdConfig {
+            max_docs: Some(0),
+            skip_initial_walk: true,
+            bm25_b: Some(2.0),
+            ..Default::default()
+        };
+        assert_eq!(
+            fields(config.problems(None)),
+            vec![
+                ("index_paths", false),
+                ("data_dir", false),
+                ("skip_initial_walk", false),
+                ("max_docs", false),
+                ("bm25_b", false),
+            ]
+        );
+    }
+}
@@ -0,0 +1,150 @@
+//! Running in the background, for when lookrd isn't started by a supervisor.
+
+use nix::errno::Errno;
+use nix::sys::signal::{kill, SigSet, Signal};
+use nix::unistd::{dup2, fork, setsid, ForkResult, Pid};
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::thread;
+use tokio::sync::oneshot;
+
+/// Detach from the terminal and carry on in a new background process, with
+/// stdin read from /dev/null, and stdout and stderr, and so the logs,
+/// appended to `log_file`. The calling process exits.
+///
+/// Only the calling thread survives, so this has to be called before any
+/// others are started.
+pub fn daemonize(log_file: &Path) -> io::Result<()> {
+    // Opened first, so a bad path is still reported to the terminal.
+    let log = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(log_file)?;
+    let null = File::open("/dev/null")?;
+
+    fork_and_exit_parent()?;
+    // A session of its own, without a controlling terminal.
+    setsid()?;
+    // No longer the session leader, so it can never get a terminal back.
+    fork_and_exit_parent()?;
+
+    dup2(null.as_raw_fd(), 0)?;
+    dup2(log.as_raw_fd(), 1)?;
+    dup2(log.as_raw_fd(), 2)?;
+    Ok(())
+}
+
+fn fork_and_exit_parent() -> io::Result<()> {
+    // Safe as long as there's only one thread, which `daemonize` requires.
+    match unsafe { fork() }? {
+        ForkResult::Parent { .. } => process::exit(0),
+        ForkResult::Child => Ok(()),
+    }
+}
+
+/// Block SIGINT and SIGTERM, and wait for either on a thread of its own,
+/// sending it on the returned channel when it arrives. The blocked signals
+/// are inherited by threads started afterwards, so this is called before the
+/// others are, leaving none of them to be interrupted instead.
+pub fn shutdown_signal() -> io::Result<oneshot::Receiver<Signal>> {
+    let mut signals = SigSet::empty();
+    signals.add(Signal::SIGINT);
+    signals.add(Signal::SIGTERM);
+    signals.thread_block()?;
+
+    let (tx, rx) = oneshot::channel();
+    thread::Builder::new()
+        .name("signals".to_string())
+        .spawn(move || {
+            if let Ok(signal) = signals.wait() {
+                let _ = tx.send(signal);
+            }
+        })?;
+    Ok(rx)
+}
+
+/// A file holding the daemon's pid, for scripts to find and signal it,
+/// removed when dropped.
+#[derive(Debug)]
+pub struct PidFile {
+    path: PathBuf,
+}
+
+impl PidFile {
+    /// Write this process's pid to `path`. Fails if the file names a process
+    /// that is still running, which is likely another lookrd using it.
+    pub fn create(path: &Path) -> io::Result<Self> {
+        if let Some(pid) = read_pid(path) {
+            if running(pid) {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!(
+                        "lookrd is already running as pid {}, going by {:?}",
+                        pid, path
+                    ),
+                ));
+            }
+        }
+        fs::write(path, format!("{}\n", process::id()))?;
+        Ok(PidFile {
+            path: path.to_path_buf(),
+        })
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        // Left alone if another daemon has taken it over since.
+        if read_pid(&self.path) == Some(process::id() as i32) {
+            if let Err(e) = fs::remove_file(&self.path) {
+                warn!("Could not remove the pid file {:?}: {}", self.path, e);
+            }
+        }
+    }
+}
+
+fn read_pid(path: &Path) -> Option<i32> {
+    fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+/// Whether another process with this pid exists. One that can't be signalled
+/// because it belongs to someone else still counts.
+fn running(pid: i32) -> bool {
+    // 0 and negative pids would ask about process groups.
+    if pid <= 0 || pid == process::id() as i32 {
+        return false;
+    }
+    !matches!(kill(Pid::from_raw(pid), None), Err(Errno::ESRCH))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_pid_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("lookrd.pid");
+
+        let pid_file = PidFile::create(&path).unwrap();
+        assert_eq!(read_pid(&path), Some(process::id() as i32));
+        drop(pid_file);
+        assert!(!path.exists());
+
+        // Left by a daemon that has exited. Pids are far below this.
+        fs::write(&path, "2147483000\n").unwrap();
+        let pid_file = PidFile::create(&path).unwrap();
+        assert_eq!(read_pid(&path), Some(process::id() as i32));
+        drop(pid_file);
+
+        // Held by a running process, the parent of this one.
+        let parent = nix::unistd::getppid();
+        fs::write(&path, format!("{}\n", parent)).unwrap();
+        let e = PidFile::create(&path).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(read_pid(&path), Some(parent.as_raw()));
+    }
+}
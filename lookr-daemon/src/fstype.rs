@@ -0,0 +1,132 @@
+//! Telling which filesystem each index path is on, for `watch_mode` `auto`
+//! to poll those on network filesystems, whose notifications miss changes
+//! made by other machines. Only Linux says, in /proc/mounts; elsewhere the
+//! filesystem is unknown.
+
+use crate::config::WatchMode;
+use std::path::{Path, PathBuf};
+
+/// Network filesystems, as /proc/mounts names them, and `fuse` for FUSE
+/// filesystems like sshfs, listed as `fuse.sshfs`.
+const NETWORK_FILESYSTEMS: &[&str] = &[
+    "nfs",
+    "nfs4",
+    "cifs",
+    "smb3",
+    "smbfs",
+    "afs",
+    "9p",
+    "ceph",
+    "glusterfs",
+    "lustre",
+    "fuse",
+];
+
+/// How changes to a path on this filesystem are best noticed: by polling a
+/// network filesystem, or by notifications. `fuseblk`, FUSE on a local disk
+/// as ntfs-3g mounts them, counts as local.
+pub(crate) fn watch_mode(fstype: &str) -> WatchMode {
+    let base = fstype.split('.').next().unwrap_or(fstype);
+    if NETWORK_FILESYSTEMS.contains(&base) {
+        WatchMode::Poll
+    } else {
+        WatchMode::Native
+    }
+}
+
+/// The type of the filesystem `path` is on, or `None` if it can't be told.
+pub(crate) fn of(path: &Path) -> Option<String> {
+    if !cfg!(target_os = "linux") {
+        return None;
+    }
+    // Mount points are listed with their symlinks resolved.
+    let path = path.canonicalize().ok()?;
+    match std::fs::read_to_string("/proc/mounts") {
+        Ok(mounts) => mounted_on(&mounts, &path),
+        Err(e) => {
+            debug!("Could not read /proc/mounts: {}", e);
+            None
+        }
+    }
+}
+
+/// The type of the filesystem in `mounts`, in the format of /proc/mounts,
+/// mounted on the deepest directory above or at `path`. Of those mounted
+/// on the same directory, the last mounted is the one seen.
+fn mounted_on(mounts: &str, path: &Path) -> Option<String> {
+    let mut found: Option<(PathBuf, &str)> = None;
+    for line in mounts.lines() {
+        let mut fields = line.split_whitespace();
+        let (mount_point, fstype) = match (fields.next(), fields.next(), fields.next()) {
+            (Some(_), Some(mount_point), Some(fstype)) => (unescape(mount_point), fstype),
+            _ => continue,
+        };
+        let deeper = found
+            .as_ref()
+            .is_none_or(|(p, _)| mount_point.components().count() >= p.components().count());
+        if path.starts_with(&mount_point) && deeper {
+            found = Some((mount_point, fstype));
+        }
+    }
+    found.map(|(_, fstype)| fstype.to_string())
+}
+
+/// The mount point with the octal escapes /proc/mounts uses for spaces,
+/// tabs, newlines and backslashes, like `\040`, turned back into them.
+fn unescape(field: &str) -> PathBuf {
+    let bytes = field.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes
+            .get(i + 1..i + 4)
+            .filter(|_| bytes[i] == b'\\')
+            .and_then(|octal| std::str::from_utf8(octal).ok())
+            .and_then(|octal| u8::from_str_radix(octal, 8).ok());
+        match escaped {
+            Some(b) => {
+                out.push(b);
+                i += 4;
+            }
+            None => {
+                out.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    PathBuf::from(String::from_utf8_lossy(&out).into_owned())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_watch_mode() {
+        for fstype in &["nfs", "nfs4", "cifs", "smb3", "fuse", "fuse.sshfs", "9p"] {
+            assert_eq!(watch_mode(fstype), WatchMode::Poll, "{}", fstype);
+        }
+        for fstype in &["ext4", "xfs", "btrfs", "tmpfs", "overlay", "fuseblk", "zfs"] {
+            assert_eq!(watch_mode(fstype), WatchMode::Native, "{}", fstype);
+        }
+    }
+
+    #[test]
+    fn test_mounted_on() {
+        let mounts = "\
+/dev/sda1 / ext4 rw,relatime 0 0
+server:/export /mnt/nfs nfs4 rw,vers=4.2 0 0
+//nas/share /mnt/my\\040share cifs rw 0 0
+tmpfs /mnt/nfs/scratch tmpfs rw 0 0
+";
+        let fstype = |p: &str| mounted_on(mounts, Path::new(p));
+        assert_eq!(fstype("/home/me"), Some("ext4".to_string()));
+        assert_eq!(fstype("/mnt/nfs/src"), Some("nfs4".to_string()));
+        assert_eq!(fstype("/mnt/nfs"), Some("nfs4".to_string()));
+        assert_eq!(fstype("/mnt/nfs/scratch/a"), Some("tmpfs".to_string()));
+        // By component, not by prefix.
+        assert_eq!(fstype("/mnt/nfsx"), Some("ext4".to_string()));
+        assert_eq!(fstype("/mnt/my share/a"), Some("cifs".to_string()));
+        assert_eq!(mounted_on("", Path::new("/")), None);
+    }
+}
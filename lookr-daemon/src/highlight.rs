@@ -0,0 +1,82 @@
+//! Marking where a query matched in the paths it found, for clients to show
+//! them in bold or colour.
+
+use std::collections::BTreeSet;
+use tantivy::query::Query;
+use tantivy::schema::Field;
+
+/// The markers put around matches if the request doesn't say.
+pub(crate) const DEFAULT_MARKER: &str = "**";
+
+/// The words the query looks for in `fields`, as they're indexed. Those in
+/// other fields, like the filters', aren't part of the path as written.
+pub(crate) fn terms(query: &dyn Query, fields: &[Field]) -> Vec<String> {
+    let mut terms = BTreeSet::new();
+    query.query_terms(&mut terms);
+    terms
+        .iter()
+        .filter(|t| fields.contains(&t.field()))
+        .map(|t| t.text().to_lowercase())
+        .filter(|t| !t.is_empty())
+        .collect()
+}
+
+/// The path with every occurrence of the terms put between `start` and
+/// `end`, ignoring ASCII case. Overlapping and adjacent matches are marked
+/// as one.
+pub(crate) fn highlight(path: &str, terms: &[String], start: &str, end: &str) -> String {
+    let lower = path.to_ascii_lowercase();
+    let mut ranges = Vec::new();
+    for term in terms {
+        ranges.extend(
+            lower
+                .match_indices(term.as_str())
+                .map(|(i, m)| (i, i + m.len())),
+        );
+    }
+    ranges.sort_unstable();
+    let mut merged: Vec<(usize, usize)> = Vec::with_capacity(ranges.len());
+    for (from, to) in ranges {
+        match merged.last_mut() {
+            Some(last) if from <= last.1 => last.1 = last.1.max(to),
+            _ => merged.push((from, to)),
+        }
+    }
+
+    let mut out = String::with_capacity(path.len() + merged.len() * (start.len() + end.len()));
+    let mut at = 0;
+    for (from, to) in merged {
+        out.push_str(&path[at..from]);
+        out.push_str(start);
+        out.push_str(&path[from..to]);
+        out.push_str(end);
+        at = to;
+    }
+    out.push_str(&path[at..]);
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_highlight() {
+        let terms = |ts: &[&str]| ts.iter().map(|t| t.to_string()).collect::<Vec<_>>();
+
+        assert_eq!(
+            highlight("/src/Report.rs", &terms(&["report"]), "**", "**"),
+            "/src/**Report**.rs"
+        );
+        assert_eq!(
+            highlight("/a/ab/b", &terms(&["ab", "b"]), "[", "]"),
+            "/a/[ab]/[b]"
+        );
+        // Overlapping matches are marked once.
+        assert_eq!(
+            highlight("/abcd", &terms(&["abc", "bcd"]), "<", ">"),
+            "/<abcd>"
+        );
+        assert_eq!(highlight("/none", &terms(&["x"]), "<", ">"), "/none");
+    }
+}
@@ -0,0 +1,86 @@
+//! A bounded record of the queries run, for seeing what people search for.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+/// A query as it was run.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct RecentQuery {
+    pub query: String,
+    pub time: SystemTime,
+    pub total_hits: u64,
+}
+
+/// The last `capacity` queries, oldest dropped first.
+#[derive(Debug)]
+pub(crate) struct QueryHistory {
+    capacity: usize,
+    queries: Mutex<VecDeque<RecentQuery>>,
+}
+
+impl QueryHistory {
+    pub(crate) fn new(capacity: usize) -> Self {
+        QueryHistory {
+            capacity,
+            queries: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    pub(crate) fn record(&self, query: &str, total_hits: u64) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut queries = self.queries.lock().unwrap();
+        if queries.len() == self.capacity {
+            queries.pop_front();
+        }
+        queries.push_back(RecentQuery {
+            query: query.to_string(),
+            time: SystemTime::now(),
+            total_hits,
+        });
+    }
+
+    /// The queries kept, most recent first.
+    pub(crate) fn recent(&self) -> Vec<RecentQuery> {
+        self.queries.lock().unwrap().iter().rev().cloned().collect()
+    }
+
+    /// The `n` queries kept most often, with their counts, most frequent
+    /// first and then alphabetically.
+    pub(crate) fn top(&self, n: usize) -> Vec<(String, u64)> {
+        let mut counts: HashMap<&str, u64> = HashMap::new();
+        let queries = self.queries.lock().unwrap();
+        for q in queries.iter() {
+            *counts.entry(&q.query).or_default() += 1;
+        }
+        let mut top: Vec<(String, u64)> = counts
+            .into_iter()
+            .map(|(q, c)| (q.to_string(), c))
+            .collect();
+        top.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        top.truncate(n);
+        top
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_query_history() {
+        let history = QueryHistory::new(4);
+        for q in &["a", "b", "a", "c", "b", "b"] {
+            history.record(q, 1);
+        }
+
+        let recent: Vec<String> = history.recent().into_iter().map(|q| q.query).collect();
+        assert_eq!(recent, vec!["b", "b", "c", "a"]);
+        assert_eq!(
+            history.top(2),
+            vec![("b".to_string(), 2), ("a".to_string(), 1)]
+        );
+    }
+}
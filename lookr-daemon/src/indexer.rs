@@ -1,152 +1,772 @@
 //! Watcher for FS changes and updates the corpus.
 
-use notify::{DebouncedEvent, RecursiveMode, Watcher};
+use crate::archive;
+use crate::config::{
+    LookrdConfig, OverflowPolicy, WatchMode, DEFAULT_ARCHIVE_MAX_BYTES, DEFAULT_BINARY_SNIFF_BYTES,
+    DEFAULT_POLL_INTERVAL_SECS, DEFAULT_WATCH_DEBOUNCE_MS, DEFAULT_WATCH_QUEUE,
+    DEFAULT_WRITER_MEMORY_BYTES, DEFAULT_WRITER_THREADS,
+};
+use crate::fstype;
+use crate::proto::rpc::ReplicationBatch;
+use crate::replication::{self, Writer};
+use crate::tokenizer::{
+    self, FILENAME_TOKENIZER, FIRST_LINE_TOKENIZER, NGRAM_TOKENIZER, PATH_TOKENIZER,
+};
+use crate::users::UserAccess;
+use crate::xattr;
+use futures::executor::block_on;
+use notify::{DebouncedEvent, PollWatcher, RecursiveMode, Watcher};
+use serde::Deserialize;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
 use std::error;
 use std::fmt;
-use std::io;
+use std::fs::{self, File, FileType};
+use std::io::{self, BufReader, Read};
+use std::mem;
 use std::path::{Path, PathBuf};
-use std::sync::mpsc::{channel, RecvError, RecvTimeoutError, Sender};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{
+    channel, sync_channel, Receiver, RecvTimeoutError, SendError, Sender, SyncSender, TrySendError,
+};
+use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::{Duration, Instant};
-use tantivy::schema::{Schema, STORED, STRING, TEXT};
-use tantivy::{Document, Index, TantivyError, Term};
+use std::time::{Duration, Instant, SystemTime};
+use tantivy::collector::{Count, TopDocs};
+use tantivy::directory::error::{DeleteError, IOError, LockError, OpenReadError, OpenWriteError};
+use tantivy::directory::{
+    Directory, DirectoryLock, Lock, MmapDirectory, ReadOnlySource, WatchCallback, WatchHandle,
+    WritePtr,
+};
+use tantivy::postings::TermInfo;
+use tantivy::query::{AllQuery, BooleanQuery, Occur, Query, RangeQuery, TermQuery};
+use tantivy::schema::{
+    Facet, Field, IndexRecordOption, Schema, TextFieldIndexing, TextOptions, Value, FAST, INDEXED,
+    STORED, STRING, TEXT,
+};
+use tantivy::tokenizer::MAX_TOKEN_LEN;
+use tantivy::{DocSet, Document, Index, InvertedIndexReader, SegmentReader, TantivyError, Term};
+use tokio::sync::oneshot;
 
 pub static FIELD_ID: &str = "file_id";
 pub static FIELD_PATH: &str = "path";
+pub static FIELD_ORIGINAL_PATH: &str = "original_path";
 pub static FIELD_EXT: &str = "ext";
 pub static FIELD_FILENAME: &str = "filename";
+pub static FIELD_NAME: &str = "name";
+pub static FIELD_PARENT: &str = "parent";
+pub static FIELD_TAGS: &str = "tags";
+pub static FIELD_KIND: &str = "kind";
+pub static FIELD_FIRSTLINE: &str = "first_line";
+pub static FIELD_FILENAME_NGRAM: &str = "filename_ngram";
+pub static FIELD_MTIME: &str = "mtime";
+pub static FIELD_INODE: &str = "inode";
+pub static FIELD_DEV: &str = "dev";
+pub static FIELD_UID: &str = "uid";
+pub static FIELD_HASH: &str = "hash";
+pub static FIELD_XATTR: &str = "xattr";
+pub static FIELD_DEPTH: &str = "depth";
 
-pub(crate) struct Indexer<'a> {
+/// With `max_docs`, the share of it, in percent, the index is brought back
+/// down to when it's passed, so there's room to add to before the next
+/// eviction.
+const EVICT_TO_PERCENT: u64 = 90;
+
+/// How many of the missing and unindexed entries a verification lists.
+const VERIFY_SAMPLE: usize = 10;
+
+/// The longest ID indexed. tantivy drops longer terms from a document,
+/// leaving it without an ID to be replaced or deleted by, so entries with
+/// longer paths are skipped rather than indexed without one.
+const MAX_ID_LEN: usize = MAX_TOKEN_LEN;
+
+/// The most of a file's first line that is indexed.
+const MAX_FIRST_LINE: u64 = 1024;
+
+/// The file tantivy keeps the list of committed segments in.
+static META_FILE: &str = "meta.json";
+
+/// Bumped whenever `build_schema` changes, so an index written by another
+/// version of lookrd isn't read with the wrong fields.
+pub const SCHEMA_VERSION: u32 = 13;
+
+/// Kept alongside tantivy's files, holding the `SCHEMA_VERSION` the index
+/// was written with.
+static SCHEMA_VERSION_FILE: &str = "lookr_schema_version";
+
+/// Kept alongside tantivy's files, holding the `index_fingerprint` of the
+/// config the index was built with.
+static FINGERPRINT_FILE: &str = "lookr_config_fingerprint";
+
+/// How often paths that could not be watched are tried again.
+const WATCH_RETRY: Duration = Duration::from_secs(30);
+
+/// How many times in a row the notify watcher is rebuilt, when it can't be
+/// created or its channel closes, before the watcher gives up.
+const MAX_WATCHER_REBUILDS: u32 = 5;
+
+/// How many documents a walk adds between the pauses of `index_throttle_ms`.
+const THROTTLE_BATCH: usize = 100;
+
+/// Appended to a file's path to find its sidecar of tags.
+static SIDECAR_SUFFIX: &str = ".lookr.json";
+
+pub struct Indexer {
     index: Index,
     schema: Schema,
-    paths: &'a [&'a Path],
+    config: LookrdConfig,
+    stats: Arc<IndexerStats>,
+    tx: SyncSender<IndexerEvent>,
+    rx: Receiver<IndexerEvent>,
+    /// Opened by `index`, and committed when the indexer is dropped.
+    index_writer: Option<Writer>,
+    /// Only index what this user can see, if set.
+    user: Option<UserAccess>,
+    /// When the indexer was created, which the `rewalk_interval_secs` of
+    /// roots that haven't been walked yet count from.
+    started: SystemTime,
+}
+
+/// Lets the RPC service see the indexer's stats and send it commands.
+#[derive(Clone, Debug)]
+pub struct IndexerHandle {
+    pub(crate) stats: Arc<IndexerStats>,
+    tx: SyncSender<IndexerEvent>,
+}
+
+impl IndexerHandle {
+    /// Queue a command for the indexer, failing rather than waiting if the
+    /// queue is full.
+    pub(crate) fn send(&self, cmd: IndexerCommand) -> Result<(), TrySendError<IndexerCommand>> {
+        let unwrap = |e| match e {
+            IndexerEvent::Command(cmd) => cmd,
+            IndexerEvent::Watch(_) | IndexerEvent::Replicated(_) => unreachable!(),
+        };
+        self.tx
+            .try_send(IndexerEvent::Command(cmd))
+            .map_err(|e| match e {
+                TrySendError::Full(e) => TrySendError::Full(unwrap(e)),
+                TrySendError::Disconnected(e) => TrySendError::Disconnected(unwrap(e)),
+            })
+    }
+}
+
+/// Requests the RPC service can make of the indexer.
+#[derive(Debug)]
+pub(crate) enum IndexerCommand {
+    /// Merge all of the segments into one, replying with the number of
+    /// segments before and after.
+    Compact(oneshot::Sender<tantivy::Result<(usize, usize)>>),
+    /// Commit and copy the index into the directory, replying with where the
+    /// copy was written.
+    Snapshot(PathBuf, oneshot::Sender<Result<PathBuf, IndexerError>>),
+    /// Commit, then compare what's indexed with the index paths, replying
+    /// with how they differ.
+    Verify(oneshot::Sender<Result<Verification, IndexerError>>),
+    /// Index the path again, or remove it if it's gone, and commit, replying
+    /// with what changed, or `None` if it's neither on disk nor indexed.
+    ReindexFile(
+        PathBuf,
+        oneshot::Sender<Result<Option<Reindexed>, IndexerError>>,
+    ),
+    /// Commit, then send what's committed from then on to a new standby,
+    /// replying with where it's sent.
+    Replicate(oneshot::Sender<Result<tokio::sync::mpsc::Receiver<ReplicationBatch>, IndexerError>>),
+}
+
+/// What indexing a single path again did.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Reindexed {
+    Added,
+    Updated,
+    Removed,
+}
+
+/// Everything the indexer loop handles, changes from the watcher or commands
+/// from the RPC service.
+#[derive(Debug)]
+enum IndexerEvent {
+    Watch(WatchEvent),
+    Command(IndexerCommand),
+    /// Changes to the primary's index, for a standby.
+    Replicated(ReplicationBatch),
+}
+
+/// Stats the indexer collects as it runs, shared with the RPC service.
+#[derive(Debug, Default)]
+pub(crate) struct IndexerStats {
+    pub walk_errors: Mutex<WalkErrors>,
+    /// When the walk of each of the index paths completed.
+    pub last_indexed: Mutex<HashMap<String, SystemTime>>,
+    /// The number of watcher events that found the indexer's queue full.
+    pub queue_full: AtomicU64,
+    /// The number of watcher events dropped because the queue was full.
+    pub dropped_events: AtomicU64,
+    /// Index paths that had events missed, waiting to be walked again.
+    pub dirty_roots: Mutex<HashSet<String>>,
+    /// Index paths that could not be watched, and are being retried.
+    pub unwatched_roots: Mutex<HashSet<PathBuf>>,
+    /// Set if the watcher gave up after failing repeatedly, so changes are
+    /// no longer being picked up.
+    pub watcher_stopped: AtomicBool,
+    /// Set while indexing is paused, when changes are held rather than
+    /// applied.
+    pub paused: AtomicBool,
+    /// Set once the first walk of the index paths is committed, or the
+    /// first copy of a primary's index, so the index is complete. Until
+    /// then queries find only what's been indexed so far.
+    pub ready: AtomicBool,
+}
+
+/// How the committed index differs from what a walk of the index paths would
+/// index. The entries of archives aren't counted.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct Verification {
+    /// The IDs indexed, and the entries found walking.
+    pub indexed: u64,
+    pub found: u64,
+    /// The IDs indexed for entries that are gone, with the first few.
+    pub missing: u64,
+    pub missing_sample: Vec<String>,
+    /// The entries found that aren't indexed, with the first few.
+    pub unindexed: u64,
+    pub unindexed_sample: Vec<String>,
+    /// The IDs indexed more than once.
+    pub duplicates: u64,
+}
+
+/// Counts of the entries that could not be read during a walk, by kind.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) struct WalkErrors {
+    pub permission_denied: u64,
+    pub not_found: u64,
+    pub loops: u64,
+    pub other: u64,
+}
+
+impl WalkErrors {
+    fn record(&mut self, e: &walkdir::Error) {
+        if e.loop_ancestor().is_some() {
+            self.loops += 1;
+            return;
+        }
+        match e.io_error().map(io::Error::kind) {
+            Some(io::ErrorKind::PermissionDenied) => self.permission_denied += 1,
+            Some(io::ErrorKind::NotFound) => self.not_found += 1,
+            _ => self.other += 1,
+        }
+    }
+
+    fn add(&mut self, other: &WalkErrors) {
+        self.permission_denied += other.permission_denied;
+        self.not_found += other.not_found;
+        self.loops += other.loops;
+        self.other += other.other;
+    }
+
+    fn total(&self) -> u64 {
+        self.permission_denied + self.not_found + self.loops + self.other
+    }
 }
 
 pub fn build_schema() -> Schema {
     let mut schema_builder = Schema::builder();
+    let path_text = TextOptions::default().set_indexing_options(
+        TextFieldIndexing::default()
+            .set_tokenizer(PATH_TOKENIZER)
+            .set_index_option(IndexRecordOption::WithFreqsAndPositions),
+    );
+    let filename_text = TextOptions::default().set_indexing_options(
+        TextFieldIndexing::default()
+            .set_tokenizer(FILENAME_TOKENIZER)
+            .set_index_option(IndexRecordOption::WithFreqsAndPositions),
+    );
+    let first_line_text = TextOptions::default().set_indexing_options(
+        TextFieldIndexing::default()
+            .set_tokenizer(FIRST_LINE_TOKENIZER)
+            .set_index_option(IndexRecordOption::WithFreqsAndPositions),
+    );
     // The path is the ID for the document, type STRING will ensure it is not tokenized.
-    schema_builder.add_text_field(FIELD_ID, STRING);
+    // It's stored as it can differ from the path, with `canonical_ids`.
+    schema_builder.add_text_field(FIELD_ID, STRING | STORED);
     // We also tokenize the path and store it, so that we can report it in the results.
-    schema_builder.add_text_field(FIELD_PATH, TEXT | STORED);
+    schema_builder.add_text_field(FIELD_PATH, path_text | STORED);
+    // The path as it was found, if `normalize_separators` changed it, so
+    // results show that instead.
+    schema_builder.add_text_field(FIELD_ORIGINAL_PATH, STORED);
     // Whilst extension and filename are part of the path, we're also adding them here.
     schema_builder.add_text_field(FIELD_EXT, TEXT);
-    schema_builder.add_text_field(FIELD_FILENAME, TEXT);
+    schema_builder.add_text_field(FIELD_FILENAME, filename_text);
+    // The filename again as a single term, so entries can be grouped by it.
+    schema_builder.add_text_field(FIELD_NAME, STRING);
+    // The parent directory as a single level facet, so matches can be counted
+    // by directory.
+    schema_builder.add_facet_field(FIELD_PARENT);
+    // Tags from the file's sidecar, each one a single untokenized term.
+    schema_builder.add_text_field(FIELD_TAGS, STRING | STORED);
+    // What sort of entry this is, one of `file_kind`'s names. Stored, like
+    // everything that can't be worked out from the path again, so a copy of
+    // the index can be sent to standbys.
+    schema_builder.add_text_field(FIELD_KIND, STRING | STORED);
+    // The start of the file's contents, if configured.
+    schema_builder.add_text_field(FIELD_FIRSTLINE, first_line_text | STORED);
+    // The filename again in runs of characters, for substring queries. Only
+    // filled in with `substring_index`.
+    let ngram_text = TextOptions::default().set_indexing_options(
+        TextFieldIndexing::default()
+            .set_tokenizer(NGRAM_TOKENIZER)
+            .set_index_option(IndexRecordOption::WithFreqsAndPositions),
+    );
+    schema_builder.add_text_field(FIELD_FILENAME_NGRAM, ngram_text);
+    // When the entry was last modified, in nanoseconds since the epoch, so a
+    // persisted index can skip what hasn't changed since, `max_docs` can
+    // evict the oldest, and `recency_weight` can favour the newest. A fast
+    // field for the last, which reads it for every match. Only filled in
+    // with one of those.
+    schema_builder.add_u64_field(FIELD_MTIME, FAST | STORED);
+    // The entry's inode and the device it's on, which together identify the
+    // file all of its hard links share. Only filled in with `index_inodes`.
+    schema_builder.add_u64_field(FIELD_INODE, INDEXED | STORED);
+    schema_builder.add_u64_field(FIELD_DEV, INDEXED | STORED);
+    // The uid of the entry's owner. Only filled in with `index_owners`.
+    schema_builder.add_u64_field(FIELD_UID, INDEXED | STORED);
+    // A hash of the file's contents, as hex. Only filled in for files up to
+    // `content_hash_max_size`.
+    schema_builder.add_text_field(FIELD_HASH, STRING | STORED);
+    // The entry's extended attributes, each as its name and as
+    // `name=value`. Only filled in for those `index_xattrs` asks for.
+    schema_builder.add_text_field(FIELD_XATTR, STRING | STORED);
+    // How many levels below the index path containing it the entry is, the
+    // index path itself being 0, so queries can find entries by depth.
+    schema_builder.add_u64_field(FIELD_DEPTH, INDEXED | STORED);
 
     schema_builder.build()
 }
 
-impl<'a> Indexer<'a> {
+/// A query matching the document for `root` and every document below it.
+pub(crate) fn subtree_query(field_id: Field, root: &str) -> BooleanQuery {
+    let root = root.trim_end_matches('/');
+    let id_query = TermQuery::new(
+        Term::from_field_text(field_id, root),
+        IndexRecordOption::Basic,
+    );
+    // '0' sorts directly after '/', so this is everything prefixed by "root/".
+    let lower = format!("{}/", root);
+    let upper = format!("{}0", root);
+    let children_query = RangeQuery::new_str(field_id, &lower[..]..&upper[..]);
+
+    let clauses: Vec<(Occur, Box<dyn Query>)> = vec![
+        (Occur::Should, Box::new(id_query)),
+        (Occur::Should, Box::new(children_query)),
+        (
+            Occur::Should,
+            Box::new(archive_entries_query(field_id, root)),
+        ),
+    ];
+    BooleanQuery::from(clauses)
+}
+
+/// The number of documents in the segment with the term that haven't been
+/// deleted.
+pub(crate) fn live_docs(
+    reader: &SegmentReader,
+    inverted_index: &InvertedIndexReader,
+    term_info: &TermInfo,
+) -> u32 {
+    if !reader.has_deletes() {
+        return term_info.doc_freq;
+    }
+    let mut postings =
+        inverted_index.read_postings_from_terminfo(term_info, IndexRecordOption::Basic);
+    let mut live = 0;
+    while postings.advance() {
+        if !reader.is_deleted(postings.doc()) {
+            live += 1;
+        }
+    }
+    live
+}
+
+/// `path` with each `\` replaced by `/`.
+pub(crate) fn normalize_separators(path: &str) -> Cow<'_, str> {
+    if path.contains('\\') {
+        Cow::Owned(path.replace('\\', "/"))
+    } else {
+        Cow::Borrowed(path)
+    }
+}
+
+/// A query for the entries indexed for the archive `archive`, which are
+/// everything prefixed by "archive!/".
+fn archive_entries_query(field_id: Field, archive: &str) -> RangeQuery {
+    let lower = archive::virtual_path(archive, "");
+    let upper = format!("{}!0", archive);
+    RangeQuery::new_str(field_id, &lower[..]..&upper[..])
+}
+
+/// The fields of the schema, looked up once so documents can be built for
+/// each path.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Fields {
+    pub id: Field,
+    pub path: Field,
+    pub original_path: Field,
+    pub ext: Field,
+    pub filename: Field,
+    pub name: Field,
+    pub parent: Field,
+    pub tags: Field,
+    pub kind: Field,
+    pub first_line: Field,
+    pub filename_ngram: Field,
+    pub mtime: Field,
+    pub inode: Field,
+    pub dev: Field,
+    pub uid: Field,
+    pub hash: Field,
+    pub xattr: Field,
+    pub depth: Field,
+}
+
+impl Fields {
+    pub fn new(schema: &Schema) -> Self {
+        Fields {
+            id: schema.get_field(FIELD_ID).unwrap(),
+            path: schema.get_field(FIELD_PATH).unwrap(),
+            original_path: schema.get_field(FIELD_ORIGINAL_PATH).unwrap(),
+            ext: schema.get_field(FIELD_EXT).unwrap(),
+            filename: schema.get_field(FIELD_FILENAME).unwrap(),
+            name: schema.get_field(FIELD_NAME).unwrap(),
+            parent: schema.get_field(FIELD_PARENT).unwrap(),
+            tags: schema.get_field(FIELD_TAGS).unwrap(),
+            kind: schema.get_field(FIELD_KIND).unwrap(),
+            first_line: schema.get_field(FIELD_FIRSTLINE).unwrap(),
+            filename_ngram: schema.get_field(FIELD_FILENAME_NGRAM).unwrap(),
+            mtime: schema.get_field(FIELD_MTIME).unwrap(),
+            inode: schema.get_field(FIELD_INODE).unwrap(),
+            dev: schema.get_field(FIELD_DEV).unwrap(),
+            uid: schema.get_field(FIELD_UID).unwrap(),
+            hash: schema.get_field(FIELD_HASH).unwrap(),
+            xattr: schema.get_field(FIELD_XATTR).unwrap(),
+            depth: schema.get_field(FIELD_DEPTH).unwrap(),
+        }
+    }
+
+    /// Build the document to be indexed for the given path.
+    #[cfg(any(test, feature = "bench"))]
+    pub fn document(&self, p: &Path) -> Document {
+        self.document_with_id(&p.to_string_lossy(), p)
+    }
+
+    /// Build the document for the path, to be indexed under `id` rather than
+    /// the path itself.
+    pub fn document_with_id(&self, id: &str, p: &Path) -> Document {
+        let mut doc = Document::new();
+        doc.add_text(self.id, id);
+        doc.add_text(self.path, &p.to_string_lossy());
+        if let Some(s) = p.extension() {
+            doc.add_text(self.ext, &s.to_string_lossy());
+        }
+        if let Some(s) = p.file_name() {
+            doc.add_text(self.filename, &s.to_string_lossy());
+            doc.add_text(self.name, &s.to_string_lossy());
+        }
+        if let Some(parent) = p.parent() {
+            doc.add_facet(
+                self.parent,
+                Facet::from_path(vec![parent.to_string_lossy()]),
+            );
+        }
+        doc
+    }
+
+    /// The document as it was indexed, from what of it was stored: the
+    /// fields worked out from the path are worked out again.
+    pub(crate) fn restore(&self, stored: &Document, substring_index: bool) -> Document {
+        let text = |field| stored.get_first(field).and_then(|v| v.text());
+        let id = text(self.id).unwrap_or_default();
+        let path = Path::new(text(self.path).unwrap_or_default());
+        let mut doc = self.document_with_id(id, path);
+        let original = text(self.original_path).map(Path::new);
+        if substring_index {
+            if let Some(s) = original.unwrap_or(path).file_name() {
+                doc.add_text(self.filename_ngram, &s.to_string_lossy());
+            }
+        }
+        let copied = [
+            self.original_path,
+            self.tags,
+            self.kind,
+            self.first_line,
+            self.mtime,
+            self.inode,
+            self.dev,
+            self.uid,
+            self.hash,
+            self.xattr,
+            self.depth,
+        ];
+        for field_value in stored.field_values() {
+            if copied.contains(&field_value.field()) {
+                doc.add(field_value.clone());
+            }
+        }
+        doc
+    }
+
+    /// Build the document for the path like `document_with_id`, but with
+    /// its separators normalized, keeping the path as it was to show.
+    pub fn document_normalized(&self, id: &str, p: &Path) -> Document {
+        let path = p.to_string_lossy();
+        match normalize_separators(&path) {
+            Cow::Borrowed(_) => self.document_with_id(id, p),
+            Cow::Owned(normalized) => {
+                let mut doc = self.document_with_id(id, Path::new(&normalized));
+                doc.add_text(self.original_path, &path);
+                doc
+            }
+        }
+    }
+}
+
+impl Indexer {
     pub fn new(
         index: Index,
         schema: Schema,
-        paths: &'a [&'a Path],
+        config: LookrdConfig,
     ) -> Result<Self, Box<dyn error::Error>> {
+        tokenizer::register(&index, &config);
+        let (tx, rx) = sync_channel(config.watch_queue.unwrap_or(DEFAULT_WATCH_QUEUE));
         Ok(Indexer {
             index,
             schema,
-            paths,
+            config,
+            stats: Arc::new(IndexerStats::default()),
+            tx,
+            rx,
+            index_writer: None,
+            user: None,
+            started: SystemTime::now(),
         })
     }
 
-    /// Build the index for the given locations.
-    pub fn index(&mut self) -> Result<(), IndexerError> {
-        let (tx, rx) = channel();
+    /// Only index the entries `user` can see, which are those in directories
+    /// they can list.
+    pub fn for_user(mut self, user: UserAccess) -> Self {
+        self.user = Some(user);
+        self
+    }
 
-        info!("Starting FsWatcher thread");
-        let w = FsWatcher::new(tx, self.paths)?;
-        thread::spawn(move || {
-            // This should not return.
-            match w.watch() {
-                Ok(_) => (),
-                Err(e) => error!("Error on watcher thread: {}", e),
-            }
-        });
+    /// Whether the indexer's user, if it has one, can see what's in `dir`.
+    /// Walks check each directory on the way down, so this only looks at
+    /// `dir` itself.
+    fn can_list(&self, dir: &Path) -> bool {
+        match &self.user {
+            Some(user) => fs::metadata(dir).is_ok_and(|m| user.can_list(&m)),
+            None => true,
+        }
+    }
 
-        let mut index_writer = self.index.writer_with_num_threads(1, 50_000_000)?;
-        let field_id = self.schema.get_field(FIELD_ID).unwrap();
-        let field_path = self.schema.get_field(FIELD_PATH).unwrap();
-        let field_ext = self.schema.get_field(FIELD_EXT).unwrap();
-        let field_filename = self.schema.get_field(FIELD_FILENAME).unwrap();
+    /// Like `can_list`, for a `dir` come across other than by walking down
+    /// to it: the user also has to be able to search every directory above
+    /// it, up to the index root it's under.
+    fn can_reach(&self, dir: &Path) -> bool {
+        let user = match &self.user {
+            Some(user) => user,
+            None => return true,
+        };
+        let root = self
+            .config
+            .index_paths
+            .iter()
+            .map(Path::new)
+            .filter(|r| dir.starts_with(r))
+            .max_by_key(|r| r.components().count());
+        dir.ancestors()
+            .skip(1)
+            .take_while(|a| root.is_none_or(|r| a.starts_with(r)))
+            .all(|a| fs::metadata(a).is_ok_and(|m| user.can_search(&m)))
+            && self.can_list(dir)
+    }
 
-        let from_pathbuf = |p: &PathBuf| {
-            let mut doc = Document::new();
-            doc.add_text(field_id, &p.to_string_lossy());
-            doc.add_text(field_path, &p.to_string_lossy());
-            match p.extension() {
-                Some(s) => doc.add_text(field_ext, &s.to_string_lossy()),
-                None => (),
-            }
-            match p.file_name() {
-                Some(s) => doc.add_text(field_filename, &s.to_string_lossy()),
-                None => (),
+    /// Opens the writer, if it isn't already. There can only be one writer on
+    /// an index at a time.
+    fn open_writer(&mut self) -> tantivy::Result<()> {
+        if self.index_writer.is_none() {
+            let writer = self.index.writer_with_num_threads(
+                self.config.writer_threads.unwrap_or(DEFAULT_WRITER_THREADS),
+                self.config
+                    .writer_memory_bytes
+                    .unwrap_or(DEFAULT_WRITER_MEMORY_BYTES),
+            )?;
+            self.index_writer = Some(Writer::new(writer, self.schema.clone()));
+        }
+        Ok(())
+    }
+
+    fn writer(&self) -> &Writer {
+        self.index_writer
+            .as_ref()
+            .expect("The IndexWriter is opened before indexing")
+    }
+
+    fn writer_mut(&mut self) -> &mut Writer {
+        self.index_writer
+            .as_mut()
+            .expect("The IndexWriter is opened before indexing")
+    }
+
+    pub fn handle(&self) -> IndexerHandle {
+        IndexerHandle {
+            stats: self.stats.clone(),
+            tx: self.tx.clone(),
+        }
+    }
+
+    /// Build the index for the given locations, then keep it up to date.
+    ///
+    /// The watcher starts first, and the walk waits until it's watching.
+    /// Its changes are queued while the walk runs and applied in order once
+    /// it's committed, each looking at the filesystem as it is then, so a
+    /// file created or removed mid-walk ends up indexed as it finally is.
+    ///
+    /// A standby with `replicate_from` neither watches nor walks, but keeps
+    /// its index a copy of the primary's.
+    pub fn index(&mut self) -> Result<(), IndexerError> {
+        let ready_rx = match self.config.replicate_from.clone() {
+            Some(primary) => {
+                info!("Starting the replication thread, following {}", primary);
+                let tx = self.tx.clone();
+                thread::spawn(move || {
+                    replication::follow(&primary, |batch| {
+                        tx.send(IndexerEvent::Replicated(batch)).is_ok()
+                    })
+                });
+                None
             }
-            doc
+            None => Some(self.start_watcher()?),
         };
 
-        // index all of the items that exist.
-        for path in self.paths {
-            let start = Instant::now();
-            let path_str = path.to_string_lossy();
-            info!("Starting index of: {}", path_str);
-
-            let walker = walkdir::WalkDir::new(path);
-            for entry in walker {
-                match entry {
-                    Ok(e) => {
-                        let p = e.into_path();
-                        debug!("Indexing: {:?}", p);
-                        index_writer.add_document(from_pathbuf(&p));
-                    }
-                    Err(e) => {
-                        error!("Walkdir Error: {}", e);
-                    }
+        // Before the writer starts its threads, so they inherit it.
+        if self.config.index_nice != 0 {
+            match set_nice(self.config.index_nice) {
+                Ok(_) => info!("Indexing at nice {}", self.config.index_nice),
+                Err(e) => warn!("Could not set nice {}: {}", self.config.index_nice, e),
+            }
+        }
+        self.open_writer()?;
+        let fields = Fields::new(&self.schema);
+        match ready_rx {
+            None => info!("Replicating rather than walking the index paths"),
+            Some(_) if self.skip_walk() => {
+                info!("Skipping the walk of the index paths, watching the persisted index");
+                self.stats.ready.store(true, Ordering::Relaxed);
+            }
+            Some(ready_rx) => {
+                // Changes made once the paths are watched are queued until
+                // the walk is done, and those made before are found by it,
+                // so none fall between the two.
+                if ready_rx.recv_timeout(WATCH_RETRY).is_err() {
+                    warn!("The watcher isn't watching yet, walking without it");
                 }
+                self.walk_roots(&fields)?;
+                self.stats.ready.store(true, Ordering::Relaxed);
             }
-            debug!("Commiting the index.");
-            index_writer.commit()?;
-            let duration = start.elapsed();
-            info!(
-                "Indexing complete for: {} in {}s",
-                path_str,
-                duration.as_secs()
-            );
+        }
+
+        if self.config.prewarm {
+            let index = self.index.clone();
+            thread::spawn(move || {
+                if let Err(e) = prewarm(&index) {
+                    error!("Could not prewarm the index: {}", e);
+                }
+            });
         }
 
         info!("Indexer watching for change events...");
         // Wait for watcher events and index those.
         let mut counter: u32 = 1;
         let mut last_change = counter;
+        // Changes from the watcher while indexing is paused.
+        let mut held = Vec::new();
         loop {
+            if self.apply_held(&fields, &mut held) {
+                counter += 1;
+            }
             // This will increment the counter and commit if we have processed
             // a number of documents (1000). This is to prevent us never
             // getting to the commit timeout if we are constantly churning
             // events.
-            if counter % 1000 == 0 {
+            if counter.is_multiple_of(1000) {
                 info!("Commiting index after 1000 mutations.");
-                match index_writer.commit() {
-                    Ok(_) => (),
-                    Err(e) => error!("Could not commit IndexWriter: {}", e),
-                };
+                self.commit_changes(&fields);
             }
 
-            match rx.recv_timeout(Duration::from_secs(1)) {
-                Ok(WatchEvent::Create(pb)) => {
-                    debug!("CREATE: {:?}", pb);
-                    index_writer.add_document(from_pathbuf(&pb));
-                    counter += 1;
-                }
-                Ok(WatchEvent::Remove(pb)) => {
-                    debug!("REMOVE: {:?}", pb);
-                    let term = Term::from_field_text(field_id, &pb.to_string_lossy());
-                    index_writer.delete_term(term);
-                    counter += 1;
-                }
-                Ok(WatchEvent::Rename(pb_src, pb_dst)) => {
-                    debug!("RENAME: {:?} -> {:?}", pb_src, pb_dst);
-                    let term = Term::from_field_text(field_id, &pb_src.to_string_lossy());
-                    index_writer.delete_term(term);
-                    index_writer.add_document(from_pathbuf(&pb_dst));
-                    counter += 1;
+            match self.rx.recv_timeout(Duration::from_secs(1)) {
+                Ok(IndexerEvent::Watch(event)) => {
+                    if self.watch_event(&fields, event, &mut held) {
+                        counter += 1;
+                    }
+                }
+                Ok(IndexerEvent::Command(IndexerCommand::Compact(reply))) => {
+                    info!("Compacting index on request.");
+                    last_change = counter;
+                    let index = self.index.clone();
+                    let _ = reply.send(compact(&index, self.writer_mut()));
+                }
+                Ok(IndexerEvent::Command(IndexerCommand::Snapshot(dest, reply))) => {
+                    info!("Writing snapshot of the index to {:?}", dest);
+                    last_change = counter;
+                    let snapshot = self
+                        .writer_mut()
+                        .commit()
+                        .map_err(IndexerError::from)
+                        .and_then(|_| snapshot(&self.index, &dest));
+                    let _ = reply.send(snapshot.map(|_| dest));
+                }
+                Ok(IndexerEvent::Command(IndexerCommand::Verify(reply))) => {
+                    info!("Verifying the index against the index paths");
+                    last_change = counter;
+                    let verification = self
+                        .writer_mut()
+                        .commit()
+                        .and_then(|_| self.verify(&fields))
+                        .map_err(IndexerError::from);
+                    let _ = reply.send(verification);
+                }
+                Ok(IndexerEvent::Command(IndexerCommand::Replicate(reply))) => {
+                    info!("Replicating the index to a new standby");
+                    last_change = counter;
+                    let standby = self
+                        .writer_mut()
+                        .commit()
+                        .map(|_| self.writer_mut().add_standby())
+                        .map_err(IndexerError::from);
+                    let _ = reply.send(standby);
+                }
+                Ok(IndexerEvent::Replicated(batch)) => {
+                    let more = batch.more;
+                    let index = self.index.clone();
+                    if let Err(e) = replication::apply(self.writer_mut(), &index, batch) {
+                        error!("Could not apply the primary's changes: {}", e);
+                    }
+                    // A copy of the index is committed once it's all here.
+                    if !more {
+                        match self.writer_mut().commit() {
+                            Ok(_) => self.stats.ready.store(true, Ordering::Relaxed),
+                            Err(e) => error!("Could not commit IndexWriter: {}", e),
+                        }
+                    }
+                }
+                Ok(IndexerEvent::Command(IndexerCommand::ReindexFile(p, reply))) => {
+                    info!("Indexing {:?} again on request", p);
+                    last_change = counter;
+                    let reindexed = self
+                        .writer_mut()
+                        .commit()
+                        .and_then(|_| self.reindex_path(&fields, &p))
+                        .map_err(IndexerError::from);
+                    let _ = reply.send(reindexed);
                 }
                 Err(e) => match e {
                     RecvTimeoutError::Timeout => {
@@ -154,10 +774,16 @@ impl<'a> Indexer<'a> {
                         if last_change != counter {
                             debug!("Commiting index after receiver timeout");
                             last_change = counter;
-                            match index_writer.commit() {
-                                Ok(_) => (),
-                                Err(e) => error!("Could not commit IndexWriter: {}", e),
-                            }
+                            self.commit_changes(&fields);
+                        }
+                        // The queue has drained, so catch up on the roots
+                        // that had events dropped, unless paused. A standby
+                        // has nothing of its own to walk.
+                        if !self.stats.paused.load(Ordering::Relaxed)
+                            && self.config.replicate_from.is_none()
+                        {
+                            self.rewalk_dirty(&fields);
+                            self.rewalk_due(&fields, SystemTime::now());
                         }
                     }
                     RecvTimeoutError::Disconnected => {
@@ -168,202 +794,3493 @@ impl<'a> Indexer<'a> {
             }
         }
     }
-}
-
-impl Drop for Indexer<'_> {
-    fn drop(&mut self) {
-        // Close off open files and end watcher.
-    }
-}
 
-#[derive(Debug)]
-pub enum IndexerError {
-    IoError(io::Error),
-    Tantivy(TantivyError),
-    WatcherRxError(RecvTimeoutError),
-    Watcher(WatcherError),
-}
-
-impl error::Error for IndexerError {}
+    /// Start watching the index paths, returning a channel that's sent to
+    /// once the watcher is watching.
+    fn start_watcher(&self) -> Result<Receiver<()>, IndexerError> {
+        let index_paths = self.config.index_paths.clone();
+        let paths: Vec<&Path> = index_paths.iter().map(Path::new).collect();
 
-impl fmt::Display for IndexerError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "IndexerError: {:#?}", self)?;
-        Ok(())
+        info!("Starting FsWatcher thread");
+        let (ready_tx, ready_rx) = channel();
+        let polled = paths
+            .iter()
+            .filter(|p| self.root_watch_mode(p) == WatchMode::Poll)
+            .map(PathBuf::from)
+            .collect();
+        let poll_interval = Duration::from_secs(
+            self.config
+                .poll_interval_secs
+                .unwrap_or(DEFAULT_POLL_INTERVAL_SECS),
+        );
+        let w = FsWatcher::new(
+            self.tx.clone(),
+            &paths,
+            self.config.watch_overflow,
+            self.stats.clone(),
+        )?
+        .with_polling(polled, poll_interval)
+        .with_debounce(Duration::from_millis(
+            self.config
+                .watch_debounce_ms
+                .unwrap_or(DEFAULT_WATCH_DEBOUNCE_MS),
+        ))
+        .with_ready(ready_tx)
+        .with_attribs(!self.config.index_xattrs.is_empty());
+        thread::spawn(move || {
+            // This should not return.
+            match w.watch() {
+                Ok(_) => (),
+                Err(e) => error!("Error on watcher thread: {}", e),
+            }
+        });
+        Ok(ready_rx)
     }
-}
 
-impl From<WatcherError> for IndexerError {
-    fn from(e: WatcherError) -> Self {
-        IndexerError::Watcher(e)
+    /// How changes to the root are noticed, given the `watch_mode` and
+    /// `poll_paths`, saying which in the log.
+    fn root_watch_mode(&self, root: &Path) -> WatchMode {
+        if self.config.poll_paths.iter().any(|p| Path::new(p) == root) {
+            info!(
+                "Polling {} for changes, as listed in poll_paths",
+                root.display()
+            );
+            return WatchMode::Poll;
+        }
+        let mode = match self.config.watch_mode {
+            WatchMode::Auto => match fstype::of(root) {
+                Some(fstype) => {
+                    let mode = fstype::watch_mode(&fstype);
+                    info!("{} is on {}", root.display(), fstype);
+                    mode
+                }
+                None => {
+                    info!("Could not tell which filesystem {} is on", root.display());
+                    WatchMode::Native
+                }
+            },
+            mode => mode,
+        };
+        match mode {
+            WatchMode::Poll => info!("Polling {} for changes", root.display()),
+            _ => info!("Watching {} for notifications of changes", root.display()),
+        }
+        mode
     }
-}
 
-impl From<io::Error> for IndexerError {
-    fn from(e: io::Error) -> Self {
-        IndexerError::IoError(e)
+    /// Commit what's been indexed, then make room under `max_docs` and merge
+    /// segments as configured.
+    fn commit_changes(&mut self, fields: &Fields) {
+        match self.writer_mut().commit() {
+            Ok(_) => {
+                self.evict_over_max_docs(fields);
+                self.compact_over_threshold()
+            }
+            Err(e) => error!("Could not commit IndexWriter: {}", e),
+        }
     }
-}
 
-impl From<TantivyError> for IndexerError {
-    fn from(e: TantivyError) -> Self {
-        IndexerError::Tantivy(e)
+    /// Whether to go straight to watching, with `skip_initial_walk`. An index
+    /// with nothing in it, just created or rebuilt, is walked regardless.
+    fn skip_walk(&self) -> bool {
+        self.config.skip_initial_walk
+            && self
+                .index
+                .reader()
+                .is_ok_and(|r| r.searcher().num_docs() > 0)
     }
-}
-
-#[derive(Debug)]
-pub enum WatcherError {
-    PathIsNotADir,
-    PathDoesNotExist,
-    NotifyError(RecvError),
-}
 
-impl error::Error for WatcherError {}
-
-impl fmt::Display for WatcherError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "WatcherError: {:#?}", self)?;
+    /// Index what's in the index paths now and commit, without watching
+    /// for changes, for an index that's only needed the once.
+    pub fn index_once(&mut self) -> Result<(), IndexerError> {
+        self.open_writer()?;
+        let fields = Fields::new(&self.schema);
+        self.walk_roots(&fields)?;
+        self.stats.ready.store(true, Ordering::Relaxed);
         Ok(())
     }
-}
-
-#[derive(Debug)]
-enum WatchEvent {
-    Create(PathBuf),
-    Remove(PathBuf),
-    Rename(PathBuf, PathBuf),
-}
 
-/// Recursively watch on the paths specified, updating the sorpus when they
-/// change.
-#[derive(Debug)]
-struct FsWatcher {
-    tx: Sender<WatchEvent>,
-    paths: Vec<PathBuf>,
-}
+    /// Walk each of the index paths, committing after each.
+    fn walk_roots(&mut self, fields: &Fields) -> Result<(), IndexerError> {
+        let index_paths = self.config.index_paths.clone();
+        for path in index_paths.iter().map(Path::new) {
+            let start = Instant::now();
+            let path_str = path.to_string_lossy();
+            info!("Starting index of: {}", path_str);
 
-impl<'a> FsWatcher {
-    fn new(tx: Sender<WatchEvent>, paths: &[&Path]) -> Result<Self, WatcherError> {
-        let mut ps = Vec::with_capacity(paths.len());
-        for p in paths {
-            let p = PathBuf::from(p);
-            if !p.exists() {
-                return Err(WatcherError::PathDoesNotExist);
-            }
-            if !p.is_dir() {
-                return Err(WatcherError::PathIsNotADir);
-            }
-            ps.push(p);
+            // A persisted index still has the last run's documents. Those
+            // that haven't been modified since are kept as they are.
+            let indexed = if self.config.persist_index {
+                self.indexed_mtimes(fields, &self.id_of(path))?
+            } else {
+                HashMap::new()
+            };
+            let walk_errors = self.walk_since(fields, path, indexed);
+            self.stats.walk_errors.lock().unwrap().add(&walk_errors);
+            debug!("Commiting the index.");
+            self.writer_mut().commit()?;
+            self.evict_over_max_docs(fields);
+            self.stats
+                .last_indexed
+                .lock()
+                .unwrap()
+                .insert(path_str.to_string(), SystemTime::now());
+            let duration = start.elapsed();
+            info!(
+                "Indexing complete for: {} in {}s",
+                path_str,
+                duration.as_secs()
+            );
         }
-
-        Ok(FsWatcher { tx, paths: ps })
+        Ok(())
     }
 
-    /// This function will block until termination or an error occurs (which
-    /// will be returned in the Result).
-    fn watch(&self) -> Result<(), Box<dyn error::Error>> {
-        let (tx, rx) = channel();
-
-        let mut watcher = notify::watcher(tx, Duration::from_secs(1))?;
+    /// Walk everything below `path`, adding it to the index, and return the
+    /// entries that could not be read.
+    fn walk(&self, fields: &Fields, path: &Path) -> WalkErrors {
+        self.walk_since(fields, path, HashMap::new())
+    }
 
-        for path in &self.paths {
-            match watcher.watch(path, RecursiveMode::Recursive) {
-                Err(e) => error!(
-                    "Error attempting to watch {:?}, this path will not be watched for updates: {}",
-                    path, e
-                ),
-                _ => (),
-            }
+    /// Walk everything below `path` like `walk`, given the IDs already
+    /// indexed below it and when they were modified. Those modified since are
+    /// replaced, those not modified are left alone, and those no longer found
+    /// are deleted.
+    fn walk_since(
+        &self,
+        fields: &Fields,
+        path: &Path,
+        mut indexed: HashMap<String, Option<u64>>,
+    ) -> WalkErrors {
+        let mut walker = walkdir::WalkDir::new(path)
+            .into_iter()
+            .filter_entry(|e| !is_excluded(&self.config.exclude_paths, e.path()));
+        if !self.can_reach(path) {
+            debug!("Not indexing {:?}, the user can't list it", path);
+            self.delete_ids(fields, indexed.keys());
+            return WalkErrors::default();
         }
-
-        loop {
-            match rx.recv() {
-                Ok(DebouncedEvent::Create(pb)) => {
-                    self.tx.send(WatchEvent::Create(pb))?;
-                }
-                Ok(DebouncedEvent::Remove(pb)) => {
-                    self.tx.send(WatchEvent::Remove(pb))?;
+        let throttle = self.config.index_throttle_ms.map(Duration::from_millis);
+        // With canonical IDs, the real paths already seen in this walk.
+        let mut seen = HashSet::new();
+        // The archives seen in this walk, whose entries are dealt with along
+        // with them, and those that already had entries indexed.
+        let mut archives = HashSet::new();
+        let listed: HashSet<String> = indexed
+            .keys()
+            .filter_map(|id| archive::archive_of(id))
+            .map(str::to_string)
+            .collect();
+        let mut walk_errors = WalkErrors::default();
+        let mut added = 0;
+        let mut unchanged = 0;
+        while let Some(entry) = walker.next() {
+            match entry {
+                Ok(e) => {
+                    let file_type = e.file_type();
+                    let p = e.into_path();
+                    // The directory is seen from the one above, but not what's
+                    // in it.
+                    if file_type.is_dir() && !self.can_list(&p) {
+                        walker.skip_current_dir();
+                    }
+                    if !self.extension_indexed(&p, Some(file_type)) {
+                        continue;
+                    }
+                    let id = self.doc_id(&p);
+                    if !id_fits(&id, &p) {
+                        // Everything below is longer still.
+                        if file_type.is_dir() {
+                            walker.skip_current_dir();
+                        }
+                        continue;
+                    }
+                    if self.config.canonical_ids && !seen.insert(id.clone()) {
+                        debug!("Already indexed as {}: {:?}", id, p);
+                        continue;
+                    }
+                    let archive = self.archive_indexed(&p, Some(file_type));
+                    if archive {
+                        archives.insert(id.clone());
+                    }
+                    if let Some(mtime) = indexed.remove(&id) {
+                        if mtime.is_some() && mtime == self.modified(&p) {
+                            unchanged += 1;
+                            if archive && !listed.contains(&id) {
+                                self.index_archive(fields, &id, &p);
+                            }
+                            continue;
+                        }
+                        self.writer()
+                            .delete_term(Term::from_field_text(fields.id, &id));
+                    }
+                    debug!("Indexing: {:?}", p);
+                    let doc = self.document(fields, &id, &p, Some(file_type));
+                    self.writer().add_document(doc);
+                    if archive {
+                        self.index_archive(fields, &id, &p);
+                    }
+                    added += 1;
+                    if let Some(throttle) = throttle {
+                        if added % THROTTLE_BATCH == 0 {
+                            thread::sleep(throttle);
+                        }
+                    }
                 }
-                Ok(DebouncedEvent::Rename(pb_src, pb_dst)) => {
-                    self.tx.send(WatchEvent::Rename(pb_src, pb_dst))?;
+                Err(e) => {
+                    debug!("Walkdir Error: {}", e);
+                    walk_errors.record(&e);
+                }
+            }
+        }
+        // Whatever is left has gone since it was indexed, other than the
+        // entries of archives, which were replaced along with them.
+        indexed.retain(|id, _| !archive::archive_of(id).is_some_and(|a| archives.contains(a)));
+        self.delete_ids(fields, indexed.keys());
+        if unchanged > 0 {
+            info!(
+                "Kept {} entries under {:?} that are unchanged since they were indexed",
+                unchanged, path
+            );
+        }
+        if walk_errors.total() > 0 {
+            warn!(
+                "Could not index {} entries under {:?}: {} permission denied, {} not found, {} filesystem loops, {} other",
+                walk_errors.total(),
+                path,
+                walk_errors.permission_denied,
+                walk_errors.not_found,
+                walk_errors.loops,
+                walk_errors.other
+            );
+        }
+        walk_errors
+    }
+
+    /// Walk the index paths as indexing would, without changing the index,
+    /// and compare the IDs found with those committed.
+    fn verify(&self, fields: &Fields) -> tantivy::Result<Verification> {
+        let mut indexed = self.committed_ids(fields)?;
+        let mut verification = Verification {
+            indexed: indexed.len() as u64,
+            duplicates: indexed.values().filter(|&&n| n > 1).count() as u64,
+            ..Default::default()
+        };
+        let mut unindexed = Vec::new();
+        // Roots can overlap, and with canonical IDs paths share IDs.
+        let mut seen = HashSet::new();
+        for root in self.config.index_paths.iter().map(Path::new) {
+            if !self.can_reach(root) {
+                continue;
+            }
+            let mut walker = walkdir::WalkDir::new(root)
+                .into_iter()
+                .filter_entry(|e| !is_excluded(&self.config.exclude_paths, e.path()));
+            while let Some(entry) = walker.next() {
+                let e = match entry {
+                    Ok(e) => e,
+                    Err(_) => continue,
+                };
+                let file_type = e.file_type();
+                let p = e.into_path();
+                if file_type.is_dir() && !self.can_list(&p) {
+                    walker.skip_current_dir();
+                }
+                if !self.extension_indexed(&p, Some(file_type)) {
+                    continue;
+                }
+                let id = self.doc_id(&p);
+                if !seen.insert(id.clone()) {
+                    continue;
+                }
+                verification.found += 1;
+                if indexed.remove(&id).is_none() {
+                    unindexed.push(id);
+                }
+            }
+        }
+        let mut missing: Vec<String> = indexed.into_keys().collect();
+        verification.missing = missing.len() as u64;
+        missing.sort();
+        missing.truncate(VERIFY_SAMPLE);
+        verification.missing_sample = missing;
+        verification.unindexed = unindexed.len() as u64;
+        unindexed.sort();
+        unindexed.truncate(VERIFY_SAMPLE);
+        verification.unindexed_sample = unindexed;
+        Ok(verification)
+    }
+
+    /// The number of live documents committed under each ID, other than the
+    /// entries of archives. Read from the IDs' terms rather than the stored
+    /// documents, so only the postings of each are decoded.
+    fn committed_ids(&self, fields: &Fields) -> tantivy::Result<HashMap<String, u32>> {
+        let searcher = self.index.reader()?.searcher();
+        let mut ids = HashMap::new();
+        for reader in searcher.segment_readers() {
+            let inverted_index = reader.inverted_index(fields.id);
+            let mut terms = inverted_index.terms().stream();
+            while terms.advance() {
+                let live = live_docs(reader, &inverted_index, terms.value());
+                let id = String::from_utf8_lossy(terms.key());
+                if live == 0 || archive::archive_of(&id).is_some() {
+                    continue;
+                }
+                *ids.entry(id.into_owned()).or_insert(0) += live;
+            }
+        }
+        Ok(ids)
+    }
+
+    /// Apply a change from the watcher, or hold on to it while indexing is
+    /// paused, returning whether the index has changes left to commit. Those
+    /// held are applied first once indexing is resumed, keeping them in
+    /// order. Past the queue's capacity, the change is dropped and its root
+    /// marked to be walked again instead. With `durable`, changes are
+    /// committed here.
+    fn watch_event(
+        &mut self,
+        fields: &Fields,
+        event: WatchEvent,
+        held: &mut Vec<WatchEvent>,
+    ) -> bool {
+        if self.stats.paused.load(Ordering::Relaxed) {
+            if held.len() < self.config.watch_queue.unwrap_or(DEFAULT_WATCH_QUEUE) {
+                held.push(event);
+            } else {
+                self.mark_roots_dirty(&event);
+            }
+            return false;
+        }
+        let changed = self.apply_held(fields, held);
+        let changed = self.apply(fields, event) || changed;
+        if changed && self.config.durable {
+            self.commit_changes(fields);
+            return false;
+        }
+        changed
+    }
+
+    /// Apply the changes held while indexing was paused, if it no longer is.
+    fn apply_held(&mut self, fields: &Fields, held: &mut Vec<WatchEvent>) -> bool {
+        if held.is_empty() || self.stats.paused.load(Ordering::Relaxed) {
+            return false;
+        }
+        info!("Applying the {} changes held while paused", held.len());
+        let mut changed = false;
+        for event in mem::take(held) {
+            changed |= self.apply(fields, event);
+        }
+        changed
+    }
+
+    /// Mark the index paths the event's paths are under to be walked again.
+    fn mark_roots_dirty(&self, event: &WatchEvent) {
+        let mut dirty_roots = self.stats.dirty_roots.lock().unwrap();
+        for p in event.paths() {
+            if let Some(root) = self.config.index_paths.iter().find(|r| p.starts_with(r)) {
+                if dirty_roots.insert(root.clone()) {
+                    warn!("{:?} will be walked again", root);
+                }
+            }
+        }
+    }
+
+    /// Apply a change from the watcher, returning whether the index was
+    /// changed.
+    fn apply(&mut self, fields: &Fields, event: WatchEvent) -> bool {
+        match event {
+            WatchEvent::Create(pb) => {
+                if is_excluded(&self.config.exclude_paths, &pb) {
+                    debug!("CREATE (excluded): {:?}", pb);
+                    return false;
+                }
+                // Queued behind a walk, it may be gone again, its removal
+                // having been debounced away.
+                let file_type = match fs::symlink_metadata(&pb) {
+                    Ok(m) => m.file_type(),
+                    Err(_) => {
+                        debug!("CREATE (gone): {:?}", pb);
+                        if let Err(e) = self.delete_subtree(fields, &self.id_of(&pb)) {
+                            error!("Could not remove the children of {:?}: {}", pb, e);
+                        }
+                        self.reindex_tagged(fields, &pb);
+                        return true;
+                    }
+                };
+                if self.extension_indexed(&pb, Some(file_type)) {
+                    debug!("CREATE: {:?}", pb);
+                    self.replace(fields, &pb);
+                } else {
+                    debug!("CREATE (extension excluded): {:?}", pb);
+                }
+                if file_type.is_dir() {
+                    self.replace_below(fields, &pb);
+                }
+                self.reindex_tagged(fields, &pb);
+                true
+            }
+            WatchEvent::Write(pb) => {
+                if self.archive_indexed(&pb, None) && !is_excluded(&self.config.exclude_paths, &pb)
+                {
+                    debug!("WRITE (archive): {:?}", pb);
+                    self.replace(fields, &pb);
+                    return true;
+                }
+                // The hash of its contents has changed.
+                if self.config.content_hash_max_size.is_some()
+                    && !is_excluded(&self.config.exclude_paths, &pb)
+                    && self.extension_indexed(&pb, None)
+                    && pb.is_file()
+                {
+                    debug!("WRITE (hashed): {:?}", pb);
+                    self.replace(fields, &pb);
+                    return true;
+                }
+                let changed = self.reindex_tagged(fields, &pb);
+                if changed {
+                    debug!("WRITE: {:?}", pb);
+                }
+                changed
+            }
+            WatchEvent::Attrib(pb) => {
+                if self.config.index_xattrs.is_empty()
+                    || is_excluded(&self.config.exclude_paths, &pb)
+                    || !self.extension_indexed(&pb, None)
+                    || fs::symlink_metadata(&pb).is_err()
+                {
+                    return false;
+                }
+                debug!("ATTRIB: {:?}", pb);
+                self.replace(fields, &pb);
+                true
+            }
+            WatchEvent::Remove(pb) => {
+                debug!("REMOVE: {:?}", pb);
+                // A removed directory only gets the one event, so its
+                // children go with it.
+                if let Err(e) = self.delete_subtree(fields, &self.id_of(&pb)) {
+                    error!("Could not remove the children of {:?}: {}", pb, e);
+                }
+                self.reindex_tagged(fields, &pb);
+                true
+            }
+            WatchEvent::Rename(pb_src, pb_dst) => {
+                debug!("RENAME: {:?} -> {:?}", pb_src, pb_dst);
+                let src_id = self.id_of(&pb_src);
+                self.writer()
+                    .delete_term(Term::from_field_text(fields.id, &src_id));
+                if self.config.archives && archive::is_archive(&pb_src) {
+                    self.delete_archive_entries(fields, &src_id);
+                }
+                if !is_excluded(&self.config.exclude_paths, &pb_dst)
+                    && pb_dst.symlink_metadata().is_ok()
+                    && self.extension_indexed(&pb_dst, None)
+                {
+                    self.replace(fields, &pb_dst);
+                }
+                self.reindex_tagged(fields, &pb_src);
+                self.reindex_tagged(fields, &pb_dst);
+                true
+            }
+        }
+    }
+
+    /// Replace everything indexed under each dirty root with a fresh walk of
+    /// it, committing after each root.
+    fn rewalk_dirty(&mut self, fields: &Fields) {
+        let dirty: Vec<String> = self.stats.dirty_roots.lock().unwrap().drain().collect();
+        for root in dirty {
+            info!("Walking {} again after dropped events", root);
+            if let Err(e) = self.rewalk(fields, &root) {
+                error!("Could not walk {} again: {}", root, e);
+                self.stats.dirty_roots.lock().unwrap().insert(root);
+            }
+        }
+    }
+
+    /// Walk each root with a `rewalk_interval_secs` again if it's been that
+    /// long since it was last walked, as of `now`.
+    fn rewalk_due(&mut self, fields: &Fields, now: SystemTime) {
+        let roots = self.config.index_paths.clone();
+        for root in roots {
+            let secs = match self
+                .config
+                .rewalk_interval_secs
+                .iter()
+                .find(|(p, _)| Path::new(p) == Path::new(&root))
+            {
+                Some((_, secs)) => *secs,
+                None => continue,
+            };
+            let walked = self
+                .stats
+                .last_indexed
+                .lock()
+                .unwrap()
+                .get(&root)
+                .copied()
+                .unwrap_or(self.started);
+            // An interval too long to add to a time is never due.
+            match walked.checked_add(Duration::from_secs(secs)) {
+                Some(due) if now >= due => {}
+                _ => continue,
+            }
+            info!("Walking {} again, as it is every {}s", root, secs);
+            if let Err(e) = self.rewalk(fields, &root) {
+                error!("Could not walk {} again: {}", root, e);
+            }
+        }
+    }
+
+    fn rewalk(&mut self, fields: &Fields, root: &str) -> tantivy::Result<()> {
+        self.delete_subtree(fields, &self.id_of(Path::new(root)))?;
+
+        // Deletes only apply to documents added before them, so the walk
+        // re-adds everything that still exists.
+        self.walk(fields, Path::new(root));
+        self.writer_mut().commit()?;
+        self.stats
+            .last_indexed
+            .lock()
+            .unwrap()
+            .insert(root.to_string(), SystemTime::now());
+        Ok(())
+    }
+
+    /// Index `p` in place of whatever is committed under its ID, or delete it
+    /// and everything below it if it's gone or no longer to be indexed, then
+    /// commit. What the indexer's user can't see counts as gone, so they
+    /// can't learn whether it's there.
+    fn reindex_path(&mut self, fields: &Fields, p: &Path) -> tantivy::Result<Option<Reindexed>> {
+        let id = self.doc_id(p);
+        let searcher = self.index.reader()?.searcher();
+        let term = Term::from_field_text(fields.id, &id);
+        let indexed = searcher.search(&TermQuery::new(term, IndexRecordOption::Basic), &Count)? > 0;
+        let exists = fs::symlink_metadata(p).is_ok()
+            && !is_excluded(&self.config.exclude_paths, p)
+            && self.extension_indexed(p, None)
+            && p.parent().is_none_or(|parent| self.can_reach(parent));
+        if !exists && !indexed {
+            return Ok(None);
+        }
+        let added = exists && self.replace(fields, p);
+        if !added {
+            self.delete_subtree(fields, &id)?;
+        }
+        self.writer_mut().commit()?;
+        Ok(match (added, indexed) {
+            (true, true) => Some(Reindexed::Updated),
+            (true, false) => Some(Reindexed::Added),
+            (false, true) => Some(Reindexed::Removed),
+            (false, false) => None,
+        })
+    }
+
+    /// Delete the document for `root` and those for everything below it.
+    /// Only committed documents can be found below it, so children added
+    /// since the last commit are left.
+    fn delete_subtree(&self, fields: &Fields, root: &str) -> tantivy::Result<()> {
+        self.writer()
+            .delete_term(Term::from_field_text(fields.id, root));
+
+        let searcher = self.index.reader()?.searcher();
+        let limit = (searcher.num_docs() as usize).max(1);
+        let indexed =
+            searcher.search(&subtree_query(fields.id, root), &TopDocs::with_limit(limit))?;
+        for (_, doc_addr) in indexed {
+            let doc = searcher.doc(doc_addr)?;
+            if let Some(id) = doc.get_first(fields.id).and_then(|v| v.text()) {
+                self.writer()
+                    .delete_term(Term::from_field_text(fields.id, id));
+            }
+        }
+        Ok(())
+    }
+
+    /// The IDs of `root` and everything below it in the committed index, with
+    /// when each was modified if that was indexed.
+    fn indexed_mtimes(
+        &self,
+        fields: &Fields,
+        root: &str,
+    ) -> tantivy::Result<HashMap<String, Option<u64>>> {
+        let searcher = self.index.reader()?.searcher();
+        let limit = (searcher.num_docs() as usize).max(1);
+        let indexed =
+            searcher.search(&subtree_query(fields.id, root), &TopDocs::with_limit(limit))?;
+        let mut mtimes = HashMap::with_capacity(indexed.len());
+        for (_, doc_addr) in indexed {
+            let doc = searcher.doc(doc_addr)?;
+            if let Some(id) = doc.get_first(fields.id).and_then(|v| v.text()) {
+                let mtime = match doc.get_first(fields.mtime) {
+                    Some(Value::U64(mtime)) => Some(*mtime),
+                    _ => None,
+                };
+                mtimes.insert(id.to_string(), mtime);
+            }
+        }
+        Ok(mtimes)
+    }
+
+    /// Whether entries are indexed with their modification times, for
+    /// something that needs them.
+    fn tracks_mtime(&self) -> bool {
+        self.config.persist_index
+            || self.config.max_docs.is_some()
+            || self.config.recency_weight.is_some()
+    }
+
+    /// When `p` was last modified, in nanoseconds since the epoch. With tags,
+    /// editing the sidecar counts as modifying the file. With xattrs or
+    /// owners indexed, so does setting or removing an xattr, or a chown,
+    /// which only change the ctime.
+    fn modified(&self, p: &Path) -> Option<u64> {
+        let with_ctime = !self.config.index_xattrs.is_empty() || self.config.index_owners;
+        let mtime = |p: &Path| {
+            let metadata = fs::symlink_metadata(p).ok()?;
+            let modified = metadata.modified().ok()?;
+            let since_epoch = modified.duration_since(SystemTime::UNIX_EPOCH).ok()?;
+            let mtime = since_epoch.as_nanos() as u64;
+            Some(match changed(&metadata) {
+                Some(ctime) if with_ctime => mtime.max(ctime),
+                _ => mtime,
+            })
+        };
+        let file = mtime(p)?;
+        if self.config.tags {
+            if let Some(sidecar) = mtime(&sidecar_path(p)) {
+                return Some(file.max(sidecar));
+            }
+        }
+        Some(file)
+    }
+
+    fn delete_ids<'a>(&self, fields: &Fields, ids: impl Iterator<Item = &'a String>) {
+        for id in ids {
+            debug!("Removing: {}", id);
+            self.writer()
+                .delete_term(Term::from_field_text(fields.id, id));
+        }
+    }
+
+    /// Build the document for a path, along with what it is and anything the
+    /// config asks to be read from the filesystem for it. The file type is
+    /// looked up if it isn't already known.
+    pub(crate) fn document(
+        &self,
+        fields: &Fields,
+        id: &str,
+        p: &Path,
+        file_type: Option<FileType>,
+    ) -> Document {
+        let mut doc = self.base_document(fields, id, p);
+        if self.config.substring_index {
+            if let Some(s) = p.file_name() {
+                doc.add_text(fields.filename_ngram, &s.to_string_lossy());
+            }
+        }
+        let file_type = file_type.or_else(|| fs::symlink_metadata(p).ok().map(|m| m.file_type()));
+        if let Some(file_type) = file_type {
+            doc.add_text(fields.kind, file_kind(&file_type));
+        }
+        if let Some(max_size) = self.config.first_line_max_size {
+            if file_type.is_some_and(|t| t.is_file()) {
+                let sniff_bytes = self
+                    .config
+                    .binary_sniff_bytes
+                    .unwrap_or(DEFAULT_BINARY_SNIFF_BYTES);
+                if let Some(line) = read_first_line(p, max_size, sniff_bytes) {
+                    doc.add_text(fields.first_line, &line);
+                }
+            }
+        }
+        if let Some(max_size) = self.config.content_hash_max_size {
+            if file_type.is_some_and(|t| t.is_file()) {
+                if let Some(hash) = content_hash(p, max_size) {
+                    doc.add_text(fields.hash, &hash);
+                }
+            }
+        }
+        if self.config.tags {
+            for tag in read_tags(p) {
+                doc.add_text(fields.tags, &tag);
+            }
+        }
+        for (name, value) in xattr::read(p, &self.config.index_xattrs) {
+            for term in xattr::terms(&name, value.as_deref()) {
+                doc.add_text(fields.xattr, &term);
+            }
+        }
+        if self.tracks_mtime() {
+            if let Some(mtime) = self.modified(p) {
+                doc.add_u64(fields.mtime, mtime);
+            }
+        }
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+
+            if self.config.index_inodes || self.config.index_owners {
+                if let Ok(m) = fs::symlink_metadata(p) {
+                    if self.config.index_inodes {
+                        doc.add_u64(fields.inode, m.ino());
+                        doc.add_u64(fields.dev, m.dev());
+                    }
+                    if self.config.index_owners {
+                        doc.add_u64(fields.uid, u64::from(m.uid()));
+                    }
+                }
+            }
+        }
+        doc
+    }
+
+    /// Whether `p` passes the configured extension lists. Directories always
+    /// do, so everything below them is still walked. The file type is looked
+    /// up if it isn't already known.
+    fn extension_indexed(&self, p: &Path, file_type: Option<FileType>) -> bool {
+        let config = &self.config;
+        if config.index_extensions.is_none() && config.exclude_extensions.is_none() {
+            return true;
+        }
+        let file_type = file_type.or_else(|| fs::symlink_metadata(p).ok().map(|m| m.file_type()));
+        if file_type.is_some_and(|t| t.is_dir()) {
+            return true;
+        }
+        extension_allowed(
+            config.index_extensions.as_deref(),
+            config.exclude_extensions.as_deref(),
+            p,
+        )
+    }
+
+    /// If `p` is a sidecar, re-index the file it holds the tags for. Returns
+    /// true if it was a sidecar.
+    fn reindex_tagged(&self, fields: &Fields, p: &Path) -> bool {
+        if !self.config.tags {
+            return false;
+        }
+        let target = match sidecar_target(p) {
+            Some(t) => t,
+            None => return false,
+        };
+        if is_excluded(&self.config.exclude_paths, &target) {
+            return true;
+        }
+
+        debug!("Re-indexing {:?} for its tags", target);
+        if target.exists() && self.extension_indexed(&target, None) {
+            self.replace(fields, &target);
+        } else {
+            let term = Term::from_field_text(fields.id, &self.id_of(&target));
+            self.writer().delete_term(term);
+        }
+        true
+    }
+
+    /// The ID `p` is indexed under, which is the path itself unless
+    /// `canonical_ids` is set. Then it's the real path, so a file reached
+    /// through symlinks is only indexed once, or the path itself if it has
+    /// no real path, like a broken symlink.
+    fn doc_id(&self, p: &Path) -> String {
+        if self.config.canonical_ids {
+            if let Ok(real) = fs::canonicalize(p) {
+                return self.id_of(&real);
+            }
+        }
+        self.id_of(p)
+    }
+
+    /// The ID `p` itself is indexed under, with its separators normalized if
+    /// the config asks for it.
+    fn id_of(&self, p: &Path) -> String {
+        let path = p.to_string_lossy();
+        if self.config.normalize_separators {
+            normalize_separators(&path).into_owned()
+        } else {
+            path.into_owned()
+        }
+    }
+
+    /// The document with just the path's own fields, normalized if the
+    /// config asks for it, and its depth.
+    fn base_document(&self, fields: &Fields, id: &str, p: &Path) -> Document {
+        let mut doc = if self.config.normalize_separators {
+            fields.document_normalized(&normalize_separators(id), p)
+        } else {
+            fields.document_with_id(id, p)
+        };
+        if let Some(depth) = self.depth(p) {
+            doc.add_u64(fields.depth, depth);
+        }
+        doc
+    }
+
+    /// How many levels below the index path containing it `p` is, or `None`
+    /// if it isn't in one. An archive's entries are a level below it, along
+    /// with any directories in it.
+    fn depth(&self, p: &Path) -> Option<u64> {
+        let root = self
+            .config
+            .index_paths
+            .iter()
+            .map(Path::new)
+            .find(|r| p.starts_with(r))?;
+        Some((p.components().count() - root.components().count()) as u64)
+    }
+
+    /// Index `p` in place of whatever is indexed under its ID, returning
+    /// whether it was, rather than only deleted because the user can't see
+    /// it or its ID is too long.
+    fn replace(&self, fields: &Fields, p: &Path) -> bool {
+        let id = self.doc_id(p);
+        self.writer()
+            .delete_term(Term::from_field_text(fields.id, &id));
+        if !p.parent().is_none_or(|parent| self.can_reach(parent)) || !id_fits(&id, p) {
+            return false;
+        }
+        let doc = self.document(fields, &id, p, None);
+        self.writer().add_document(doc);
+        if self.archive_indexed(p, None) {
+            self.index_archive(fields, &id, p);
+        }
+        true
+    }
+
+    /// Index everything below the new directory `dir` in place of whatever
+    /// is indexed under the same IDs. What was made in it before the watcher
+    /// was watching it has no events of its own.
+    fn replace_below(&self, fields: &Fields, dir: &Path) {
+        if !self.can_reach(dir) {
+            return;
+        }
+        let mut walker = walkdir::WalkDir::new(dir)
+            .min_depth(1)
+            .into_iter()
+            .filter_entry(|e| !is_excluded(&self.config.exclude_paths, e.path()));
+        while let Some(entry) = walker.next() {
+            let entry = match entry {
+                Ok(e) => e,
+                Err(_) => continue,
+            };
+            if self.extension_indexed(entry.path(), Some(entry.file_type())) {
+                self.replace(fields, entry.path());
+            }
+            // As in a walk, the directory is seen but not what's in it.
+            if entry.file_type().is_dir() && !self.can_list(entry.path()) {
+                walker.skip_current_dir();
+            }
+        }
+    }
+
+    /// Whether the entries of `p` are to be indexed, which needs `archives`
+    /// set and `p` to be a zip or tar file. The file type is looked up if it
+    /// isn't already known.
+    fn archive_indexed(&self, p: &Path, file_type: Option<FileType>) -> bool {
+        if !self.config.archives || !archive::is_archive(p) {
+            return false;
+        }
+        let file_type = file_type.or_else(|| fs::symlink_metadata(p).ok().map(|m| m.file_type()));
+        file_type.is_some_and(|t| t.is_file())
+    }
+
+    /// Index the entries of the archive at `p`, indexed under `id`, in place
+    /// of those indexed before. Archives over the size limit, or that can't
+    /// be read, are left with no entries.
+    fn index_archive(&self, fields: &Fields, id: &str, p: &Path) {
+        self.delete_archive_entries(fields, id);
+        let metadata = match fs::metadata(p) {
+            Ok(m) => m,
+            Err(e) => {
+                debug!("Could not list the archive {:?}: {}", p, e);
+                return;
+            }
+        };
+        let max_bytes = self
+            .config
+            .archive_max_bytes
+            .unwrap_or(DEFAULT_ARCHIVE_MAX_BYTES);
+        if metadata.len() > max_bytes {
+            debug!(
+                "Not listing the archive {:?}, it's over {} bytes",
+                p, max_bytes
+            );
+            return;
+        }
+        // Running as root for a user, the entries are only theirs to see if
+        // they could read the archive themselves.
+        if !self.user.as_ref().is_none_or(|u| u.can_read(&metadata)) {
+            return;
+        }
+        let entries = match archive::entries(p) {
+            Ok(e) => e,
+            Err(e) => {
+                warn!("Could not list the archive {:?}: {}", p, e);
+                return;
+            }
+        };
+        debug!("Indexing {} entries of {:?}", entries.len(), p);
+        let path = p.to_string_lossy();
+        let mtime = if self.tracks_mtime() {
+            self.modified(p)
+        } else {
+            None
+        };
+        for entry in entries {
+            let entry_path = archive::virtual_path(&path, &entry.name);
+            if entry.kind != "dir"
+                && !extension_allowed(
+                    self.config.index_extensions.as_deref(),
+                    self.config.exclude_extensions.as_deref(),
+                    Path::new(&entry_path),
+                )
+            {
+                continue;
+            }
+            let entry_id = archive::virtual_path(id, &entry.name);
+            if !id_fits(&entry_id, Path::new(&entry_path)) {
+                continue;
+            }
+            let mut doc = self.base_document(fields, &entry_id, Path::new(&entry_path));
+            doc.add_text(fields.kind, entry.kind);
+            if self.config.substring_index {
+                if let Some(s) = Path::new(&entry.name).file_name() {
+                    doc.add_text(fields.filename_ngram, &s.to_string_lossy());
+                }
+            }
+            // The archive's, as its entries change along with it.
+            if let Some(mtime) = mtime {
+                doc.add_u64(fields.mtime, mtime);
+            }
+            self.writer().add_document(doc);
+        }
+    }
+
+    /// Delete the committed entries of the archive indexed under `id`.
+    fn delete_archive_entries(&self, fields: &Fields, id: &str) {
+        let delete = || -> tantivy::Result<()> {
+            let searcher = self.index.reader()?.searcher();
+            let limit = (searcher.num_docs() as usize).max(1);
+            let query = archive_entries_query(fields.id, id);
+            for (_, doc_addr) in searcher.search(&query, &TopDocs::with_limit(limit))? {
+                let doc = searcher.doc(doc_addr)?;
+                if let Some(entry_id) = doc.get_first(fields.id).and_then(|v| v.text()) {
+                    self.writer()
+                        .delete_term(Term::from_field_text(fields.id, entry_id));
+                }
+            }
+            Ok(())
+        };
+        if let Err(e) = delete() {
+            error!("Could not remove the entries of the archive {}: {}", id, e);
+        }
+    }
+
+    /// Compact the index if it has grown more segments than configured.
+    /// Once the committed index holds more than `max_docs` entries, delete
+    /// the least recently modified, bringing it down to `EVICT_TO_PERCENT` of
+    /// them. Only checked after a commit, so the index can go over by what's
+    /// added in between.
+    fn evict_over_max_docs(&mut self, fields: &Fields) {
+        let max_docs = match self.config.max_docs {
+            Some(m) => m,
+            None => return,
+        };
+        match self.evict_oldest(fields, max_docs) {
+            Ok(0) => (),
+            Ok(evicted) => info!(
+                "Evicted the {} least recently modified entries after passing {}.",
+                evicted, max_docs
+            ),
+            Err(e) => error!("Could not evict entries: {}", e),
+        }
+    }
+
+    fn evict_oldest(&mut self, fields: &Fields, max_docs: u64) -> tantivy::Result<usize> {
+        let searcher = self.index.reader()?.searcher();
+        let num_docs = searcher.num_docs();
+        if num_docs <= max_docs {
+            return Ok(0);
+        }
+        let all = searcher.search(&AllQuery, &TopDocs::with_limit(num_docs as usize))?;
+        let mut entries = Vec::with_capacity(all.len());
+        for (_, doc_addr) in all {
+            let doc = searcher.doc(doc_addr)?;
+            if let Some(id) = doc.get_first(fields.id).and_then(|v| v.text()) {
+                // Those without a modification time go first.
+                let mtime = match doc.get_first(fields.mtime) {
+                    Some(Value::U64(mtime)) => *mtime,
+                    _ => 0,
+                };
+                entries.push((mtime, id.to_string()));
+            }
+        }
+        entries.sort();
+
+        let keep = (max_docs * EVICT_TO_PERCENT / 100) as usize;
+        let evicted = entries.len().saturating_sub(keep);
+        self.delete_ids(fields, entries[..evicted].iter().map(|(_, id)| id));
+        self.writer_mut().commit()?;
+        Ok(evicted)
+    }
+
+    fn compact_over_threshold(&mut self) {
+        let threshold = match self.config.compact_segments {
+            Some(t) => t,
+            None => return,
+        };
+        match self.index.searchable_segment_ids() {
+            Ok(ids) if ids.len() > threshold => {
+                match compact(&self.index.clone(), self.writer_mut()) {
+                    Ok((before, after)) => info!(
+                        "Compacted index from {} to {} segments after passing {}.",
+                        before, after, threshold
+                    ),
+                    Err(e) => error!("Could not compact index: {}", e),
+                }
+            }
+            Ok(_) => (),
+            Err(e) => error!("Could not read segments: {}", e),
+        }
+    }
+}
+
+/// Commits any pending changes and merges all of the segments into one,
+/// returning the number of segments before and after.
+fn compact(index: &Index, index_writer: &mut Writer) -> tantivy::Result<(usize, usize)> {
+    index_writer.commit()?;
+    let segment_ids = index.searchable_segment_ids()?;
+    if segment_ids.len() > 1 {
+        block_on(index_writer.merge(&segment_ids))?;
+    }
+    let after = index.searchable_segment_ids()?.len();
+    Ok((segment_ids.len(), after))
+}
+
+/// The schema version of the index in `dir`, 0 if it was written before
+/// versions were kept, or `None` if there is no index there.
+pub fn schema_version(dir: &Path) -> Result<Option<u32>, IndexerError> {
+    if !dir.join(META_FILE).exists() {
+        return Ok(None);
+    }
+    match fs::read_to_string(dir.join(SCHEMA_VERSION_FILE)) {
+        Ok(v) => match v.trim().parse() {
+            Ok(v) => Ok(Some(v)),
+            Err(_) => Err(IndexerError::IoError(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Could not read schema version {:?} in {:?}", v, dir),
+            ))),
+        },
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Some(0)),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Open the index in `dir`, creating it if there isn't one. An index with
+/// another schema version is an `IncompatibleSchema` error.
+pub fn open_index(dir: &Path, schema: Schema) -> Result<Index, IndexerError> {
+    match schema_version(dir)? {
+        None => {
+            fs::create_dir_all(dir)?;
+            let index = Index::create_in_dir(dir, schema)?;
+            fs::write(dir.join(SCHEMA_VERSION_FILE), SCHEMA_VERSION.to_string())?;
+            Ok(index)
+        }
+        Some(SCHEMA_VERSION) => Ok(Index::open_in_dir(dir)?),
+        Some(found) => Err(IndexerError::IncompatibleSchema(found)),
+    }
+}
+
+/// Open the persisted index like `open_index`, starting it again if it was
+/// written with an older schema, can't be read, or was built with config
+/// whose `index_fingerprint` isn't `fingerprint`. Its contents are walked
+/// again on startup anyway, so nothing is lost but the time to walk. An
+/// index that can't be read is moved to `<dir>.broken` rather than deleted,
+/// and one from a newer lookrd is left alone.
+pub fn open_or_rebuild(
+    dir: &Path,
+    schema: Schema,
+    fingerprint: &str,
+) -> Result<Index, IndexerError> {
+    let index = open_or_rebuild_schema(dir, schema.clone())?;
+    let built_with = match fs::read_to_string(dir.join(FINGERPRINT_FILE)) {
+        Ok(f) => Some(f),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e.into()),
+    };
+    if built_with.as_deref() == Some(fingerprint) {
+        return Ok(index);
+    }
+    // The walk would keep what's unchanged since it was indexed, as it was
+    // indexed then.
+    let index = if index.searchable_segment_ids()?.is_empty() {
+        index
+    } else {
+        warn!(
+            "The index in {:?} was built with other indexing settings, rebuilding it",
+            dir
+        );
+        drop(index);
+        fs::remove_dir_all(dir)?;
+        open_index(dir, schema)?
+    };
+    fs::write(dir.join(FINGERPRINT_FILE), fingerprint)?;
+    Ok(index)
+}
+
+fn open_or_rebuild_schema(dir: &Path, schema: Schema) -> Result<Index, IndexerError> {
+    match open_index(dir, schema.clone()) {
+        Err(IndexerError::IncompatibleSchema(found)) if found < SCHEMA_VERSION => {
+            warn!(
+                "The index in {:?} has schema version {}, rebuilding it for version {}",
+                dir, found, SCHEMA_VERSION
+            );
+            fs::remove_dir_all(dir)?;
+            open_index(dir, schema)
+        }
+        Err(e @ IndexerError::IncompatibleSchema(_)) => Err(e),
+        Err(e) => {
+            let broken = dir.with_extension("broken");
+            warn!(
+                "The index in {:?} can't be read, moving it to {:?} and rebuilding it: {}",
+                dir, broken, e
+            );
+            if broken.exists() {
+                fs::remove_dir_all(&broken)?;
+            }
+            fs::rename(dir, &broken)?;
+            open_index(dir, schema)
+        }
+        r => r,
+    }
+}
+
+/// Open the index in `dir` to query, like a snapshot, without creating,
+/// rebuilding or writing to it. It must have been written with this
+/// lookrd's schema version.
+pub fn open_read_only(dir: &Path) -> Result<Index, IndexerError> {
+    match schema_version(dir)? {
+        None => Err(IndexerError::IoError(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("There's no index in {:?}", dir),
+        ))),
+        Some(SCHEMA_VERSION) => {
+            let directory = MmapDirectory::open(dir).map_err(TantivyError::from)?;
+            Ok(Index::open(ReadOnlyDirectory(directory))?)
+        }
+        Some(found) => Err(IndexerError::IncompatibleSchema(found)),
+    }
+}
+
+/// An index directory opened only to query. It takes no locks and refuses
+/// to write, so the directory is left as it was found, and needn't be one
+/// lookrd can write to.
+#[derive(Clone, Debug)]
+struct ReadOnlyDirectory(MmapDirectory);
+
+impl ReadOnlyDirectory {
+    fn refused(path: &Path) -> io::Error {
+        io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("Can't write {:?} to an index opened read-only", path),
+        )
+    }
+}
+
+impl Directory for ReadOnlyDirectory {
+    fn open_read(&self, path: &Path) -> Result<ReadOnlySource, OpenReadError> {
+        self.0.open_read(path)
+    }
+
+    fn delete(&self, path: &Path) -> Result<(), DeleteError> {
+        Err(IOError::from(ReadOnlyDirectory::refused(path)).into())
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.0.exists(path)
+    }
+
+    fn open_write(&mut self, path: &Path) -> Result<WritePtr, OpenWriteError> {
+        Err(IOError::from(ReadOnlyDirectory::refused(path)).into())
+    }
+
+    fn atomic_read(&self, path: &Path) -> Result<Vec<u8>, OpenReadError> {
+        self.0.atomic_read(path)
+    }
+
+    fn atomic_write(&mut self, path: &Path, _data: &[u8]) -> io::Result<()> {
+        Err(ReadOnlyDirectory::refused(path))
+    }
+
+    /// Nothing else can change the index while it's read, so there's
+    /// nothing to lock against, and taking a lock would write its file.
+    fn acquire_lock(&self, _lock: &Lock) -> Result<DirectoryLock, LockError> {
+        Ok(DirectoryLock::from(Box::new(())))
+    }
+
+    fn watch(&self, watch_callback: WatchCallback) -> tantivy::Result<WatchHandle> {
+        self.0.watch(watch_callback)
+    }
+}
+
+/// Copies the committed index into `dest`, which must be empty or not exist,
+/// as an index that can be opened with `Index::open_in_dir`. The files are
+/// copied rather than linked, so `dest` can be on any filesystem.
+fn snapshot(index: &Index, dest: &Path) -> Result<(), IndexerError> {
+    if fs::read_dir(dest).is_ok_and(|mut d| d.next().is_some()) {
+        return Err(IndexerError::IoError(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("Snapshot destination {:?} is not empty", dest),
+        )));
+    }
+
+    // A merge can land while the segments are being copied, so go again if
+    // the list of segments has changed by the end.
+    let directory = index.directory();
+    for _ in 0..3 {
+        fs::create_dir_all(dest)?;
+        let meta = directory
+            .atomic_read(Path::new(META_FILE))
+            .map_err(TantivyError::from)?;
+        for segment in index.searchable_segment_metas()? {
+            for file in segment.list_files() {
+                // Read whole rather than opened, which would strip the footer.
+                match directory.atomic_read(&file) {
+                    Ok(data) => fs::write(dest.join(&file), data)?,
+                    // Not every segment has every component.
+                    Err(OpenReadError::FileDoesNotExist(_)) => (),
+                    Err(e) => return Err(TantivyError::from(e).into()),
+                }
+            }
+        }
+
+        let meta_after = directory
+            .atomic_read(Path::new(META_FILE))
+            .map_err(TantivyError::from)?;
+        if meta_after == meta {
+            fs::write(dest.join(SCHEMA_VERSION_FILE), SCHEMA_VERSION.to_string())?;
+            // Written last, so an incomplete copy can't be opened.
+            fs::write(dest.join(META_FILE), meta)?;
+            return Ok(());
+        }
+        debug!("Segments changed while writing snapshot, starting again");
+        fs::remove_dir_all(dest)?;
+    }
+    Err(IndexerError::IoError(io::Error::other(
+        "The index kept changing while writing the snapshot",
+    )))
+}
+
+/// The name indexed for each type of entry: "file", "dir", "symlink", or
+/// "other" for sockets, devices and the like.
+pub(crate) fn file_kind(file_type: &FileType) -> &'static str {
+    if file_type.is_symlink() {
+        "symlink"
+    } else if file_type.is_dir() {
+        "dir"
+    } else if file_type.is_file() {
+        "file"
+    } else {
+        "other"
+    }
+}
+
+/// Reads the first line of the file if it is no bigger than `max_size`. Files
+/// whose first `sniff_bytes` look binary are skipped, and a character cut off
+/// by the length limit is dropped.
+fn read_first_line(p: &Path, max_size: u64, sniff_bytes: u64) -> Option<String> {
+    let file = File::open(p).ok()?;
+    if file.metadata().ok()?.len() > max_size {
+        return None;
+    }
+    let mut start = Vec::new();
+    file.take(sniff_bytes.max(MAX_FIRST_LINE))
+        .read_to_end(&mut start)
+        .ok()?;
+    if is_binary(&start[..start.len().min(sniff_bytes as usize)]) {
+        return None;
+    }
+    let start = &start[..start.len().min(MAX_FIRST_LINE as usize)];
+    let line = match start.iter().position(|b| *b == b'\n') {
+        Some(end) => &start[..end],
+        None => start,
+    };
+
+    let line = match std::str::from_utf8(line) {
+        Ok(s) => s,
+        // Only the end is incomplete, cut off mid character.
+        Err(e) if e.error_len().is_none() => std::str::from_utf8(&line[..e.valid_up_to()]).ok()?,
+        Err(_) => return None,
+    };
+    let line = line.trim_end_matches(&['\r', '\n'][..]);
+    if line.is_empty() {
+        None
+    } else {
+        Some(line.to_string())
+    }
+}
+
+/// A BLAKE2b hash of the contents of the file at `p` as hex, or `None` if
+/// it's over `max_size` bytes or can't be read.
+fn content_hash(p: &Path, max_size: u64) -> Option<String> {
+    let file = File::open(p).ok()?;
+    if file.metadata().ok()?.len() > max_size {
+        return None;
+    }
+    // 128 bits is plenty to tell files apart, and keeps the terms short.
+    let mut state = blake2b_simd::Params::new().hash_length(16).to_state();
+    // It may have grown since.
+    if io::copy(&mut file.take(max_size + 1), &mut state).ok()? > max_size {
+        return None;
+    }
+    Some(state.finalize().to_hex().to_string())
+}
+
+/// Whether the start of a file looks like something other than text: it has
+/// a NUL byte, or isn't UTF-8. A character cut off at the end doesn't count.
+fn is_binary(start: &[u8]) -> bool {
+    if start.contains(&0) {
+        return true;
+    }
+    match std::str::from_utf8(start) {
+        Ok(_) => false,
+        Err(e) => e.error_len().is_some(),
+    }
+}
+
+/// The contents of a file's sidecar.
+#[derive(Debug, Default, Deserialize)]
+struct Sidecar {
+    #[serde(default)]
+    tags: Vec<String>,
+}
+
+fn sidecar_path(p: &Path) -> PathBuf {
+    let mut s = p.as_os_str().to_owned();
+    s.push(SIDECAR_SUFFIX);
+    PathBuf::from(s)
+}
+
+/// Returns the file that `p` holds the tags for, if `p` is a sidecar.
+fn sidecar_target(p: &Path) -> Option<PathBuf> {
+    let s = p.to_str()?;
+    if !s.ends_with(SIDECAR_SUFFIX) || p.file_name()? == SIDECAR_SUFFIX {
+        return None;
+    }
+    Some(PathBuf::from(&s[..s.len() - SIDECAR_SUFFIX.len()]))
+}
+
+/// Reads the tags from the path's sidecar, if it has one.
+fn read_tags(p: &Path) -> Vec<String> {
+    let sidecar = sidecar_path(p);
+    let file = match File::open(&sidecar) {
+        Ok(f) => f,
+        Err(_) => return Vec::new(),
+    };
+    match serde_json::from_reader::<_, Sidecar>(BufReader::new(file)) {
+        Ok(s) => s.tags,
+        Err(e) => {
+            warn!("Could not read tags from {:?}: {}", sidecar, e);
+            Vec::new()
+        }
+    }
+}
+
+/// Returns true if the path is one of the excluded paths, or is below one.
+fn is_excluded(exclude_paths: &[String], path: &Path) -> bool {
+    exclude_paths.iter().any(|e| path.starts_with(e))
+}
+
+/// Sets the nice value of the calling thread, which on Linux is inherited by
+/// the threads it starts. Lowering it (a positive `nice`) needs no privileges.
+#[cfg(unix)]
+fn set_nice(nice: i32) -> io::Result<()> {
+    // A `who` of 0 is the calling thread on Linux, and the process elsewhere.
+    if unsafe { libc::setpriority(libc::PRIO_PROCESS as _, 0, nice) } == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(not(unix))]
+fn set_nice(_nice: i32) -> io::Result<()> {
+    Err(io::Error::other(
+        "Setting the nice value is only supported on Unix",
+    ))
+}
+
+/// When the inode was last changed, in nanoseconds since the epoch, which
+/// covers changes to its metadata that leave the mtime alone.
+#[cfg(unix)]
+fn changed(metadata: &fs::Metadata) -> Option<u64> {
+    use std::convert::TryFrom;
+    use std::os::unix::fs::MetadataExt;
+
+    let secs = u64::try_from(metadata.ctime()).ok()?;
+    Some(secs * 1_000_000_000 + metadata.ctime_nsec() as u64)
+}
+
+#[cfg(not(unix))]
+fn changed(_metadata: &fs::Metadata) -> Option<u64> {
+    None
+}
+
+/// Whether the extension of `path` is in `include`, if given, and not in
+/// `exclude`, which wins if it's in both. Extensions are matched without
+/// regard to case or a leading dot, and a path without one only matches if
+/// there is no `include` list.
+fn extension_allowed(include: Option<&[String]>, exclude: Option<&[String]>, path: &Path) -> bool {
+    let ext = path.extension().map(|e| e.to_string_lossy());
+    let listed = |list: &[String]| {
+        ext.as_ref().is_some_and(|ext| {
+            list.iter()
+                .any(|l| l.trim_start_matches('.').eq_ignore_ascii_case(ext))
+        })
+    };
+    if exclude.is_some_and(listed) {
+        return false;
+    }
+    include.is_none_or(listed)
+}
+
+/// Whether the ID is short enough to index, warning about the entry if not.
+fn id_fits(id: &str, p: &Path) -> bool {
+    if id.len() <= MAX_ID_LEN {
+        return true;
+    }
+    warn!(
+        "Not indexing {:?}, its path is {} bytes, more than the {} an index term can hold",
+        p,
+        id.len(),
+        MAX_ID_LEN
+    );
+    false
+}
+
+/// Walks the term dictionary of every indexed field and runs a search over
+/// all documents, so the first real query finds everything already loaded.
+fn prewarm(index: &Index) -> tantivy::Result<()> {
+    let start = Instant::now();
+    let searcher = index.reader()?.searcher();
+    let schema = index.schema();
+
+    for segment_reader in searcher.segment_readers() {
+        for (field, entry) in schema.fields() {
+            if !entry.is_indexed() {
+                continue;
+            }
+            let inverted_index = segment_reader.inverted_index(field);
+            let mut terms = inverted_index.terms().stream();
+            while terms.advance() {}
+        }
+    }
+    let docs = searcher.search(&AllQuery, &Count)?;
+
+    info!(
+        "Prewarmed index ({} docs) in {}ms",
+        docs,
+        start.elapsed().as_millis()
+    );
+    Ok(())
+}
+
+impl Drop for Indexer {
+    fn drop(&mut self) {
+        // Flush anything not yet committed, and let running merges finish
+        // rather than leaving their files behind.
+        if let Some(mut index_writer) = self.index_writer.take() {
+            if let Err(e) = index_writer.commit() {
+                error!("Could not commit IndexWriter on shutdown: {}", e);
+            }
+            if let Err(e) = index_writer.into_inner().wait_merging_threads() {
+                error!("Could not finish merging on shutdown: {}", e);
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum IndexerError {
+    IoError(io::Error),
+    Tantivy(TantivyError),
+    WatcherRxError(RecvTimeoutError),
+    Watcher(WatcherError),
+    /// The index was written with a schema version this lookrd can't read.
+    IncompatibleSchema(u32),
+}
+
+impl error::Error for IndexerError {}
+
+impl fmt::Display for IndexerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IndexerError::IoError(e) => write!(f, "IndexerError: IO: {}", e),
+            IndexerError::Tantivy(e) => write!(f, "IndexerError: Tantivy: {}", e),
+            IndexerError::WatcherRxError(e) => write!(f, "IndexerError: Watcher channel: {}", e),
+            IndexerError::Watcher(e) => write!(f, "IndexerError: {}", e),
+            IndexerError::IncompatibleSchema(found) => write!(
+                f,
+                "IndexerError: The index has schema version {}, this lookrd reads version {}",
+                found, SCHEMA_VERSION
+            ),
+        }
+    }
+}
+
+impl From<WatcherError> for IndexerError {
+    fn from(e: WatcherError) -> Self {
+        IndexerError::Watcher(e)
+    }
+}
+
+impl From<io::Error> for IndexerError {
+    fn from(e: io::Error) -> Self {
+        IndexerError::IoError(e)
+    }
+}
+
+impl From<TantivyError> for IndexerError {
+    fn from(e: TantivyError) -> Self {
+        IndexerError::Tantivy(e)
+    }
+}
+
+#[derive(Debug)]
+pub enum WatcherError {
+    PathIsNotADir,
+    PathDoesNotExist,
+}
+
+impl error::Error for WatcherError {}
+
+impl fmt::Display for WatcherError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "WatcherError: {:?}", self)
+    }
+}
+
+#[derive(Debug)]
+enum WatchEvent {
+    Create(PathBuf),
+    Write(PathBuf),
+    // Its permissions, owner or extended attributes changed.
+    Attrib(PathBuf),
+    Remove(PathBuf),
+    Rename(PathBuf, PathBuf),
+}
+
+impl WatchEvent {
+    fn paths(&self) -> Vec<&Path> {
+        match self {
+            WatchEvent::Create(p)
+            | WatchEvent::Write(p)
+            | WatchEvent::Attrib(p)
+            | WatchEvent::Remove(p) => vec![p],
+            WatchEvent::Rename(src, dst) => vec![src, dst],
+        }
+    }
+}
+
+/// How long to wait before rebuilding the notify watcher, doubling with each
+/// rebuild that doesn't last, up to `WATCH_RETRY`.
+#[derive(Debug, Default)]
+struct RebuildBackoff {
+    failures: u32,
+}
+
+impl RebuildBackoff {
+    /// Called each time the watcher needs rebuilding, with how long the last
+    /// one ran for. `None` once it has failed `MAX_WATCHER_REBUILDS` times in
+    /// a row.
+    fn next(&mut self, ran_for: Duration) -> Option<Duration> {
+        // One that lasted a while doesn't count against the next.
+        if ran_for >= WATCH_RETRY {
+            self.failures = 0;
+        }
+        if self.failures >= MAX_WATCHER_REBUILDS {
+            return None;
+        }
+        let delay = Duration::from_secs(1 << self.failures).min(WATCH_RETRY);
+        self.failures += 1;
+        Some(delay)
+    }
+}
+
+/// Recursively watch on the paths specified, updating the sorpus when they
+/// change.
+#[derive(Debug)]
+struct FsWatcher {
+    tx: SyncSender<IndexerEvent>,
+    paths: Vec<PathBuf>,
+    overflow: OverflowPolicy,
+    stats: Arc<IndexerStats>,
+    // The paths watched by a `PollWatcher` rather than the native one.
+    polled: Vec<PathBuf>,
+    poll_interval: Duration,
+    // How long the native watcher waits for a path to settle.
+    debounce: Duration,
+    // Told each time the paths are being watched.
+    ready: Option<Sender<()>>,
+    // Whether changes to attributes are passed on, rather than only to
+    // contents and names.
+    attribs: bool,
+}
+
+impl FsWatcher {
+    fn new(
+        tx: SyncSender<IndexerEvent>,
+        paths: &[&Path],
+        overflow: OverflowPolicy,
+        stats: Arc<IndexerStats>,
+    ) -> Result<Self, WatcherError> {
+        let mut ps = Vec::with_capacity(paths.len());
+        for p in paths {
+            let p = PathBuf::from(p);
+            if !p.exists() {
+                return Err(WatcherError::PathDoesNotExist);
+            }
+            if !p.is_dir() {
+                return Err(WatcherError::PathIsNotADir);
+            }
+            ps.push(p);
+        }
+
+        Ok(FsWatcher {
+            tx,
+            paths: ps,
+            overflow,
+            stats,
+            polled: Vec::new(),
+            poll_interval: Duration::from_secs(DEFAULT_POLL_INTERVAL_SECS),
+            debounce: Duration::from_millis(DEFAULT_WATCH_DEBOUNCE_MS),
+            ready: None,
+            attribs: false,
+        })
+    }
+
+    /// Send on `ready` once the paths are being watched, and again whenever
+    /// the watcher is rebuilt.
+    fn with_ready(mut self, ready: Sender<()>) -> Self {
+        self.ready = Some(ready);
+        self
+    }
+
+    /// Pass on changes to the permissions, owner and extended attributes of
+    /// paths, where the platform reports them: inotify and FSEvents do, but
+    /// polling only notices changes to permissions.
+    fn with_attribs(mut self, attribs: bool) -> Self {
+        self.attribs = attribs;
+        self
+    }
+
+    /// Have the native watcher wait this long for a path to stop changing
+    /// before reporting it, rather than a second.
+    fn with_debounce(mut self, debounce: Duration) -> Self {
+        self.debounce = debounce;
+        self
+    }
+
+    /// Poll the given paths for changes every `interval`, rather than relying
+    /// on the platform's notifications.
+    fn with_polling(mut self, polled: Vec<PathBuf>, interval: Duration) -> Self {
+        self.polled = polled;
+        self.poll_interval = interval;
+        self
+    }
+
+    /// Queue an event for the indexer, following the overflow policy if the
+    /// queue is full.
+    fn send(&self, event: WatchEvent) -> Result<(), SendError<IndexerEvent>> {
+        let event = match self.tx.try_send(IndexerEvent::Watch(event)) {
+            Ok(()) => return Ok(()),
+            Err(TrySendError::Disconnected(e)) => return Err(SendError(e)),
+            Err(TrySendError::Full(e)) => e,
+        };
+        self.stats.queue_full.fetch_add(1, Ordering::Relaxed);
+
+        match self.overflow {
+            OverflowPolicy::Block => self.tx.send(event),
+            OverflowPolicy::Drop => {
+                if let IndexerEvent::Watch(event) = &event {
+                    for p in event.paths() {
+                        if let Some(root) = self.paths.iter().find(|r| p.starts_with(r)) {
+                            self.mark_dirty(root);
+                        }
+                    }
+                }
+                self.stats.dropped_events.fetch_add(1, Ordering::Relaxed);
+                Ok(())
+            }
+        }
+    }
+
+    /// This function will block until termination or an error occurs (which
+    /// will be returned in the Result). Paths that can't be watched are tried
+    /// again every `WATCH_RETRY`, and the notify watcher is rebuilt, backing
+    /// off, if it can't be created or its channel closes. Polled paths share
+    /// the channel with a `PollWatcher`.
+    fn watch(&self) -> Result<(), Box<dyn error::Error>> {
+        let (polled, native): (Vec<PathBuf>, Vec<PathBuf>) = self
+            .paths
+            .iter()
+            .cloned()
+            .partition(|p| self.polled.contains(p));
+        let mut backoff = RebuildBackoff::default();
+        loop {
+            let started = Instant::now();
+            let (tx, rx) = channel();
+            let watchers = notify::watcher(tx.clone(), self.debounce).and_then(|w| {
+                if polled.is_empty() {
+                    Ok((w, None))
+                } else {
+                    PollWatcher::new(tx, self.poll_interval).map(|p| (w, Some(p)))
+                }
+            });
+            let (mut watcher, mut poller) = match watchers {
+                Ok(w) => w,
+                Err(e) => {
+                    let delay = match backoff.next(Duration::from_secs(0)) {
+                        Some(d) => d,
+                        None => return Err(self.give_up(e.into())),
+                    };
+                    error!(
+                        "Could not create a watcher, trying again in {}s: {}",
+                        delay.as_secs(),
+                        e
+                    );
+                    thread::sleep(delay);
+                    continue;
+                }
+            };
+            let mut failed = self.watch_paths(&mut watcher, native.clone());
+            let mut failed_polled = match &mut poller {
+                Some(p) => self.watch_paths(p, polled.clone()),
+                None => Vec::new(),
+            };
+            if let Some(ready) = &self.ready {
+                // Only the first is waited for.
+                let _ = ready.send(());
+            }
+            let mut last_retry = Instant::now();
+
+            self.forward_events(&rx, || {
+                if (!failed.is_empty() || !failed_polled.is_empty())
+                    && last_retry.elapsed() >= WATCH_RETRY
+                {
+                    failed = self.watch_paths(&mut watcher, mem::take(&mut failed));
+                    if let Some(p) = &mut poller {
+                        failed_polled = self.watch_paths(p, mem::take(&mut failed_polled));
+                    }
+                    last_retry = Instant::now();
+                }
+            })?;
+
+            // Anything that changed while there was no watcher was missed.
+            for path in &self.paths {
+                self.mark_dirty(path);
+            }
+            let e = "The watcher's channel closed";
+            match backoff.next(started.elapsed()) {
+                Some(delay) => {
+                    error!("{}, rebuilding it in {}s", e, delay.as_secs());
+                    thread::sleep(delay);
+                }
+                None => return Err(self.give_up(e.into())),
+            }
+        }
+    }
+
+    /// Pass the notify watcher's events on to the indexer until its channel
+    /// closes, calling `tick` after each event and at least every
+    /// `WATCH_RETRY`.
+    fn forward_events<F: FnMut()>(
+        &self,
+        rx: &Receiver<DebouncedEvent>,
+        mut tick: F,
+    ) -> Result<(), SendError<IndexerEvent>> {
+        loop {
+            match rx.recv_timeout(WATCH_RETRY) {
+                Ok(DebouncedEvent::Create(pb)) => {
+                    self.send(WatchEvent::Create(pb))?;
+                }
+                Ok(DebouncedEvent::Write(pb)) => {
+                    self.send(WatchEvent::Write(pb))?;
+                }
+                Ok(DebouncedEvent::Chmod(pb)) if self.attribs => {
+                    self.send(WatchEvent::Attrib(pb))?;
+                }
+                Ok(DebouncedEvent::Remove(pb)) => {
+                    self.send(WatchEvent::Remove(pb))?;
+                }
+                Ok(DebouncedEvent::Rename(pb_src, pb_dst)) => {
+                    self.send(WatchEvent::Rename(pb_src, pb_dst))?;
                 }
                 Ok(event) => {
                     debug!("Watcher: Other event: {:?}", event);
                 }
+                Err(RecvTimeoutError::Timeout) => (),
+                Err(RecvTimeoutError::Disconnected) => return Ok(()),
+            }
+            tick();
+        }
+    }
+
+    /// Record that changes are no longer being watched for, returning the
+    /// last error.
+    fn give_up(&self, e: Box<dyn error::Error>) -> Box<dyn error::Error> {
+        error!(
+            "The watcher failed {} times in a row, no longer watching for changes",
+            MAX_WATCHER_REBUILDS + 1
+        );
+        self.stats.watcher_stopped.store(true, Ordering::Relaxed);
+        e
+    }
+
+    /// Watch each of the paths, returning those that could not be watched.
+    /// A path that was retried is marked dirty, as its changes have been
+    /// missed until now.
+    fn watch_paths<W: Watcher>(&self, watcher: &mut W, paths: Vec<PathBuf>) -> Vec<PathBuf> {
+        let mut failed = Vec::new();
+        for path in paths {
+            let retry = !self
+                .stats
+                .unwatched_roots
+                .lock()
+                .unwrap()
+                .insert(path.clone());
+            match watcher.watch(&path, RecursiveMode::Recursive) {
+                Ok(()) => {
+                    self.stats.unwatched_roots.lock().unwrap().remove(&path);
+                    if retry {
+                        info!("Watching {:?} after it failed before", path);
+                        self.mark_dirty(&path);
+                    }
+                }
                 Err(e) => {
-                    error!("Error on watcher channel: {}", e);
-                    return Err(Box::new(WatcherError::NotifyError(e)));
+                    if !retry {
+                        error!(
+                            "Error attempting to watch {:?}, trying again every {}s: {}",
+                            path,
+                            WATCH_RETRY.as_secs(),
+                            e
+                        );
+                    }
+                    failed.push(path);
+                }
+            }
+        }
+        failed
+    }
+
+    /// Mark the root to be walked again once the indexer is idle.
+    fn mark_dirty(&self, root: &Path) {
+        let mut dirty_roots = self.stats.dirty_roots.lock().unwrap();
+        if dirty_roots.insert(root.to_string_lossy().to_string()) {
+            warn!("{:?} will be walked again", root);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_is_excluded() {
+        let excludes = vec![
+            "/home/me/Downloads".to_string(),
+            "/home/me/.cache".to_string(),
+        ];
+
+        assert!(is_excluded(&excludes, Path::new("/home/me/Downloads")));
+        assert!(is_excluded(&excludes, Path::new("/home/me/.cache/a/b.txt")));
+        assert!(!is_excluded(&excludes, Path::new("/home/me")));
+        assert!(!is_excluded(&excludes, Path::new("/home/me/Downloads2/f")));
+        assert!(!is_excluded(&[], Path::new("/home/me/Downloads")));
+    }
+
+    #[test]
+    fn test_walk_errors() {
+        let mut walk_errors = WalkErrors::default();
+        for entry in walkdir::WalkDir::new("/lookr/does/not/exist") {
+            if let Err(e) = entry {
+                walk_errors.record(&e);
+            }
+        }
+
+        assert_eq!(walk_errors.not_found, 1);
+        assert_eq!(walk_errors.total(), 1);
+    }
+
+    #[test]
+    fn test_sidecar_target() {
+        assert_eq!(
+            sidecar_target(Path::new("/a/b.txt.lookr.json")),
+            Some(PathBuf::from("/a/b.txt"))
+        );
+        assert_eq!(sidecar_target(Path::new("/a/.lookr.json")), None);
+        assert_eq!(sidecar_target(Path::new("/a/b.txt")), None);
+        assert_eq!(
+            sidecar_path(Path::new("/a/b.txt")),
+            PathBuf::from("/a/b.txt.lookr.json")
+        );
+    }
+
+    #[test]
+    fn test_compact() {
+        let schema = build_schema();
+        let index = Index::create_in_ram(schema.clone());
+        let fields = Fields::new(&schema);
+        let index_writer = index.writer_with_num_threads(1, 50_000_000).unwrap();
+        let mut index_writer = Writer::new(index_writer, schema);
+        for p in &["/a", "/b", "/c"] {
+            index_writer.add_document(fields.document(Path::new(p)));
+            index_writer.commit().unwrap();
+        }
+
+        assert_eq!(compact(&index, &mut index_writer).unwrap(), (3, 1));
+        assert_eq!(index.reader().unwrap().searcher().num_docs(), 3);
+    }
+
+    #[test]
+    fn test_pb() {
+        let pb = PathBuf::from("/foo/bar/baz/some/file.f");
+        for p in pb.iter() {
+            println!("{:?}", p);
+        }
+        println!("{:?}", pb.extension());
+        println!("{:?}", pb.file_name());
+
+        // let pb2 = pb.canonicalize().unwrap();
+
+        for c in pb.components() {
+            match c {
+                std::path::Component::Normal(n) => {
+                    println!("NORMAL: {:?}", n);
+                }
+                c => println!("OTHER: {:?}", c),
+            }
+        }
+    }
+
+    #[test]
+    fn test_tantivy() {
+        use tantivy::collector::TopDocs;
+        use tantivy::query::QueryParser;
+        use tantivy::schema::*;
+        use tantivy::{doc, Index};
+
+        let mut schema_builder = Schema::builder();
+        let title = schema_builder.add_text_field("doc_id", STRING | STORED);
+        let schema = schema_builder.build();
+
+        let index = Index::create_in_ram(schema.clone());
+
+        let title_str = "/foo/bar/baz";
+
+        let mut index_writer = index.writer_with_num_threads(1, 50_000_000).unwrap();
+        index_writer.add_document(doc!(title => title_str));
+        index_writer.commit().unwrap();
+
+        let searcher = index.reader().unwrap().searcher();
+        let query_parser = QueryParser::for_index(&index, vec![title]);
+        let query_promo = query_parser.parse_query(title_str).unwrap();
+        let top_docs_promo = searcher
+            .search(&query_promo, &TopDocs::with_limit(1))
+            .unwrap();
+
+        assert_eq!(top_docs_promo.len(), 1);
+
+        let term = Term::from_field_text(title, title_str);
+        index_writer.delete_term(term);
+        index_writer.commit().unwrap();
+
+        let reader = index.reader().unwrap();
+        reader.reload().unwrap();
+        let searcher2 = reader.searcher();
+        let query_promo2 = query_parser.parse_query(title_str).unwrap();
+        let top_docs_promo2 = searcher2
+            .search(&query_promo2, &TopDocs::with_limit(1))
+            .unwrap();
+
+        assert_eq!(top_docs_promo2.len(), 0);
+    }
+
+    #[test]
+    fn test_watch_overflow() {
+        let root = tempfile::tempdir().unwrap();
+        let stats = Arc::new(IndexerStats::default());
+        let (tx, rx) = sync_channel(1);
+        let watcher =
+            FsWatcher::new(tx, &[root.path()], OverflowPolicy::Drop, stats.clone()).unwrap();
+
+        watcher
+            .send(WatchEvent::Create(root.path().join("a")))
+            .unwrap();
+        watcher
+            .send(WatchEvent::Create(root.path().join("b")))
+            .unwrap();
+
+        assert_eq!(stats.queue_full.load(Ordering::Relaxed), 1);
+        assert_eq!(stats.dropped_events.load(Ordering::Relaxed), 1);
+        assert!(stats
+            .dirty_roots
+            .lock()
+            .unwrap()
+            .contains(&root.path().to_string_lossy().to_string()));
+        assert!(rx.try_recv().is_ok());
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_rewalk() {
+        let root = tempfile::tempdir().unwrap();
+        let root_str = root.path().to_string_lossy().to_string();
+        std::fs::write(root.path().join("kept"), "").unwrap();
+        let schema = build_schema();
+        let index = Index::create_in_ram(schema.clone());
+        let fields = Fields::new(&schema);
+        let config = LookrdConfig {
+            index_paths: vec![root_str.clone()],
+            ..Default::default()
+        };
+        let mut indexer = Indexer::new(index.clone(), schema, config).unwrap();
+        indexer.open_writer().unwrap();
+
+        // The index knows about a file that has since gone, and not about one
+        // that has been created.
+        indexer.walk(&fields, root.path());
+        let writer = indexer.writer_mut();
+        writer.add_document(fields.document(&root.path().join("gone")));
+        writer.commit().unwrap();
+        std::fs::write(root.path().join("new"), "").unwrap();
+
+        indexer.stats.dirty_roots.lock().unwrap().insert(root_str);
+        indexer.rewalk_dirty(&fields);
+
+        let searcher = index.reader().unwrap().searcher();
+        let mut paths: Vec<String> = searcher
+            .search(&AllQuery, &TopDocs::with_limit(10))
+            .unwrap()
+            .into_iter()
+            .map(|(_, addr)| {
+                let doc = searcher.doc(addr).unwrap();
+                doc.get_first(fields.path)
+                    .unwrap()
+                    .text()
+                    .unwrap()
+                    .to_string()
+            })
+            .collect();
+        paths.sort();
+        let expected: Vec<String> = ["", "/kept", "/new"]
+            .iter()
+            .map(|p| format!("{}{}", root.path().to_string_lossy(), p))
+            .collect();
+        assert_eq!(paths, expected);
+        assert!(indexer.stats.dirty_roots.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_rewalk_due() {
+        let root = tempfile::tempdir().unwrap();
+        let root_str = root.path().to_string_lossy().to_string();
+        let schema = build_schema();
+        let index = Index::create_in_ram(schema.clone());
+        let fields = Fields::new(&schema);
+        let config = LookrdConfig {
+            index_paths: vec![root_str.clone()],
+            rewalk_interval_secs: vec![(root_str.clone(), 1)].into_iter().collect(),
+            ..Default::default()
+        };
+        let mut indexer = Indexer::new(index.clone(), schema, config).unwrap();
+        indexer.index_once().unwrap();
+        let walked = indexer.stats.last_indexed.lock().unwrap()[&root_str];
+
+        // Nothing is watching, so only walking the root again finds this.
+        std::fs::write(root.path().join("new"), "").unwrap();
+        let id = indexer.id_of(&root.path().join("new"));
+        let count = || {
+            let term = Term::from_field_text(fields.id, &id);
+            index
+                .reader()
+                .unwrap()
+                .searcher()
+                .search(&TermQuery::new(term, IndexRecordOption::Basic), &Count)
+                .unwrap()
+        };
+        indexer.rewalk_due(&fields, walked + Duration::from_millis(500));
+        assert_eq!(count(), 0);
+        indexer.rewalk_due(&fields, walked + Duration::from_secs(1));
+        assert_eq!(count(), 1);
+        assert!(indexer.stats.last_indexed.lock().unwrap()[&root_str] > walked);
+
+        let walked = indexer.stats.last_indexed.lock().unwrap()[&root_str];
+        indexer
+            .config
+            .rewalk_interval_secs
+            .insert(root_str.clone(), u64::MAX);
+        indexer.rewalk_due(&fields, walked + Duration::from_secs(3600));
+        assert_eq!(
+            indexer.stats.last_indexed.lock().unwrap()[&root_str],
+            walked
+        );
+    }
+
+    #[test]
+    fn test_pause_resume() {
+        let root = tempfile::tempdir().unwrap();
+        let root_str = root.path().to_string_lossy().to_string();
+        let schema = build_schema();
+        let index = Index::create_in_ram(schema.clone());
+        let fields = Fields::new(&schema);
+        let config = LookrdConfig {
+            index_paths: vec![root_str.clone()],
+            watch_queue: Some(1),
+            ..Default::default()
+        };
+        let mut indexer = Indexer::new(index.clone(), schema, config).unwrap();
+        indexer.open_writer().unwrap();
+        let indexed = |path: &str| {
+            let searcher = index.reader().unwrap().searcher();
+            let term = Term::from_field_text(fields.id, path);
+            searcher.doc_freq(&term) > 0
+        };
+
+        // Held while paused, and past the queue's capacity the root is
+        // marked to be walked again instead.
+        indexer.stats.paused.store(true, Ordering::Relaxed);
+        let mut held = Vec::new();
+        let a = root.path().join("a");
+        let b = root.path().join("b");
+        std::fs::write(&a, "").unwrap();
+        std::fs::write(&b, "").unwrap();
+        assert!(!indexer.watch_event(&fields, WatchEvent::Create(a.clone()), &mut held));
+        assert!(!indexer.watch_event(&fields, WatchEvent::Create(b), &mut held));
+        assert_eq!(held.len(), 1);
+        assert!(indexer
+            .stats
+            .dirty_roots
+            .lock()
+            .unwrap()
+            .contains(&root_str));
+        assert!(!indexer.apply_held(&fields, &mut held));
+        indexer.writer_mut().commit().unwrap();
+        assert!(!indexed(&a.to_string_lossy()));
+
+        // Applied once resumed.
+        indexer.stats.paused.store(false, Ordering::Relaxed);
+        assert!(indexer.apply_held(&fields, &mut held));
+        assert!(held.is_empty());
+        indexer.writer_mut().commit().unwrap();
+        assert!(indexed(&a.to_string_lossy()));
+    }
+
+    #[test]
+    fn test_replication() {
+        let root = tempfile::tempdir().unwrap();
+        let a = root.path().join("a.txt");
+        fs::write(&a, "first line\nsecond").unwrap();
+        fs::create_dir(root.path().join("dir")).unwrap();
+        fs::write(root.path().join("dir/b.rs"), "").unwrap();
+        let schema = build_schema();
+        let fields = Fields::new(&schema);
+        let indexer = |replicate_from: Option<&str>| {
+            let config = LookrdConfig {
+                index_paths: vec![root.path().to_string_lossy().to_string()],
+                substring_index: true,
+                replicate_from: replicate_from.map(str::to_string),
+                ..Default::default()
+            };
+            let index = Index::create_in_ram(schema.clone());
+            let indexer = Indexer::new(index.clone(), schema.clone(), config).unwrap();
+            (index, indexer)
+        };
+        let (primary_index, mut primary) = indexer(None);
+        primary.index_once().unwrap();
+        let (standby_index, mut standby) = indexer(Some("http://primary:8901"));
+        standby.open_writer().unwrap();
+        // Each document as stored, and how many match a field that isn't.
+        let contents = |index: &Index| {
+            let searcher = index.reader().unwrap().searcher();
+            let mut docs = Vec::new();
+            for (segment_ord, reader) in searcher.segment_readers().iter().enumerate() {
+                for doc_id in 0..reader.max_doc() {
+                    if !reader.is_deleted(doc_id) {
+                        let doc = searcher.doc(tantivy::DocAddress(segment_ord as u32, doc_id));
+                        docs.push(schema.to_json(&doc.unwrap()));
+                    }
                 }
             }
+            docs.sort();
+            let term = Term::from_field_text(fields.name, "a.txt");
+            let query = TermQuery::new(term, IndexRecordOption::Basic);
+            (docs, searcher.search(&query, &Count).unwrap())
+        };
+
+        let mut changes = primary.writer_mut().add_standby();
+        let searcher = primary_index.reader().unwrap().searcher();
+        let mut batches = Vec::new();
+        replication::copy_index(&searcher, &fields, true, |b| {
+            batches.push(b);
+            true
+        })
+        .unwrap();
+        assert_eq!(batches.len(), 1);
+        assert!(batches[0].reset && !batches[0].more);
+        // Copied twice, as after a reconnect, the standby has one of each.
+        for batch in batches.iter().chain(&batches) {
+            replication::apply(standby.writer_mut(), &standby_index, batch.clone()).unwrap();
+            standby.writer_mut().commit().unwrap();
+        }
+        standby_index.reader().unwrap().reload().unwrap();
+        let (docs, matches) = contents(&standby_index);
+        assert_eq!(docs.len(), 4);
+        assert_eq!(matches, 1);
+        assert_eq!((docs, matches), contents(&primary_index));
+
+        // Then gets what's committed after.
+        let c = root.path().join("c.md");
+        fs::write(&c, "").unwrap();
+        fs::remove_file(&a).unwrap();
+        let mut held = Vec::new();
+        assert!(primary.watch_event(&fields, WatchEvent::Create(c), &mut held));
+        assert!(primary.watch_event(&fields, WatchEvent::Remove(a), &mut held));
+        primary.writer_mut().commit().unwrap();
+        let batch = changes.try_recv().unwrap();
+        assert!(!batch.reset && !batch.more);
+        replication::apply(standby.writer_mut(), &standby_index, batch).unwrap();
+        standby.writer_mut().commit().unwrap();
+        primary_index.reader().unwrap().reload().unwrap();
+        standby_index.reader().unwrap().reload().unwrap();
+        let (docs, matches) = contents(&standby_index);
+        assert_eq!(docs.len(), 4);
+        assert_eq!(matches, 0);
+        assert_eq!((docs, matches), contents(&primary_index));
+
+        // Nothing is sent for a commit without changes.
+        primary.writer_mut().commit().unwrap();
+        assert!(changes.try_recv().is_err());
+
+        // A copy sent in more than one batch isn't committed until it's all
+        // there, whatever else would commit meanwhile.
+        let before = contents(&standby_index);
+        let d = root.path().join("d.md");
+        fs::write(&d, "").unwrap();
+        assert!(primary.watch_event(&fields, WatchEvent::Create(d), &mut held));
+        primary.writer_mut().commit().unwrap();
+        primary_index.reader().unwrap().reload().unwrap();
+        let searcher = primary_index.reader().unwrap().searcher();
+        let mut copy = None;
+        replication::copy_index(&searcher, &fields, true, |b| {
+            copy = Some(b);
+            true
+        })
+        .unwrap();
+        let mut last = copy.unwrap();
+        let first = ReplicationBatch {
+            reset: true,
+            more: true,
+            mutations: last.mutations.drain(..2).collect(),
+        };
+        last.reset = false;
+        replication::apply(standby.writer_mut(), &standby_index, first).unwrap();
+        compact(&standby_index, standby.writer_mut()).unwrap();
+        standby.commit_changes(&fields);
+        standby.writer_mut().commit().unwrap();
+        standby_index.reader().unwrap().reload().unwrap();
+        assert_eq!(contents(&standby_index), before);
+
+        replication::apply(standby.writer_mut(), &standby_index, last).unwrap();
+        standby.writer_mut().commit().unwrap();
+        primary_index.reader().unwrap().reload().unwrap();
+        standby_index.reader().unwrap().reload().unwrap();
+        assert_eq!(contents(&standby_index).0.len(), 5);
+        assert_eq!(contents(&standby_index), contents(&primary_index));
+    }
+
+    #[test]
+    fn test_durable() {
+        let root = tempfile::tempdir().unwrap();
+        let schema = build_schema();
+        let fields = Fields::new(&schema);
+        let indexer = |durable: bool| {
+            let config = LookrdConfig {
+                index_paths: vec![root.path().to_string_lossy().to_string()],
+                durable,
+                ..Default::default()
+            };
+            let index = Index::create_in_ram(schema.clone());
+            let mut indexer = Indexer::new(index.clone(), schema.clone(), config).unwrap();
+            indexer.open_writer().unwrap();
+            (index, indexer)
+        };
+        let indexed = |index: &Index, path: &Path| {
+            let searcher = index.reader().unwrap().searcher();
+            let term = Term::from_field_text(fields.id, &path.to_string_lossy());
+            searcher.doc_freq(&term) > 0
+        };
+        let a = root.path().join("a");
+        fs::write(&a, "").unwrap();
+
+        // Left for the indexer's loop to commit.
+        let (index, mut batched) = indexer(false);
+        assert!(batched.watch_event(&fields, WatchEvent::Create(a.clone()), &mut Vec::new()));
+        assert!(!indexed(&index, &a));
+
+        // Searchable as soon as it's applied, with nothing left to commit.
+        let (index, mut durable) = indexer(true);
+        assert!(!durable.watch_event(&fields, WatchEvent::Create(a.clone()), &mut Vec::new()));
+        assert!(indexed(&index, &a));
+    }
+
+    #[test]
+    fn test_content_hash() {
+        let root = tempfile::tempdir().unwrap();
+        let a = root.path().join("a");
+        fs::write(&a, "abc").unwrap();
+        let schema = build_schema();
+        let index = Index::create_in_ram(schema.clone());
+        let fields = Fields::new(&schema);
+        let config = LookrdConfig {
+            index_paths: vec![root.path().to_string_lossy().to_string()],
+            content_hash_max_size: Some(4),
+            ..Default::default()
+        };
+        let mut indexer = Indexer::new(index.clone(), schema, config).unwrap();
+        indexer.index_once().unwrap();
+        let hash = || {
+            let searcher = index.reader().unwrap().searcher();
+            let term = Term::from_field_text(fields.id, &a.to_string_lossy());
+            let query = TermQuery::new(term, IndexRecordOption::Basic);
+            let (_, addr) = searcher.search(&query, &TopDocs::with_limit(1)).unwrap()[0];
+            let doc = searcher.doc(addr).unwrap();
+            doc.get_first(fields.hash)
+                .map(|v| v.text().unwrap().to_string())
+        };
+        assert_eq!(hash(), content_hash(&a, 4));
+        assert_eq!(hash().unwrap().len(), 32);
+
+        // Rehashed when the watcher sees it change, until it's over the limit.
+        let before = hash();
+        fs::write(&a, "abcd").unwrap();
+        assert!(indexer.watch_event(&fields, WatchEvent::Write(a.clone()), &mut Vec::new()));
+        indexer.writer_mut().commit().unwrap();
+        assert_ne!(hash(), before);
+        assert_eq!(hash(), content_hash(&a, 4));
+        fs::write(&a, "abcde").unwrap();
+        assert!(indexer.watch_event(&fields, WatchEvent::Write(a.clone()), &mut Vec::new()));
+        indexer.writer_mut().commit().unwrap();
+        assert_eq!(hash(), None);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_xattrs() {
+        use std::os::unix::fs::MetadataExt;
+
+        let root = tempfile::tempdir().unwrap();
+        let a = root.path().join("a");
+        fs::write(&a, "a").unwrap();
+        xattr::set(&a, "user.project", b"lookr");
+        let schema = build_schema();
+        let index = Index::create_in_ram(schema.clone());
+        let fields = Fields::new(&schema);
+        let config = LookrdConfig {
+            index_paths: vec![root.path().to_string_lossy().to_string()],
+            index_xattrs: vec!["user.".to_string()],
+            persist_index: true,
+            ..Default::default()
+        };
+        let mut indexer = Indexer::new(index.clone(), schema.clone(), config.clone()).unwrap();
+        indexer.index_once().unwrap();
+        let xattrs = || {
+            let searcher = index.reader().unwrap().searcher();
+            let term = Term::from_field_text(fields.id, &a.to_string_lossy());
+            let query = TermQuery::new(term, IndexRecordOption::Basic);
+            let (_, addr) = searcher.search(&query, &TopDocs::with_limit(1)).unwrap()[0];
+            let doc = searcher.doc(addr).unwrap();
+            doc.get_all(fields.xattr)
+                .into_iter()
+                .map(|v| v.text().unwrap().to_string())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(xattrs(), vec!["user.project", "user.project=lookr"]);
+
+        // Indexed again when the watcher sees them change.
+        xattr::set(&a, "user.project", b"other");
+        assert!(indexer.watch_event(&fields, WatchEvent::Attrib(a.clone()), &mut Vec::new()));
+        indexer.writer_mut().commit().unwrap();
+        assert_eq!(xattrs(), vec!["user.project", "user.project=other"]);
+        assert!(!indexer.watch_event(
+            &fields,
+            WatchEvent::Attrib(root.path().join("gone")),
+            &mut Vec::new()
+        ));
+
+        // And by the walk after a restart, though only the ctime changed.
+        drop(indexer);
+        let ctime = || {
+            let m = fs::metadata(&a).unwrap();
+            (m.ctime(), m.ctime_nsec())
+        };
+        let earlier = ctime();
+        while ctime() == earlier {
+            thread::sleep(Duration::from_millis(10));
+            xattr::set(&a, "user.project", b"again");
         }
+        let mut indexer = Indexer::new(index.clone(), schema, config).unwrap();
+        indexer.index_once().unwrap();
+        assert_eq!(xattrs(), vec!["user.project", "user.project=again"]);
     }
-}
 
-#[cfg(test)]
-mod test {
-    use super::*;
+    #[cfg(unix)]
+    #[test]
+    fn test_owners() {
+        if !nix::unistd::geteuid().is_root() {
+            eprintln!("Skipping, not running as root");
+            return;
+        }
+        let root = tempfile::tempdir().unwrap();
+        let a = root.path().join("a");
+        fs::write(&a, "a").unwrap();
+        let schema = build_schema();
+        let index = Index::create_in_ram(schema.clone());
+        let fields = Fields::new(&schema);
+        let config = LookrdConfig {
+            index_paths: vec![root.path().to_string_lossy().to_string()],
+            index_owners: true,
+            persist_index: true,
+            ..Default::default()
+        };
+        let mut indexer = Indexer::new(index.clone(), schema.clone(), config.clone()).unwrap();
+        indexer.index_once().unwrap();
+        let uid = || {
+            let searcher = index.reader().unwrap().searcher();
+            let term = Term::from_field_text(fields.id, &a.to_string_lossy());
+            let query = TermQuery::new(term, IndexRecordOption::Basic);
+            let (_, addr) = searcher.search(&query, &TopDocs::with_limit(1)).unwrap()[0];
+            searcher
+                .doc(addr)
+                .unwrap()
+                .get_first(fields.uid)
+                .unwrap()
+                .u64_value()
+        };
+        assert_eq!(uid(), 0);
+
+        // Given away while lookrd isn't running, which only changes the
+        // ctime, it's indexed again by the walk after a restart.
+        drop(indexer);
+        thread::sleep(Duration::from_millis(20));
+        let nobody = nix::unistd::User::from_name("nobody").unwrap().unwrap();
+        nix::unistd::chown(&a, Some(nobody.uid), None).unwrap();
+        let mut indexer = Indexer::new(index.clone(), schema, config).unwrap();
+        indexer.index_once().unwrap();
+        assert_eq!(uid(), u64::from(nobody.uid.as_raw()));
+    }
 
     #[test]
-    fn test_pb() {
-        let pb = PathBuf::from("/foo/bar/baz/some/file.f");
-        for p in pb.iter() {
-            println!("{:?}", p);
+    fn test_long_paths() {
+        let schema = build_schema();
+        let index = Index::create_in_ram(schema.clone());
+        let fields = Fields::new(&schema);
+        let mut indexer = Indexer::new(index.clone(), schema, LookrdConfig::default()).unwrap();
+        indexer.open_writer().unwrap();
+        // Deeper than any filesystem allows, so only seen through events.
+        let path = |bytes: usize| format!("/{}", "a/".repeat(bytes / 2))[..bytes].to_string();
+        let longest = path(MAX_ID_LEN);
+        let too_long = path(MAX_ID_LEN + 1);
+
+        indexer.replace(&fields, Path::new(&longest));
+        indexer.replace(&fields, Path::new(&too_long));
+        indexer.writer_mut().commit().unwrap();
+        let searcher = index.reader().unwrap().searcher();
+        assert_eq!(searcher.num_docs(), 1);
+        assert_eq!(
+            searcher.doc_freq(&Term::from_field_text(fields.id, &longest)),
+            1
+        );
+
+        // Still found by its ID, so it can be replaced.
+        indexer.replace(&fields, Path::new(&longest));
+        indexer.writer_mut().commit().unwrap();
+        assert_eq!(index.reader().unwrap().searcher().num_docs(), 1);
+    }
+
+    #[test]
+    fn test_file_kind() {
+        let root = tempfile::tempdir().unwrap();
+        fs::write(root.path().join("file"), "").unwrap();
+        fs::create_dir(root.path().join("dir")).unwrap();
+        std::os::unix::fs::symlink(root.path().join("file"), root.path().join("link")).unwrap();
+        let schema = build_schema();
+        let index = Index::create_in_ram(schema.clone());
+        let fields = Fields::new(&schema);
+        let mut indexer = Indexer::new(index.clone(), schema, LookrdConfig::default()).unwrap();
+        indexer.open_writer().unwrap();
+
+        indexer.walk(&fields, root.path());
+        // As the watcher would, with only the path to go on.
+        let doc = indexer.document(&fields, "/dev/null", Path::new("/dev/null"), None);
+        indexer.writer().add_document(doc);
+        indexer.writer_mut().commit().unwrap();
+
+        let searcher = index.reader().unwrap().searcher();
+        let count = |kind| {
+            let term = Term::from_field_text(fields.kind, kind);
+            searcher
+                .search(&TermQuery::new(term, IndexRecordOption::Basic), &Count)
+                .unwrap()
+        };
+        // The root itself is a dir.
+        assert_eq!(count("dir"), 2);
+        assert_eq!(count("file"), 1);
+        assert_eq!(count("symlink"), 1);
+        assert_eq!(count("other"), 1);
+    }
+
+    #[test]
+    fn test_first_line() {
+        let root = tempfile::tempdir().unwrap();
+        fs::write(root.path().join("run"), "#!/bin/bash\necho hi\n").unwrap();
+        fs::write(
+            root.path().join("big"),
+            format!("#!/bin/bash\n{}", "x".repeat(100)),
+        )
+        .unwrap();
+        fs::write(root.path().join("binary"), b"\xff\xfebash\n").unwrap();
+        fs::write(root.path().join("notes.md"), "# Caf\u{e9} menu\r\n").unwrap();
+        let schema = build_schema();
+        let index = Index::create_in_ram(schema.clone());
+        let fields = Fields::new(&schema);
+        let config = LookrdConfig {
+            first_line_max_size: Some(64),
+            ..Default::default()
+        };
+        let mut indexer = Indexer::new(index.clone(), schema, config).unwrap();
+        indexer.open_writer().unwrap();
+
+        indexer.walk(&fields, root.path());
+        indexer.writer_mut().commit().unwrap();
+
+        let searcher = index.reader().unwrap().searcher();
+        let count = |word| {
+            let term = Term::from_field_text(fields.first_line, word);
+            searcher
+                .search(&TermQuery::new(term, IndexRecordOption::Basic), &Count)
+                .unwrap()
+        };
+        assert_eq!(count("bash"), 1);
+        assert_eq!(count("echo"), 0);
+        assert_eq!(count("café"), 1);
+
+        // Cut off partway through the "é".
+        let path = root.path().join("notes.md");
+        assert_eq!(
+            read_first_line(&path, 64, DEFAULT_BINARY_SNIFF_BYTES),
+            Some("# Café menu".to_string())
+        );
+        let long = root.path().join("long");
+        fs::write(
+            &long,
+            format!("{}é", "a".repeat(MAX_FIRST_LINE as usize - 1)),
+        )
+        .unwrap();
+        assert_eq!(
+            read_first_line(&long, 2048, DEFAULT_BINARY_SNIFF_BYTES),
+            Some("a".repeat(MAX_FIRST_LINE as usize - 1))
+        );
+    }
+
+    #[test]
+    fn test_binary_first_line() {
+        let root = tempfile::tempdir().unwrap();
+        let mut contents = b"#!/bin/bash\n".to_vec();
+        contents.extend_from_slice(&[0; 32]);
+        fs::write(root.path().join("tool"), &contents).unwrap();
+        let path = root.path().join("tool").to_string_lossy().to_string();
+
+        for (sniff_bytes, first_lines) in &[(None, 0), (Some(8), 1)] {
+            let schema = build_schema();
+            let index = Index::create_in_ram(schema.clone());
+            let fields = Fields::new(&schema);
+            let config = LookrdConfig {
+                first_line_max_size: Some(1024),
+                binary_sniff_bytes: *sniff_bytes,
+                ..Default::default()
+            };
+            let mut indexer = Indexer::new(index.clone(), schema, config).unwrap();
+            indexer.open_writer().unwrap();
+            indexer.walk(&fields, root.path());
+            indexer.writer_mut().commit().unwrap();
+
+            let searcher = index.reader().unwrap().searcher();
+            let count = |term| {
+                searcher
+                    .search(&TermQuery::new(term, IndexRecordOption::Basic), &Count)
+                    .unwrap()
+            };
+            // The path is indexed either way.
+            assert_eq!(count(Term::from_field_text(fields.id, &path)), 1);
+            // The NULs past the first 8 bytes aren't looked at.
+            assert_eq!(
+                count(Term::from_field_text(fields.first_line, "bash")),
+                *first_lines
+            );
         }
-        println!("{:?}", pb.extension());
-        println!("{:?}", pb.file_name());
+    }
 
-        // let pb2 = pb.canonicalize().unwrap();
+    #[test]
+    fn test_max_docs() {
+        let root = tempfile::tempdir().unwrap();
+        // Each an hour older than the one before. The root is newest of all.
+        let now = SystemTime::now();
+        for i in 0..10 {
+            let path = root.path().join(format!("{}.txt", i));
+            let file = File::create(&path).unwrap();
+            file.set_modified(now - Duration::from_secs(3600 * i))
+                .unwrap();
+        }
+        let schema = build_schema();
+        let index = Index::create_in_ram(schema.clone());
+        let fields = Fields::new(&schema);
+        let config = LookrdConfig {
+            index_paths: vec![root.path().to_string_lossy().to_string()],
+            max_docs: Some(5),
+            ..Default::default()
+        };
+        let mut indexer = Indexer::new(index.clone(), schema, config).unwrap();
 
-        for c in pb.components() {
-            match c {
-                std::path::Component::Normal(n) => {
-                    println!("NORMAL: {:?}", n);
-                }
-                c => println!("OTHER: {:?}", c),
+        indexer.index_once().unwrap();
+
+        // Passing 5 brings it down to 90% of that, the newest 4 entries.
+        let searcher = index.reader().unwrap().searcher();
+        let mut ids: Vec<String> = searcher
+            .search(&AllQuery, &TopDocs::with_limit(100))
+            .unwrap()
+            .into_iter()
+            .map(|(_, addr)| {
+                let doc = searcher.doc(addr).unwrap();
+                doc.get_first(fields.id)
+                    .unwrap()
+                    .text()
+                    .unwrap()
+                    .to_string()
+            })
+            .collect();
+        ids.sort();
+        let path = |name: &str| root.path().join(name).to_string_lossy().to_string();
+        let mut expected = vec![
+            root.path().to_string_lossy().to_string(),
+            path("0.txt"),
+            path("1.txt"),
+            path("2.txt"),
+        ];
+        expected.sort();
+        assert_eq!(ids, expected);
+    }
+
+    #[test]
+    fn test_skip_initial_walk() {
+        let root = tempfile::tempdir().unwrap();
+        let schema = build_schema();
+        let index = Index::create_in_ram(schema.clone());
+        let config = LookrdConfig {
+            index_paths: vec![root.path().to_string_lossy().to_string()],
+            persist_index: true,
+            skip_initial_walk: true,
+            ..Default::default()
+        };
+        let mut indexer = Indexer::new(index.clone(), schema.clone(), config.clone()).unwrap();
+        assert!(!indexer.skip_walk());
+
+        indexer.index_once().unwrap();
+        assert!(indexer.skip_walk());
+
+        let config = LookrdConfig {
+            skip_initial_walk: false,
+            ..config
+        };
+        let indexer = Indexer::new(index, schema, config).unwrap();
+        assert!(!indexer.skip_walk());
+    }
+
+    #[test]
+    fn test_verify() {
+        let root = tempfile::tempdir().unwrap();
+        let path = |name: &str| root.path().join(name);
+        for name in &["a.txt", "b.txt", "c.txt"] {
+            File::create(path(name)).unwrap();
+        }
+        let schema = build_schema();
+        let index = Index::create_in_ram(schema.clone());
+        let fields = Fields::new(&schema);
+        let config = LookrdConfig {
+            index_paths: vec![root.path().to_string_lossy().to_string()],
+            ..Default::default()
+        };
+        let mut indexer = Indexer::new(index, schema, config).unwrap();
+        indexer.index_once().unwrap();
+        assert_eq!(
+            indexer.verify(&fields).unwrap(),
+            Verification {
+                indexed: 4,
+                found: 4,
+                ..Default::default()
+            }
+        );
+
+        // Changes the indexer didn't see, and one it indexed twice.
+        fs::remove_file(path("a.txt")).unwrap();
+        File::create(path("d.txt")).unwrap();
+        let doc = fields.document(&path("b.txt"));
+        indexer.writer().add_document(doc);
+        indexer.writer_mut().commit().unwrap();
+        let name = |name: &str| path(name).to_string_lossy().to_string();
+        assert_eq!(
+            indexer.verify(&fields).unwrap(),
+            Verification {
+                indexed: 4,
+                found: 4,
+                missing: 1,
+                missing_sample: vec![name("a.txt")],
+                unindexed: 1,
+                unindexed_sample: vec![name("d.txt")],
+                duplicates: 1,
             }
+        );
+    }
+
+    #[test]
+    fn test_reindex_path() {
+        let root = tempfile::tempdir().unwrap();
+        let path = root.path().join("a.txt");
+        let schema = build_schema();
+        let index = Index::create_in_ram(schema.clone());
+        let fields = Fields::new(&schema);
+        let config = LookrdConfig {
+            index_paths: vec![root.path().to_string_lossy().to_string()],
+            ..Default::default()
+        };
+        let mut indexer = Indexer::new(index.clone(), schema, config).unwrap();
+        indexer.index_once().unwrap();
+        let count = || {
+            let term = Term::from_field_text(fields.id, &path.to_string_lossy());
+            let query = TermQuery::new(term, IndexRecordOption::Basic);
+            index
+                .reader()
+                .unwrap()
+                .searcher()
+                .search(&query, &Count)
+                .unwrap()
+        };
+
+        assert_eq!(indexer.reindex_path(&fields, &path).unwrap(), None);
+        File::create(&path).unwrap();
+        assert_eq!(
+            indexer.reindex_path(&fields, &path).unwrap(),
+            Some(Reindexed::Added)
+        );
+        assert_eq!(count(), 1);
+        assert_eq!(
+            indexer.reindex_path(&fields, &path).unwrap(),
+            Some(Reindexed::Updated)
+        );
+        assert_eq!(count(), 1);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(
+            indexer.reindex_path(&fields, &path).unwrap(),
+            Some(Reindexed::Removed)
+        );
+        assert_eq!(count(), 0);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_for_user() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let root = tempfile::tempdir().unwrap();
+        let schema = build_schema();
+        let index = Index::create_in_ram(schema.clone());
+        let fields = Fields::new(&schema);
+        let config = LookrdConfig {
+            index_paths: vec![root.path().to_string_lossy().to_string()],
+            ..Default::default()
+        };
+        // Someone who owns nothing here, so only the other bits apply.
+        let user = UserAccess {
+            name: "bob".to_string(),
+            uid: u32::MAX - 1,
+            gids: vec![u32::MAX - 1],
+        };
+        let chmod =
+            |p: &Path, mode| fs::set_permissions(p, fs::Permissions::from_mode(mode)).unwrap();
+        let indexed = |p: &Path| {
+            let term = Term::from_field_text(fields.id, &p.to_string_lossy());
+            let query = TermQuery::new(term, IndexRecordOption::Basic);
+            index
+                .reader()
+                .unwrap()
+                .searcher()
+                .search(&query, &Count)
+                .unwrap()
+        };
+        // Anyone could list pub, if they could get to it through alice.
+        let alice = root.path().join("alice");
+        let public = alice.join("pub");
+        fs::create_dir_all(&public).unwrap();
+        File::create(public.join("old.txt")).unwrap();
+        chmod(root.path(), 0o755);
+        chmod(&alice, 0o700);
+        let mut indexer = Indexer::new(index.clone(), schema, config)
+            .unwrap()
+            .for_user(user);
+        indexer.index_once().unwrap();
+        assert_eq!(indexed(&alice), 1);
+        assert_eq!(indexed(&public), 0);
+        assert_eq!(indexed(&public.join("old.txt")), 0);
+
+        // Nor is what's made in it later, or below a new directory the user
+        // can't list.
+        let mut held = Vec::new();
+        let new = public.join("new.txt");
+        File::create(&new).unwrap();
+        indexer.watch_event(&fields, WatchEvent::Create(new.clone()), &mut held);
+        let hidden = root.path().join("hidden");
+        let inner = hidden.join("inner");
+        fs::create_dir_all(&inner).unwrap();
+        File::create(inner.join("x.txt")).unwrap();
+        chmod(&hidden, 0o700);
+        indexer.watch_event(&fields, WatchEvent::Create(hidden.clone()), &mut held);
+        indexer.writer_mut().commit().unwrap();
+        assert_eq!(indexed(&new), 0);
+        assert_eq!(indexed(&hidden), 1);
+        assert_eq!(indexed(&inner), 0);
+        assert_eq!(indexed(&inner.join("x.txt")), 0);
+
+        // Nor can the user find out it's there by asking for it.
+        assert_eq!(indexer.reindex_path(&fields, &new).unwrap(), None);
+        assert_eq!(indexed(&new), 0);
+
+        // Once alice can be searched, pub can be seen through it.
+        chmod(&alice, 0o711);
+        assert_eq!(
+            indexer.reindex_path(&fields, &new).unwrap(),
+            Some(Reindexed::Added)
+        );
+        assert_eq!(indexed(&new), 1);
+
+        // And once it can't again, it's as if it were gone.
+        chmod(&alice, 0o700);
+        assert_eq!(
+            indexer.reindex_path(&fields, &new).unwrap(),
+            Some(Reindexed::Removed)
+        );
+        assert_eq!(indexed(&new), 0);
+    }
+
+    #[test]
+    fn test_archives() {
+        let root = tempfile::tempdir().unwrap();
+        let tar_path = root.path().join("code.tar");
+        std::fs::write(
+            &tar_path,
+            crate::archive::test::tar(&[
+                ("src/", b'5', b""),
+                ("src/lib.rs", b'0', b"pub fn lib() {}"),
+                ("README.md", b'0', b"# Code"),
+            ]),
+        )
+        .unwrap();
+        let schema = build_schema();
+        let index = Index::create_in_ram(schema.clone());
+        let fields = Fields::new(&schema);
+        let config = LookrdConfig {
+            index_paths: vec![root.path().to_string_lossy().to_string()],
+            archives: true,
+            ..Default::default()
+        };
+        let mut indexer = Indexer::new(index.clone(), schema, config).unwrap();
+        let ids = || {
+            let searcher = index.reader().unwrap().searcher();
+            let mut ids: Vec<String> = searcher
+                .search(&AllQuery, &TopDocs::with_limit(100))
+                .unwrap()
+                .into_iter()
+                .map(|(_, addr)| {
+                    let doc = searcher.doc(addr).unwrap();
+                    doc.get_first(fields.id)
+                        .unwrap()
+                        .text()
+                        .unwrap()
+                        .to_string()
+                })
+                .collect();
+            ids.sort();
+            ids
+        };
+        let entry = |name: &str| format!("{}!/{}", tar_path.to_string_lossy(), name);
+        let root_and_tar = vec![
+            root.path().to_string_lossy().to_string(),
+            tar_path.to_string_lossy().to_string(),
+        ];
+
+        indexer.index_once().unwrap();
+        let mut expected = root_and_tar.clone();
+        expected.extend([entry("README.md"), entry("src"), entry("src/lib.rs")]);
+        assert_eq!(ids(), expected);
+
+        // Changing the archive replaces its entries.
+        std::fs::write(
+            &tar_path,
+            crate::archive::test::tar(&[("main.rs", b'0', b"fn main() {}")]),
+        )
+        .unwrap();
+        assert!(indexer.apply(&fields, WatchEvent::Write(tar_path.clone())));
+        indexer.writer_mut().commit().unwrap();
+        let mut expected = root_and_tar.clone();
+        expected.push(entry("main.rs"));
+        assert_eq!(ids(), expected);
+
+        // And removing it removes them.
+        std::fs::remove_file(&tar_path).unwrap();
+        assert!(indexer.apply(&fields, WatchEvent::Remove(tar_path.clone())));
+        indexer.writer_mut().commit().unwrap();
+        assert_eq!(ids(), vec![root.path().to_string_lossy().to_string()]);
+    }
+
+    #[test]
+    fn test_watch_retry() {
+        let root = tempfile::tempdir().unwrap();
+        let path = root.path().join("mnt");
+        fs::create_dir(&path).unwrap();
+        let stats = Arc::new(IndexerStats::default());
+        let (tx, _rx) = sync_channel(1);
+        let watcher = FsWatcher::new(tx, &[&path], OverflowPolicy::Block, stats.clone()).unwrap();
+        let (notify_tx, _notify_rx) = channel();
+        let mut notify_watcher = notify::watcher(notify_tx, Duration::from_secs(1)).unwrap();
+
+        // Not there when the daemon starts.
+        fs::remove_dir(&path).unwrap();
+        let failed = watcher.watch_paths(&mut notify_watcher, vec![path.clone()]);
+        assert_eq!(failed, vec![path.clone()]);
+        assert!(stats.unwatched_roots.lock().unwrap().contains(&path));
+        assert!(stats.dirty_roots.lock().unwrap().is_empty());
+
+        fs::create_dir(&path).unwrap();
+        assert!(watcher.watch_paths(&mut notify_watcher, failed).is_empty());
+        assert!(stats.unwatched_roots.lock().unwrap().is_empty());
+        assert!(stats
+            .dirty_roots
+            .lock()
+            .unwrap()
+            .contains(&path.to_string_lossy().to_string()));
+    }
+
+    #[test]
+    fn test_rebuild_backoff() {
+        let mut backoff = RebuildBackoff::default();
+        let quick = Duration::from_millis(10);
+        let delays: Vec<_> = (0..MAX_WATCHER_REBUILDS)
+            .map(|_| backoff.next(quick).unwrap().as_secs())
+            .collect();
+        assert_eq!(delays, vec![1, 2, 4, 8, 16]);
+        assert_eq!(backoff.next(quick), None);
+
+        // A watcher that ran for a while starts the count again.
+        assert_eq!(backoff.next(WATCH_RETRY), Some(Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn test_forward_events_channel_closed() {
+        let root = tempfile::tempdir().unwrap();
+        let stats = Arc::new(IndexerStats::default());
+        let (tx, rx) = sync_channel(10);
+        let watcher = FsWatcher::new(tx, &[root.path()], OverflowPolicy::Block, stats).unwrap();
+
+        let (notify_tx, notify_rx) = channel();
+        notify_tx
+            .send(DebouncedEvent::Create(root.path().join("a")))
+            .unwrap();
+        // The notify watcher going away closes its channel.
+        drop(notify_tx);
+
+        let mut ticks = 0;
+        watcher.forward_events(&notify_rx, || ticks += 1).unwrap();
+        assert_eq!(ticks, 1);
+        match rx.try_recv() {
+            Ok(IndexerEvent::Watch(WatchEvent::Create(p))) => assert_eq!(p, root.path().join("a")),
+            r => panic!("Expected the create to be forwarded, got {:?}", r),
         }
     }
 
     #[test]
-    fn test_tantivy() {
-        use tantivy::collector::TopDocs;
-        use tantivy::query::QueryParser;
-        use tantivy::schema::*;
-        use tantivy::{doc, Index};
+    fn test_watch_debounce() {
+        let root = tempfile::tempdir().unwrap();
+        let watch = |debounce| {
+            let stats = Arc::new(IndexerStats::default());
+            let (tx, rx) = sync_channel(10);
+            let watcher = FsWatcher::new(tx, &[root.path()], OverflowPolicy::Block, stats)
+                .unwrap()
+                .with_debounce(debounce);
+            thread::spawn(move || {
+                watcher.watch().unwrap();
+            });
+            rx
+        };
+        let quick = watch(Duration::from_millis(50));
+        let slow = watch(Duration::from_secs(3600));
+        thread::sleep(Duration::from_millis(200));
 
-        let mut schema_builder = Schema::builder();
-        let title = schema_builder.add_text_field("doc_id", STRING | STORED);
-        let schema = schema_builder.build();
+        // Each watcher holds on to the change for as long as it's set to.
+        let created = root.path().join("a");
+        File::create(&created).unwrap();
+        match quick.recv_timeout(Duration::from_secs(5)) {
+            Ok(IndexerEvent::Watch(WatchEvent::Create(p))) => assert_eq!(p, created),
+            r => panic!("Expected a Create event, got {:?}", r.map(|_| ())),
+        }
+        assert!(matches!(
+            slow.try_recv(),
+            Err(std::sync::mpsc::TryRecvError::Empty)
+        ));
+    }
 
+    #[test]
+    fn test_walk_with_churn() {
+        let root = tempfile::tempdir().unwrap();
+        let old = root.path().join("old");
+        fs::create_dir(&old).unwrap();
+        for i in 0..500 {
+            File::create(old.join(i.to_string())).unwrap();
+        }
+        let schema = build_schema();
         let index = Index::create_in_ram(schema.clone());
+        let fields = Fields::new(&schema);
+        let config = LookrdConfig {
+            index_paths: vec![root.path().to_string_lossy().to_string()],
+            // Five pauses, long enough for the churn to overlap the walk.
+            index_throttle_ms: Some(100),
+            ..Default::default()
+        };
+        let mut indexer = Indexer::new(index, schema, config).unwrap();
+        let (ready_tx, ready_rx) = channel();
+        let watcher = FsWatcher::new(
+            indexer.tx.clone(),
+            &[root.path()],
+            OverflowPolicy::Block,
+            indexer.stats.clone(),
+        )
+        .unwrap()
+        .with_debounce(Duration::from_millis(50))
+        .with_ready(ready_tx);
+        thread::spawn(move || {
+            watcher.watch().unwrap();
+        });
+        ready_rx.recv_timeout(Duration::from_secs(5)).unwrap();
 
-        let title_str = "/foo/bar/baz";
+        let churned = root.path().to_path_buf();
+        let churn = thread::spawn(move || {
+            for round in 0..5 {
+                // Files made in a new directory before it's watched.
+                let new = churned.join(format!("new{}", round));
+                fs::create_dir(&new).unwrap();
+                for i in 0..20 {
+                    File::create(new.join(i.to_string())).unwrap();
+                }
+                for i in round * 50..round * 50 + 50 {
+                    fs::remove_file(churned.join("old").join(i.to_string())).unwrap();
+                }
+                // Gone before it's indexed.
+                let brief = churned.join(format!("brief{}", round));
+                File::create(&brief).unwrap();
+                fs::remove_file(&brief).unwrap();
+                let moved = churned.join("old").join((400 + round).to_string());
+                fs::rename(&moved, churned.join(format!("moved{}", round))).unwrap();
+                thread::sleep(Duration::from_millis(80));
+            }
+        });
+        indexer.index_once().unwrap();
+        churn.join().unwrap();
+
+        // Everything queued during the walk, applied after it, as the
+        // indexer's loop would. notify's debouncer can hold on to the end of
+        // a burst until something else changes, so something does, until
+        // the change comes through.
+        let mut sentinels = 0;
+        let mut sentinel_seen = false;
+        while !sentinel_seen {
+            assert!(sentinels < 20, "No events for the sentinels");
+            let sentinel = root.path().join(format!("sentinel{}", sentinels));
+            File::create(&sentinel).unwrap();
+            sentinels += 1;
+            while let Ok(event) = indexer.rx.recv_timeout(Duration::from_millis(500)) {
+                if let IndexerEvent::Watch(event) = event {
+                    sentinel_seen |= event.paths().contains(&sentinel.as_path());
+                    indexer.watch_event(&fields, event, &mut Vec::new());
+                }
+            }
+        }
+        indexer.writer_mut().commit().unwrap();
+        let v = indexer.verify(&fields).unwrap();
+        assert_eq!((v.missing, v.unindexed, v.duplicates), (0, 0, 0), "{:?}", v);
+        assert_eq!(v.indexed, v.found);
+    }
+
+    #[test]
+    fn test_poll_watcher() {
+        let root = tempfile::tempdir().unwrap();
+        let stats = Arc::new(IndexerStats::default());
+        let (tx, rx) = sync_channel(10);
+        let watcher = FsWatcher::new(tx, &[root.path()], OverflowPolicy::Block, stats)
+            .unwrap()
+            .with_polling(vec![root.path().to_path_buf()], Duration::from_millis(200));
+        thread::spawn(move || {
+            watcher.watch().unwrap();
+        });
+
+        // Give the first scan time to see the empty directory.
+        thread::sleep(Duration::from_millis(500));
+        let created = root.path().join("remote.txt");
+        File::create(&created).unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        loop {
+            match rx.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Ok(IndexerEvent::Watch(event)) if event.paths().contains(&created.as_path()) => {
+                    break
+                }
+                Ok(_) => (),
+                Err(e) => panic!("No event for {:?}: {}", created, e),
+            }
+        }
+    }
 
+    #[test]
+    fn test_snapshot() {
+        let schema = build_schema();
+        let index = Index::create_in_ram(schema.clone());
+        let fields = Fields::new(&schema);
         let mut index_writer = index.writer_with_num_threads(1, 50_000_000).unwrap();
-        index_writer.add_document(doc!(title => title_str));
+        for p in &["/a", "/b", "/c"] {
+            index_writer.add_document(fields.document(Path::new(p)));
+            index_writer.commit().unwrap();
+        }
+        index_writer.delete_term(Term::from_field_text(fields.id, "/b"));
         index_writer.commit().unwrap();
 
-        let searcher = index.reader().unwrap().searcher();
-        let query_parser = QueryParser::for_index(&index, vec![title]);
-        let query_promo = query_parser.parse_query(title_str).unwrap();
-        let top_docs_promo = searcher
-            .search(&query_promo, &TopDocs::with_limit(1))
-            .unwrap();
+        let dest = tempfile::tempdir().unwrap();
+        let dest = dest.path().join("snapshot");
+        snapshot(&index, &dest).unwrap();
 
-        assert_eq!(top_docs_promo.len(), 1);
+        let copy = Index::open_in_dir(&dest).unwrap();
+        assert_eq!(copy.reader().unwrap().searcher().num_docs(), 2);
+        assert!(snapshot(&index, &dest).is_err());
+    }
 
-        let term = Term::from_field_text(title, title_str);
-        index_writer.delete_term(term);
-        index_writer.commit().unwrap();
+    #[test]
+    fn test_open_index() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path().join("index");
+        assert_eq!(schema_version(&dir).unwrap(), None);
+
+        let index = open_index(&dir, build_schema()).unwrap();
+        let fields = Fields::new(&build_schema());
+        let mut writer = index.writer_with_num_threads(1, 50_000_000).unwrap();
+        writer.add_document(fields.document(Path::new("/a")));
+        writer.commit().unwrap();
+        drop(writer);
+        assert_eq!(schema_version(&dir).unwrap(), Some(SCHEMA_VERSION));
+        let index = open_index(&dir, build_schema()).unwrap();
+        assert_eq!(index.reader().unwrap().searcher().num_docs(), 1);
+
+        let snapshot_dir = dir.with_file_name("snapshot");
+        snapshot(&index, &snapshot_dir).unwrap();
+        assert_eq!(schema_version(&snapshot_dir).unwrap(), Some(SCHEMA_VERSION));
+
+        // Written before versions were kept.
+        fs::remove_file(dir.join(SCHEMA_VERSION_FILE)).unwrap();
+        match open_index(&dir, build_schema()) {
+            Err(IndexerError::IncompatibleSchema(0)) => (),
+            r => panic!("Expected IncompatibleSchema, got {:?}", r.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_open_or_rebuild() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path().join("index");
+        let fields = Fields::new(&build_schema());
+        let add = |index: &Index| {
+            let mut writer = index.writer_with_num_threads(1, 50_000_000).unwrap();
+            writer.add_document(fields.document(Path::new("/a")));
+            writer.commit().unwrap();
+        };
+        let num_docs = |index: &Index| index.reader().unwrap().searcher().num_docs();
+
+        let open_or_rebuild = |dir: &Path, schema| open_or_rebuild(dir, schema, "settings");
+
+        add(&open_or_rebuild(&dir, build_schema()).unwrap());
+        assert_eq!(num_docs(&open_or_rebuild(&dir, build_schema()).unwrap()), 1);
+
+        // Built with other settings, so started again.
+        let index = super::open_or_rebuild(&dir, build_schema(), "other settings").unwrap();
+        assert_eq!(num_docs(&index), 0);
+        add(&index);
+        assert_eq!(num_docs(&open_or_rebuild(&dir, build_schema()).unwrap()), 0);
+        add(&open_or_rebuild(&dir, build_schema()).unwrap());
+        assert_eq!(num_docs(&open_or_rebuild(&dir, build_schema()).unwrap()), 1);
+        // Or by a lookrd that didn't keep them.
+        fs::remove_file(dir.join(FINGERPRINT_FILE)).unwrap();
+        assert_eq!(num_docs(&open_or_rebuild(&dir, build_schema()).unwrap()), 0);
+
+        // Corrupted, so started again with the broken one kept aside.
+        fs::write(dir.join(META_FILE), "{ not json").unwrap();
+        let index = open_or_rebuild(&dir, build_schema()).unwrap();
+        assert_eq!(num_docs(&index), 0);
+        assert_eq!(
+            fs::read_to_string(dir.with_extension("broken").join(META_FILE)).unwrap(),
+            "{ not json"
+        );
+
+        // Older, so started again.
+        add(&index);
+        fs::write(dir.join(SCHEMA_VERSION_FILE), "1").unwrap();
+        assert_eq!(num_docs(&open_or_rebuild(&dir, build_schema()).unwrap()), 0);
+
+        // Newer, so left alone.
+        let newer = (SCHEMA_VERSION + 1).to_string();
+        fs::write(dir.join(SCHEMA_VERSION_FILE), &newer).unwrap();
+        match open_or_rebuild(&dir, build_schema()) {
+            Err(IndexerError::IncompatibleSchema(v)) => assert_eq!(v, SCHEMA_VERSION + 1),
+            r => panic!("Expected IncompatibleSchema, got {:?}", r.map(|_| ())),
+        }
+        assert_eq!(
+            fs::read_to_string(dir.join(SCHEMA_VERSION_FILE)).unwrap(),
+            newer
+        );
+    }
+
+    #[test]
+    fn test_drop_commits() {
+        let schema = build_schema();
+        let index = Index::create_in_ram(schema.clone());
+        let fields = Fields::new(&schema);
+        let mut indexer = Indexer::new(index.clone(), schema, LookrdConfig::default()).unwrap();
+        indexer.open_writer().unwrap();
+        indexer
+            .writer()
+            .add_document(fields.document(Path::new("/pending")));
 
         let reader = index.reader().unwrap();
+        assert_eq!(reader.searcher().num_docs(), 0);
+
+        drop(indexer);
         reader.reload().unwrap();
-        let searcher2 = reader.searcher();
-        let query_promo2 = query_parser.parse_query(title_str).unwrap();
-        let top_docs_promo2 = searcher2
-            .search(&query_promo2, &TopDocs::with_limit(1))
+        assert_eq!(reader.searcher().num_docs(), 1);
+        // The writer's lock has been released.
+        assert!(index.writer_with_num_threads(1, 50_000_000).is_ok());
+    }
+
+    #[test]
+    fn test_extension_filter() {
+        let root = tempfile::tempdir().unwrap();
+        fs::create_dir(root.path().join("src.d")).unwrap();
+        for f in [
+            "src.d/main.rs",
+            "lib.RS",
+            "blob.bin",
+            "notes.md",
+            "Makefile",
+            "gen.rs.bin",
+        ] {
+            fs::write(root.path().join(f), "").unwrap();
+        }
+        let schema = build_schema();
+        let index = Index::create_in_ram(schema.clone());
+        let fields = Fields::new(&schema);
+        let config = LookrdConfig {
+            index_extensions: Some(vec!["rs".to_string(), ".md".to_string()]),
+            exclude_extensions: Some(vec!["bin".to_string(), "md".to_string()]),
+            ..Default::default()
+        };
+        let mut indexer = Indexer::new(index.clone(), schema, config).unwrap();
+        indexer.open_writer().unwrap();
+        indexer.walk(&fields, root.path());
+        indexer.writer_mut().commit().unwrap();
+
+        let searcher = index.reader().unwrap().searcher();
+        let mut names: Vec<String> = searcher
+            .search(&AllQuery, &TopDocs::with_limit(10))
+            .unwrap()
+            .into_iter()
+            .map(|(_, addr)| {
+                let doc = searcher.doc(addr).unwrap();
+                let path = doc.get_first(fields.path).unwrap().text().unwrap();
+                path[root.path().to_string_lossy().len()..].to_string()
+            })
+            .collect();
+        names.sort();
+        // Directories are kept whatever their name, and exclude wins.
+        assert_eq!(names, vec!["", "/lib.RS", "/src.d", "/src.d/main.rs"]);
+    }
+
+    #[test]
+    fn test_throttle() {
+        let root = tempfile::tempdir().unwrap();
+        for i in 0..250 {
+            fs::write(root.path().join(i.to_string()), "").unwrap();
+        }
+        let schema = build_schema();
+        let index = Index::create_in_ram(schema.clone());
+        let fields = Fields::new(&schema);
+        let config = LookrdConfig {
+            index_throttle_ms: Some(50),
+            ..Default::default()
+        };
+        let mut indexer = Indexer::new(index, schema, config).unwrap();
+        indexer.open_writer().unwrap();
+
+        // The root and 250 files is two pauses.
+        let start = Instant::now();
+        indexer.walk(&fields, root.path());
+        assert!(start.elapsed() >= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn test_delete_subtree() {
+        let root = tempfile::tempdir().unwrap();
+        fs::create_dir_all(root.path().join("sub/deeper")).unwrap();
+        fs::write(root.path().join("sub/a"), "").unwrap();
+        fs::write(root.path().join("sub/deeper/b"), "").unwrap();
+        fs::write(root.path().join("sub-sibling"), "").unwrap();
+        let schema = build_schema();
+        let index = Index::create_in_ram(schema.clone());
+        let fields = Fields::new(&schema);
+        let mut indexer = Indexer::new(index.clone(), schema, LookrdConfig::default()).unwrap();
+        indexer.open_writer().unwrap();
+        indexer.walk(&fields, root.path());
+        indexer.writer_mut().commit().unwrap();
+
+        let sub = root.path().join("sub");
+        fs::remove_dir_all(&sub).unwrap();
+        indexer
+            .delete_subtree(&fields, &sub.to_string_lossy())
             .unwrap();
+        indexer.writer_mut().commit().unwrap();
 
-        assert_eq!(top_docs_promo2.len(), 0);
+        let searcher = index.reader().unwrap().searcher();
+        let mut names: Vec<String> = searcher
+            .search(&AllQuery, &TopDocs::with_limit(10))
+            .unwrap()
+            .into_iter()
+            .map(|(_, addr)| {
+                let doc = searcher.doc(addr).unwrap();
+                let path = doc.get_first(fields.path).unwrap().text().unwrap();
+                path[root.path().to_string_lossy().len()..].to_string()
+            })
+            .collect();
+        names.sort();
+        assert_eq!(names, vec!["", "/sub-sibling"]);
+    }
+
+    #[test]
+    fn test_walk_since() {
+        let root = tempfile::tempdir().unwrap();
+        for f in ["unchanged", "changed", "gone"] {
+            fs::write(root.path().join(f), "").unwrap();
+        }
+        let root_str = root.path().to_string_lossy().to_string();
+        let schema = build_schema();
+        let index = Index::create_in_ram(schema.clone());
+        let fields = Fields::new(&schema);
+        let config = LookrdConfig {
+            persist_index: true,
+            ..Default::default()
+        };
+        let mut indexer = Indexer::new(index.clone(), schema, config).unwrap();
+        indexer.open_writer().unwrap();
+        indexer.walk(&fields, root.path());
+        indexer.writer_mut().commit().unwrap();
+
+        // As if the daemon had been restarted after these changes.
+        let changed = root.path().join("changed");
+        let earlier = fs::metadata(&changed).unwrap().modified().unwrap();
+        while fs::metadata(&changed).unwrap().modified().unwrap() == earlier {
+            thread::sleep(Duration::from_millis(10));
+            fs::write(&changed, "more").unwrap();
+        }
+        fs::remove_file(root.path().join("gone")).unwrap();
+        fs::write(root.path().join("new"), "").unwrap();
+        let indexed = indexer.indexed_mtimes(&fields, &root_str).unwrap();
+        assert_eq!(indexed.len(), 4);
+        indexer.walk_since(&fields, root.path(), indexed);
+        indexer.writer_mut().commit().unwrap();
+
+        let searcher = index.reader().unwrap().searcher();
+        let mut names: Vec<String> = searcher
+            .search(&AllQuery, &TopDocs::with_limit(10))
+            .unwrap()
+            .into_iter()
+            .map(|(_, addr)| {
+                let doc = searcher.doc(addr).unwrap();
+                let path = doc.get_first(fields.path).unwrap().text().unwrap();
+                path[root_str.len()..].to_string()
+            })
+            .collect();
+        names.sort();
+        assert_eq!(names, vec!["", "/changed", "/new", "/unchanged"]);
+        // Only the changed and new files, and the root whose entries changed,
+        // were written again, next to the four from the first walk.
+        let written: u32 = searcher.segment_readers().iter().map(|s| s.max_doc()).sum();
+        assert_eq!(written, 7);
+    }
+
+    #[test]
+    fn test_canonical_ids() {
+        let root = tempfile::tempdir().unwrap();
+        // The tempdir's own path may go through a symlink.
+        let real_root = fs::canonicalize(root.path()).unwrap();
+        fs::write(root.path().join("real.txt"), "").unwrap();
+        std::os::unix::fs::symlink(root.path().join("real.txt"), root.path().join("link.txt"))
+            .unwrap();
+        std::os::unix::fs::symlink(root.path().join("nowhere"), root.path().join("broken"))
+            .unwrap();
+        let schema = build_schema();
+        let index = Index::create_in_ram(schema.clone());
+        let fields = Fields::new(&schema);
+        let config = LookrdConfig {
+            canonical_ids: true,
+            ..Default::default()
+        };
+        let mut indexer = Indexer::new(index.clone(), schema, config).unwrap();
+        indexer.open_writer().unwrap();
+        indexer.walk(&fields, root.path());
+        indexer.writer_mut().commit().unwrap();
+
+        let searcher = index.reader().unwrap().searcher();
+        let mut ids: Vec<(String, String)> = searcher
+            .search(&AllQuery, &TopDocs::with_limit(10))
+            .unwrap()
+            .into_iter()
+            .map(|(_, addr)| {
+                let doc = searcher.doc(addr).unwrap();
+                let text = |f| doc.get_first(f).unwrap().text().unwrap().to_string();
+                (text(fields.id), text(fields.path))
+            })
+            .collect();
+        ids.sort();
+
+        // The file is indexed once, under its real path, as whichever of its
+        // paths was walked first.
+        assert_eq!(ids.len(), 3);
+        assert_eq!(ids[0].0, real_root.to_string_lossy());
+        // A broken link falls back to its own path.
+        assert_eq!(ids[1].0, root.path().join("broken").to_string_lossy());
+        assert_eq!(ids[2].0, real_root.join("real.txt").to_string_lossy());
+        let seen_as = Path::new(&ids[2].1).file_name().unwrap();
+        assert!(seen_as == "real.txt" || seen_as == "link.txt");
     }
 }
@@ -1 +1,29 @@
+#[macro_use]
+extern crate log;
+
+mod archive;
+pub mod audit;
+pub mod config;
+#[cfg(unix)]
+pub mod daemon;
+mod fstype;
+mod highlight;
+mod history;
+pub mod indexer;
+pub mod limits;
+pub mod logging;
+#[cfg(unix)]
+pub mod privileges;
 pub mod proto;
+mod query_cache;
+mod replication;
+pub mod rpc;
+mod scoring;
+#[cfg(unix)]
+pub mod secret;
+pub mod selftest;
+#[cfg(any(test, feature = "bench"))]
+pub mod testing;
+mod tokenizer;
+pub mod users;
+mod xattr;
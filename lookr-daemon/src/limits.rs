@@ -0,0 +1,131 @@
+//! Limits on the queries the daemon answers, so one busy or misbehaving
+//! client can't take all of its threads, or keep others waiting.
+
+use crate::config::{LookrdConfig, DEFAULT_CLIENT_QUERIES_PER_SEC, DEFAULT_MAX_CONCURRENT_QUERIES};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Past this many clients, those that have been quiet long enough to have
+/// refilled are forgotten.
+const MAX_CLIENTS: usize = 10_000;
+
+/// Why a query was turned away.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Rejection {
+    /// As many queries as allowed are already running.
+    Busy,
+    /// The client has sent more queries than allowed in the last second.
+    RateLimited,
+}
+
+/// The queries allowed to run at once, shared by every user of a multi-user
+/// daemon, and how many a second each client can send.
+#[derive(Debug)]
+pub struct QueryLimits {
+    running: Arc<Semaphore>,
+    per_client: f64,
+    clients: Mutex<HashMap<Option<IpAddr>, Bucket>>,
+}
+
+/// A client's allowance, refilled at `per_client` a second up to a second's
+/// worth.
+#[derive(Debug)]
+struct Bucket {
+    queries: f64,
+    updated: Instant,
+}
+
+impl QueryLimits {
+    pub fn new(max_concurrent: usize, per_client: u32) -> Self {
+        QueryLimits {
+            running: Arc::new(Semaphore::new(max_concurrent)),
+            per_client: f64::from(per_client),
+            clients: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn from_config(config: &LookrdConfig) -> Self {
+        QueryLimits::new(
+            config
+                .max_concurrent_queries
+                .unwrap_or(DEFAULT_MAX_CONCURRENT_QUERIES),
+            config
+                .client_queries_per_sec
+                .unwrap_or(DEFAULT_CLIENT_QUERIES_PER_SEC),
+        )
+    }
+
+    /// Let a query from `client` run if it's within the limits, holding one
+    /// of the places to run until the permit is dropped. Clients without an
+    /// address share an allowance.
+    pub(crate) fn admit(&self, client: Option<IpAddr>) -> Result<OwnedSemaphorePermit, Rejection> {
+        if !self.take(client, Instant::now()) {
+            return Err(Rejection::RateLimited);
+        }
+        self.running
+            .clone()
+            .try_acquire_owned()
+            .map_err(|_| Rejection::Busy)
+    }
+
+    /// Take one query from the client's allowance as of `now`, if there's
+    /// one left.
+    fn take(&self, client: Option<IpAddr>, now: Instant) -> bool {
+        let per_client = self.per_client;
+        let mut clients = self.clients.lock().unwrap();
+        let refill = |b: &mut Bucket| {
+            let elapsed = now.saturating_duration_since(b.updated).as_secs_f64();
+            b.queries = (b.queries + elapsed * per_client).min(per_client);
+            b.updated = now;
+        };
+        if clients.len() >= MAX_CLIENTS && !clients.contains_key(&client) {
+            clients.retain(|_, b| {
+                refill(b);
+                b.queries < per_client
+            });
+        }
+        let bucket = clients.entry(client).or_insert(Bucket {
+            queries: per_client,
+            updated: now,
+        });
+        refill(bucket);
+        if bucket.queries >= 1.0 {
+            bucket.queries -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_rate_limit() {
+        let limits = QueryLimits::new(10, 2);
+        let a = Some(IpAddr::from([10, 0, 0, 1]));
+        let b = Some(IpAddr::from([10, 0, 0, 2]));
+        let start = Instant::now();
+
+        assert!(limits.take(a, start));
+        assert!(limits.take(a, start));
+        assert!(!limits.take(a, start));
+        // Each client has its own allowance.
+        assert!(limits.take(b, start));
+        // Which refills at the rate, a query every half second.
+        assert!(!limits.take(a, start + Duration::from_millis(400)));
+        assert!(limits.take(a, start + Duration::from_millis(600)));
+        assert!(!limits.take(a, start + Duration::from_millis(600)));
+        // Up to a second's worth.
+        let later = start + Duration::from_secs(60);
+        assert!(limits.take(a, later));
+        assert!(limits.take(a, later));
+        assert!(!limits.take(a, later));
+    }
+}
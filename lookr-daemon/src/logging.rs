@@ -0,0 +1,120 @@
+//! How the daemon's logs are written: coloured text for reading at a
+//! terminal, or JSON lines for log aggregators to parse.
+
+use serde::Serialize;
+use std::fmt;
+use std::io::Write;
+use std::str::FromStr;
+use std::thread;
+
+/// The values `--log-format` takes.
+pub const LOG_FORMATS: [&str; 2] = ["pretty", "json"];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LogFormat {
+    /// `pretty_env_logger`'s format, coloured when writing to a terminal.
+    Pretty,
+    /// A JSON object on each line, with the timestamp, level, target and
+    /// thread alongside the message.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pretty" => Ok(LogFormat::Pretty),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!(
+                "Unknown log format {:?}, expected one of {}",
+                s,
+                LOG_FORMATS.join(", ")
+            )),
+        }
+    }
+}
+
+impl fmt::Display for LogFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LogFormat::Pretty => write!(f, "pretty"),
+            LogFormat::Json => write!(f, "json"),
+        }
+    }
+}
+
+/// Log to stderr in `format`, at the levels `RUST_LOG` asks for.
+pub fn init(format: LogFormat) {
+    match format {
+        LogFormat::Pretty => pretty_env_logger::init(),
+        LogFormat::Json => env_logger::Builder::from_default_env()
+            .format(|buf, record| {
+                let timestamp = buf.timestamp_millis().to_string();
+                writeln!(buf, "{}", json_line(record, &timestamp))
+            })
+            .init(),
+    }
+}
+
+/// A log record as it's written with `LogFormat::Json`.
+#[derive(Debug, Serialize)]
+struct JsonLine<'a> {
+    /// RFC 3339, in UTC.
+    timestamp: &'a str,
+    level: String,
+    /// The module logged from, unless the record says otherwise.
+    target: &'a str,
+    /// The name of the thread logged from, like `audit`, if it has one.
+    thread: Option<&'a str>,
+    message: String,
+}
+
+/// The record as one line of JSON. Newlines in the message are escaped, so
+/// every record is a line of its own.
+fn json_line(record: &log::Record, timestamp: &str) -> String {
+    let current = thread::current();
+    let line = JsonLine {
+        timestamp,
+        level: record.level().to_string(),
+        target: record.target(),
+        thread: current.name(),
+        message: record.args().to_string(),
+    };
+    // Only fails for maps with keys that aren't strings.
+    serde_json::to_string(&line).unwrap()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use log::{Level, Record};
+
+    #[test]
+    fn test_log_format() {
+        for name in &LOG_FORMATS {
+            assert_eq!(name.parse::<LogFormat>().unwrap().to_string(), *name);
+        }
+        assert!("yaml".parse::<LogFormat>().is_err());
+    }
+
+    #[test]
+    fn test_json_line() {
+        let line = json_line(
+            &Record::builder()
+                .args(format_args!("Indexed {:?}\nin {}ms", "/a \"b\"", 5))
+                .level(Level::Warn)
+                .target("lookrd::indexer")
+                .build(),
+            "2020-06-01T12:00:00.123Z",
+        );
+        assert!(!line.contains('\n'));
+        let parsed: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(parsed["timestamp"], "2020-06-01T12:00:00.123Z");
+        assert_eq!(parsed["level"], "WARN");
+        assert_eq!(parsed["target"], "lookrd::indexer");
+        assert_eq!(parsed["message"], "Indexed \"/a \\\"b\\\"\"\nin 5ms");
+        // Each test runs on a thread named after it.
+        assert_eq!(parsed["thread"], "logging::test::test_json_line");
+    }
+}
@@ -1,48 +1,131 @@
-mod indexer;
-mod proto;
-mod rpc;
-mod secret;
-
 #[macro_use]
 extern crate log;
 
-use crate::proto::rpc::lookr_server::LookrServer;
-use clap::{App, AppSettings, Arg};
-use serde::{Deserialize, Serialize};
-use std::fs::File;
-use std::io::{self, BufReader};
-use std::path::Path;
+use clap::{value_t, App, AppSettings, Arg, ArgMatches, Shell, SubCommand};
+use lookrd::audit::AuditLog;
+use lookrd::config::{self, read_config, LookrdConfig};
+#[cfg(unix)]
+use lookrd::daemon::{self, PidFile};
+use lookrd::indexer::IndexerError;
+use lookrd::limits::QueryLimits;
+use lookrd::logging::{self, LogFormat, LOG_FORMATS};
+#[cfg(unix)]
+use lookrd::privileges;
+use lookrd::proto::rpc::lookr_server::{Lookr, LookrServer};
+#[cfg(unix)]
+use lookrd::secret::SecretManager;
+use lookrd::users::{self, UserAccess};
+use lookrd::{indexer, rpc, selftest};
+use std::env;
+use std::fs;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Arc;
 use std::thread;
 use tantivy::Index;
+use tokio::net::TcpListener;
 use tonic::transport::Server;
 
 static DEFAULT_ADDR: &str = "[::1]:50051";
-static DEFAULT_CONFIG: &str = ".lookrd";
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
-struct LookrdConfig {
-    /// The paths that will be indexed by the indexer.
-    index_paths: Vec<String>,
-    /// The location this data will be written to.
-    data_dir: String,
-    // Optional list of users to generate secrets for, if not provided will
-    // generate them for all users.
-    users: Option<String>,
-}
+/// Where the pid is written in the data dir when daemonized.
+static DEFAULT_PID_FILE: &str = "lookrd.pid";
 
-fn read_config(cfg: &Path) -> io::Result<LookrdConfig> {
-    let reader = BufReader::new(File::open(cfg)?);
-    let config = serde_json::from_reader(reader)?;
-    Ok(config)
-}
+/// Where the logs go in the data dir when daemonized.
+static DEFAULT_LOG_FILE: &str = "lookrd.log";
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    pretty_env_logger::init();
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let helps = Helps::new();
+    let matches = app(&helps).get_matches();
+    if matches.is_present("completions") {
+        let shell = value_t!(matches, "completions", Shell).unwrap_or_else(|e| e.exit());
+        app(&helps).gen_completions_to(env!("CARGO_PKG_NAME"), shell, &mut io::stdout());
+        return Ok(());
+    }
+    // Checked by the argument's possible values.
+    let log_format = value_t!(matches, "log_format", LogFormat).unwrap_or(LogFormat::Pretty);
+    logging::init(log_format);
 
     info!("{} v{}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+    if matches.subcommand_matches("selftest").is_some() {
+        process::exit(if run_selftest() { 0 } else { 1 });
+    }
+
+    let addr = matches.value_of("addr").unwrap_or(DEFAULT_ADDR);
+    let addr: SocketAddr = match addr.parse() {
+        Ok(a) => a,
+        Err(_) => {
+            eprintln!(
+                "Invalid address {:?}, expected an ip:port to bind to, e.g. {}",
+                addr, DEFAULT_ADDR
+            );
+            process::exit(1);
+        }
+    };
+    let config_path = config::config_path(
+        matches.value_of("config"),
+        env::var_os(config::CONFIG_ENV),
+        env::var_os("XDG_CONFIG_HOME"),
+        dirs::home_dir(),
+    )
+    .expect("No home directory found...");
+    info!("Loading config from {:?}", config_path);
+    let config = match read_config(&config_path) {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("Could not load the config: {}", e);
+            process::exit(1);
+        }
+    };
+
+    // Before any other threads are started, as only this one survives
+    // daemonizing, and the others have to inherit the blocked signals.
+    let pid_file = match detach(&matches, &config) {
+        Ok(p) => p,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    };
+    let shutdown = shutdown_signal()?;
+    let readonly = matches.value_of("readonly").map(PathBuf::from);
+    run(addr, config, readonly, shutdown)?;
+    drop(pid_file);
+    Ok(())
+}
+
+/// The help text naming the defaults, which has to outlive the `App`.
+struct Helps {
+    addr: String,
+    daemonize: String,
+    log_file: String,
+}
+
+impl Helps {
+    fn new() -> Self {
+        Helps {
+            addr: format!(
+                "Override the default interface address to bind to: {}",
+                DEFAULT_ADDR
+            ),
+            daemonize: format!(
+                "Run in the background, logging to --log-file. Writes a pid file to <data_dir>/{} unless --pid-file is given",
+                DEFAULT_PID_FILE
+            ),
+            log_file: format!(
+                "Where to append the logs with --daemonize. Defaults to <data_dir>/{}",
+                DEFAULT_LOG_FILE
+            ),
+        }
+    }
+}
 
-    let matches = App::new(env!("CARGO_PKG_NAME"))
+/// The command line, also used to generate the shell completions.
+fn app(helps: &Helps) -> App<'static, '_> {
+    App::new(env!("CARGO_PKG_NAME"))
         .setting(AppSettings::ColoredHelp)
         .version(env!("CARGO_PKG_VERSION"))
         .author(env!("CARGO_PKG_AUTHORS"))
@@ -51,13 +134,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             Arg::with_name("addr")
                 .short("a")
                 .long("addr")
-                .help(
-                    format!(
-                        "Override the default interface address to bind to: {}",
-                        DEFAULT_ADDR
-                    )
-                    .as_str(),
-                )
+                .help(&helps.addr)
                 .takes_value(true)
                 .required(false)
                 .global(true),
@@ -71,53 +148,456 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 .required(false)
                 .global(true),
         )
-        .get_matches();
-
-    let addr = matches.value_of("addr").unwrap_or(DEFAULT_ADDR).parse()?;
-    let config = match matches.value_of("config") {
-        Some(c) => read_config(Path::new(c))?,
-        None => {
-            let mut home = dirs::home_dir().expect("No home directory found...");
-            home.push(DEFAULT_CONFIG);
-            read_config(home.as_path())?
+        .arg(
+            Arg::with_name("daemonize")
+                .short("d")
+                .long("daemonize")
+                .help(&helps.daemonize),
+        )
+        .arg(
+            Arg::with_name("pid_file")
+                .long("pid-file")
+                .value_name("PATH")
+                .help("Write the daemon's pid to this file, removing it on shutdown")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("log_file")
+                .long("log-file")
+                .value_name("PATH")
+                .help(&helps.log_file)
+                .takes_value(true)
+                .requires("daemonize"),
+        )
+        .arg(
+            Arg::with_name("log_format")
+                .long("log-format")
+                .value_name("FORMAT")
+                .help("How to write the logs: pretty, for reading, or json, a JSON object on each line for log aggregators. Defaults to pretty")
+                .takes_value(true)
+                .possible_values(&LOG_FORMATS)
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("readonly")
+                .long("readonly")
+                .value_name("INDEX_DIR")
+                .help("Only serve queries of the index in this directory, like a snapshot, without indexing or changing it")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("completions")
+                .long("generate-completions")
+                .value_name("SHELL")
+                .help("Print the completion script for this shell and exit")
+                .possible_values(&Shell::variants())
+                .hidden(true),
+        )
+        .subcommand(
+            SubCommand::with_name("selftest")
+                .about("Index a temporary directory and query it, printing PASS or FAIL"),
+        )
+}
+
+/// Start the indexers, or open the read-only index, and serve requests until
+/// told to shut down.
+#[tokio::main]
+async fn run(
+    addr: SocketAddr,
+    config: LookrdConfig,
+    readonly: Option<PathBuf>,
+    shutdown: impl Future<Output = ()>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if config.multi_user && config.run_as_user.is_some() {
+        eprintln!("run_as_user can't be used with multi_user, which reads every user's files");
+        process::exit(1);
+    }
+    let listener = match bind(addr, &config).await {
+        Ok(l) => l,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    };
+    let audit_log = match open_audit_log(&config) {
+        Ok(a) => a,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    };
+    let limits = Arc::new(QueryLimits::from_config(&config));
+    if let Some(dir) = readonly {
+        if config.multi_user {
+            eprintln!("--readonly can't be used with multi_user, which has an index for each user");
+            process::exit(1);
+        }
+        let service = match open_read_only(&config, &dir) {
+            Ok(service) => match audit_log {
+                Some(audit_log) => service.with_audit_log(audit_log),
+                None => service,
+            }
+            .with_limits(limits),
+            Err(e) => {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
+        };
+        info!("Starting RPC server, serving {:?} read-only", dir);
+        serve(service, listener, shutdown).await?;
+    } else if config.multi_user {
+        let service = match start_user_indexers(&config, audit_log, limits) {
+            Ok(s) => s,
+            Err(e) => {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
+        };
+        info!("Starting RPC server");
+        serve(service, listener, shutdown).await?;
+    } else {
+        info!("Creating index");
+        let service = match start_indexer(&config, Path::new(&config.data_dir), None) {
+            Ok(service) => match audit_log {
+                Some(audit_log) => service.with_audit_log(audit_log),
+                None => service,
+            }
+            .with_limits(limits),
+            Err(e) => {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
+        };
+        info!("Starting RPC server");
+        serve(service, listener, shutdown).await?;
+    }
+
+    // The indexer threads stop with the process.
+    Ok(())
+}
+
+/// Run the self test, printing how it went.
+#[tokio::main]
+async fn run_selftest() -> bool {
+    match selftest::run().await {
+        Ok(checks) => {
+            println!("PASS ({} checks)", checks);
+            true
         }
+        Err(e) => {
+            println!("FAIL: {}", e);
+            false
+        }
+    }
+}
+
+/// Start writing the audit log, if one is configured. Opened once
+/// privileges are dropped, so it's the `run_as_user`'s to write and rotate.
+fn open_audit_log(
+    config: &LookrdConfig,
+) -> Result<Option<Arc<AuditLog>>, Box<dyn std::error::Error>> {
+    let path = match &config.audit_log {
+        Some(p) => Path::new(p),
+        None => return Ok(None),
     };
+    let max_bytes = config
+        .audit_log_max_bytes
+        .unwrap_or(config::DEFAULT_AUDIT_LOG_MAX_BYTES);
+    let audit_log = AuditLog::open(path, max_bytes)
+        .map_err(|e| format!("Could not open the audit log {:?}: {}", path, e))?;
+    info!("Auditing queries to {:?}", path);
+    Ok(Some(Arc::new(audit_log)))
+}
+
+/// Bind `addr`, then with `run_as_user` switch to that user before anything
+/// else is opened or started, so the indexer only sees what the user can,
+/// and what's written in the data dir is theirs.
+async fn bind(
+    addr: SocketAddr,
+    config: &LookrdConfig,
+) -> Result<TcpListener, Box<dyn std::error::Error>> {
+    // Bound here rather than by the server, so a port only root can bind
+    // can be used.
+    let listener = TcpListener::bind(addr).await?;
+    if let Some(user) = &config.run_as_user {
+        run_as(user)?;
+    }
+    Ok(listener)
+}
 
-    // Plan: What needs to happen before we index things:
-    // 1. We need to get a list of the users on the system
-    // 2. We generate a user read-only sercret key for them
-    // 3. Add an endpoint for a request to get a local path for the key for a given user
-    // 4. Add the key requirement to the query to authenticate the request.
-    // 5. Also index the file permissions to make sure we filter the correct files out.
+/// Serve the RPC service on the listener until `shutdown` completes.
+async fn serve<S: Lookr>(
+    service: S,
+    mut listener: TcpListener,
+    shutdown: impl Future<Output = ()>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    Server::builder()
+        .add_service(LookrServer::new(service))
+        .serve_with_incoming_shutdown(listener.incoming(), shutdown)
+        .await?;
+    Ok(())
+}
 
-    info!("Creating index");
+/// Open the index kept in `dir`, or one in memory, and start indexing into it
+/// on its own thread, only what `user` can see if given.
+fn start_indexer(
+    config: &LookrdConfig,
+    dir: &Path,
+    user: Option<UserAccess>,
+) -> Result<rpc::LookrService, Box<dyn std::error::Error>> {
     let schema = indexer::build_schema();
-    let schema_indexer = schema.clone();
-    let schema_lookr = schema.clone();
-    let index = Index::create_in_ram(schema);
-    let index_lookr = index.clone();
+    let index = if config.persist_index {
+        let dir = dir.join("index");
+        match indexer::open_or_rebuild(&dir, schema.clone(), &config.index_fingerprint()) {
+            Ok(index) => index,
+            Err(e @ IndexerError::IncompatibleSchema(_)) => {
+                return Err(format!("Could not open the index in {:?}: {}", dir, e).into())
+            }
+            // Still answer queries, from a walk that has to start over anyway.
+            Err(e) => {
+                warn!(
+                    "Could not open the index in {:?}, indexing in memory instead: {}",
+                    dir, e
+                );
+                Index::create_in_ram(schema.clone())
+            }
+        }
+    } else {
+        Index::create_in_ram(schema.clone())
+    };
+    let mut indexer = indexer::Indexer::new(index.clone(), schema.clone(), config.clone())?;
+    if let Some(user) = user {
+        indexer = indexer.for_user(user);
+    }
+    let indexer_handle = indexer.handle();
 
     info!("Starting indexer thread");
-    let idx_thread = thread::spawn(move || {
-        let mut paths = Vec::with_capacity(config.index_paths.len());
-        for p in &config.index_paths {
-            paths.push(Path::new(p));
-        }
-        let mut indexer = indexer::Indexer::new(index, schema_indexer, &paths).unwrap();
+    thread::spawn(move || {
         indexer
             .index()
             .expect("Indexer thread terminating on error");
     });
+    Ok(rpc::LookrService::new(
+        index,
+        schema,
+        config,
+        indexer_handle,
+    ))
+}
 
-    info!("Starting RPC server");
-    // RPC service and server.
-    let lookr = rpc::LookrService::new(index_lookr, schema_lookr);
-    Server::builder()
-        .add_service(LookrServer::new(lookr))
-        .serve(addr)
-        .await?;
+/// Open the index in `dir` to serve queries of as it is, with no indexer.
+fn open_read_only(
+    config: &LookrdConfig,
+    dir: &Path,
+) -> Result<rpc::LookrService, Box<dyn std::error::Error>> {
+    let index = indexer::open_read_only(dir)
+        .map_err(|e| format!("Could not open the index in {:?}: {}", dir, e))?;
+    let service = rpc::LookrService::read_only(index, indexer::build_schema(), config)
+        .map_err(|e| format!("Could not read the index in {:?}: {}", dir, e))?;
+    Ok(service)
+}
 
-    idx_thread.join().expect("Could not join indexer thread");
+/// Create a secret and start an indexer for each of the configured users,
+/// kept under `<data_dir>/<user>/`.
+#[cfg(unix)]
+fn start_user_indexers(
+    config: &LookrdConfig,
+    audit_log: Option<Arc<AuditLog>>,
+    limits: Arc<QueryLimits>,
+) -> Result<rpc::MultiUserService, Box<dyn std::error::Error>> {
+    let data_dir = Path::new(&config.data_dir);
+    fs::create_dir_all(data_dir)?;
+    let secrets = SecretManager::new(data_dir)?;
 
-    Ok(())
+    let mut service = rpc::MultiUserService::default();
+    for name in users::configured_users(config.users.as_deref()) {
+        let user = UserAccess::lookup(&name)?;
+        let (secret, secret_path) = secrets.create_for_user(&user)?;
+        info!("Creating index for {}", name);
+        let uid = user.uid;
+        let mut lookr = start_indexer(config, &users::user_dir(data_dir, &name), Some(user))?
+            .for_user(&name)
+            .with_owner_uid(uid)
+            .with_limits(limits.clone());
+        if let Some(audit_log) = &audit_log {
+            lookr = lookr.with_audit_log(audit_log.clone());
+        }
+        service.add_user(&name, secret, secret_path, lookr);
+    }
+    Ok(service)
+}
+
+#[cfg(not(unix))]
+fn start_user_indexers(
+    _config: &LookrdConfig,
+    _audit_log: Option<Arc<AuditLog>>,
+    _limits: Arc<QueryLimits>,
+) -> Result<rpc::MultiUserService, Box<dyn std::error::Error>> {
+    Err("multi_user is only supported on Unix".into())
+}
+
+/// Daemonize if asked to, and write the pid file if there is one.
+#[cfg(unix)]
+fn detach(
+    matches: &ArgMatches<'_>,
+    config: &LookrdConfig,
+) -> Result<Option<PidFile>, Box<dyn std::error::Error>> {
+    let data_dir = Path::new(&config.data_dir);
+    let daemonize = matches.is_present("daemonize");
+    if daemonize {
+        let log_file = match matches.value_of("log_file") {
+            Some(path) => PathBuf::from(path),
+            None => {
+                fs::create_dir_all(data_dir)?;
+                data_dir.join(DEFAULT_LOG_FILE)
+            }
+        };
+        daemon::daemonize(&log_file)
+            .map_err(|e| format!("Could not daemonize, logging to {:?}: {}", log_file, e))?;
+    }
+
+    let pid_file = match matches.value_of("pid_file") {
+        Some(path) => PathBuf::from(path),
+        None if daemonize => data_dir.join(DEFAULT_PID_FILE),
+        None => return Ok(None),
+    };
+    let pid_file = PidFile::create(&pid_file)
+        .map_err(|e| format!("Could not write the pid file {:?}: {}", pid_file, e))?;
+    Ok(Some(pid_file))
+}
+
+#[cfg(not(unix))]
+fn detach(
+    matches: &ArgMatches<'_>,
+    _config: &LookrdConfig,
+) -> Result<Option<()>, Box<dyn std::error::Error>> {
+    if matches.is_present("daemonize") || matches.is_present("pid_file") {
+        return Err("--daemonize and --pid-file are only supported on Unix".into());
+    }
+    Ok(None)
+}
+
+/// Completes on SIGINT or SIGTERM, so the server can stop taking requests
+/// and the pid file be removed.
+#[cfg(unix)]
+fn shutdown_signal() -> Result<impl Future<Output = ()>, Box<dyn std::error::Error>> {
+    let signal = daemon::shutdown_signal()?;
+    Ok(async {
+        match signal.await {
+            Ok(signal) => info!("Shutting down on {}", signal),
+            // The signal thread failed, so there's nothing to shut down on.
+            Err(_) => futures::future::pending().await,
+        }
+    })
+}
+
+#[cfg(not(unix))]
+fn shutdown_signal() -> Result<impl Future<Output = ()>, Box<dyn std::error::Error>> {
+    Ok(futures::future::pending())
+}
+
+#[cfg(unix)]
+fn run_as(user: &str) -> Result<(), Box<dyn std::error::Error>> {
+    Ok(privileges::drop_privileges(user)?)
+}
+
+#[cfg(not(unix))]
+fn run_as(_user: &str) -> Result<(), Box<dyn std::error::Error>> {
+    Err("run_as_user is only supported on Unix".into())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[cfg(unix)]
+    use lookrd::proto::rpc::{QueryReq, SortBy};
+    #[cfg(unix)]
+    use std::os::unix::fs::PermissionsExt;
+    #[cfg(unix)]
+    use tonic::Request;
+
+    #[test]
+    fn test_completions() {
+        let helps = Helps::new();
+        let mut script = Vec::new();
+        app(&helps).gen_completions_to(env!("CARGO_PKG_NAME"), Shell::Bash, &mut script);
+        let script = String::from_utf8(script).unwrap();
+        assert!(script.contains("_lookrd()"));
+        for flag in ["--readonly", "--log-format", "selftest"] {
+            assert!(script.contains(flag), "{}", flag);
+        }
+    }
+
+    /// Set in the copy of the test binary that drops privileges, to the
+    /// directory to index.
+    #[cfg(unix)]
+    const RUN_AS_DIR: &str = "LOOKRD_TEST_RUN_AS_DIR";
+
+    /// Privileges can't be got back once dropped, so a copy of the test
+    /// binary does the dropping, and only as root.
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_run_as_user() {
+        if let Some(dir) = env::var_os(RUN_AS_DIR) {
+            return run_as_user_walk(Path::new(&dir)).await;
+        }
+        if !nix::unistd::geteuid().is_root() {
+            eprintln!("Skipping, not running as root");
+            return;
+        }
+        let dir = tempfile::tempdir().unwrap();
+        fs::set_permissions(dir.path(), fs::Permissions::from_mode(0o755)).unwrap();
+        fs::create_dir(dir.path().join("public")).unwrap();
+        fs::write(dir.path().join("public/open.txt"), "").unwrap();
+        let nobody = nix::unistd::User::from_name("nobody").unwrap().unwrap();
+        fs::write(dir.path().join("public/mine.txt"), "").unwrap();
+        nix::unistd::chown(&dir.path().join("public/mine.txt"), Some(nobody.uid), None).unwrap();
+        fs::create_dir(dir.path().join("private")).unwrap();
+        fs::write(dir.path().join("private/secret.txt"), "").unwrap();
+        fs::set_permissions(
+            dir.path().join("private"),
+            fs::Permissions::from_mode(0o700),
+        )
+        .unwrap();
+
+        let status = process::Command::new(env::current_exe().unwrap())
+            .args(["--exact", "test::test_run_as_user", "--nocapture"])
+            .env(RUN_AS_DIR, dir.path())
+            .status()
+            .unwrap();
+        assert!(status.success());
+    }
+
+    #[cfg(unix)]
+    async fn run_as_user_walk(dir: &Path) {
+        let config = LookrdConfig {
+            index_paths: vec![dir.to_string_lossy().to_string()],
+            run_as_user: Some("nobody".to_string()),
+            index_owners: true,
+            ..Default::default()
+        };
+        let _listener = bind("127.0.0.1:0".parse().unwrap(), &config).await.unwrap();
+        assert!(!nix::unistd::geteuid().is_root());
+
+        let service = start_indexer(&config, Path::new(&config.data_dir), None).unwrap();
+        let txt = |owned_by_me| {
+            Request::new(QueryReq {
+                query: "txt".to_string(),
+                wait_for_ready: true,
+                owned_by_me,
+                sort_by: SortBy::Alphabetical as i32,
+                ..Default::default()
+            })
+        };
+        let path = |p: &str| dir.join(p).to_string_lossy().to_string();
+        let resp = service.query(txt(false)).await.unwrap().into_inner();
+        assert!(!resp.warming_up);
+        assert_eq!(
+            resp.results,
+            vec![path("public/mine.txt"), path("public/open.txt")]
+        );
+        // Mine being the user's run as, not root's.
+        let resp = service.query(txt(true)).await.unwrap().into_inner();
+        assert_eq!(resp.results, vec![path("public/mine.txt")]);
+    }
 }
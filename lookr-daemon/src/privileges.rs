@@ -0,0 +1,87 @@
+//! Dropping root privileges once the daemon has bound its socket.
+
+use std::error;
+use std::fmt;
+
+#[derive(Debug)]
+pub enum PrivilegeError {
+    /// There is no user with the configured name.
+    NoSuchUser(String),
+    /// The user could not be looked up.
+    Lookup(String, nix::Error),
+    /// Changing to the user failed, usually because the daemon isn't root.
+    Drop(String, nix::Error),
+    /// The user's groups could not be set.
+    Groups(String, nix::Error),
+}
+
+impl error::Error for PrivilegeError {}
+
+impl fmt::Display for PrivilegeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PrivilegeError::NoSuchUser(user) => {
+                write!(f, "Cannot run as {:?}, there is no such user", user)
+            }
+            PrivilegeError::Lookup(user, e) => {
+                write!(f, "Cannot run as {:?}, could not look it up: {}", user, e)
+            }
+            PrivilegeError::Drop(user, e) => write!(f, "Cannot run as {:?}: {}", user, e),
+            PrivilegeError::Groups(user, e) => {
+                write!(
+                    f,
+                    "Cannot run as {:?}, could not set its groups: {}",
+                    user, e
+                )
+            }
+        }
+    }
+}
+
+/// Switch the whole process to `name`'s user, group and supplementary groups.
+/// This can't be undone, so everything, the indexer included, runs as that
+/// user afterwards.
+pub fn drop_privileges(name: &str) -> Result<(), PrivilegeError> {
+    use nix::unistd::{setgid, setuid, User};
+
+    let user = match User::from_name(name) {
+        Ok(Some(u)) => u,
+        Ok(None) => return Err(PrivilegeError::NoSuchUser(name.to_string())),
+        Err(e) => return Err(PrivilegeError::Lookup(name.to_string(), e)),
+    };
+
+    // Groups first, as they can't be changed once the user has been, and
+    // root's supplementary groups would otherwise be kept.
+    let c_name =
+        std::ffi::CString::new(name).map_err(|_| PrivilegeError::NoSuchUser(name.to_string()))?;
+    #[cfg(not(target_vendor = "apple"))]
+    nix::unistd::initgroups(&c_name, user.gid)
+        .map_err(|e| PrivilegeError::Groups(name.to_string(), e))?;
+    // nix leaves initgroups out on Apple platforms, where the group is an int.
+    #[cfg(target_vendor = "apple")]
+    if unsafe { libc::initgroups(c_name.as_ptr(), user.gid.as_raw() as libc::c_int) } != 0 {
+        return Err(PrivilegeError::Groups(name.to_string(), nix::Error::last()));
+    }
+    setgid(user.gid).map_err(|e| PrivilegeError::Drop(name.to_string(), e))?;
+    setuid(user.uid).map_err(|e| PrivilegeError::Drop(name.to_string(), e))?;
+
+    // Make sure there's no way back.
+    if !user.uid.is_root() && setuid(nix::unistd::Uid::from_raw(0)).is_ok() {
+        return Err(PrivilegeError::Drop(name.to_string(), nix::Error::EPERM));
+    }
+    info!("Running as {:?}", name);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_no_such_user() {
+        match drop_privileges("lookr-no-such-user") {
+            Err(PrivilegeError::NoSuchUser(user)) => assert_eq!(user, "lookr-no-such-user"),
+            r => panic!("Expected NoSuchUser, got {:?}", r),
+        }
+    }
+}
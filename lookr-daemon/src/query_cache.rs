@@ -0,0 +1,262 @@
+//! Caches of query responses, for clients that send the same query again
+//! and again, like dashboards and prompts that search on every keystroke.
+
+use crate::proto::rpc::{QueryReq, QueryResp};
+use prost::Message;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use tantivy::{DocId, Searcher, SegmentId};
+
+/// What a searcher sees of the index: its segments, and how many of each
+/// one's documents are deleted. Every commit that changes the index changes
+/// this, whether it adds a segment, deletes from one, or merges some.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct Generation(Vec<(SegmentId, DocId)>);
+
+impl Generation {
+    pub(crate) fn of(searcher: &Searcher) -> Self {
+        Generation(
+            searcher
+                .segment_readers()
+                .iter()
+                .map(|r| (r.segment_id(), r.num_deleted_docs()))
+                .collect(),
+        )
+    }
+}
+
+/// Responses to the last `capacity` distinct requests, all searched on the
+/// same generation of the index. Past `capacity`, the least recently used
+/// is dropped to make room.
+#[derive(Debug)]
+pub(crate) struct QueryCache {
+    capacity: usize,
+    entries: Mutex<Entries>,
+}
+
+#[derive(Debug, Default)]
+struct Entries {
+    generation: Generation,
+    responses: HashMap<Vec<u8>, Entry>,
+    /// Counts lookups, to tell which entry was used least recently.
+    clock: u64,
+}
+
+#[derive(Debug)]
+struct Entry {
+    used: u64,
+    resp: QueryResp,
+}
+
+impl Entries {
+    /// Drop everything searched on another generation.
+    fn advance(&mut self, generation: &Generation) {
+        if self.generation != *generation {
+            self.responses.clear();
+            self.generation = generation.clone();
+        }
+    }
+}
+
+impl QueryCache {
+    pub(crate) fn new(capacity: usize) -> Self {
+        QueryCache {
+            capacity: capacity.max(1),
+            entries: Mutex::new(Entries::default()),
+        }
+    }
+
+    /// The response to the same request, if it was searched on this
+    /// generation of the index.
+    pub(crate) fn get(&self, generation: &Generation, req: &QueryReq) -> Option<QueryResp> {
+        let mut entries = self.entries.lock().unwrap();
+        entries.advance(generation);
+        entries.clock += 1;
+        let clock = entries.clock;
+        let entry = entries.responses.get_mut(&key(req))?;
+        entry.used = clock;
+        Some(entry.resp.clone())
+    }
+
+    pub(crate) fn insert(&self, generation: &Generation, req: &QueryReq, resp: &QueryResp) {
+        let key = key(req);
+        let mut entries = self.entries.lock().unwrap();
+        entries.advance(generation);
+        if !entries.responses.contains_key(&key) && entries.responses.len() >= self.capacity {
+            let oldest = entries
+                .responses
+                .iter()
+                .min_by_key(|(_, e)| e.used)
+                .map(|(k, _)| k.clone());
+            if let Some(oldest) = oldest {
+                entries.responses.remove(&oldest);
+            }
+        }
+        entries.clock += 1;
+        let used = entries.clock;
+        entries.responses.insert(
+            key,
+            Entry {
+                used,
+                resp: resp.clone(),
+            },
+        );
+    }
+}
+
+/// Requests that found nothing, all searched on the same generation of the
+/// index, so they and those that only narrow them can be answered without
+/// searching. Past `capacity`, the least recently used is dropped to make
+/// room.
+#[derive(Debug)]
+pub(crate) struct NegativeCache {
+    capacity: usize,
+    entries: Mutex<NegativeEntries>,
+}
+
+#[derive(Debug, Default)]
+struct NegativeEntries {
+    generation: Generation,
+    /// When each request was last used, by the clock.
+    requests: HashMap<Vec<u8>, u64>,
+    clock: u64,
+}
+
+impl NegativeEntries {
+    /// Drop everything searched on another generation.
+    fn advance(&mut self, generation: &Generation) {
+        if self.generation != *generation {
+            self.requests.clear();
+            self.generation = generation.clone();
+        }
+    }
+}
+
+impl NegativeCache {
+    pub(crate) fn new(capacity: usize) -> Self {
+        NegativeCache {
+            capacity: capacity.max(1),
+            entries: Mutex::new(NegativeEntries::default()),
+        }
+    }
+
+    /// Whether the request, with any of `queries` as its query, found
+    /// nothing on this generation of the index.
+    pub(crate) fn contains(
+        &self,
+        generation: &Generation,
+        req: &QueryReq,
+        queries: &[String],
+    ) -> bool {
+        let mut entries = self.entries.lock().unwrap();
+        entries.advance(generation);
+        entries.clock += 1;
+        let clock = entries.clock;
+        for query in queries {
+            if let Some(used) = entries.requests.get_mut(&key_with(req, query)) {
+                *used = clock;
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Remember that the request, with `query` as its query, found nothing.
+    pub(crate) fn insert(&self, generation: &Generation, req: &QueryReq, query: &str) {
+        let key = key_with(req, query);
+        let mut entries = self.entries.lock().unwrap();
+        entries.advance(generation);
+        if !entries.requests.contains_key(&key) && entries.requests.len() >= self.capacity {
+            let oldest = entries
+                .requests
+                .iter()
+                .min_by_key(|(_, used)| **used)
+                .map(|(k, _)| k.clone());
+            if let Some(oldest) = oldest {
+                entries.requests.remove(&oldest);
+            }
+        }
+        entries.clock += 1;
+        let used = entries.clock;
+        entries.requests.insert(key, used);
+    }
+}
+
+/// The request as sent, with `query` in place of its own.
+fn key_with(req: &QueryReq, query: &str) -> Vec<u8> {
+    let mut req = req.clone();
+    req.query = query.to_string();
+    key(&req)
+}
+
+/// The request as sent, other than the secret. Each user of a multi-user
+/// daemon has their own service, and so their own cache.
+fn key(req: &QueryReq) -> Vec<u8> {
+    let mut req = req.clone();
+    req.secret.clear();
+    let mut key = Vec::with_capacity(req.encoded_len());
+    // Only fails if the buffer is too small, and it grows.
+    req.encode(&mut key).unwrap();
+    key
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_query_cache() {
+        let req = |query: &str| QueryReq {
+            query: query.to_string(),
+            ..Default::default()
+        };
+        let resp = |path: &str| QueryResp {
+            results: vec![path.to_string()],
+            ..Default::default()
+        };
+        let generation = Generation::default();
+        let cache = QueryCache::new(2);
+
+        cache.insert(&generation, &req("a"), &resp("/a"));
+        cache.insert(&generation, &req("b"), &resp("/b"));
+        assert_eq!(cache.get(&generation, &req("a")), Some(resp("/a")));
+        let mut secret = req("a");
+        secret.secret = "secret".to_string();
+        assert_eq!(cache.get(&generation, &secret), Some(resp("/a")));
+        let mut counted = req("a");
+        counted.count = 5;
+        assert_eq!(cache.get(&generation, &counted), None);
+
+        // b was used least recently, so makes way for c.
+        cache.insert(&generation, &req("c"), &resp("/c"));
+        assert_eq!(cache.get(&generation, &req("b")), None);
+        assert_eq!(cache.get(&generation, &req("a")), Some(resp("/a")));
+        assert_eq!(cache.get(&generation, &req("c")), Some(resp("/c")));
+    }
+
+    #[test]
+    fn test_negative_cache() {
+        let req = |query: &str| QueryReq {
+            query: query.to_string(),
+            ..Default::default()
+        };
+        let queries = |qs: &[&str]| qs.iter().map(|q| q.to_string()).collect::<Vec<_>>();
+        let generation = Generation::default();
+        let cache = NegativeCache::new(2);
+
+        cache.insert(&generation, &req("a"), "a");
+        cache.insert(&generation, &req("b c"), "b c");
+        assert!(cache.contains(&generation, &req("a"), &queries(&["a"])));
+        // Found through any of the queries given.
+        assert!(cache.contains(&generation, &req("b c d"), &queries(&["b", "b c"])));
+        assert!(!cache.contains(&generation, &req("b"), &queries(&["b"])));
+        let mut counted = req("a");
+        counted.count = 5;
+        assert!(!cache.contains(&generation, &counted, &queries(&["a"])));
+
+        // a was used least recently, so makes way for e.
+        cache.insert(&generation, &req("e"), "e");
+        assert!(!cache.contains(&generation, &req("a"), &queries(&["a"])));
+        assert!(cache.contains(&generation, &req("e"), &queries(&["e"])));
+    }
+}
@@ -0,0 +1,298 @@
+//! Keeping standby daemons' indexes in step with a primary's. The primary's
+//! writer records what it adds and deletes, and once that's committed sends
+//! it on to each standby streaming `Replicate`, which applies it to its own
+//! index and commits in turn.
+
+use crate::indexer::{Fields, IndexerError, FIELD_ID};
+use crate::proto::rpc::lookr_client::LookrClient;
+use crate::proto::rpc::mutation::Change;
+use crate::proto::rpc::{DeleteTerm, Mutation, ReplicateReq, ReplicationBatch};
+use std::error;
+use std::io;
+use std::mem;
+use std::ops::{Deref, DerefMut};
+use std::sync::Mutex;
+use std::time::Duration;
+use tantivy::schema::Schema;
+use tantivy::{DocAddress, Document, Index, IndexWriter, Opstamp, Searcher, Term};
+use tokio::sync::mpsc::{self, error::TrySendError, Receiver, Sender};
+
+/// How many committed batches can wait to be sent to a standby. One that
+/// falls further behind than this is disconnected, and gets a new copy of
+/// the index when it reconnects.
+const REPLICATION_QUEUE: usize = 1024;
+
+/// How many documents go in each batch of a copy of the index.
+pub(crate) const COPY_BATCH: usize = 1000;
+
+/// How long a standby waits before reconnecting to its primary, doubling
+/// with each failure up to `MAX_RECONNECT_DELAY`.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+/// The index writer, recording what's written while there are standbys, and
+/// sending it to them once it's committed. Everything else is the
+/// `IndexWriter`'s own.
+pub(crate) struct Writer {
+    writer: IndexWriter,
+    schema: Schema,
+    pending: Mutex<Vec<Mutation>>,
+    standbys: Vec<Sender<ReplicationBatch>>,
+    /// Set while a copy of the primary's index is being applied, until its
+    /// last batch.
+    copying: bool,
+}
+
+impl Writer {
+    pub(crate) fn new(writer: IndexWriter, schema: Schema) -> Self {
+        Writer {
+            writer,
+            schema,
+            pending: Mutex::new(Vec::new()),
+            standbys: Vec::new(),
+            copying: false,
+        }
+    }
+
+    pub(crate) fn into_inner(self) -> IndexWriter {
+        self.writer
+    }
+
+    pub(crate) fn add_document(&self, doc: Document) -> Opstamp {
+        if !self.standbys.is_empty() {
+            self.record(Change::Add(self.schema.to_json(&doc)));
+        }
+        self.writer.add_document(doc)
+    }
+
+    /// Throw away everything written since the last commit, including what
+    /// was recorded of it.
+    pub(crate) fn rollback(&mut self) -> tantivy::Result<Opstamp> {
+        self.pending.get_mut().unwrap().clear();
+        self.writer.rollback()
+    }
+
+    pub(crate) fn delete_term(&self, term: Term) -> Opstamp {
+        if !self.standbys.is_empty() {
+            self.record(Change::Delete(DeleteTerm {
+                field: self.schema.get_field_name(term.field()).to_string(),
+                text: term.text().to_string(),
+            }));
+        }
+        self.writer.delete_term(term)
+    }
+
+    fn record(&self, change: Change) {
+        self.pending.lock().unwrap().push(Mutation {
+            change: Some(change),
+        });
+    }
+
+    /// Commit, then send what was committed to the standbys. Part way
+    /// through a copy of the primary's index nothing is committed, however
+    /// the commit comes about, so a standby never shows half of one.
+    pub(crate) fn commit(&mut self) -> tantivy::Result<Opstamp> {
+        if self.copying {
+            debug!("Not committing part way through a copy of the primary's index");
+            return Ok(self.writer.commit_opstamp());
+        }
+        let opstamp = self.writer.commit()?;
+        let mutations = mem::take(self.pending.get_mut().unwrap());
+        if !mutations.is_empty() {
+            self.publish(ReplicationBatch {
+                reset: false,
+                more: false,
+                mutations,
+            });
+        }
+        Ok(opstamp)
+    }
+
+    /// Send the batch to each standby, dropping those that have gone or
+    /// fallen too far behind.
+    fn publish(&mut self, batch: ReplicationBatch) {
+        self.standbys
+            .retain_mut(|standby| match standby.try_send(batch.clone()) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    warn!("A standby has fallen behind, disconnecting it");
+                    false
+                }
+                Err(TrySendError::Closed(_)) => {
+                    info!("A standby has disconnected");
+                    false
+                }
+            });
+    }
+
+    /// Start recording for another standby, returning what it's sent. Only
+    /// what's written from now on is recorded, so everything written before
+    /// has to be committed first and copied to it another way.
+    pub(crate) fn add_standby(&mut self) -> Receiver<ReplicationBatch> {
+        let (tx, rx) = mpsc::channel(REPLICATION_QUEUE);
+        self.standbys.push(tx);
+        rx
+    }
+}
+
+impl Deref for Writer {
+    type Target = IndexWriter;
+
+    fn deref(&self) -> &IndexWriter {
+        &self.writer
+    }
+}
+
+impl DerefMut for Writer {
+    fn deref_mut(&mut self) -> &mut IndexWriter {
+        &mut self.writer
+    }
+}
+
+/// A copy of everything the searcher sees, `COPY_BATCH` documents at a time,
+/// passed to `send` until it returns false. The first batch resets the
+/// standby's index, and all but the last say there's more to come.
+pub(crate) fn copy_index<F>(
+    searcher: &Searcher,
+    fields: &Fields,
+    substring_index: bool,
+    mut send: F,
+) -> tantivy::Result<()>
+where
+    F: FnMut(ReplicationBatch) -> bool,
+{
+    let schema = searcher.schema();
+    let mut batch = ReplicationBatch {
+        reset: true,
+        more: true,
+        mutations: Vec::new(),
+    };
+    for (segment_ord, reader) in searcher.segment_readers().iter().enumerate() {
+        for doc_id in 0..reader.max_doc() {
+            if reader.is_deleted(doc_id) {
+                continue;
+            }
+            let stored = searcher.doc(DocAddress(segment_ord as u32, doc_id))?;
+            let doc = fields.restore(&stored, substring_index);
+            batch.mutations.push(Mutation {
+                change: Some(Change::Add(schema.to_json(&doc))),
+            });
+            if batch.mutations.len() == COPY_BATCH {
+                let next = ReplicationBatch {
+                    reset: false,
+                    more: true,
+                    mutations: Vec::new(),
+                };
+                if !send(mem::replace(&mut batch, next)) {
+                    return Ok(());
+                }
+            }
+        }
+    }
+    batch.more = false;
+    send(batch);
+    Ok(())
+}
+
+/// Apply a batch from the primary to the standby's writer, without
+/// committing. A reset first throws away what's uncommitted, and deletes
+/// everything committed to `index`. Until the last batch of a copy, the
+/// writer won't commit.
+pub(crate) fn apply(
+    writer: &mut Writer,
+    index: &Index,
+    batch: ReplicationBatch,
+) -> Result<(), IndexerError> {
+    let invalid = |msg| IndexerError::IoError(io::Error::new(io::ErrorKind::InvalidData, msg));
+    let schema = index.schema();
+    let id_field = schema.get_field(FIELD_ID).unwrap();
+    writer.copying = batch.more;
+    if batch.reset {
+        writer.rollback()?;
+        let searcher = index.reader()?.searcher();
+        for reader in searcher.segment_readers() {
+            let inverted_index = reader.inverted_index(id_field);
+            let mut ids = inverted_index.terms().stream();
+            while ids.advance() {
+                let id = String::from_utf8_lossy(ids.key());
+                writer.delete_term(Term::from_field_text(id_field, &id));
+            }
+        }
+    }
+    for mutation in batch.mutations {
+        match mutation.change {
+            Some(Change::Add(json)) => {
+                let doc = schema.parse_document(&json).map_err(|e| {
+                    invalid(format!(
+                        "Could not read a document from the primary: {:?}",
+                        e
+                    ))
+                })?;
+                // Copies can overlap with the changes sent after them, so
+                // nothing is added twice.
+                if let Some(id) = doc.get_first(id_field).and_then(|v| v.text()) {
+                    writer.delete_term(Term::from_field_text(id_field, id));
+                }
+                writer.add_document(doc);
+            }
+            Some(Change::Delete(delete)) => {
+                let field = schema.get_field(&delete.field).ok_or_else(|| {
+                    invalid(format!(
+                        "The primary deleted by an unknown field {}",
+                        delete.field
+                    ))
+                })?;
+                writer.delete_term(Term::from_field_text(field, &delete.text));
+            }
+            None => (),
+        }
+    }
+    Ok(())
+}
+
+/// Stream the primary's index and changes to it, passing each batch to
+/// `apply` until it returns false. Whenever the stream ends or fails, the
+/// primary is connected to again after a delay, and sends a new copy.
+pub(crate) fn follow<F>(primary: &str, mut apply: F)
+where
+    F: FnMut(ReplicationBatch) -> bool,
+{
+    let mut runtime = match tokio::runtime::Builder::new()
+        .basic_scheduler()
+        .enable_all()
+        .build()
+    {
+        Ok(r) => r,
+        Err(e) => {
+            error!("Could not start replicating from {}: {}", primary, e);
+            return;
+        }
+    };
+    let mut delay = RECONNECT_DELAY;
+    loop {
+        let mut received = false;
+        let streamed = runtime.block_on(async {
+            let mut client = LookrClient::connect(primary.to_string()).await?;
+            let mut stream = client.replicate(ReplicateReq {}).await?.into_inner();
+            info!("Replicating the index from {}", primary);
+            while let Some(batch) = stream.message().await? {
+                received = true;
+                if !apply(batch) {
+                    return Ok(false);
+                }
+            }
+            Ok::<_, Box<dyn error::Error>>(true)
+        });
+        match streamed {
+            Ok(false) => return,
+            Ok(true) => warn!("{} stopped replicating", primary),
+            Err(e) => warn!("Could not replicate from {}: {}", primary, e),
+        }
+        if received {
+            delay = RECONNECT_DELAY;
+        }
+        info!("Reconnecting to {} in {}s", primary, delay.as_secs());
+        std::thread::sleep(delay);
+        delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+    }
+}
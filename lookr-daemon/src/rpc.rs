@@ -1,94 +1,4022 @@
+use crate::audit::{AuditEntry, AuditLog};
+use crate::config::{LookrdConfig, DEFAULT_MAX_READY_WAIT_MS, DEFAULT_QUERY_TIMEOUT_MS};
+use crate::highlight;
+use crate::history::QueryHistory;
+use crate::indexer::{
+    live_docs, normalize_separators, subtree_query, Fields, IndexerCommand, IndexerHandle,
+    IndexerStats, Reindexed,
+};
+use crate::limits::{QueryLimits, Rejection};
 use crate::proto::rpc::lookr_server::Lookr;
-use crate::proto::rpc::{QueryReq, QueryResp, SecretPathReq, SecretPathResp};
-use tantivy::collector::TopDocs;
-use tantivy::query::QueryParser;
-use tantivy::schema::{Field, Schema};
-use tantivy::Index;
-use tonic::{Request, Response, Status};
-
-pub(crate) struct LookrService {
+use crate::proto::rpc::{
+    BatchQueryReq, BatchQueryResp, CompactReq, CompactResp, DirectoryCount, DocField,
+    DuplicateContent, DuplicateName, ErrorCode, ErrorDetails, FieldBoosts, FindDuplicateContentReq,
+    FindDuplicateContentResp, FindDuplicateNamesReq, FindDuplicateNamesResp, FindMarkerReq,
+    FindMarkerResp, GetDocReq, GetDocResp, HealthCheckReq, HealthCheckResp, ListRootsReq,
+    ListRootsResp, PauseReq, PauseResp, QueryCount, QueryReq, QueryResp, RecentQueriesReq,
+    RecentQueriesResp, RecentQuery, ReindexFileReq, ReindexFileResp, ReindexOutcome, ReindexReq,
+    ReindexResp, ReplicateReq, ReplicationBatch, ResumeReq, ResumeResp, Root, SearchResult,
+    SecretPathReq, SecretPathResp, SnapshotReq, SnapshotResp, SortBy, StatsReq, StatsResp,
+    VerifyReq, VerifyResp, VersionReq, VersionResp, WalkErrors,
+};
+use crate::query_cache::{Generation, NegativeCache, QueryCache};
+use crate::replication;
+use crate::scoring::{Recency, Rescorer, Similarity};
+use bytes::Bytes;
+use prost::Message;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ops::Bound;
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::TrySendError;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tantivy::collector::{Collector, Count, FacetCollector, TopDocs};
+use tantivy::query::{AllQuery, BooleanQuery, Occur, Query, QueryParser, RangeQuery, TermQuery};
+use tantivy::schema::{Facet, Field, IndexRecordOption, Schema, Value};
+use tantivy::{DocAddress, Document, Index, IndexReader, LeasedItem, ReloadPolicy, Searcher, Term};
+use tokio::sync::{mpsc, oneshot, OwnedSemaphorePermit};
+use tonic::{Code, Request, Response, Status};
+
+/// The request metadata a multi-user daemon reads the secret from.
+pub static SECRET_HEADER: &str = "lookr-secret";
+
+/// The number of results returned when the request doesn't specify a count.
+const DEFAULT_LIMIT: usize = 1000;
+
+/// The most results a request can ask for. The collector sets aside room
+/// for as many as are asked for, before it finds any.
+const MAX_LIMIT: usize = 100 * DEFAULT_LIMIT;
+
+/// The metadata a hit can have, which is only filled in if asked for.
+const METADATA_FIELDS: [&str; 1] = ["mtime"];
+
+/// The number of groups of duplicate names returned when the request doesn't
+/// say, and the most it can ask for.
+const DEFAULT_DUPLICATE_GROUPS: usize = 100;
+const MAX_DUPLICATE_GROUPS: usize = 1000;
+
+/// The number of most frequent queries returned when the request doesn't
+/// say.
+const DEFAULT_TOP_QUERIES: usize = 10;
+
+/// How often a query with `wait_for_ready` looks to see if the first walk
+/// is done.
+const READY_POLL: Duration = Duration::from_millis(50);
+
+#[derive(Clone)]
+pub struct LookrService {
     index: Index,
+    /// The one reader of a read-only index, which never changes. Otherwise
+    /// each query gets a reader of what's last committed.
+    reader: Option<IndexReader>,
     query_parser: QueryParser,
+    filename_query_parser: QueryParser,
+    first_line_query_parser: QueryParser,
+    substring_query_parser: Option<QueryParser>,
+    field_id: Field,
     field_path: Field,
+    field_original_path: Field,
+    field_filename: Field,
+    field_filename_ngram: Field,
+    field_name: Field,
+    field_ext: Field,
+    field_parent: Field,
+    field_tags: Field,
+    field_kind: Field,
+    field_inode: Field,
+    field_dev: Field,
+    field_mtime: Field,
+    field_uid: Field,
+    field_hash: Field,
+    field_xattr: Field,
+    field_depth: Field,
+    roots: Vec<String>,
+    query_timeout: Duration,
+    max_ready_wait: Duration,
+    /// `None` when serving a read-only index, which nothing changes.
+    indexer: Option<IndexerHandle>,
+    /// The indexer's, or none to speak of for a read-only index.
+    stats: Arc<IndexerStats>,
+    history: Option<Arc<QueryHistory>>,
+    query_cache: Option<Arc<QueryCache>>,
+    negative_cache: Option<Arc<NegativeCache>>,
+    audit_log: Option<Arc<AuditLog>>,
+    limits: Option<Arc<QueryLimits>>,
+    /// The user the index is kept for, in a multi-user daemon.
+    user: Option<String>,
+    split_words: bool,
+    default_and: bool,
+    /// Lowercased, to compare with the lowercased words of queries.
+    stop_words: HashSet<String>,
+    /// The configured groups, with their extensions as they're indexed.
+    extension_groups: HashMap<String, Vec<String>>,
+    index_inodes: bool,
+    index_owners: bool,
+    content_hashes: bool,
+    index_xattrs: bool,
+    serve_replication: bool,
+    /// Whether this is a standby, with `replicate_from`, which has nothing
+    /// of its own to walk or index.
+    standby: bool,
+    normalize_separators: bool,
+    /// Whose files `owned_by_me` finds.
+    owner_uid: Option<u32>,
+    /// How matches are scored, if not by tantivy's own BM25.
+    similarity: Option<Similarity>,
+    recency_weight: Option<f32>,
 }
 
 impl LookrService {
-    pub fn new(index: Index, schema: Schema) -> Self {
+    pub fn new(
+        index: Index,
+        schema: Schema,
+        config: &LookrdConfig,
+        indexer: IndexerHandle,
+    ) -> Self {
+        LookrService::with_indexer(index, schema, config, Some(indexer))
+    }
+
+    /// Serve queries of an index without an indexer, like a snapshot opened
+    /// with `open_read_only`. Requests that would change it are refused.
+    pub fn read_only(index: Index, schema: Schema, config: &LookrdConfig) -> tantivy::Result<Self> {
+        crate::tokenizer::register(&index, config);
+        let reader = index
+            .reader_builder()
+            .reload_policy(ReloadPolicy::Manual)
+            .try_into()?;
+        Ok(LookrService {
+            reader: Some(reader),
+            ..LookrService::with_indexer(index, schema, config, None)
+        })
+    }
+
+    fn with_indexer(
+        index: Index,
+        schema: Schema,
+        config: &LookrdConfig,
+        indexer: Option<IndexerHandle>,
+    ) -> Self {
+        let field_id = schema.get_field(crate::indexer::FIELD_ID).unwrap();
         let field_path = schema.get_field(crate::indexer::FIELD_PATH).unwrap();
-        let query_parser = QueryParser::for_index(&index, vec![field_path]);
+        let field_parent = schema.get_field(crate::indexer::FIELD_PARENT).unwrap();
+        let field_tags = schema.get_field(crate::indexer::FIELD_TAGS).unwrap();
+        let field_kind = schema.get_field(crate::indexer::FIELD_KIND).unwrap();
+        let field_filename = schema.get_field(crate::indexer::FIELD_FILENAME).unwrap();
+        let field_ext = schema.get_field(crate::indexer::FIELD_EXT).unwrap();
+        let field_inode = schema.get_field(crate::indexer::FIELD_INODE).unwrap();
+        let field_dev = schema.get_field(crate::indexer::FIELD_DEV).unwrap();
+        let field_mtime = schema.get_field(crate::indexer::FIELD_MTIME).unwrap();
+        let field_name = schema.get_field(crate::indexer::FIELD_NAME).unwrap();
+        let field_original_path = schema
+            .get_field(crate::indexer::FIELD_ORIGINAL_PATH)
+            .unwrap();
+        let field_uid = schema.get_field(crate::indexer::FIELD_UID).unwrap();
+        let field_hash = schema.get_field(crate::indexer::FIELD_HASH).unwrap();
+        let field_xattr = schema.get_field(crate::indexer::FIELD_XATTR).unwrap();
+        let field_depth = schema.get_field(crate::indexer::FIELD_DEPTH).unwrap();
+        let field_first_line = schema.get_field(crate::indexer::FIELD_FIRSTLINE).unwrap();
+        let field_filename_ngram = schema
+            .get_field(crate::indexer::FIELD_FILENAME_NGRAM)
+            .unwrap();
+        let query_parser_for = |fields| {
+            let mut query_parser = QueryParser::for_index(&index, fields);
+            if config.default_and {
+                query_parser.set_conjunction_by_default();
+            }
+            query_parser
+        };
+        // The words of filenames are only indexed in the filename field.
+        let query_parser = if config.split_words {
+            query_parser_for(vec![field_path, field_filename])
+        } else {
+            query_parser_for(vec![field_path])
+        };
+        let filename_query_parser = query_parser_for(vec![field_filename]);
+        let first_line_query_parser = query_parser_for(vec![field_first_line]);
+        let substring_query_parser = if config.substring_index {
+            Some(query_parser_for(vec![field_filename_ngram]))
+        } else {
+            None
+        };
         LookrService {
             index,
+            reader: None,
             query_parser,
+            filename_query_parser,
+            first_line_query_parser,
+            substring_query_parser,
+            field_id,
             field_path,
+            field_original_path,
+            field_filename,
+            field_filename_ngram,
+            field_name,
+            field_ext,
+            field_parent,
+            field_tags,
+            field_kind,
+            field_inode,
+            field_dev,
+            field_mtime,
+            field_uid,
+            field_hash,
+            field_xattr,
+            field_depth,
+            roots: config.index_paths.clone(),
+            query_timeout: Duration::from_millis(
+                config.query_timeout_ms.unwrap_or(DEFAULT_QUERY_TIMEOUT_MS),
+            ),
+            max_ready_wait: Duration::from_millis(
+                config
+                    .max_ready_wait_ms
+                    .unwrap_or(DEFAULT_MAX_READY_WAIT_MS),
+            ),
+            // Nothing changes a read-only index, so it's as ready as it'll be.
+            stats: indexer.as_ref().map_or_else(
+                || {
+                    Arc::new(IndexerStats {
+                        ready: true.into(),
+                        ..Default::default()
+                    })
+                },
+                |i| i.stats.clone(),
+            ),
+            indexer,
+            history: config.query_history.map(|n| Arc::new(QueryHistory::new(n))),
+            query_cache: config
+                .query_cache_size
+                .map(|n| Arc::new(QueryCache::new(n))),
+            negative_cache: config
+                .negative_cache_size
+                .map(|n| Arc::new(NegativeCache::new(n))),
+            audit_log: None,
+            limits: None,
+            user: None,
+            split_words: config.split_words,
+            default_and: config.default_and,
+            stop_words: config.stop_words.iter().map(|w| w.to_lowercase()).collect(),
+            extension_groups: config
+                .extension_groups
+                .iter()
+                .map(|(name, extensions)| {
+                    let extensions = extensions
+                        .iter()
+                        .map(|e| e.trim_start_matches('.').to_lowercase())
+                        .filter(|e| !e.is_empty())
+                        .collect();
+                    (name.clone(), extensions)
+                })
+                .collect(),
+            index_inodes: config.index_inodes,
+            index_owners: config.index_owners,
+            index_xattrs: !config.index_xattrs.is_empty(),
+            content_hashes: config.content_hash_max_size.is_some(),
+            serve_replication: config.serve_replication,
+            standby: config.replicate_from.is_some(),
+            normalize_separators: config.normalize_separators,
+            // Made once any `run_as_user` has taken effect.
+            #[cfg(unix)]
+            owner_uid: Some(nix::unistd::geteuid().as_raw()),
+            #[cfg(not(unix))]
+            owner_uid: None,
+            similarity: Similarity::from_config(config),
+            recency_weight: config.recency_weight,
         }
     }
-}
 
-#[tonic::async_trait]
-impl Lookr for LookrService {
-    async fn query(&self, req: Request<QueryReq>) -> Result<Response<QueryResp>, Status> {
-        let query = &req.get_ref().query;
+    /// Record every query answered in `audit_log`, which can be shared with
+    /// the other users' services.
+    pub fn with_audit_log(mut self, audit_log: Arc<AuditLog>) -> Self {
+        self.audit_log = Some(audit_log);
+        self
+    }
 
-        let results = {
-            let searcher = match self.index.reader() {
-                Ok(r) => r.searcher(),
-                Err(e) => {
-                    error!("{}", e);
-                    return Err(Status::internal(format!("Index reader error: {}", e)));
+    /// Turn away queries past the limits, which can be shared with the other
+    /// users' services.
+    pub fn with_limits(mut self, limits: Arc<QueryLimits>) -> Self {
+        self.limits = Some(limits);
+        self
+    }
+
+    /// Name the user the queries are answered for, in the audit log.
+    pub fn for_user(mut self, user: &str) -> Self {
+        self.user = Some(user.to_string());
+        self
+    }
+
+    /// Answer `owned_by_me` queries with the files owned by `uid`, rather
+    /// than by the user the daemon runs as.
+    pub fn with_owner_uid(mut self, uid: u32) -> Self {
+        self.owner_uid = Some(uid);
+        self
+    }
+
+    /// Add the query to the audit log, if there is one.
+    fn audit<T>(&self, req: &Request<T>, query: &str, resp: &QueryResp) {
+        if let Some(audit_log) = &self.audit_log {
+            audit_log.record(AuditEntry::now(
+                self.user.clone(),
+                req.remote_addr().map(|a| a.to_string()),
+                query,
+                resp.total_hits,
+                resp.results.len() as u64,
+            ));
+        }
+    }
+
+    /// Whether the first walk of the index paths is done, waiting up to
+    /// `max_ready_wait` for it if asked to. This is before the query is
+    /// admitted, so it doesn't hold a place to run queries while it waits.
+    async fn ready(&self, wait: bool) -> bool {
+        let ready = || self.stats.ready.load(Ordering::Relaxed);
+        if wait && !ready() {
+            // A wait too long to add to now has no deadline.
+            let deadline = Instant::now().checked_add(self.max_ready_wait);
+            while !ready() {
+                let poll = match deadline {
+                    Some(deadline) => {
+                        let now = Instant::now();
+                        if now >= deadline {
+                            break;
+                        }
+                        READY_POLL.min(deadline - now)
+                    }
+                    None => READY_POLL,
+                };
+                tokio::time::delay_for(poll).await;
+            }
+        }
+        ready()
+    }
+
+    /// A place to run the request's queries, held until the permit is
+    /// dropped, or `resource_exhausted` if it's over the limits.
+    fn admit<T>(&self, req: &Request<T>) -> Result<Option<OwnedSemaphorePermit>, Status> {
+        let limits = match &self.limits {
+            Some(l) => l,
+            None => return Ok(None),
+        };
+        match limits.admit(req.remote_addr().map(|a| a.ip())) {
+            Ok(permit) => Ok(Some(permit)),
+            Err(Rejection::Busy) => {
+                warn!("Turned away a query, too many are running");
+                Err(error_status(
+                    ErrorCode::Overloaded,
+                    "Too many queries are running, try again shortly",
+                ))
+            }
+            Err(Rejection::RateLimited) => {
+                warn!(
+                    "Turned away a query from {:?}, over its rate",
+                    req.remote_addr()
+                );
+                Err(error_status(
+                    ErrorCode::Overloaded,
+                    "Too many queries from this client, try again shortly",
+                ))
+            }
+        }
+    }
+
+    /// The indexer, or a refusal if the index is read-only.
+    fn indexer(&self) -> Result<&IndexerHandle, Status> {
+        self.indexer.as_ref().ok_or_else(|| {
+            error_status(
+                ErrorCode::ReadOnly,
+                "The index is read-only, served without an indexer",
+            )
+        })
+    }
+
+    /// Refuse what a standby can't do, with nothing of its own to index.
+    fn not_standby(&self, what: &str) -> Result<(), Status> {
+        if self.standby {
+            return Err(error_status(
+                ErrorCode::InvalidRequest,
+                format!(
+                    "A standby can't {}, it copies its index from replicate_from",
+                    what
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    fn searcher(&self) -> Result<LeasedItem<Searcher>, Status> {
+        if let Some(reader) = &self.reader {
+            return Ok(reader.searcher());
+        }
+        match self.index.reader() {
+            Ok(r) => Ok(r.searcher()),
+            Err(e) => {
+                error!("{}", e);
+                Err(error_status(
+                    ErrorCode::IndexUnavailable,
+                    format!("Index reader error: {}", e),
+                ))
+            }
+        }
+    }
+
+    /// Run blocking search work off the async runtime, failing with
+    /// `deadline_exceeded` if it takes longer than the query timeout. The
+    /// work itself can't be interrupted, so carries on to completion in the
+    /// background.
+    async fn blocking<T, F>(&self, f: F) -> Result<T, Status>
+    where
+        F: FnOnce() -> Result<T, Status> + Send + 'static,
+        T: Send + 'static,
+    {
+        let task = tokio::task::spawn_blocking(f);
+        match tokio::time::timeout(self.query_timeout, task).await {
+            Ok(Ok(r)) => r,
+            Ok(Err(e)) => {
+                error!("{}", e);
+                Err(error_status(
+                    ErrorCode::Internal,
+                    format!("Search failed: {}", e),
+                ))
+            }
+            Err(_) => {
+                warn!("Query timed out after {}ms", self.query_timeout.as_millis());
+                Err(error_status(
+                    ErrorCode::Timeout,
+                    format!(
+                        "Query took longer than {}ms",
+                        self.query_timeout.as_millis()
+                    ),
+                ))
+            }
+        }
+    }
+
+    /// Matches files with any of the extensions in the named group.
+    fn ext_group_query(&self, name: &str) -> Result<BooleanQuery, Status> {
+        let extensions = match self.extension_groups.get(name) {
+            Some(extensions) => extensions,
+            None => {
+                return Err(error_status(
+                    ErrorCode::InvalidRequest,
+                    format!("The daemon's config has no extension group {}", name),
+                ))
+            }
+        };
+        let clauses: Vec<(Occur, Box<dyn Query>)> = extensions
+            .iter()
+            .map(|ext| (Occur::Should, self.ext_query(ext)))
+            .collect();
+        Ok(BooleanQuery::from(clauses))
+    }
+
+    /// Matches entries from `min` to `max` levels below their index path, or
+    /// any number from `min` if `max` is 0.
+    fn depth_query(&self, min: u32, max: u32) -> Result<RangeQuery, Status> {
+        let upper = match max {
+            0 => Bound::Unbounded,
+            max if max < min => {
+                return Err(error_status(
+                    ErrorCode::InvalidRequest,
+                    format!("max_depth {} is less than min_depth {}", max, min),
+                ))
+            }
+            max => Bound::Included(u64::from(max)),
+        };
+        Ok(RangeQuery::new_u64_bounds(
+            self.field_depth,
+            Bound::Included(u64::from(min)),
+            upper,
+        ))
+    }
+
+    /// Matches files with the extension, given with or without its dot in
+    /// any case.
+    fn ext_query(&self, ext: &str) -> Box<dyn Query> {
+        let ext = ext.trim_start_matches('.').to_lowercase();
+        let term = Term::from_field_text(self.field_ext, &ext);
+        Box::new(TermQuery::new(term, IndexRecordOption::Basic))
+    }
+
+    /// Parse the request's query and combine it with the filters it asks for.
+    fn build_query(&self, searcher: &Searcher, req: &QueryReq) -> Result<Box<dyn Query>, Status> {
+        let mut filters: Vec<Box<dyn Query>> = Vec::new();
+        if !req.tag.is_empty() {
+            let term = Term::from_field_text(self.field_tags, &req.tag);
+            filters.push(Box::new(TermQuery::new(term, IndexRecordOption::Basic)));
+        }
+        if !req.kind.is_empty() {
+            let term = Term::from_field_text(self.field_kind, &req.kind);
+            filters.push(Box::new(TermQuery::new(term, IndexRecordOption::Basic)));
+        }
+        if !req.ext_group.is_empty() {
+            filters.push(Box::new(self.ext_group_query(&req.ext_group)?));
+        }
+        let extensions = |exts: &[String]| {
+            exts.iter()
+                .filter(|e| !e.trim_start_matches('.').is_empty())
+                .map(|e| self.ext_query(e))
+                .collect::<Vec<_>>()
+        };
+        let included = extensions(&req.extensions);
+        if !included.is_empty() {
+            let clauses: Vec<(Occur, Box<dyn Query>)> =
+                included.into_iter().map(|q| (Occur::Should, q)).collect();
+            filters.push(Box::new(BooleanQuery::from(clauses)));
+        }
+        let excluded = extensions(&req.exclude_extensions);
+        if !req.within.is_empty() {
+            if !Path::new(&req.within).is_absolute() {
+                return Err(error_status(
+                    ErrorCode::InvalidRequest,
+                    "within must be an absolute path",
+                ));
+            }
+            filters.push(Box::new(subtree_query(
+                self.field_id,
+                &self.path_id(&req.within),
+            )));
+        }
+        if !req.same_file_as.is_empty() {
+            filters.push(Box::new(self.same_file_query(searcher, &req.same_file_as)?));
+        }
+        if req.owned_by_me {
+            let uid = match self.owner_uid {
+                Some(uid) if self.index_owners => uid,
+                _ => {
+                    return Err(error_status(
+                        ErrorCode::InvalidRequest,
+                        "Finding your own files needs index_owners in the daemon's config",
+                    ))
                 }
             };
-
-            let query_promo = match self.query_parser.parse_query(query) {
-                Ok(q) => q,
+            let term = Term::from_field_u64(self.field_uid, u64::from(uid));
+            filters.push(Box::new(TermQuery::new(term, IndexRecordOption::Basic)));
+        }
+        if !req.xattr.is_empty() {
+            if !self.index_xattrs {
+                return Err(error_status(
+                    ErrorCode::InvalidRequest,
+                    "Finding files by attribute needs index_xattrs in the daemon's config",
+                ));
+            }
+            let term = Term::from_field_text(self.field_xattr, &req.xattr);
+            filters.push(Box::new(TermQuery::new(term, IndexRecordOption::Basic)));
+        }
+        if req.min_depth > 0 || req.max_depth > 0 {
+            filters.push(Box::new(self.depth_query(req.min_depth, req.max_depth)?));
+        }
+        if !req.first_line.trim().is_empty() {
+            match self.first_line_query_parser.parse_query(&req.first_line) {
+                Ok(q) => filters.push(q),
                 Err(e) => {
                     error!("{}", e);
-                    return Err(Status::internal(format!("Could not parse query: {}", e)));
+                    return Err(error_status(
+                        ErrorCode::QueryParse,
+                        format!("Could not parse query: {}", e),
+                    ));
                 }
-            };
+            }
+        }
 
-            let top_docs: Vec<(f32, tantivy::DocAddress)> =
-                match searcher.search(&query_promo, &TopDocs::with_limit(1000)) {
-                    Ok(r) => r,
-                    Err(e) => {
-                        error!("{}", e);
-                        return Err(Status::internal(format!("Could not search: {}", e)));
-                    }
-                };
-            let mut results = Vec::with_capacity(top_docs.len());
-
-            for (_, doc_addr) in top_docs {
-                match searcher.doc(doc_addr) {
-                    Ok(d) => {
-                        // TODO: fix, like, all of this...
-                        match d.get_first(self.field_path).unwrap() {
-                            tantivy::schema::Value::Str(s) => {
-                                results.push(s.clone());
-                            }
-                            _ => (),
+        // With only filters given, match everything they let through.
+        let boosted;
+        let query: Box<dyn Query> =
+            if req.query.trim().is_empty() && !(filters.is_empty() && excluded.is_empty()) {
+                Box::new(AllQuery)
+            } else {
+                let query_parser = if req.substring {
+                    match &self.substring_query_parser {
+                        Some(p) => p,
+                        None => {
+                            return Err(error_status(
+                                ErrorCode::InvalidRequest,
+                                "Substring queries need substring_index in the daemon's config",
+                            ))
                         }
                     }
+                } else if req.filename_only {
+                    &self.filename_query_parser
+                } else if let Some(boosts) = &req.boosts {
+                    boosted = self.boosted_query_parser(boosts)?;
+                    &boosted
+                } else {
+                    &self.query_parser
+                };
+                let text = if req.keep_stop_words {
+                    Cow::Borrowed(req.query.as_str())
+                } else {
+                    self.without_stop_words(&req.query)
+                };
+                match query_parser.parse_query(&text) {
+                    Ok(q) => q,
                     Err(e) => {
-                        error!(
-                            "Could not load DocAddress ({:?}) from searcher: {}",
-                            doc_addr, e
-                        );
+                        error!("{}", e);
+                        return Err(error_status(
+                            ErrorCode::QueryParse,
+                            format!("Could not parse query: {}", e),
+                        ));
+                    }
+                }
+            };
+
+        if filters.is_empty() && excluded.is_empty() {
+            return Ok(query);
+        }
+        let clauses = std::iter::once(query)
+            .chain(filters)
+            .map(|q| (Occur::Must, q))
+            .chain(excluded.into_iter().map(|q| (Occur::MustNot, q)))
+            .collect::<Vec<_>>();
+        Ok(Box::new(BooleanQuery::from(clauses)))
+    }
+
+    /// The query with the stop words left out. Only whole words outside of
+    /// quotes are dropped, and if they're all stop words they're all kept.
+    fn without_stop_words<'a>(&self, query: &'a str) -> Cow<'a, str> {
+        if self.stop_words.is_empty() {
+            return Cow::Borrowed(query);
+        }
+        let mut quoted = false;
+        let mut dropped = false;
+        let mut kept = Vec::new();
+        for word in query.split_whitespace() {
+            let quotes = word.matches('"').count();
+            if !quoted && quotes == 0 && self.stop_words.contains(&word.to_lowercase()) {
+                dropped = true;
+            } else {
+                kept.push(word);
+            }
+            quoted ^= quotes % 2 == 1;
+        }
+        if !dropped || kept.is_empty() {
+            Cow::Borrowed(query)
+        } else {
+            Cow::Owned(kept.join(" "))
+        }
+    }
+
+    /// A query matching the entries with the same inode and device as the one
+    /// indexed at `path`, other than itself.
+    fn same_file_query(&self, searcher: &Searcher, path: &str) -> Result<BooleanQuery, Status> {
+        if !self.index_inodes {
+            return Err(error_status(
+                ErrorCode::InvalidRequest,
+                "Finding hard links needs index_inodes in the daemon's config",
+            ));
+        }
+        if !Path::new(path).is_absolute() {
+            return Err(error_status(
+                ErrorCode::InvalidRequest,
+                "same_file_as must be an absolute path",
+            ));
+        }
+        let not_indexed = || {
+            error_status(
+                ErrorCode::InvalidRequest,
+                format!("{} isn't indexed with its inode", path),
+            )
+        };
+        let doc = match self.indexed_doc(searcher, path)? {
+            Some(doc) => doc,
+            None => return Err(not_indexed()),
+        };
+        let id = Term::from_field_text(self.field_id, &self.path_id(path));
+        let (inode, dev) = match (
+            doc.get_first(self.field_inode),
+            doc.get_first(self.field_dev),
+        ) {
+            (Some(Value::U64(inode)), Some(Value::U64(dev))) => (*inode, *dev),
+            _ => return Err(not_indexed()),
+        };
+
+        let term = |field, value| -> Box<dyn Query> {
+            Box::new(TermQuery::new(
+                Term::from_field_u64(field, value),
+                IndexRecordOption::Basic,
+            ))
+        };
+        Ok(BooleanQuery::from(vec![
+            (Occur::Must, term(self.field_inode, inode)),
+            (Occur::Must, term(self.field_dev, dev)),
+            (
+                Occur::MustNot,
+                Box::new(TermQuery::new(id, IndexRecordOption::Basic)) as Box<dyn Query>,
+            ),
+        ]))
+    }
+
+    /// The document indexed for exactly `path`, if there is one.
+    fn indexed_doc(&self, searcher: &Searcher, path: &str) -> Result<Option<Document>, Status> {
+        let search_error =
+            |e| error_status(ErrorCode::Internal, format!("Could not search: {}", e));
+        let id = Term::from_field_text(self.field_id, &self.path_id(path));
+        let indexed = searcher
+            .search(
+                &TermQuery::new(id, IndexRecordOption::Basic),
+                &TopDocs::with_limit(1),
+            )
+            .map_err(search_error)?;
+        match indexed.first() {
+            Some((_, addr)) => Ok(Some(searcher.doc(*addr).map_err(search_error)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// The deepest of `start` and its ancestors with `marker` in it. The
+    /// marker's ID in each is searched for at once, and the deepest found
+    /// wins.
+    fn marker_dir(
+        &self,
+        searcher: &Searcher,
+        start: &Path,
+        marker: &str,
+    ) -> Result<Option<String>, Status> {
+        let search_error =
+            |e| error_status(ErrorCode::Internal, format!("Could not search: {}", e));
+        let dirs: Vec<(&Path, String)> = start
+            .ancestors()
+            .map(|dir| {
+                let id = self
+                    .path_id(&dir.join(marker).to_string_lossy())
+                    .into_owned();
+                (dir, id)
+            })
+            .collect();
+        let clauses: Vec<(Occur, Box<dyn Query>)> = dirs
+            .iter()
+            .map(|(_, id)| {
+                let term = Term::from_field_text(self.field_id, id);
+                let query: Box<dyn Query> =
+                    Box::new(TermQuery::new(term, IndexRecordOption::Basic));
+                (Occur::Should, query)
+            })
+            .collect();
+        let found = searcher
+            .search(
+                &BooleanQuery::from(clauses),
+                &TopDocs::with_limit(dirs.len()),
+            )
+            .map_err(search_error)?;
+        let mut found_ids = HashSet::new();
+        for (_, addr) in found {
+            let doc = searcher.doc(addr).map_err(search_error)?;
+            if let Some(id) = doc.get_first(self.field_id).and_then(|v| v.text()) {
+                found_ids.insert(id.to_string());
+            }
+        }
+        Ok(dirs
+            .into_iter()
+            .find(|(_, id)| found_ids.contains(id))
+            .map(|(dir, _)| dir.to_string_lossy().to_string()))
+    }
+
+    /// A parser for the default fields with the request's boosts, which also
+    /// searches the filename and extension if they're boosted.
+    fn boosted_query_parser(&self, boosts: &FieldBoosts) -> Result<QueryParser, Status> {
+        for boost in [boosts.path, boosts.filename, boosts.ext] {
+            if boost != 0.0 && !(boost.is_finite() && boost > 0.0) {
+                return Err(error_status(
+                    ErrorCode::InvalidRequest,
+                    "Boosts must be positive numbers",
+                ));
+            }
+        }
+        let mut fields = vec![(self.field_path, boosts.path)];
+        if self.split_words || boosts.filename != 0.0 {
+            fields.push((self.field_filename, boosts.filename));
+        }
+        if boosts.ext != 0.0 {
+            fields.push((self.field_ext, boosts.ext));
+        }
+
+        let mut query_parser =
+            QueryParser::for_index(&self.index, fields.iter().map(|(f, _)| *f).collect());
+        if self.default_and {
+            query_parser.set_conjunction_by_default();
+        }
+        for (field, boost) in fields {
+            if boost != 0.0 {
+                query_parser.set_field_boost(field, boost);
+            }
+        }
+        Ok(query_parser)
+    }
+
+    /// Run a single query against the given searcher.
+    /// Search, or answer from the caches if the same request was searched
+    /// since the index last changed, or a broader one found nothing.
+    fn cached_search(&self, searcher: &Searcher, req: &QueryReq) -> Result<QueryResp, Status> {
+        if self.query_cache.is_none() && self.negative_cache.is_none() {
+            return self.search(searcher, req);
+        }
+        let generation = Generation::of(searcher);
+        let mut broader = self.broader_queries(req);
+        if let Some(cache) = &self.negative_cache {
+            if cache.contains(&generation, req, &broader) {
+                return Ok(QueryResp::default());
+            }
+        }
+        if let Some(cache) = &self.query_cache {
+            if let Some(resp) = cache.get(&generation, req) {
+                return Ok(resp);
+            }
+        }
+        let resp = self.search(searcher, req)?;
+        if let Some(cache) = &self.query_cache {
+            cache.insert(&generation, req, &resp);
+        }
+        if let (Some(cache), Some(query)) = (&self.negative_cache, broader.pop()) {
+            if resp.total_hits == 0 {
+                cache.insert(&generation, req, &query);
+            }
+        }
+        Ok(resp)
+    }
+
+    /// The queries that finding nothing means the request's would find
+    /// nothing too, the rest of it being the same, ending with its own.
+    ///
+    /// With `default_and`, every word added to a query narrows it, so those
+    /// made of its first few words are broader. That only holds for words
+    /// every tokenizer makes a term of, so not for stop words, which can be
+    /// dropped. Queries of such words are compared by their words, whatever
+    /// the spaces between them, and others as they are.
+    fn broader_queries(&self, req: &QueryReq) -> Vec<String> {
+        let words: Vec<&str> = req.query.split_whitespace().collect();
+        // Shorter words make no ngrams, and longer ones are dropped.
+        let plain = |w: &&str| {
+            (2..40).contains(&w.len())
+                && w.bytes().all(|b| b.is_ascii_alphanumeric())
+                && !matches!(*w, "AND" | "OR")
+        };
+        if words.is_empty() || !words.iter().all(plain) {
+            return vec![req.query.clone()];
+        }
+        if !self.default_and || (!self.stop_words.is_empty() && !req.keep_stop_words) {
+            return vec![words.join(" ")];
+        }
+        (1..=words.len()).map(|n| words[..n].join(" ")).collect()
+    }
+
+    fn search(&self, searcher: &Searcher, req: &QueryReq) -> Result<QueryResp, Status> {
+        let query = &req.query;
+        if req.count < 0 || req.offset < 0 {
+            return Err(error_status(
+                ErrorCode::InvalidRequest,
+                "count and offset must not be negative",
+            ));
+        }
+        let count = match req.count as usize {
+            0 => DEFAULT_LIMIT,
+            c if c > MAX_LIMIT => {
+                return Err(error_status(
+                    ErrorCode::InvalidRequest,
+                    format!("count must be at most {}", MAX_LIMIT),
+                ))
+            }
+            c => c,
+        };
+        let offset = req.offset as usize;
+        let sort_by = match SortBy::from_i32(req.sort_by) {
+            Some(s) => s,
+            None => return Err(error_status(ErrorCode::InvalidRequest, "Unknown sort_by")),
+        };
+        let result_fields = ResultFields::from_request(req)?;
+
+        let query_promo = self.build_query(searcher, req)?;
+
+        // TopDocs has no offset, so collect up to the end of the page and
+        // skip to the start of it. Other orders need every match to sort.
+        // There are never more matches than documents.
+        let num_docs = (searcher.num_docs() as usize).max(1);
+        let (limit, skip) = match sort_by {
+            SortBy::Relevance => ((offset + count).min(num_docs), offset),
+            _ => (num_docs, 0),
+        };
+        let top_docs_collector = TopDocs::with_limit(limit);
+        let search = if self.similarity.is_some() || self.recency_weight.is_some() {
+            let boosts = self.field_boosts(req);
+            let mut rescorer = Rescorer::new(searcher, &*query_promo, self.similarity, boosts);
+            if let Some(weight) = self.recency_weight {
+                rescorer = rescorer
+                    .with_recency(self.field_mtime, Recency::new(weight, SystemTime::now()));
+            }
+            let collector = top_docs_collector.tweak_score(rescorer);
+            self.collect(searcher, &*query_promo, collector, req.directories)
+        } else {
+            self.collect(searcher, &*query_promo, top_docs_collector, req.directories)
+        };
+        let (top_docs, total_hits, directories) = match search {
+            Ok(r) => r,
+            Err(e) => {
+                error!("{}", e);
+                return Err(error_status(
+                    ErrorCode::Internal,
+                    format!("Could not search: {}", e),
+                ));
+            }
+        };
+        let mut hits = Vec::with_capacity(top_docs.len().saturating_sub(skip));
+
+        for (score, doc_addr) in top_docs.into_iter().skip(skip) {
+            match searcher.doc(doc_addr) {
+                Ok(d) => {
+                    if let Some(hit) = self.search_result(&d, score, &result_fields) {
+                        hits.push(hit);
                     }
                 }
+                Err(e) => {
+                    error!(
+                        "Could not load DocAddress ({:?}) from searcher: {}",
+                        doc_addr, e
+                    );
+                }
+            }
+        }
+
+        if sort_by != SortBy::Relevance {
+            sort_results(&mut hits, sort_by);
+            hits = hits.into_iter().skip(offset).take(count).collect();
+        }
+        if req.highlight {
+            let terms = highlight::terms(
+                &*query_promo,
+                &[
+                    self.field_path,
+                    self.field_filename,
+                    self.field_filename_ngram,
+                ],
+            );
+            let marker = |m: &str| {
+                if m.is_empty() {
+                    highlight::DEFAULT_MARKER
+                } else {
+                    m
+                }
+                .to_string()
+            };
+            let (start, end) = (marker(&req.highlight_start), marker(&req.highlight_end));
+            for hit in &mut hits {
+                hit.highlighted = highlight::highlight(&hit.path, &terms, &start, &end);
+            }
+        }
+
+        debug!("Query: {:?} => {} results", query, hits.len());
+        let truncated = total_hits > offset + hits.len();
+        Ok(QueryResp {
+            results: hits.iter().map(|h| h.path.clone()).collect(),
+            directories,
+            total_hits: total_hits as u64,
+            truncated,
+            hits,
+            warming_up: false,
+        })
+    }
+
+    /// Collect the top matches and their count, and the number in each
+    /// directory if asked for.
+    fn collect<C>(
+        &self,
+        searcher: &Searcher,
+        query: &dyn Query,
+        top_docs_collector: C,
+        directories: bool,
+    ) -> tantivy::Result<Collected>
+    where
+        C: Collector<Fruit = Vec<(f32, DocAddress)>>,
+    {
+        if directories {
+            let mut facet_collector = FacetCollector::for_field(self.field_parent);
+            facet_collector.add_facet(Facet::root());
+            searcher
+                .search(query, &(top_docs_collector, Count, facet_collector))
+                .map(|(top_docs, total_hits, facet_counts)| {
+                    let directories = facet_counts
+                        .get(Facet::root())
+                        .map(|(facet, count)| DirectoryCount {
+                            path: facet.to_path().concat(),
+                            count,
+                        })
+                        .collect();
+                    (top_docs, total_hits, directories)
+                })
+        } else {
+            searcher
+                .search(query, &(top_docs_collector, Count))
+                .map(|(top_docs, total_hits)| (top_docs, total_hits, Vec::new()))
+        }
+    }
+
+    /// The boost the request's query gives each field, for rescoring.
+    fn field_boosts(&self, req: &QueryReq) -> impl Fn(Field) -> f32 {
+        // Only the default parser takes boosts.
+        let boosts = match &req.boosts {
+            Some(b) if !req.substring && !req.filename_only => b.clone(),
+            _ => FieldBoosts::default(),
+        };
+        let (path, filename, ext) = (self.field_path, self.field_filename, self.field_ext);
+        move |field| {
+            let boost = if field == path {
+                boosts.path
+            } else if field == filename {
+                boosts.filename
+            } else if field == ext {
+                boosts.ext
+            } else {
+                0.0
+            };
+            if boost == 0.0 {
+                1.0
+            } else {
+                boost
+            }
+        }
+    }
+
+    /// The ID `path` is indexed under.
+    fn path_id<'a>(&self, path: &'a str) -> Cow<'a, str> {
+        if self.normalize_separators {
+            normalize_separators(path)
+        } else {
+            Cow::Borrowed(path)
+        }
+    }
+
+    /// A page of the groups of entries sharing a name. The names come from the
+    /// name field's terms, so only those on more than one live document are
+    /// searched for.
+    fn duplicate_names(
+        &self,
+        searcher: &Searcher,
+        req: &FindDuplicateNamesReq,
+        count: usize,
+    ) -> tantivy::Result<FindDuplicateNamesResp> {
+        let (groups, next) =
+            self.duplicates(searcher, self.field_name, &req.after, &req.kind, count)?;
+        let groups = groups
+            .into_iter()
+            .map(|(name, count, paths)| DuplicateName { name, count, paths })
+            .collect();
+        Ok(FindDuplicateNamesResp { groups, next })
+    }
+
+    fn duplicate_content(
+        &self,
+        searcher: &Searcher,
+        req: &FindDuplicateContentReq,
+        count: usize,
+    ) -> tantivy::Result<FindDuplicateContentResp> {
+        let (groups, next) = self.duplicates(searcher, self.field_hash, &req.after, "", count)?;
+        let groups = groups
+            .into_iter()
+            .map(|(hash, count, paths)| DuplicateContent { hash, count, paths })
+            .collect();
+        Ok(FindDuplicateContentResp { groups, next })
+    }
+
+    /// Up to `count` of the terms of `field` after `after` that more than one
+    /// live document of the kind has, each with the number of documents and
+    /// the paths of the first of them. Also returns the after for the next
+    /// page, or an empty string if there are no more.
+    fn duplicates(
+        &self,
+        searcher: &Searcher,
+        field: Field,
+        after: &str,
+        kind: &str,
+        count: usize,
+    ) -> tantivy::Result<Duplicates> {
+        let mut counts: BTreeMap<String, u32> = BTreeMap::new();
+        for reader in searcher.segment_readers() {
+            let inverted_index = reader.inverted_index(field);
+            let mut terms = inverted_index.terms().range().gt(after).into_stream();
+            while terms.advance() {
+                let live = live_docs(reader, &inverted_index, terms.value());
+                if live > 0 {
+                    let name = String::from_utf8_lossy(terms.key()).into_owned();
+                    *counts.entry(name).or_insert(0) += live;
+                }
             }
+        }
 
-            results
+        let mut names = counts
+            .into_iter()
+            .filter(|(_, n)| *n > 1)
+            .map(|(name, _)| name)
+            .peekable();
+        let mut groups = Vec::new();
+        while groups.len() < count {
+            let name = match names.next() {
+                Some(name) => name,
+                None => break,
+            };
+            let mut clauses: Vec<(Occur, Box<dyn Query>)> = vec![(
+                Occur::Must,
+                Box::new(TermQuery::new(
+                    Term::from_field_text(field, &name),
+                    IndexRecordOption::Basic,
+                )),
+            )];
+            if !kind.is_empty() {
+                let term = Term::from_field_text(self.field_kind, kind);
+                clauses.push((
+                    Occur::Must,
+                    Box::new(TermQuery::new(term, IndexRecordOption::Basic)),
+                ));
+            }
+            let query = BooleanQuery::from(clauses);
+            let (top_docs, total) =
+                searcher.search(&query, &(TopDocs::with_limit(DEFAULT_LIMIT), Count))?;
+            if total < 2 {
+                continue;
+            }
+            let no_fields = ResultFields {
+                score: false,
+                mtime: false,
+            };
+            let mut paths = Vec::with_capacity(top_docs.len());
+            for (_, addr) in top_docs {
+                if let Some(hit) = self.search_result(&searcher.doc(addr)?, 0.0, &no_fields) {
+                    paths.push(hit.path);
+                }
+            }
+            paths.sort();
+            groups.push((name, total as u64, paths));
+        }
+        let next = match groups.last() {
+            Some((last, _, _)) if names.peek().is_some() => last.clone(),
+            _ => String::new(),
         };
+        Ok((groups, next))
+    }
+
+    /// The result for a matching document, with the fields asked for that it
+    /// has stored.
+    fn search_result(
+        &self,
+        doc: &Document,
+        score: f32,
+        fields: &ResultFields,
+    ) -> Option<SearchResult> {
+        let path = doc
+            .get_first(self.field_original_path)
+            .or_else(|| doc.get_first(self.field_path))?
+            .text()?
+            .to_string();
+        let mut metadata = HashMap::new();
+        if fields.mtime {
+            if let Some(Value::U64(mtime)) = doc.get_first(self.field_mtime) {
+                metadata.insert("mtime".to_string(), mtime.to_string());
+            }
+        }
+        Some(SearchResult {
+            path,
+            score: if fields.score { score } else { 0.0 },
+            metadata,
+            highlighted: String::new(),
+        })
+    }
+}
+
+/// Which fields of each hit a query wants filled in, besides the path.
+#[derive(Debug, PartialEq)]
+struct ResultFields {
+    score: bool,
+    mtime: bool,
+}
+
+impl ResultFields {
+    fn from_request(req: &QueryReq) -> Result<Self, Status> {
+        if req.fields.is_empty() {
+            return Ok(ResultFields {
+                score: true,
+                mtime: false,
+            });
+        }
+        for field in &req.fields {
+            if !matches!(field.as_str(), "path" | "score") && !METADATA_FIELDS.contains(&&**field) {
+                return Err(error_status(
+                    ErrorCode::InvalidRequest,
+                    format!(
+                        "Unknown field {:?}, expected path, score or one of {}",
+                        field,
+                        METADATA_FIELDS.join(", ")
+                    ),
+                ));
+            }
+        }
+        let wants = |name: &str| req.fields.iter().any(|f| f == name);
+        Ok(ResultFields {
+            score: wants("score"),
+            mtime: wants("mtime"),
+        })
+    }
+}
+
+/// The top matches with their scores, the number of matches in all, and the
+/// number in each directory.
+type Collected = (Vec<(f32, DocAddress)>, usize, Vec<DirectoryCount>);
+
+/// Terms shared by several documents, each with the number of documents and
+/// the paths of the first of them, and the after for the next page.
+type Duplicates = (Vec<(String, u64, Vec<String>)>, String);
+
+/// A status for the failure, carrying an `ErrorDetails` with its code so
+/// clients can tell failures apart without reading the message.
+fn error_status(code: ErrorCode, message: impl Into<String>) -> Status {
+    let status_code = match code {
+        ErrorCode::Internal => Code::Internal,
+        ErrorCode::InvalidRequest | ErrorCode::QueryParse => Code::InvalidArgument,
+        ErrorCode::IndexUnavailable | ErrorCode::IndexerBusy | ErrorCode::IndexerStopped => {
+            Code::Unavailable
+        }
+        ErrorCode::Timeout => Code::DeadlineExceeded,
+        ErrorCode::ReadOnly => Code::FailedPrecondition,
+        ErrorCode::Unauthenticated => Code::Unauthenticated,
+        ErrorCode::Overloaded => Code::ResourceExhausted,
+    };
+    let details = ErrorDetails { code: code as i32 };
+    let mut buf = Vec::with_capacity(details.encoded_len());
+    // Only fails if the buffer is too small.
+    details.encode(&mut buf).unwrap();
+    Status::with_details(status_code, message, Bytes::from(buf))
+}
 
-        debug!("Query: {:?} => {} results", query, results.len());
-        let resp = QueryResp { results };
+/// The number of groups of duplicates to return for a request's count.
+fn duplicate_groups(count: i32) -> Result<usize, Status> {
+    match count {
+        0 => Ok(DEFAULT_DUPLICATE_GROUPS),
+        c if c < 0 => Err(error_status(
+            ErrorCode::InvalidRequest,
+            "count must not be negative",
+        )),
+        c => Ok((c as usize).min(MAX_DUPLICATE_GROUPS)),
+    }
+}
+
+/// Sort results into any order but relevance, which they are collected in.
+fn sort_results(results: &mut [SearchResult], sort_by: SortBy) {
+    match sort_by {
+        SortBy::Relevance => (),
+        SortBy::PathDepth => results
+            .sort_by_cached_key(|r| (Path::new(&r.path).components().count(), r.path.clone())),
+        SortBy::Alphabetical => results.sort_by(|a, b| a.path.cmp(&b.path)),
+    }
+}
+
+#[tonic::async_trait]
+impl Lookr for LookrService {
+    async fn query(&self, req: Request<QueryReq>) -> Result<Response<QueryResp>, Status> {
+        let ready = self.ready(req.get_ref().wait_for_ready).await;
+        let permit = self.admit(&req)?;
+        let service = self.clone();
+        let query_req = req.get_ref().clone();
+        let mut resp = self
+            .blocking(move || {
+                // Held until the search is done, even if it times out.
+                let _permit = permit;
+                let searcher = service.searcher()?;
+                service.cached_search(&searcher, &query_req)
+            })
+            .await?;
+        // Set on the way out rather than cached, as the caches drop what
+        // they hold once the walk commits more anyway.
+        resp.warming_up = !ready;
 
+        let query = &req.get_ref().query;
+        if let Some(history) = &self.history {
+            history.record(query, resp.total_hits);
+        }
+        self.audit(&req, query, &resp);
         Ok(Response::new(resp))
     }
 
+    async fn batch_query(
+        &self,
+        req: Request<BatchQueryReq>,
+    ) -> Result<Response<BatchQueryResp>, Status> {
+        let wait = req.get_ref().queries.iter().any(|q| q.wait_for_ready);
+        let ready = self.ready(wait).await;
+        let permit = self.admit(&req)?;
+        let service = self.clone();
+        let queries = req.get_ref().queries.clone();
+        let mut responses = self
+            .blocking(move || {
+                let _permit = permit;
+                let searcher = service.searcher()?;
+                queries
+                    .iter()
+                    .map(|query| service.cached_search(&searcher, query))
+                    .collect::<Result<Vec<_>, _>>()
+            })
+            .await?;
+        for resp in &mut responses {
+            resp.warming_up = !ready;
+        }
+
+        for (query, resp) in req.get_ref().queries.iter().zip(&responses) {
+            self.audit(&req, &query.query, resp);
+        }
+        Ok(Response::new(BatchQueryResp { responses }))
+    }
+
+    /// Serving one index, there's no need for a secret.
     async fn get_secret_path(
         &self,
-        req: Request<SecretPathReq>,
+        _req: Request<SecretPathReq>,
     ) -> Result<Response<SecretPathResp>, Status> {
-        todo!()
+        Ok(Response::new(SecretPathResp::default()))
+    }
+
+    async fn stats(&self, _req: Request<StatsReq>) -> Result<Response<StatsResp>, Status> {
+        let service = self.clone();
+        let num_docs = self
+            .blocking(move || Ok(service.searcher()?.num_docs()))
+            .await?;
+        let walk_errors = *self.stats.walk_errors.lock().unwrap();
+
+        let resp = StatsResp {
+            num_docs,
+            walk_errors: Some(WalkErrors {
+                permission_denied: walk_errors.permission_denied,
+                not_found: walk_errors.not_found,
+                loops: walk_errors.loops,
+                other: walk_errors.other,
+            }),
+        };
+
+        Ok(Response::new(resp))
+    }
+
+    async fn list_roots(
+        &self,
+        _req: Request<ListRootsReq>,
+    ) -> Result<Response<ListRootsResp>, Status> {
+        let service = self.clone();
+        let doc_counts = self
+            .blocking(move || {
+                let searcher = service.searcher()?;
+                service
+                    .roots
+                    .iter()
+                    .map(|root| {
+                        match searcher.search(&subtree_query(service.field_id, root), &Count) {
+                            Ok(c) => Ok(c as u64),
+                            Err(e) => {
+                                error!("{}", e);
+                                Err(error_status(
+                                    ErrorCode::Internal,
+                                    format!("Could not search: {}", e),
+                                ))
+                            }
+                        }
+                    })
+                    .collect::<Result<Vec<_>, _>>()
+            })
+            .await?;
+        let last_indexed = self.stats.last_indexed.lock().unwrap().clone();
+
+        let mut roots = Vec::with_capacity(self.roots.len());
+        for (root, doc_count) in self.roots.iter().zip(doc_counts) {
+            let last_indexed = last_indexed
+                .get(root)
+                .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                .map(|d| d.as_secs())
+                .unwrap_or(0);
+            roots.push(Root {
+                path: root.clone(),
+                doc_count,
+                last_indexed,
+            });
+        }
+
+        Ok(Response::new(ListRootsResp { roots }))
+    }
+
+    async fn compact(&self, _req: Request<CompactReq>) -> Result<Response<CompactResp>, Status> {
+        let (tx, rx) = oneshot::channel();
+        match self.indexer()?.send(IndexerCommand::Compact(tx)) {
+            Ok(()) => (),
+            Err(TrySendError::Full(_)) => {
+                return Err(error_status(
+                    ErrorCode::IndexerBusy,
+                    "The indexer is busy, try again later",
+                ))
+            }
+            Err(TrySendError::Disconnected(_)) => {
+                return Err(error_status(
+                    ErrorCode::IndexerStopped,
+                    "The indexer is not running",
+                ))
+            }
+        }
+
+        let (before, after) = match rx.await {
+            Ok(Ok(r)) => r,
+            Ok(Err(e)) => {
+                error!("{}", e);
+                return Err(error_status(
+                    ErrorCode::Internal,
+                    format!("Could not compact: {}", e),
+                ));
+            }
+            Err(_) => {
+                return Err(error_status(
+                    ErrorCode::IndexerStopped,
+                    "The indexer is not running",
+                ))
+            }
+        };
+        info!("Compacted index from {} to {} segments", before, after);
+
+        let resp = CompactResp {
+            segments_before: before as u64,
+            segments_after: after as u64,
+        };
+        Ok(Response::new(resp))
+    }
+
+    async fn snapshot(&self, req: Request<SnapshotReq>) -> Result<Response<SnapshotResp>, Status> {
+        let indexer = self.indexer()?;
+        let dest = PathBuf::from(&req.get_ref().dest_dir);
+        if !dest.is_absolute() {
+            return Err(error_status(
+                ErrorCode::InvalidRequest,
+                "The snapshot directory must be an absolute path",
+            ));
+        }
+
+        let (tx, rx) = oneshot::channel();
+        match indexer.send(IndexerCommand::Snapshot(dest, tx)) {
+            Ok(()) => (),
+            Err(TrySendError::Full(_)) => {
+                return Err(error_status(
+                    ErrorCode::IndexerBusy,
+                    "The indexer is busy, try again later",
+                ))
+            }
+            Err(TrySendError::Disconnected(_)) => {
+                return Err(error_status(
+                    ErrorCode::IndexerStopped,
+                    "The indexer is not running",
+                ))
+            }
+        }
+
+        let path = match rx.await {
+            Ok(Ok(p)) => p,
+            Ok(Err(e)) => {
+                error!("{}", e);
+                return Err(error_status(
+                    ErrorCode::Internal,
+                    format!("Could not write snapshot: {}", e),
+                ));
+            }
+            Err(_) => {
+                return Err(error_status(
+                    ErrorCode::IndexerStopped,
+                    "The indexer is not running",
+                ))
+            }
+        };
+        info!("Wrote snapshot to {:?}", path);
+
+        Ok(Response::new(SnapshotResp {
+            path: path.to_string_lossy().to_string(),
+        }))
+    }
+
+    async fn find_duplicate_names(
+        &self,
+        req: Request<FindDuplicateNamesReq>,
+    ) -> Result<Response<FindDuplicateNamesResp>, Status> {
+        let req = req.into_inner();
+        let count = duplicate_groups(req.count)?;
+        let service = self.clone();
+        let resp = self
+            .blocking(move || {
+                let searcher = service.searcher()?;
+                service
+                    .duplicate_names(&searcher, &req, count)
+                    .map_err(|e| {
+                        error!("{}", e);
+                        error_status(ErrorCode::Internal, format!("Could not search: {}", e))
+                    })
+            })
+            .await?;
+        Ok(Response::new(resp))
+    }
+
+    async fn find_duplicate_content(
+        &self,
+        req: Request<FindDuplicateContentReq>,
+    ) -> Result<Response<FindDuplicateContentResp>, Status> {
+        if !self.content_hashes {
+            return Err(error_status(
+                ErrorCode::InvalidRequest,
+                "Finding duplicate content needs content_hash_max_size in the daemon's config",
+            ));
+        }
+        let req = req.into_inner();
+        let count = duplicate_groups(req.count)?;
+        let service = self.clone();
+        let resp = self
+            .blocking(move || {
+                let searcher = service.searcher()?;
+                service
+                    .duplicate_content(&searcher, &req, count)
+                    .map_err(|e| {
+                        error!("{}", e);
+                        error_status(ErrorCode::Internal, format!("Could not search: {}", e))
+                    })
+            })
+            .await?;
+        Ok(Response::new(resp))
+    }
+
+    type ReplicateStream = mpsc::Receiver<Result<ReplicationBatch, Status>>;
+
+    /// A copy of the index as of the latest commit, sent from a searcher on
+    /// the blocking pool, then each commit after it as the indexer makes
+    /// them. The standby is dropped if the indexer gets too far ahead.
+    async fn replicate(
+        &self,
+        _req: Request<ReplicateReq>,
+    ) -> Result<Response<Self::ReplicateStream>, Status> {
+        if !self.serve_replication {
+            return Err(error_status(
+                ErrorCode::InvalidRequest,
+                "Replicating needs serve_replication in the daemon's config",
+            ));
+        }
+
+        let (tx, rx) = oneshot::channel();
+        match self.indexer()?.send(IndexerCommand::Replicate(tx)) {
+            Ok(()) => (),
+            Err(TrySendError::Full(_)) => {
+                return Err(error_status(
+                    ErrorCode::IndexerBusy,
+                    "The indexer is busy, try again later",
+                ))
+            }
+            Err(TrySendError::Disconnected(_)) => {
+                return Err(error_status(
+                    ErrorCode::IndexerStopped,
+                    "The indexer is not running",
+                ))
+            }
+        }
+
+        let mut changes = match rx.await {
+            Ok(Ok(changes)) => changes,
+            Ok(Err(e)) => {
+                error!("{}", e);
+                return Err(error_status(
+                    ErrorCode::Internal,
+                    format!("Could not replicate the index: {}", e),
+                ));
+            }
+            Err(_) => {
+                return Err(error_status(
+                    ErrorCode::IndexerStopped,
+                    "The indexer is not running",
+                ))
+            }
+        };
+        // The indexer committed before it started recording, so a searcher
+        // from now sees everything before the first change it sends.
+        let searcher = self.searcher()?;
+        info!("Replicating the index to a standby");
+
+        let (mut tx, rx) = mpsc::channel(4);
+        let fields = Fields::new(&self.index.schema());
+        let substring_index = self.substring_query_parser.is_some();
+        tokio::spawn(async move {
+            let mut copy_tx = tx.clone();
+            let copied = tokio::task::spawn_blocking(move || {
+                replication::copy_index(&searcher, &fields, substring_index, |batch| {
+                    futures::executor::block_on(copy_tx.send(Ok(batch))).is_ok()
+                })
+            })
+            .await;
+            match copied {
+                Ok(Ok(())) => (),
+                Ok(Err(e)) => {
+                    error!("Could not copy the index to a standby: {}", e);
+                    let status = error_status(
+                        ErrorCode::Internal,
+                        format!("Could not copy the index: {}", e),
+                    );
+                    let _ = tx.send(Err(status)).await;
+                    return;
+                }
+                Err(_) => return,
+            }
+            while let Some(batch) = changes.recv().await {
+                if tx.send(Ok(batch)).await.is_err() {
+                    break;
+                }
+            }
+            info!("Stopped replicating the index to a standby");
+        });
+        Ok(Response::new(rx))
+    }
+
+    async fn verify(&self, _req: Request<VerifyReq>) -> Result<Response<VerifyResp>, Status> {
+        self.not_standby("verify")?;
+        let (tx, rx) = oneshot::channel();
+        match self.indexer()?.send(IndexerCommand::Verify(tx)) {
+            Ok(()) => (),
+            Err(TrySendError::Full(_)) => {
+                return Err(error_status(
+                    ErrorCode::IndexerBusy,
+                    "The indexer is busy, try again later",
+                ))
+            }
+            Err(TrySendError::Disconnected(_)) => {
+                return Err(error_status(
+                    ErrorCode::IndexerStopped,
+                    "The indexer is not running",
+                ))
+            }
+        }
+
+        let v = match rx.await {
+            Ok(Ok(v)) => v,
+            Ok(Err(e)) => {
+                error!("{}", e);
+                return Err(error_status(
+                    ErrorCode::Internal,
+                    format!("Could not verify the index: {}", e),
+                ));
+            }
+            Err(_) => {
+                return Err(error_status(
+                    ErrorCode::IndexerStopped,
+                    "The indexer is not running",
+                ))
+            }
+        };
+        info!(
+            "Verified the index: {} missing, {} unindexed, {} duplicated",
+            v.missing, v.unindexed, v.duplicates
+        );
+
+        Ok(Response::new(VerifyResp {
+            indexed: v.indexed,
+            found: v.found,
+            missing: v.missing,
+            missing_sample: v.missing_sample,
+            unindexed: v.unindexed,
+            unindexed_sample: v.unindexed_sample,
+            duplicates: v.duplicates,
+        }))
+    }
+
+    async fn reindex_file(
+        &self,
+        req: Request<ReindexFileReq>,
+    ) -> Result<Response<ReindexFileResp>, Status> {
+        let indexer = self.indexer()?;
+        self.not_standby("reindex")?;
+        let path = PathBuf::from(req.into_inner().path);
+        // Anything with a .. could climb out of the root it starts with.
+        if !path.is_absolute()
+            || path.components().any(|c| c == Component::ParentDir)
+            || !self.roots.iter().any(|r| path.starts_with(r))
+        {
+            return Err(error_status(
+                ErrorCode::InvalidRequest,
+                format!("{:?} isn't an absolute path under an index path", path),
+            ));
+        }
+
+        let (tx, rx) = oneshot::channel();
+        match indexer.send(IndexerCommand::ReindexFile(path.clone(), tx)) {
+            Ok(()) => (),
+            Err(TrySendError::Full(_)) => {
+                return Err(error_status(
+                    ErrorCode::IndexerBusy,
+                    "The indexer is busy, try again later",
+                ))
+            }
+            Err(TrySendError::Disconnected(_)) => {
+                return Err(error_status(
+                    ErrorCode::IndexerStopped,
+                    "The indexer is not running",
+                ))
+            }
+        }
+
+        let outcome = match rx.await {
+            Ok(Ok(Some(Reindexed::Added))) => ReindexOutcome::Added,
+            Ok(Ok(Some(Reindexed::Updated))) => ReindexOutcome::Updated,
+            Ok(Ok(Some(Reindexed::Removed))) => ReindexOutcome::Removed,
+            Ok(Ok(None)) => {
+                return Err(error_status(
+                    ErrorCode::InvalidRequest,
+                    format!("{:?} is neither on disk nor indexed", path),
+                ))
+            }
+            Ok(Err(e)) => {
+                error!("{}", e);
+                return Err(error_status(
+                    ErrorCode::Internal,
+                    format!("Could not index {:?}: {}", path, e),
+                ));
+            }
+            Err(_) => {
+                return Err(error_status(
+                    ErrorCode::IndexerStopped,
+                    "The indexer is not running",
+                ))
+            }
+        };
+
+        Ok(Response::new(ReindexFileResp {
+            outcome: outcome as i32,
+        }))
+    }
+
+    async fn health_check(
+        &self,
+        _req: Request<HealthCheckReq>,
+    ) -> Result<Response<HealthCheckResp>, Status> {
+        let stats = &self.stats;
+        let mut dirty_roots: Vec<String> =
+            stats.dirty_roots.lock().unwrap().iter().cloned().collect();
+        dirty_roots.sort();
+        let mut unwatched_roots: Vec<String> = stats
+            .unwatched_roots
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|p| p.to_string_lossy().to_string())
+            .collect();
+        unwatched_roots.sort();
+
+        let watcher_stopped = stats.watcher_stopped.load(Ordering::Relaxed);
+
+        let resp = HealthCheckResp {
+            healthy: dirty_roots.is_empty() && unwatched_roots.is_empty() && !watcher_stopped,
+            queue_full: stats.queue_full.load(Ordering::Relaxed),
+            dropped_events: stats.dropped_events.load(Ordering::Relaxed),
+            dirty_roots,
+            unwatched_roots,
+            watcher_stopped,
+            paused: stats.paused.load(Ordering::Relaxed),
+            ready: stats.ready.load(Ordering::Relaxed),
+        };
+        Ok(Response::new(resp))
+    }
+
+    async fn pause(&self, _req: Request<PauseReq>) -> Result<Response<PauseResp>, Status> {
+        let was_paused = self.indexer()?.stats.paused.swap(true, Ordering::Relaxed);
+        if !was_paused {
+            info!("Indexing paused");
+        }
+        Ok(Response::new(PauseResp { was_paused }))
+    }
+
+    /// The indexer applies what it held the next time it looks at its
+    /// queue, within a second.
+    async fn resume(&self, _req: Request<ResumeReq>) -> Result<Response<ResumeResp>, Status> {
+        let was_paused = self.indexer()?.stats.paused.swap(false, Ordering::Relaxed);
+        if was_paused {
+            info!("Indexing resumed");
+        }
+        Ok(Response::new(ResumeResp { was_paused }))
+    }
+
+    /// Marks the roots dirty, like dropped events do, so the indexer walks
+    /// them again once it's idle.
+    async fn reindex(&self, req: Request<ReindexReq>) -> Result<Response<ReindexResp>, Status> {
+        let indexer = self.indexer()?;
+        self.not_standby("reindex")?;
+        let root = req.into_inner().root;
+        let roots: Vec<String> = if root.is_empty() {
+            self.roots.clone()
+        } else {
+            let root = root.trim_end_matches('/');
+            match self.roots.iter().find(|r| r.trim_end_matches('/') == root) {
+                Some(r) => vec![r.clone()],
+                None => {
+                    return Err(error_status(
+                        ErrorCode::InvalidRequest,
+                        format!("{} isn't one of the index paths", root),
+                    ))
+                }
+            }
+        };
+        let mut dirty_roots = indexer.stats.dirty_roots.lock().unwrap();
+        for root in &roots {
+            info!("Walking {} again on request", root);
+            dirty_roots.insert(root.clone());
+        }
+        let mut roots: Vec<String> = dirty_roots.iter().cloned().collect();
+        roots.sort();
+        Ok(Response::new(ReindexResp { roots }))
+    }
+
+    async fn version(&self, _req: Request<VersionReq>) -> Result<Response<VersionResp>, Status> {
+        Ok(Response::new(VersionResp {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+        }))
+    }
+
+    async fn recent_queries(
+        &self,
+        req: Request<RecentQueriesReq>,
+    ) -> Result<Response<RecentQueriesResp>, Status> {
+        let history = match &self.history {
+            Some(h) => h,
+            None => return Ok(Response::new(RecentQueriesResp::default())),
+        };
+        let top = match req.get_ref().top {
+            0 => DEFAULT_TOP_QUERIES,
+            n => n as usize,
+        };
+
+        let recent = history
+            .recent()
+            .into_iter()
+            .map(|q| RecentQuery {
+                timestamp: q
+                    .time
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_secs())
+                    .unwrap_or(0),
+                query: q.query,
+                total_hits: q.total_hits,
+            })
+            .collect();
+        let top = history
+            .top(top)
+            .into_iter()
+            .map(|(query, count)| QueryCount { query, count })
+            .collect();
+        Ok(Response::new(RecentQueriesResp {
+            enabled: true,
+            recent,
+            top,
+        }))
+    }
+
+    async fn get_doc(&self, req: Request<GetDocReq>) -> Result<Response<GetDocResp>, Status> {
+        let path = req.into_inner().path;
+        if !Path::new(&path).is_absolute() {
+            return Err(error_status(
+                ErrorCode::InvalidRequest,
+                "The path must be absolute",
+            ));
+        }
+        let service = self.clone();
+        let doc = self
+            .blocking(move || {
+                let searcher = service.searcher()?;
+                service.indexed_doc(&searcher, &path)
+            })
+            .await?;
+
+        let doc = match doc {
+            Some(doc) => doc,
+            None => return Ok(Response::new(GetDocResp::default())),
+        };
+        let schema = self.index.schema();
+        let mut field_values: Vec<_> = doc.field_values().iter().collect();
+        field_values.sort_by_key(|fv| fv.field());
+        let fields = field_values
+            .into_iter()
+            .map(|fv| DocField {
+                name: schema.get_field_name(fv.field()).to_string(),
+                value: match fv.value() {
+                    Value::Str(s) => s.clone(),
+                    Value::U64(n) => n.to_string(),
+                    Value::I64(n) => n.to_string(),
+                    Value::F64(n) => n.to_string(),
+                    // The parent is one level, so its slashes aren't escaped.
+                    Value::Facet(f) => f.to_path().join("/"),
+                    v => format!("{:?}", v),
+                },
+            })
+            .collect();
+        Ok(Response::new(GetDocResp {
+            found: true,
+            fields,
+        }))
+    }
+
+    async fn find_marker(
+        &self,
+        req: Request<FindMarkerReq>,
+    ) -> Result<Response<FindMarkerResp>, Status> {
+        let req = req.into_inner();
+        let start = PathBuf::from(&req.start_path);
+        if !start.is_absolute() || start.components().any(|c| c == Component::ParentDir) {
+            return Err(error_status(
+                ErrorCode::InvalidRequest,
+                "The start path must be absolute, without any ..",
+            ));
+        }
+        // One name, so it's looked for in each directory and not below.
+        let mut components = Path::new(&req.marker).components();
+        match (components.next(), components.next()) {
+            (Some(Component::Normal(_)), None) => (),
+            _ => {
+                return Err(error_status(
+                    ErrorCode::InvalidRequest,
+                    format!("{:?} isn't the name of a file or directory", req.marker),
+                ))
+            }
+        }
+        let service = self.clone();
+        let dir = self
+            .blocking(move || {
+                let searcher = service.searcher()?;
+                service.marker_dir(&searcher, &start, &req.marker)
+            })
+            .await?;
+        Ok(Response::new(FindMarkerResp {
+            found: dir.is_some(),
+            dir: dir.unwrap_or_default(),
+        }))
+    }
+}
+
+/// Serves each user from their own `LookrService`, picked by the secret sent
+/// in the request's `SECRET_HEADER` metadata, or in a query's `secret`.
+#[derive(Clone, Default)]
+pub struct MultiUserService {
+    /// Each user's service, by their secret.
+    services: HashMap<String, LookrService>,
+    /// Where each user's secret is kept, by their name.
+    secret_paths: HashMap<String, PathBuf>,
+}
+
+impl MultiUserService {
+    pub fn add_user(
+        &mut self,
+        user: &str,
+        secret: String,
+        secret_path: PathBuf,
+        service: LookrService,
+    ) {
+        self.services.insert(secret, service);
+        self.secret_paths.insert(user.to_string(), secret_path);
+    }
+
+    /// The service for the user whose secret was sent with the request, or
+    /// given here.
+    fn service<T>(&self, req: &Request<T>, secret: Option<&str>) -> Result<&LookrService, Status> {
+        let secret = secret.filter(|s| !s.is_empty()).or_else(|| {
+            req.metadata()
+                .get(SECRET_HEADER)
+                .and_then(|v| v.to_str().ok())
+        });
+        match secret.and_then(|s| self.services.get(s)) {
+            Some(service) => Ok(service),
+            None => Err(error_status(
+                ErrorCode::Unauthenticated,
+                "A valid secret is needed, from the file named by GetSecretPath",
+            )),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl Lookr for MultiUserService {
+    async fn query(&self, req: Request<QueryReq>) -> Result<Response<QueryResp>, Status> {
+        let secret = req.get_ref().secret.clone();
+        self.service(&req, Some(&secret))?.query(req).await
+    }
+
+    async fn batch_query(
+        &self,
+        req: Request<BatchQueryReq>,
+    ) -> Result<Response<BatchQueryResp>, Status> {
+        let secrets: HashSet<String> = req
+            .get_ref()
+            .queries
+            .iter()
+            .filter(|q| !q.secret.is_empty())
+            .map(|q| q.secret.clone())
+            .collect();
+        // The whole batch is run against one index.
+        if secrets.len() > 1 {
+            return Err(error_status(
+                ErrorCode::Unauthenticated,
+                "Every query in a batch has to have the same secret",
+            ));
+        }
+        let secret = secrets.iter().next().map(String::as_str);
+        self.service(&req, secret)?.batch_query(req).await
+    }
+
+    async fn get_secret_path(
+        &self,
+        req: Request<SecretPathReq>,
+    ) -> Result<Response<SecretPathResp>, Status> {
+        match self.secret_paths.get(&req.get_ref().user) {
+            Some(path) => Ok(Response::new(SecretPathResp {
+                path: path.to_string_lossy().to_string(),
+            })),
+            None => Err(error_status(
+                ErrorCode::InvalidRequest,
+                format!("lookrd isn't indexing for {:?}", req.get_ref().user),
+            )),
+        }
+    }
+
+    async fn stats(&self, req: Request<StatsReq>) -> Result<Response<StatsResp>, Status> {
+        self.service(&req, None)?.stats(req).await
+    }
+
+    async fn list_roots(
+        &self,
+        req: Request<ListRootsReq>,
+    ) -> Result<Response<ListRootsResp>, Status> {
+        self.service(&req, None)?.list_roots(req).await
+    }
+
+    async fn compact(&self, req: Request<CompactReq>) -> Result<Response<CompactResp>, Status> {
+        self.service(&req, None)?.compact(req).await
+    }
+
+    async fn health_check(
+        &self,
+        req: Request<HealthCheckReq>,
+    ) -> Result<Response<HealthCheckResp>, Status> {
+        self.service(&req, None)?.health_check(req).await
+    }
+
+    async fn pause(&self, req: Request<PauseReq>) -> Result<Response<PauseResp>, Status> {
+        self.service(&req, None)?.pause(req).await
+    }
+
+    async fn resume(&self, req: Request<ResumeReq>) -> Result<Response<ResumeResp>, Status> {
+        self.service(&req, None)?.resume(req).await
+    }
+
+    async fn reindex(&self, req: Request<ReindexReq>) -> Result<Response<ReindexResp>, Status> {
+        self.service(&req, None)?.reindex(req).await
+    }
+
+    async fn verify(&self, req: Request<VerifyReq>) -> Result<Response<VerifyResp>, Status> {
+        self.service(&req, None)?.verify(req).await
+    }
+
+    async fn reindex_file(
+        &self,
+        req: Request<ReindexFileReq>,
+    ) -> Result<Response<ReindexFileResp>, Status> {
+        self.service(&req, None)?.reindex_file(req).await
+    }
+
+    async fn find_duplicate_names(
+        &self,
+        req: Request<FindDuplicateNamesReq>,
+    ) -> Result<Response<FindDuplicateNamesResp>, Status> {
+        self.service(&req, None)?.find_duplicate_names(req).await
+    }
+
+    async fn find_duplicate_content(
+        &self,
+        req: Request<FindDuplicateContentReq>,
+    ) -> Result<Response<FindDuplicateContentResp>, Status> {
+        self.service(&req, None)?.find_duplicate_content(req).await
+    }
+
+    type ReplicateStream = mpsc::Receiver<Result<ReplicationBatch, Status>>;
+
+    async fn replicate(
+        &self,
+        _req: Request<ReplicateReq>,
+    ) -> Result<Response<Self::ReplicateStream>, Status> {
+        Err(error_status(
+            ErrorCode::InvalidRequest,
+            "A multi-user daemon can't replicate its indexes",
+        ))
+    }
+
+    /// Refused, as the snapshot would be written wherever the user asked,
+    /// with the daemon's privileges rather than theirs.
+    async fn snapshot(&self, req: Request<SnapshotReq>) -> Result<Response<SnapshotResp>, Status> {
+        self.service(&req, None)?;
+        Err(error_status(
+            ErrorCode::InvalidRequest,
+            "A multi-user daemon can't snapshot its indexes",
+        ))
+    }
+
+    /// Needed before a client knows it has to authenticate, so open to all.
+    async fn version(&self, _req: Request<VersionReq>) -> Result<Response<VersionResp>, Status> {
+        Ok(Response::new(VersionResp {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+        }))
+    }
+
+    async fn get_doc(&self, req: Request<GetDocReq>) -> Result<Response<GetDocResp>, Status> {
+        self.service(&req, None)?.get_doc(req).await
+    }
+
+    async fn find_marker(
+        &self,
+        req: Request<FindMarkerReq>,
+    ) -> Result<Response<FindMarkerResp>, Status> {
+        self.service(&req, None)?.find_marker(req).await
+    }
+
+    async fn recent_queries(
+        &self,
+        req: Request<RecentQueriesReq>,
+    ) -> Result<Response<RecentQueriesResp>, Status> {
+        self.service(&req, None)?.recent_queries(req).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::indexer::{build_schema, Fields, Indexer};
+    use crate::testing::service;
+    use std::collections::BTreeSet;
+
+    async fn query(service: &LookrService, query: &str) -> Vec<String> {
+        let req = Request::new(QueryReq {
+            query: query.to_string(),
+            ..Default::default()
+        });
+        service.query(req).await.unwrap().into_inner().results
+    }
+
+    #[tokio::test]
+    async fn test_default_and() {
+        let paths = ["/src/foo/x.rs", "/src/bar/y.rs", "/src/foo/bar.rs"];
+
+        let or_service = service(&paths, &LookrdConfig::default());
+        let and_service = service(
+            &paths,
+            &LookrdConfig {
+                default_and: true,
+                ..Default::default()
+            },
+        );
+
+        assert_eq!(query(&or_service, "foo bar").await.len(), 3);
+        assert_eq!(
+            query(&and_service, "foo bar").await,
+            vec!["/src/foo/bar.rs"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_reindex() {
+        let config = LookrdConfig {
+            index_paths: vec!["/src/foo".to_string(), "/src/bar/".to_string()],
+            ..Default::default()
+        };
+        let service = service(&["/src/foo/a.rs"], &config);
+        let reindex = |root: &str| {
+            let service = service.clone();
+            let req = Request::new(ReindexReq {
+                root: root.to_string(),
+            });
+            async move { service.reindex(req).await.map(|r| r.into_inner().roots) }
+        };
+
+        assert_eq!(reindex("/src/bar").await.unwrap(), vec!["/src/bar/"]);
+        assert_eq!(reindex("").await.unwrap(), vec!["/src/bar/", "/src/foo"]);
+        let health = service
+            .health_check(Request::new(HealthCheckReq {}))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(health.dirty_roots, vec!["/src/bar/", "/src/foo"]);
+        assert_eq!(
+            reindex("/src").await.unwrap_err().code(),
+            Code::InvalidArgument
+        );
+
+        // Single paths have to be absolute, and under a root.
+        for path in &["src/foo/a.rs", "/src/foobar/a.rs", "/src/foo/../baz/a.rs"] {
+            let req = Request::new(ReindexFileReq {
+                path: path.to_string(),
+            });
+            let err = service.reindex_file(req).await.unwrap_err();
+            assert_eq!(err.code(), Code::InvalidArgument);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_list_roots() {
+        let paths = [
+            "/src/foo",
+            "/src/foo/a.rs",
+            "/src/foo/b/c.rs",
+            "/src/foobar/d.rs",
+            "/src/foo-e.rs",
+        ];
+        let config = LookrdConfig {
+            index_paths: vec!["/src/foo".to_string(), "/src/foobar/".to_string()],
+            ..Default::default()
+        };
+        let service = service(&paths, &config);
+
+        let roots = service
+            .list_roots(Request::new(ListRootsReq {}))
+            .await
+            .unwrap()
+            .into_inner()
+            .roots;
+
+        assert_eq!(roots.len(), 2);
+        assert_eq!(roots[0].path, "/src/foo");
+        assert_eq!(roots[0].doc_count, 3);
+        assert_eq!(roots[0].last_indexed, 0);
+        assert_eq!(roots[1].doc_count, 1);
+    }
+
+    #[tokio::test]
+    async fn test_query_directories() {
+        let paths = [
+            "/src/foo/a.rs",
+            "/src/foo/b.rs",
+            "/src/bar/a.rs",
+            "/src/bar/c.txt",
+        ];
+        let service = service(&paths, &LookrdConfig::default());
+
+        let req = Request::new(QueryReq {
+            query: "rs".to_string(),
+            directories: true,
+            ..Default::default()
+        });
+        let resp = service.query(req).await.unwrap().into_inner();
+
+        assert_eq!(resp.results.len(), 3);
+        assert_eq!(
+            resp.directories,
+            vec![
+                DirectoryCount {
+                    path: "/src/bar".to_string(),
+                    count: 1
+                },
+                DirectoryCount {
+                    path: "/src/foo".to_string(),
+                    count: 2
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search_results() {
+        let paths = ["/src/foo/foo.rs", "/src/bar/foo.txt", "/src/baz.rs"];
+        let service = service(&paths, &LookrdConfig::default());
+
+        for sort_by in [SortBy::Relevance, SortBy::Alphabetical] {
+            let req = Request::new(QueryReq {
+                query: "foo".to_string(),
+                sort_by: sort_by as i32,
+                ..Default::default()
+            });
+            let resp = service.query(req).await.unwrap().into_inner();
+            let hit_paths: Vec<&str> = resp.hits.iter().map(|h| h.path.as_str()).collect();
+            assert_eq!(hit_paths, resp.results);
+            assert_eq!(hit_paths.len(), 2);
+            assert!(resp.hits.iter().all(|h| h.score > 0.0));
+            // Nothing else is stored unless the index is persisted.
+            assert!(resp.hits.iter().all(|h| h.metadata.is_empty()));
+        }
+
+        // The path with foo in it twice matches better.
+        let resp = service
+            .query(Request::new(QueryReq {
+                query: "foo".to_string(),
+                ..Default::default()
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(resp.hits[0].path, "/src/foo/foo.rs");
+        assert!(resp.hits[0].score > resp.hits[1].score);
+    }
+
+    #[tokio::test]
+    async fn test_highlight() {
+        let paths = ["/src/Report.rs", "/docs/report.txt"];
+        let service = service(&paths, &LookrdConfig::default());
+        let highlighted = |highlight: bool, start: &str, end: &str| {
+            let req = Request::new(QueryReq {
+                query: "report rs".to_string(),
+                highlight,
+                highlight_start: start.to_string(),
+                highlight_end: end.to_string(),
+                ..Default::default()
+            });
+            let service = service.clone();
+            async move {
+                let resp = service.query(req).await.unwrap().into_inner();
+                resp.hits
+                    .into_iter()
+                    .map(|h| (h.path, h.highlighted))
+                    .collect::<Vec<_>>()
+            }
+        };
+
+        assert_eq!(
+            highlighted(true, "", "").await,
+            vec![
+                (
+                    "/src/Report.rs".to_string(),
+                    "/src/**Report**.**rs**".to_string()
+                ),
+                (
+                    "/docs/report.txt".to_string(),
+                    "/docs/**report**.txt".to_string()
+                ),
+            ]
+        );
+        assert_eq!(
+            highlighted(true, "\x1b[1m", "\x1b[0m").await[0].1,
+            "/src/\x1b[1mReport\x1b[0m.\x1b[1mrs\x1b[0m"
+        );
+        assert_eq!(highlighted(false, "[", "]").await[0].1, "");
+    }
+
+    #[tokio::test]
+    async fn test_find_duplicate_names() {
+        let root = tempfile::tempdir().unwrap();
+        for file in &["a/src/lib.rs", "b/src/lib.rs", "c/lib.rs", "c/main.rs"] {
+            let path = root.path().join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(&path, "").unwrap();
+        }
+        let config = LookrdConfig {
+            index_paths: vec![root.path().to_string_lossy().to_string()],
+            ..Default::default()
+        };
+        let schema = build_schema();
+        let index = Index::create_in_ram(schema.clone());
+        let mut indexer = Indexer::new(index.clone(), schema.clone(), config.clone()).unwrap();
+        indexer.index_once().unwrap();
+        let service = LookrService::new(index, schema, &config, indexer.handle());
+        let find = |count: i32, after: &str, kind: &str| {
+            let service = service.clone();
+            let req = Request::new(FindDuplicateNamesReq {
+                count,
+                after: after.to_string(),
+                kind: kind.to_string(),
+            });
+            async move {
+                service
+                    .find_duplicate_names(req)
+                    .await
+                    .map(|r| r.into_inner())
+            }
+        };
+        let path = |file: &str| root.path().join(file).to_string_lossy().to_string();
+
+        let resp = find(0, "", "").await.unwrap();
+        assert_eq!(
+            resp.groups,
+            vec![
+                DuplicateName {
+                    name: "lib.rs".to_string(),
+                    count: 3,
+                    paths: vec![path("a/src/lib.rs"), path("b/src/lib.rs"), path("c/lib.rs")],
+                },
+                DuplicateName {
+                    name: "src".to_string(),
+                    count: 2,
+                    paths: vec![path("a/src"), path("b/src")],
+                },
+            ]
+        );
+        assert_eq!(resp.next, "");
+
+        let page = find(1, "", "").await.unwrap();
+        assert_eq!(page.groups.len(), 1);
+        assert_eq!(page.next, "lib.rs");
+        let page = find(1, &page.next, "").await.unwrap();
+        assert_eq!(page.groups[0].name, "src");
+        assert_eq!(page.next, "");
+
+        let files = find(0, "", "file").await.unwrap();
+        assert_eq!(files.groups.len(), 1);
+        assert_eq!(files.groups[0].name, "lib.rs");
+
+        assert_eq!(
+            find(-1, "", "").await.unwrap_err().code(),
+            Code::InvalidArgument
+        );
+    }
+
+    #[tokio::test]
+    async fn test_find_duplicate_content() {
+        let root = tempfile::tempdir().unwrap();
+        let files = [
+            ("a.txt", "same"),
+            ("b/copy.txt", "same"),
+            ("c.txt", "different"),
+            ("big.txt", "same, but over the limit"),
+        ];
+        for (file, contents) in &files {
+            let path = root.path().join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(&path, contents).unwrap();
+        }
+        let config = LookrdConfig {
+            index_paths: vec![root.path().to_string_lossy().to_string()],
+            content_hash_max_size: Some(16),
+            ..Default::default()
+        };
+        let schema = build_schema();
+        let index = Index::create_in_ram(schema.clone());
+        let mut indexer = Indexer::new(index.clone(), schema.clone(), config.clone()).unwrap();
+        indexer.index_once().unwrap();
+        let service = LookrService::new(index.clone(), schema.clone(), &config, indexer.handle());
+        let path = |file: &str| root.path().join(file).to_string_lossy().to_string();
+
+        let req = Request::new(FindDuplicateContentReq::default());
+        let resp = service.find_duplicate_content(req).await.unwrap();
+        assert_eq!(resp.get_ref().groups.len(), 1);
+        let group = &resp.get_ref().groups[0];
+        assert_eq!(group.hash.len(), 32);
+        assert_eq!(group.count, 2);
+        assert_eq!(group.paths, vec![path("a.txt"), path("b/copy.txt")]);
+        assert_eq!(resp.get_ref().next, "");
+
+        let config = LookrdConfig {
+            content_hash_max_size: None,
+            ..config
+        };
+        let service = LookrService::new(index, schema, &config, indexer.handle());
+        let req = Request::new(FindDuplicateContentReq::default());
+        assert_eq!(
+            service
+                .find_duplicate_content(req)
+                .await
+                .unwrap_err()
+                .code(),
+            Code::InvalidArgument
+        );
+    }
+
+    #[tokio::test]
+    async fn test_ext_group() {
+        let mut extension_groups = BTreeMap::new();
+        extension_groups.insert(
+            "images".to_string(),
+            vec!["png".to_string(), ".JPG".to_string()],
+        );
+        let config = LookrdConfig {
+            extension_groups,
+            ..Default::default()
+        };
+        let service = service(
+            &[
+                "/photos/a.png",
+                "/photos/b.jpg",
+                "/photos/c.txt",
+                "/png/d.rs",
+            ],
+            &config,
+        );
+
+        let mut terms = BTreeSet::new();
+        service
+            .ext_group_query("images")
+            .unwrap()
+            .query_terms(&mut terms);
+        let expected: BTreeSet<Term> = ["jpg", "png"]
+            .iter()
+            .map(|e| Term::from_field_text(service.field_ext, e))
+            .collect();
+        assert_eq!(terms, expected);
+
+        let group = |name: &str| {
+            let service = service.clone();
+            let req = Request::new(QueryReq {
+                query: "photos png".to_string(),
+                ext_group: name.to_string(),
+                sort_by: SortBy::Alphabetical as i32,
+                ..Default::default()
+            });
+            async move { service.query(req).await.map(|r| r.into_inner().results) }
+        };
+        assert_eq!(
+            group("images").await.unwrap(),
+            vec!["/photos/a.png", "/photos/b.jpg"]
+        );
+        assert_eq!(
+            group("code").await.unwrap_err().code(),
+            Code::InvalidArgument
+        );
+    }
+
+    #[tokio::test]
+    async fn test_extensions() {
+        let service = service(
+            &[
+                "/src/main.rs",
+                "/src/Cargo.toml",
+                "/src/Cargo.lock",
+                "/src/README.md",
+            ],
+            &LookrdConfig::default(),
+        );
+        let with_extensions = |query: &str, include: &[&str], exclude: &[&str]| {
+            let service = service.clone();
+            let req = Request::new(QueryReq {
+                query: query.to_string(),
+                extensions: include.iter().map(|e| e.to_string()).collect(),
+                exclude_extensions: exclude.iter().map(|e| e.to_string()).collect(),
+                sort_by: SortBy::Alphabetical as i32,
+                ..Default::default()
+            });
+            async move { service.query(req).await.unwrap().into_inner().results }
+        };
+
+        // Only those with one of the extensions, given in any case or with
+        // their dot.
+        assert_eq!(
+            with_extensions("src", &["rs", ".TOML"], &[]).await,
+            vec!["/src/Cargo.toml", "/src/main.rs"]
+        );
+        // Everything but those with the extensions, with or without a query.
+        assert_eq!(
+            with_extensions("src", &[], &["lock", "md"]).await,
+            vec!["/src/Cargo.toml", "/src/main.rs"]
+        );
+        assert_eq!(
+            with_extensions("", &[], &["lock"]).await,
+            vec!["/src/Cargo.toml", "/src/README.md", "/src/main.rs"]
+        );
+        assert_eq!(
+            with_extensions("cargo", &["toml", "lock"], &["lock"]).await,
+            vec!["/src/Cargo.toml"]
+        );
+        // Empty extensions are ignored.
+        assert_eq!(with_extensions("src", &["", "."], &[""]).await.len(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_result_fields() {
+        let root = tempfile::tempdir().unwrap();
+        let path = root.path().join("foo.txt").to_string_lossy().to_string();
+        std::fs::write(&path, "").unwrap();
+        // Keeps the mtime, to return as metadata.
+        let config = LookrdConfig {
+            max_docs: Some(10),
+            ..Default::default()
+        };
+        let service = service(&[&path], &config);
+        let hits = |fields: &[&str]| {
+            let service = service.clone();
+            let req = Request::new(QueryReq {
+                query: "foo".to_string(),
+                fields: fields.iter().map(|f| f.to_string()).collect(),
+                ..Default::default()
+            });
+            async move { service.query(req).await.map(|r| r.into_inner().hits) }
+        };
+
+        // The path and score by default.
+        let hit = hits(&[]).await.unwrap().remove(0);
+        assert_eq!(hit.path, path);
+        assert!(hit.score > 0.0);
+        assert!(hit.metadata.is_empty());
+
+        let hit = hits(&["path"]).await.unwrap().remove(0);
+        assert_eq!(hit.path, path);
+        assert_eq!(hit.score, 0.0);
+        assert!(hit.metadata.is_empty());
+
+        let hit = hits(&["mtime"]).await.unwrap().remove(0);
+        assert_eq!(hit.path, path);
+        assert_eq!(hit.score, 0.0);
+        assert_eq!(hit.metadata.keys().collect::<Vec<_>>(), ["mtime"]);
+
+        let hit = hits(&["score", "mtime"]).await.unwrap().remove(0);
+        assert!(hit.score > 0.0);
+        assert!(hit.metadata.contains_key("mtime"));
+
+        assert_eq!(
+            hits(&["size"]).await.unwrap_err().code(),
+            Code::InvalidArgument
+        );
+    }
+
+    #[tokio::test]
+    async fn test_scoring() {
+        // A short path with the term once, and a long one with it twice.
+        let paths = ["/a/foo", "/b/c/d/e/f/g/foo/foo"];
+        let hits = |config: LookrdConfig| async move {
+            let service = service(&paths, &config);
+            let req = Request::new(QueryReq {
+                query: "foo".to_string(),
+                ..Default::default()
+            });
+            let hits = service.query(req).await.unwrap().into_inner().hits;
+            hits.into_iter()
+                .map(|h| (h.path, h.score))
+                .collect::<Vec<_>>()
+        };
+        let bm25 = |k1, b| LookrdConfig {
+            bm25_k1: Some(k1),
+            bm25_b: Some(b),
+            ..Default::default()
+        };
+
+        // Rescored with tantivy's parameters, nothing changes.
+        let default = hits(LookrdConfig::default()).await;
+        let rescored = hits(bm25(1.2, 0.75)).await;
+        assert_eq!(default[0].0, "/a/foo");
+        for ((path, score), (rescored_path, rescored_score)) in default.iter().zip(&rescored) {
+            assert_eq!(path, rescored_path);
+            assert!((score - rescored_score).abs() < 1e-4);
+        }
+
+        // Ignoring length, the repeated term wins.
+        let unnormalized = hits(bm25(2.0, 0.0)).await;
+        assert_eq!(unnormalized[0].0, "/b/c/d/e/f/g/foo/foo");
+        assert_eq!(hits(bm25(2.0, 1.0)).await[0].0, "/a/foo");
+
+        let tfidf = hits(LookrdConfig {
+            scoring: crate::config::Scoring::Tfidf,
+            ..Default::default()
+        })
+        .await;
+        assert_eq!(tfidf[0].0, "/a/foo");
+        assert!(tfidf[0].1 > tfidf[1].1);
+    }
+
+    #[tokio::test]
+    async fn test_recency() {
+        // The same name at the same depth, so the text scores the same.
+        let schema = build_schema();
+        let fields = Fields::new(&schema);
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let day = Duration::from_secs(24 * 60 * 60);
+        let hits = |recency_weight: Option<f32>| {
+            let schema = schema.clone();
+            async move {
+                let index = Index::create_in_ram(schema.clone());
+                let config = LookrdConfig {
+                    recency_weight,
+                    ..Default::default()
+                };
+                let indexer = Indexer::new(index.clone(), schema.clone(), config.clone()).unwrap();
+                let mut index_writer = index.writer_with_num_threads(1, 50_000_000).unwrap();
+                for (path, age) in [("/old/notes.md", day * 60), ("/new/notes.md", day)] {
+                    let mut doc = fields.document(Path::new(path));
+                    doc.add_u64(fields.mtime, (now - age).as_nanos() as u64);
+                    index_writer.add_document(doc);
+                }
+                // Never modified, as far as the index knows.
+                index_writer.add_document(fields.document(Path::new("/any/notes.md")));
+                index_writer.commit().unwrap();
+                let service = LookrService::new(index, schema, &config, indexer.handle());
+                let req = Request::new(QueryReq {
+                    query: "notes".to_string(),
+                    ..Default::default()
+                });
+                let hits = service.query(req).await.unwrap().into_inner().hits;
+                hits.into_iter()
+                    .map(|h| (h.path, h.score))
+                    .collect::<Vec<_>>()
+            }
+        };
+
+        let unweighted = hits(None).await;
+        assert!((unweighted[0].1 - unweighted[1].1).abs() < 1e-6);
+        let weighted = hits(Some(1.0)).await;
+        assert_eq!(weighted[0].0, "/new/notes.md");
+        assert_eq!(weighted[1].0, "/old/notes.md");
+        assert_eq!(weighted[2].0, "/any/notes.md");
+        // A day old is nearly doubled, two months old barely raised.
+        let base = unweighted[0].1;
+        assert!((weighted[0].1 / base - 1.9).abs() < 0.01);
+        assert!(weighted[1].1 / base < 1.01);
+        assert!((weighted[2].1 - base).abs() < 1e-6);
+        assert_eq!(hits(Some(0.0)).await[0].1, base);
+    }
+
+    #[tokio::test]
+    async fn test_query_filename_only() {
+        let paths = ["/home/src/main.rs", "/home/lib/src.rs"];
+        let service = service(&paths, &LookrdConfig::default());
+
+        assert_eq!(query(&service, "src").await.len(), 2);
+
+        let req = Request::new(QueryReq {
+            query: "src".to_string(),
+            filename_only: true,
+            ..Default::default()
+        });
+        let resp = service.query(req).await.unwrap().into_inner();
+        assert_eq!(resp.results, vec!["/home/lib/src.rs"]);
+    }
+
+    #[tokio::test]
+    async fn test_batch_query() {
+        let paths = ["/src/a.rs", "/src/b.rs", "/src/c.rs", "/lib/d.txt"];
+        let service = service(&paths, &LookrdConfig::default());
+
+        let queries = vec![
+            QueryReq {
+                query: "rs".to_string(),
+                ..Default::default()
+            },
+            QueryReq {
+                query: "rs".to_string(),
+                count: 2,
+                offset: 2,
+                ..Default::default()
+            },
+            QueryReq {
+                query: "txt".to_string(),
+                ..Default::default()
+            },
+        ];
+        let req = Request::new(BatchQueryReq { queries });
+        let responses = service
+            .batch_query(req)
+            .await
+            .unwrap()
+            .into_inner()
+            .responses;
+
+        assert_eq!(responses.len(), 3);
+        assert_eq!(responses[0].results.len(), 3);
+        assert_eq!(responses[1].results.len(), 1);
+        assert!(responses[0].results.contains(&responses[1].results[0]));
+        assert_eq!(responses[2].results, vec!["/lib/d.txt"]);
+    }
+
+    #[tokio::test]
+    async fn test_read_only() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = tempfile::tempdir().unwrap();
+        let index_dir = dir.path().join("index");
+        assert!(crate::indexer::open_read_only(&index_dir).is_err());
+        let schema = build_schema();
+        let fields = Fields::new(&schema);
+        {
+            let index = crate::indexer::open_index(&index_dir, schema.clone()).unwrap();
+            crate::tokenizer::register(&index, &LookrdConfig::default());
+            let mut index_writer = index.writer_with_num_threads(1, 50_000_000).unwrap();
+            index_writer.add_document(fields.document(Path::new("/srv/notes.md")));
+            index_writer.commit().unwrap();
+            index_writer.wait_merging_threads().unwrap();
+        }
+        // Served from a directory it can't write to, which it leaves as it
+        // was: not even a lock file, which a copy of the index wouldn't have.
+        for lock in &[".tantivy-meta.lock", ".tantivy-writer.lock"] {
+            let _ = std::fs::remove_file(index_dir.join(lock));
+        }
+        let read_only = std::fs::Permissions::from_mode(0o555);
+        std::fs::set_permissions(&index_dir, read_only).unwrap();
+        let listing = || {
+            let mut names: Vec<_> = std::fs::read_dir(&index_dir)
+                .unwrap()
+                .map(|e| e.unwrap().file_name())
+                .collect();
+            names.sort();
+            (
+                names,
+                std::fs::metadata(&index_dir).unwrap().modified().unwrap(),
+            )
+        };
+        let before = listing();
+        let index = crate::indexer::open_read_only(&index_dir).unwrap();
+        let service = LookrService::read_only(index, schema, &LookrdConfig::default()).unwrap();
+
+        assert_eq!(query(&service, "notes").await, vec!["/srv/notes.md"]);
+        assert_eq!(query(&service, "notes").await, vec!["/srv/notes.md"]);
+        let stats = service.stats(Request::new(StatsReq {})).await.unwrap();
+        assert_eq!(stats.into_inner().num_docs, 1);
+        let health = service.health_check(Request::new(HealthCheckReq {})).await;
+        assert!(health.unwrap().into_inner().healthy);
+        assert_eq!(listing(), before);
+        std::fs::set_permissions(&index_dir, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let refused = |r: Result<(), Status>| {
+            let e = r.unwrap_err();
+            assert_eq!(e.code(), Code::FailedPrecondition);
+            // Held base64 encoded until it's sent.
+            let details = base64::decode(e.details()).unwrap();
+            let details = ErrorDetails::decode(&details[..]).unwrap();
+            assert_eq!(details.code, ErrorCode::ReadOnly as i32);
+        };
+        let dest = dir.path().join("snapshot").to_string_lossy().to_string();
+        refused(
+            service
+                .compact(Request::new(CompactReq {}))
+                .await
+                .map(|_| ()),
+        );
+        refused(
+            service
+                .snapshot(Request::new(SnapshotReq { dest_dir: dest }))
+                .await
+                .map(|_| ()),
+        );
+        refused(service.pause(Request::new(PauseReq {})).await.map(|_| ()));
+        refused(service.resume(Request::new(ResumeReq {})).await.map(|_| ()));
+        refused(
+            service
+                .reindex(Request::new(ReindexReq::default()))
+                .await
+                .map(|_| ()),
+        );
+        refused(
+            service
+                .reindex_file(Request::new(ReindexFileReq {
+                    path: "/srv/notes.md".to_string(),
+                }))
+                .await
+                .map(|_| ()),
+        );
+        refused(service.verify(Request::new(VerifyReq {})).await.map(|_| ()));
+    }
+
+    #[tokio::test]
+    async fn test_query_tag() {
+        let schema = build_schema();
+        let index = Index::create_in_ram(schema.clone());
+        let fields = Fields::new(&schema);
+        let config = LookrdConfig::default();
+        let indexer = Indexer::new(index.clone(), schema.clone(), config.clone()).unwrap();
+        let mut index_writer = index.writer_with_num_threads(1, 50_000_000).unwrap();
+        let mut tagged = fields.document(Path::new("/notes/plan.md"));
+        tagged.add_text(fields.tags, "draft");
+        index_writer.add_document(tagged);
+        index_writer.add_document(fields.document(Path::new("/notes/done.md")));
+        index_writer.commit().unwrap();
+        let service = LookrService::new(index, schema, &config, indexer.handle());
+
+        for query in &["md", ""] {
+            let req = Request::new(QueryReq {
+                query: query.to_string(),
+                tag: "draft".to_string(),
+                ..Default::default()
+            });
+            let resp = service.query(req).await.unwrap().into_inner();
+            assert_eq!(resp.results, vec!["/notes/plan.md"]);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sort_by() {
+        let paths = ["/a/b/c/d/foo", "/foo/foo/foo", "/z/foo"];
+        let service = service(&paths, &LookrdConfig::default());
+        let sorted = |sort_by: SortBy, count, offset| {
+            let req = Request::new(QueryReq {
+                query: "foo".to_string(),
+                sort_by: sort_by as i32,
+                count,
+                offset,
+                ..Default::default()
+            });
+            let service = &service;
+            async move { service.query(req).await.unwrap().into_inner().results }
+        };
+
+        assert_eq!(
+            sorted(SortBy::Relevance, 0, 0).await,
+            vec!["/foo/foo/foo", "/z/foo", "/a/b/c/d/foo"]
+        );
+        assert_eq!(
+            sorted(SortBy::PathDepth, 0, 0).await,
+            vec!["/z/foo", "/foo/foo/foo", "/a/b/c/d/foo"]
+        );
+        assert_eq!(
+            sorted(SortBy::Alphabetical, 0, 0).await,
+            vec!["/a/b/c/d/foo", "/foo/foo/foo", "/z/foo"]
+        );
+        // The page is taken after sorting.
+        assert_eq!(
+            sorted(SortBy::Alphabetical, 1, 1).await,
+            vec!["/foo/foo/foo"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_query_timeout() {
+        let config = LookrdConfig {
+            query_timeout_ms: Some(100),
+            ..Default::default()
+        };
+        let service = service(&["/src/a.rs"], &config);
+
+        let slow = service
+            .blocking(|| {
+                std::thread::sleep(Duration::from_millis(500));
+                Ok(())
+            })
+            .await;
+        assert_eq!(slow.unwrap_err().code(), tonic::Code::DeadlineExceeded);
+        assert_eq!(query(&service, "rs").await, vec!["/src/a.rs"]);
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_queries() {
+        let service = service(&["/src/a.rs"], &LookrdConfig::default());
+        // Each waits until the query is answered, failing if it isn't.
+        let (releases, waits): (Vec<_>, Vec<_>) =
+            (0..3).map(|_| std::sync::mpsc::channel::<()>()).unzip();
+        let slow = |wait: std::sync::mpsc::Receiver<()>| {
+            service.blocking(move || {
+                wait.recv_timeout(Duration::from_secs(5))
+                    .map_err(|_| Status::internal("The query wasn't answered meanwhile"))
+            })
+        };
+
+        // The test's runtime has one thread, so the query is only answered
+        // while the others wait if they're waiting off it.
+        let (slow_results, fast) = futures::join!(
+            futures::future::join_all(waits.into_iter().map(slow)),
+            async {
+                let results = query(&service, "rs").await;
+                for release in &releases {
+                    release.send(()).unwrap();
+                }
+                results
+            }
+        );
+        assert!(slow_results.iter().all(Result::is_ok), "{:?}", slow_results);
+        assert_eq!(fast, vec!["/src/a.rs"]);
+    }
+
+    #[tokio::test]
+    async fn test_ascii_fold() {
+        let paths = ["/docs/caf\u{e9}.txt", "/docs/re\u{301}sume\u{301}.md"];
+        let config = LookrdConfig {
+            ascii_fold: true,
+            ..Default::default()
+        };
+        let folded = service(&paths, &config);
+        assert_eq!(query(&folded, "cafe").await, vec![paths[0]]);
+        assert_eq!(query(&folded, "caf\u{e9}").await, vec![paths[0]]);
+        assert_eq!(query(&folded, "resume").await, vec![paths[1]]);
+
+        let unfolded = service(&paths, &LookrdConfig::default());
+        assert!(query(&unfolded, "cafe").await.is_empty());
+        // Composed and decomposed forms match without folding.
+        assert_eq!(query(&unfolded, "r\u{e9}sum\u{e9}").await, vec![paths[1]]);
+    }
+
+    #[tokio::test]
+    async fn test_query_cache() {
+        let config = LookrdConfig {
+            query_cache_size: Some(10),
+            ..Default::default()
+        };
+        let service = service(&["/a/foo.rs"], &config);
+        assert_eq!(query(&service, "foo").await, vec!["/a/foo.rs"]);
+
+        // Answered from the cache, which the last query filled in for this
+        // generation of the index.
+        let req = QueryReq {
+            query: "foo".to_string(),
+            ..Default::default()
+        };
+        let cached = QueryResp {
+            results: vec!["/cached".to_string()],
+            ..Default::default()
+        };
+        let generation = Generation::of(&service.searcher().unwrap());
+        let cache = service.query_cache.as_ref().unwrap();
+        assert!(cache.get(&generation, &req).is_some());
+        cache.insert(&generation, &req, &cached);
+        assert_eq!(query(&service, "foo").await, vec!["/cached"]);
+
+        // Searched again once a commit changes the index.
+        let fields = Fields::new(&service.index.schema());
+        let mut writer = service
+            .index
+            .writer_with_num_threads(1, 50_000_000)
+            .unwrap();
+        writer.add_document(fields.document(Path::new("/b/foo.rs")));
+        writer.commit().unwrap();
+        let mut results = query(&service, "foo").await;
+        results.sort();
+        assert_eq!(results, vec!["/a/foo.rs", "/b/foo.rs"]);
+    }
+
+    #[tokio::test]
+    async fn test_depth() {
+        let paths = [
+            "/srv/a",
+            "/srv/a/README.md",
+            "/srv/a/src",
+            "/srv/a/src/main.rs",
+            "/srv/a/src/bin/cli.rs",
+            "/home/b",
+            "/home/b/notes.md",
+        ];
+        let config = LookrdConfig {
+            index_paths: vec!["/srv".to_string(), "/home/b".to_string()],
+            ..Default::default()
+        };
+        let service = service(&paths, &config);
+        let depth = |query: &str, min_depth: u32, max_depth: u32| {
+            let service = service.clone();
+            let req = Request::new(QueryReq {
+                query: query.to_string(),
+                min_depth,
+                max_depth,
+                sort_by: SortBy::Alphabetical as i32,
+                ..Default::default()
+            });
+            async move { service.query(req).await.map(|r| r.into_inner().results) }
+        };
+
+        // Counted from the index path each is in.
+        assert_eq!(
+            depth("", 1, 1).await.unwrap(),
+            vec!["/home/b/notes.md", "/srv/a"]
+        );
+        assert_eq!(
+            depth("", 2, 2).await.unwrap(),
+            vec!["/srv/a/README.md", "/srv/a/src"]
+        );
+        assert_eq!(
+            depth("", 3, 0).await.unwrap(),
+            vec!["/srv/a/src/bin/cli.rs", "/srv/a/src/main.rs"]
+        );
+        // The index paths themselves are at 0.
+        assert_eq!(
+            depth("", 0, 1).await.unwrap(),
+            vec!["/home/b", "/home/b/notes.md", "/srv/a"]
+        );
+        // Along with the query.
+        assert_eq!(depth("rs", 0, 3).await.unwrap(), vec!["/srv/a/src/main.rs"]);
+        assert_eq!(
+            depth("home", 0, 0).await.unwrap(),
+            vec!["/home/b", "/home/b/notes.md"]
+        );
+        assert_eq!(depth("home", 1, 1).await.unwrap(), vec!["/home/b/notes.md"]);
+
+        let status = depth("", 3, 2).await.unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_ready() {
+        let config = LookrdConfig {
+            max_ready_wait_ms: Some(100),
+            ..Default::default()
+        };
+        let service = service(&["/a/foo.rs"], &config);
+        // As if the walk had only got as far as this.
+        service.stats.ready.store(false, Ordering::Relaxed);
+        let req = |wait_for_ready: bool| {
+            Request::new(QueryReq {
+                query: "foo".to_string(),
+                wait_for_ready,
+                ..Default::default()
+            })
+        };
+        let health = |service: &LookrService| {
+            let service = service.clone();
+            async move {
+                service
+                    .health_check(Request::new(HealthCheckReq {}))
+                    .await
+                    .unwrap()
+                    .into_inner()
+            }
+        };
+
+        // Answered from what's indexed so far, flagged as such.
+        let resp = service.query(req(false)).await.unwrap().into_inner();
+        assert_eq!(resp.results, vec!["/a/foo.rs"]);
+        assert!(resp.warming_up);
+        assert!(!health(&service).await.ready);
+
+        // Or after waiting as long as allowed.
+        let start = Instant::now();
+        let resp = service.query(req(true)).await.unwrap().into_inner();
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        assert_eq!(resp.results, vec!["/a/foo.rs"]);
+        assert!(resp.warming_up);
+
+        // A query waiting when the walk is done is answered then, however
+        // long it could have waited.
+        let ready_service = service.clone();
+        let waiting = LookrService {
+            max_ready_wait: Duration::from_secs(u64::MAX),
+            ..service.clone()
+        };
+        tokio::spawn(async move {
+            tokio::time::delay_for(Duration::from_millis(50)).await;
+            ready_service.stats.ready.store(true, Ordering::Relaxed);
+        });
+        let resp = waiting.query(req(true)).await.unwrap().into_inner();
+        assert_eq!(resp.results, vec!["/a/foo.rs"]);
+        assert!(!resp.warming_up);
+        assert!(health(&service).await.ready);
+
+        let resp = service.query(req(false)).await.unwrap().into_inner();
+        assert!(!resp.warming_up);
+    }
+
+    #[tokio::test]
+    async fn test_negative_cache() {
+        let config = LookrdConfig {
+            negative_cache_size: Some(10),
+            default_and: true,
+            ..Default::default()
+        };
+        let service = service(&["/a/foo.rs"], &config);
+        let req = |query: &str, directories: bool| QueryReq {
+            query: query.to_string(),
+            directories,
+            ..Default::default()
+        };
+        let cache = service.negative_cache.as_ref().unwrap();
+        let generation = Generation::of(&service.searcher().unwrap());
+
+        // Found nothing, which is remembered, and which an answer from the
+        // cache matches.
+        for directories in [false, true] {
+            let searched = service
+                .query(Request::new(req("bar", directories)))
+                .await
+                .unwrap()
+                .into_inner();
+            assert_eq!(searched, QueryResp::default());
+            let bar = req("bar", directories);
+            assert!(cache.contains(&generation, &bar, &["bar".to_string()]));
+        }
+
+        // Known to find nothing, along with anything that adds to it.
+        cache.insert(&generation, &req("foo", false), "foo");
+        assert!(query(&service, "foo").await.is_empty());
+        assert!(query(&service, "foo  rs").await.is_empty());
+        assert_eq!(query(&service, "rs").await, vec!["/a/foo.rs"]);
+        assert_eq!(
+            service.broader_queries(&req(" foo  rs ", false)),
+            vec!["foo", "foo rs"]
+        );
+        assert_eq!(
+            service.broader_queries(&req("foo OR rs", false)),
+            vec!["foo OR rs"]
+        );
+
+        // Searched again once a commit changes the index.
+        let fields = Fields::new(&service.index.schema());
+        let mut writer = service
+            .index
+            .writer_with_num_threads(1, 50_000_000)
+            .unwrap();
+        writer.add_document(fields.document(Path::new("/b/bar.rs")));
+        writer.commit().unwrap();
+        assert_eq!(query(&service, "bar").await, vec!["/b/bar.rs"]);
+        assert_eq!(query(&service, "foo").await, vec!["/a/foo.rs"]);
+    }
+
+    #[tokio::test]
+    async fn test_stop_words() {
+        let paths = [
+            "/src/foo/main.rs",
+            "/src/bar/lib.rs",
+            "/docs/src.md",
+            "/foo/notes.md",
+        ];
+        let config = LookrdConfig {
+            stop_words: vec!["SRC".to_string()],
+            ..Default::default()
+        };
+        let service = service(&paths, &config);
+        let keep_stop_words = |query: &str| {
+            let service = service.clone();
+            let req = Request::new(QueryReq {
+                query: query.to_string(),
+                keep_stop_words: true,
+                ..Default::default()
+            });
+            async move { service.query(req).await.unwrap().into_inner().results }
+        };
+
+        assert_eq!(query(&service, "src main").await, vec!["/src/foo/main.rs"]);
+        assert_eq!(keep_stop_words("src main").await.len(), 3);
+        // A query of nothing but stop words keeps them.
+        assert_eq!(query(&service, "src").await.len(), 3);
+        // As do phrases.
+        assert_eq!(
+            query(&service, "\"src foo\"").await,
+            vec!["/src/foo/main.rs"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_split_words() {
+        let paths = ["/src/getUserName.rs", "/src/username.rs"];
+        let config = LookrdConfig {
+            split_words: true,
+            ..Default::default()
+        };
+        let split = service(&paths, &config);
+        assert_eq!(query(&split, "user").await, vec![paths[0]]);
+        assert_eq!(query(&split, "getUserName").await, vec![paths[0]]);
+        assert_eq!(query(&split, "username").await, vec![paths[1]]);
+    }
+
+    #[tokio::test]
+    async fn test_total_hits() {
+        let paths: Vec<String> = (0..10).map(|i| format!("/src/{}.rs", i)).collect();
+        let paths: Vec<&str> = paths.iter().map(String::as_str).collect();
+        let service = service(&paths, &LookrdConfig::default());
+
+        for directories in [false, true] {
+            let req = Request::new(QueryReq {
+                query: "rs".to_string(),
+                count: 3,
+                offset: 8,
+                directories,
+                ..Default::default()
+            });
+            let resp = service.query(req).await.unwrap().into_inner();
+            assert_eq!(resp.results.len(), 2);
+            assert_eq!(resp.total_hits, 10);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_max_count() {
+        let service = service(&["/src/a.rs", "/src/b.rs"], &LookrdConfig::default());
+        let req = |count, offset| {
+            Request::new(QueryReq {
+                query: "rs".to_string(),
+                count,
+                offset,
+                ..Default::default()
+            })
+        };
+
+        let status = service.query(req(i32::MAX, 0)).await.unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+        let status = service
+            .batch_query(Request::new(BatchQueryReq {
+                queries: vec![req(i32::MAX, 0).into_inner()],
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+
+        // Past the last match, however far, there's nothing more.
+        let resp = service.query(req(100_000, i32::MAX)).await.unwrap();
+        assert!(resp.into_inner().results.is_empty());
+        let resp = service.query(req(100_000, 0)).await.unwrap();
+        assert_eq!(resp.into_inner().results.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_truncated() {
+        let paths: Vec<String> = (0..10).map(|i| format!("/src/{}.rs", i)).collect();
+        let paths: Vec<&str> = paths.iter().map(String::as_str).collect();
+        let service = service(&paths, &LookrdConfig::default());
+
+        for sort_by in [SortBy::Relevance, SortBy::Alphabetical] {
+            for (count, offset, truncated) in
+                [(3, 0, true), (3, 6, true), (3, 7, false), (20, 0, false)]
+            {
+                let req = Request::new(QueryReq {
+                    query: "rs".to_string(),
+                    count,
+                    offset,
+                    sort_by: sort_by as i32,
+                    ..Default::default()
+                });
+                let resp = service.query(req).await.unwrap().into_inner();
+                assert_eq!(
+                    resp.truncated, truncated,
+                    "count {} offset {}",
+                    count, offset
+                );
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_error_codes() {
+        let config = LookrdConfig {
+            query_timeout_ms: Some(100),
+            ..Default::default()
+        };
+        let service = service(&["/src/a.rs"], &config);
+        let failed = |status: Status| {
+            // Held base64 encoded until it's sent.
+            let details = base64::decode(status.details()).unwrap();
+            let details = ErrorDetails::decode(&details[..]).unwrap();
+            (status.code(), ErrorCode::from_i32(details.code).unwrap())
+        };
+        let query = |req: QueryReq| {
+            let service = &service;
+            async move { failed(service.query(Request::new(req)).await.unwrap_err()) }
+        };
+
+        assert_eq!(
+            query(QueryReq {
+                query: "nosuchfield:rs".to_string(),
+                ..Default::default()
+            })
+            .await,
+            (Code::InvalidArgument, ErrorCode::QueryParse)
+        );
+        assert_eq!(
+            query(QueryReq {
+                query: "rs".to_string(),
+                first_line: "nosuchfield:bash".to_string(),
+                ..Default::default()
+            })
+            .await,
+            (Code::InvalidArgument, ErrorCode::QueryParse)
+        );
+        assert_eq!(
+            query(QueryReq {
+                query: "rs".to_string(),
+                count: -1,
+                ..Default::default()
+            })
+            .await,
+            (Code::InvalidArgument, ErrorCode::InvalidRequest)
+        );
+
+        let slow = service
+            .blocking(|| {
+                std::thread::sleep(Duration::from_millis(500));
+                Ok(())
+            })
+            .await;
+        assert_eq!(
+            failed(slow.unwrap_err()),
+            (Code::DeadlineExceeded, ErrorCode::Timeout)
+        );
+
+        // The service's indexer was never started.
+        let compact = service.compact(Request::new(CompactReq {})).await;
+        assert_eq!(
+            failed(compact.unwrap_err()),
+            (Code::Unavailable, ErrorCode::IndexerStopped)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_recent_queries() {
+        let paths = ["/src/main.rs", "/src/lib.rs", "/README.md"];
+        let recent = |service: &LookrService| {
+            let service = service.clone();
+            async move {
+                let req = Request::new(RecentQueriesReq { top: 1 });
+                service.recent_queries(req).await.unwrap().into_inner()
+            }
+        };
+
+        // Off by default.
+        let service = service(&paths, &LookrdConfig::default());
+        query(&service, "rs").await;
+        assert_eq!(recent(&service).await, RecentQueriesResp::default());
+
+        let config = LookrdConfig {
+            query_history: Some(2),
+            ..Default::default()
+        };
+        let service = crate::testing::service(&paths, &config);
+        for q in &["main", "rs", "rs"] {
+            query(&service, q).await;
+        }
+        let resp = recent(&service).await;
+        assert!(resp.enabled);
+        let recent: Vec<(&str, u64)> = resp
+            .recent
+            .iter()
+            .map(|q| (q.query.as_str(), q.total_hits))
+            .collect();
+        assert_eq!(recent, vec![("rs", 2), ("rs", 2)]);
+        assert_eq!(
+            resp.top,
+            vec![QueryCount {
+                query: "rs".to_string(),
+                count: 2
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_query_limits() {
+        let limits = Arc::new(QueryLimits::new(2, 1000));
+        let service =
+            service(&["/src/main.rs"], &LookrdConfig::default()).with_limits(limits.clone());
+        let req = || {
+            Request::new(QueryReq {
+                query: "main".to_string(),
+                ..Default::default()
+            })
+        };
+
+        // Two queries running, so a third is turned away until one is done.
+        let first = limits.admit(None).unwrap();
+        let _second = limits.admit(None).unwrap();
+        let err = service.query(req()).await.unwrap_err();
+        assert_eq!(err.code(), Code::ResourceExhausted);
+        let batch = Request::new(BatchQueryReq {
+            queries: vec![req().into_inner()],
+        });
+        let err = service.batch_query(batch).await.unwrap_err();
+        assert_eq!(err.code(), Code::ResourceExhausted);
+        drop(first);
+        assert_eq!(service.query(req()).await.unwrap().get_ref().total_hits, 1);
+
+        // A client over its rate is turned away however few are running.
+        let limits = Arc::new(QueryLimits::new(2, 1));
+        let service = service.with_limits(limits);
+        service.query(req()).await.unwrap();
+        let err = service.query(req()).await.unwrap_err();
+        assert_eq!(err.code(), Code::ResourceExhausted);
+    }
+
+    #[tokio::test]
+    async fn test_audit_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.log");
+        let audit_log = Arc::new(AuditLog::open(&path, 1024).unwrap());
+        let service = service(&["/src/main.rs", "/src/lib.rs"], &LookrdConfig::default())
+            .for_user("me")
+            .with_audit_log(audit_log);
+
+        query(&service, "main").await;
+        let req = Request::new(BatchQueryReq {
+            queries: vec![QueryReq {
+                query: "rs".to_string(),
+                count: 1,
+                ..Default::default()
+            }],
+        });
+        service.batch_query(req).await.unwrap();
+        // Dropping the last reference writes what's queued.
+        drop(service);
+
+        let entries: Vec<serde_json::Value> = std::fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        let summary: Vec<_> = entries
+            .iter()
+            .map(|e| {
+                (
+                    e["user"].as_str(),
+                    e["query"].as_str(),
+                    e["total_hits"].as_u64(),
+                    e["returned"].as_u64(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (Some("me"), Some("main"), Some(1), Some(1)),
+                (Some("me"), Some("rs"), Some(2), Some(1)),
+            ]
+        );
+        // Not sent over a connection.
+        assert!(entries[0]["addr"].is_null());
+    }
+
+    #[tokio::test]
+    async fn test_get_doc() {
+        let service = service(&["/src/main.rs"], &LookrdConfig::default());
+        let get_doc = |path: &str| {
+            let service = service.clone();
+            let req = Request::new(GetDocReq {
+                path: path.to_string(),
+            });
+            async move { service.get_doc(req).await }
+        };
+
+        let resp = get_doc("/src/main.rs").await.unwrap().into_inner();
+        assert!(resp.found);
+        let fields: Vec<(&str, &str)> = resp
+            .fields
+            .iter()
+            .map(|f| (f.name.as_str(), f.value.as_str()))
+            .collect();
+        assert_eq!(
+            fields,
+            vec![
+                ("file_id", "/src/main.rs"),
+                ("path", "/src/main.rs"),
+                ("parent", "/src")
+            ]
+        );
+
+        // Only the exact path.
+        let resp = get_doc("/src/main").await.unwrap().into_inner();
+        assert_eq!(resp, GetDocResp::default());
+        let e = get_doc("src/main.rs").await.unwrap_err();
+        assert_eq!(e.code(), Code::InvalidArgument);
+    }
+
+    #[tokio::test]
+    async fn test_find_marker() {
+        let service = service(
+            &[
+                "/home/me/work",
+                "/home/me/work/.git",
+                "/home/me/work/lookr",
+                "/home/me/work/lookr/Cargo.toml",
+                "/home/me/work/lookr/lookr-daemon",
+                "/home/me/work/lookr/lookr-daemon/Cargo.toml",
+                "/home/me/work/lookr/lookr-daemon/src",
+                "/home/me/work/lookr/lookr-daemon/src/rpc.rs",
+                "/home/me/work/notes",
+                "/home/me/work/notes/todo.md",
+            ],
+            &LookrdConfig::default(),
+        );
+        let find_marker = |start: &str, marker: &str| {
+            let service = service.clone();
+            let req = Request::new(FindMarkerReq {
+                start_path: start.to_string(),
+                marker: marker.to_string(),
+            });
+            async move { service.find_marker(req).await.map(|r| r.into_inner()) }
+        };
+        let found = |dir: &str| FindMarkerResp {
+            found: true,
+            dir: dir.to_string(),
+        };
+
+        // The deepest wins.
+        assert_eq!(
+            find_marker("/home/me/work/lookr/lookr-daemon/src/rpc.rs", "Cargo.toml")
+                .await
+                .unwrap(),
+            found("/home/me/work/lookr/lookr-daemon")
+        );
+        assert_eq!(
+            find_marker("/home/me/work/lookr", "Cargo.toml")
+                .await
+                .unwrap(),
+            found("/home/me/work/lookr")
+        );
+        assert_eq!(
+            find_marker("/home/me/work/lookr/lookr-daemon/src", ".git")
+                .await
+                .unwrap(),
+            found("/home/me/work")
+        );
+        // The start needn't be indexed itself.
+        assert_eq!(
+            find_marker("/home/me/work/notes/new.md", ".git")
+                .await
+                .unwrap(),
+            found("/home/me/work")
+        );
+        assert_eq!(
+            find_marker("/home/me/work/notes", "Cargo.toml")
+                .await
+                .unwrap(),
+            FindMarkerResp::default()
+        );
+
+        for (start, marker) in [
+            ("work/lookr", "Cargo.toml"),
+            ("/home/me/work/../work", "Cargo.toml"),
+            ("/home/me/work", "lookr/Cargo.toml"),
+            ("/home/me/work", ".."),
+            ("/home/me/work", ""),
+        ] {
+            let e = find_marker(start, marker).await.unwrap_err();
+            assert_eq!(e.code(), Code::InvalidArgument);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_query_within() {
+        let service = service(
+            &[
+                "/home/me/projects/foo/src/main.rs",
+                "/home/me/projects/foobar/main.rs",
+                "/home/me/projects/bar/main.rs",
+            ],
+            &LookrdConfig::default(),
+        );
+        let within = |within: &str| {
+            let service = service.clone();
+            let req = Request::new(QueryReq {
+                query: "main".to_string(),
+                within: within.to_string(),
+                ..Default::default()
+            });
+            async move { service.query(req).await.map(|r| r.into_inner().results) }
+        };
+
+        assert_eq!(
+            within("/home/me/projects/foo").await.unwrap(),
+            vec!["/home/me/projects/foo/src/main.rs"]
+        );
+        assert_eq!(
+            within("/home/me/projects/foo/").await.unwrap(),
+            vec!["/home/me/projects/foo/src/main.rs"]
+        );
+        assert_eq!(within("/home/me/projects").await.unwrap().len(), 3);
+        assert_eq!(
+            within("projects/foo").await.unwrap_err().code(),
+            Code::InvalidArgument
+        );
+    }
+
+    #[tokio::test]
+    async fn test_normalize_separators() {
+        let paths = [
+            r"/mnt/c\Users\me\report.txt",
+            r"/mnt/c\Users\me\Documents\notes.txt",
+            "/home/me/report.txt",
+        ];
+        let config = LookrdConfig {
+            normalize_separators: true,
+            ..Default::default()
+        };
+        let service = service(&paths, &config);
+        let query_within = |query: &str, within: &str| {
+            let service = service.clone();
+            let req = Request::new(QueryReq {
+                query: query.to_string(),
+                within: within.to_string(),
+                filename_only: true,
+                sort_by: SortBy::Alphabetical as i32,
+                ..Default::default()
+            });
+            async move { service.query(req).await.unwrap().into_inner().results }
+        };
+
+        // Matched by their file names, and shown as they were found.
+        assert_eq!(query_within("report", "").await, vec![paths[2], paths[0]]);
+        // Either separator finds them below a directory.
+        for within in &["/mnt/c/Users/me", r"/mnt/c\Users\me"] {
+            assert_eq!(query_within("", within).await, vec![paths[1], paths[0]]);
+        }
+        assert_eq!(
+            query_within("", "/mnt/c/Users/me/Documents").await,
+            vec![paths[1]]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_ngram_filename() {
+        let paths = ["/docs/report.txt", "/docs/notes.txt", "/repos/a.txt"];
+        let filename_query = |service: &LookrService, query: &str| {
+            let service = service.clone();
+            let req = Request::new(QueryReq {
+                query: query.to_string(),
+                filename_only: true,
+                ..Default::default()
+            });
+            async move { service.query(req).await.unwrap().into_inner().results }
+        };
+
+        let service = service(&paths, &LookrdConfig::default());
+        assert!(filename_query(&service, "epo").await.is_empty());
+
+        let mut config = LookrdConfig::default();
+        config.tokenizers.filename = crate::config::TokenizerKind::Ngram;
+        let service = crate::testing::service(&paths, &config);
+        assert_eq!(
+            filename_query(&service, "epo").await,
+            vec!["/docs/report.txt"]
+        );
+        assert_eq!(
+            filename_query(&service, "port").await,
+            vec!["/docs/report.txt"]
+        );
+        assert!(filename_query(&service, "xyz").await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_substring() {
+        let paths = ["/docs/report.txt", "/docs/notes.txt", "/epo/a.txt"];
+        let substring = |service: &LookrService, query: &str| {
+            let service = service.clone();
+            let req = Request::new(QueryReq {
+                query: query.to_string(),
+                substring: true,
+                ..Default::default()
+            });
+            async move { service.query(req).await.map(|r| r.into_inner().results) }
+        };
+
+        let service = service(&paths, &LookrdConfig::default());
+        assert_eq!(
+            substring(&service, "epo").await.unwrap_err().code(),
+            Code::InvalidArgument
+        );
+
+        let config = LookrdConfig {
+            substring_index: true,
+            ..Default::default()
+        };
+        let service = crate::testing::service(&paths, &config);
+        // Only file names, not the directories above them.
+        assert_eq!(
+            substring(&service, "epo").await.unwrap(),
+            vec!["/docs/report.txt"]
+        );
+        assert_eq!(
+            substring(&service, "EPOR").await.unwrap(),
+            vec!["/docs/report.txt"]
+        );
+        assert!(substring(&service, "xyz").await.unwrap().is_empty());
+        // Normal queries are unchanged.
+        assert!(query(&service, "epo")
+            .await
+            .contains(&"/epo/a.txt".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_boosts() {
+        // Shorter paths rank higher, as a match is more of the path.
+        let paths = ["/report/a.txt", "/a/b/c/d/report.md"];
+        let service = service(&paths, &LookrdConfig::default());
+        let ranked = |boosts: Option<FieldBoosts>| {
+            let service = service.clone();
+            let req = Request::new(QueryReq {
+                query: "report".to_string(),
+                boosts,
+                ..Default::default()
+            });
+            async move { service.query(req).await.map(|r| r.into_inner().results) }
+        };
+
+        assert_eq!(ranked(None).await.unwrap(), paths);
+        // Boosting the filename also searches it, and its matches now count
+        // for more.
+        let filename = FieldBoosts {
+            filename: 3.0,
+            ..Default::default()
+        };
+        assert_eq!(
+            ranked(Some(filename)).await.unwrap(),
+            vec!["/a/b/c/d/report.md", "/report/a.txt"]
+        );
+        // And the extension can be searched too.
+        let ext = FieldBoosts {
+            ext: 1.0,
+            ..Default::default()
+        };
+        let md = service
+            .query(Request::new(QueryReq {
+                query: "md".to_string(),
+                boosts: Some(ext),
+                ..Default::default()
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .results;
+        assert_eq!(md, vec!["/a/b/c/d/report.md"]);
+
+        for bad in [-1.0, f32::NAN, f32::INFINITY] {
+            let boosts = FieldBoosts {
+                path: bad,
+                ..Default::default()
+            };
+            assert_eq!(
+                ranked(Some(boosts)).await.unwrap_err().code(),
+                Code::InvalidArgument
+            );
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_same_file_as() {
+        let root = tempfile::tempdir().unwrap();
+        let path = |name: &str| root.path().join(name).to_string_lossy().to_string();
+        std::fs::write(path("a"), "").unwrap();
+        std::fs::hard_link(path("a"), path("b")).unwrap();
+        std::fs::hard_link(path("a"), path("c")).unwrap();
+        std::fs::write(path("other"), "").unwrap();
+        let paths = [path("a"), path("b"), path("c"), path("other")];
+        let same_file_as = |service: &LookrService, file: &str| {
+            let service = service.clone();
+            let req = Request::new(QueryReq {
+                same_file_as: file.to_string(),
+                ..Default::default()
+            });
+            async move { service.query(req).await.map(|r| r.into_inner().results) }
+        };
+
+        let service = service(&paths, &LookrdConfig::default());
+        assert_eq!(
+            same_file_as(&service, &path("a")).await.unwrap_err().code(),
+            Code::InvalidArgument
+        );
+
+        let config = LookrdConfig {
+            index_inodes: true,
+            ..Default::default()
+        };
+        let service = crate::testing::service(&paths, &config);
+        let mut links = same_file_as(&service, &path("b")).await.unwrap();
+        links.sort();
+        assert_eq!(links, vec![path("a"), path("c")]);
+        assert!(same_file_as(&service, &path("other"))
+            .await
+            .unwrap()
+            .is_empty());
+        for bad in [path("missing"), "relative".to_string()] {
+            assert_eq!(
+                same_file_as(&service, &bad).await.unwrap_err().code(),
+                Code::InvalidArgument
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_owned_by_me() {
+        let schema = build_schema();
+        let index = Index::create_in_ram(schema.clone());
+        let fields = Fields::new(&schema);
+        let config = LookrdConfig {
+            index_owners: true,
+            ..Default::default()
+        };
+        let indexer = Indexer::new(index.clone(), schema.clone(), config.clone()).unwrap();
+        let mut index_writer = index.writer_with_num_threads(1, 50_000_000).unwrap();
+        for (path, uid) in &[("/home/a/notes.md", 1000), ("/home/b/notes.md", 1001)] {
+            let mut doc = fields.document(Path::new(path));
+            doc.add_u64(fields.uid, *uid);
+            index_writer.add_document(doc);
+        }
+        index_writer.commit().unwrap();
+        let owned_by_me = |service: &LookrService| {
+            let service = service.clone();
+            let req = Request::new(QueryReq {
+                query: "notes".to_string(),
+                owned_by_me: true,
+                ..Default::default()
+            });
+            async move { service.query(req).await.map(|r| r.into_inner().results) }
+        };
+
+        let service = LookrService::new(index.clone(), schema.clone(), &config, indexer.handle());
+        let a = service.clone().with_owner_uid(1000);
+        assert_eq!(owned_by_me(&a).await.unwrap(), vec!["/home/a/notes.md"]);
+        let b = service.with_owner_uid(1001);
+        assert_eq!(owned_by_me(&b).await.unwrap(), vec!["/home/b/notes.md"]);
+        assert_eq!(query(&b, "notes").await.len(), 2);
+
+        let service = LookrService::new(index, schema, &LookrdConfig::default(), indexer.handle())
+            .with_owner_uid(1000);
+        assert_eq!(
+            owned_by_me(&service).await.unwrap_err().code(),
+            Code::InvalidArgument
+        );
+    }
+
+    #[tokio::test]
+    async fn test_query_xattr() {
+        let schema = build_schema();
+        let index = Index::create_in_ram(schema.clone());
+        let fields = Fields::new(&schema);
+        let config = LookrdConfig {
+            index_xattrs: vec!["user.".to_string()],
+            ..Default::default()
+        };
+        let indexer = Indexer::new(index.clone(), schema.clone(), config.clone()).unwrap();
+        let mut index_writer = index.writer_with_num_threads(1, 50_000_000).unwrap();
+        for (path, value) in &[("/a/notes.md", "lookr"), ("/b/notes.md", "other")] {
+            let mut doc = fields.document(Path::new(path));
+            for term in crate::xattr::terms("user.project", Some(value)) {
+                doc.add_text(fields.xattr, &term);
+            }
+            index_writer.add_document(doc);
+        }
+        index_writer.add_document(fields.document(Path::new("/c/notes.md")));
+        index_writer.commit().unwrap();
+        let with_xattr = |service: &LookrService, xattr: &str| {
+            let service = service.clone();
+            let req = Request::new(QueryReq {
+                xattr: xattr.to_string(),
+                ..Default::default()
+            });
+            async move { service.query(req).await.map(|r| r.into_inner().results) }
+        };
+
+        let service = LookrService::new(index.clone(), schema.clone(), &config, indexer.handle());
+        let mut results = with_xattr(&service, "user.project").await.unwrap();
+        results.sort();
+        assert_eq!(results, vec!["/a/notes.md", "/b/notes.md"]);
+        assert_eq!(
+            with_xattr(&service, "user.project=lookr").await.unwrap(),
+            vec!["/a/notes.md"]
+        );
+        assert!(with_xattr(&service, "user.project=lookr2")
+            .await
+            .unwrap()
+            .is_empty());
+
+        let service = LookrService::new(index, schema, &LookrdConfig::default(), indexer.handle());
+        assert_eq!(
+            with_xattr(&service, "user.project")
+                .await
+                .unwrap_err()
+                .code(),
+            Code::InvalidArgument
+        );
+    }
+
+    #[tokio::test]
+    async fn test_multi_user() {
+        let config = LookrdConfig::default();
+        let mut multi = MultiUserService::default();
+        multi.add_user(
+            "alice",
+            "a-secret".to_string(),
+            PathBuf::from("/var/lib/lookrd/alice/secret"),
+            service(&["/home/alice/notes.txt"], &config),
+        );
+        multi.add_user(
+            "bob",
+            "b-secret".to_string(),
+            PathBuf::from("/var/lib/lookrd/bob/secret"),
+            service(&["/home/bob/notes.txt"], &config),
+        );
+        let notes = |secret: Option<&str>| {
+            let mut req = Request::new(QueryReq {
+                query: "notes".to_string(),
+                ..Default::default()
+            });
+            if let Some(secret) = secret {
+                req.metadata_mut()
+                    .insert(SECRET_HEADER, secret.parse().unwrap());
+            }
+            let multi = multi.clone();
+            async move { multi.query(req).await.map(|r| r.into_inner().results) }
+        };
+
+        assert_eq!(
+            notes(Some("a-secret")).await.unwrap(),
+            vec!["/home/alice/notes.txt"]
+        );
+        assert_eq!(
+            notes(Some("b-secret")).await.unwrap(),
+            vec!["/home/bob/notes.txt"]
+        );
+        for secret in [None, Some("c-secret")] {
+            assert_eq!(
+                notes(secret).await.unwrap_err().code(),
+                Code::Unauthenticated
+            );
+        }
+
+        // The secret can be sent in the query too.
+        let req = Request::new(QueryReq {
+            query: "notes".to_string(),
+            secret: "b-secret".to_string(),
+            ..Default::default()
+        });
+        assert_eq!(
+            multi.query(req).await.unwrap().into_inner().results,
+            vec!["/home/bob/notes.txt"]
+        );
+
+        let path = |user: &str| {
+            let req = Request::new(SecretPathReq {
+                user: user.to_string(),
+            });
+            let multi = multi.clone();
+            async move {
+                multi
+                    .get_secret_path(req)
+                    .await
+                    .map(|r| r.into_inner().path)
+            }
+        };
+        assert_eq!(path("bob").await.unwrap(), "/var/lib/lookrd/bob/secret");
+        assert_eq!(
+            path("carol").await.unwrap_err().code(),
+            Code::InvalidArgument
+        );
+
+        // Nor can a user have the daemon write a snapshot anywhere.
+        let dest = tempfile::tempdir().unwrap();
+        let mut req = Request::new(SnapshotReq {
+            dest_dir: dest.path().join("snap").to_string_lossy().to_string(),
+        });
+        req.metadata_mut()
+            .insert(SECRET_HEADER, "a-secret".parse().unwrap());
+        assert_eq!(
+            multi.snapshot(req).await.unwrap_err().code(),
+            Code::InvalidArgument
+        );
+        assert!(!dest.path().join("snap").exists());
     }
 }
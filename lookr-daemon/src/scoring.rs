@@ -0,0 +1,284 @@
+//! Ranking matches other than by tantivy's own BM25, whose parameters are
+//! fixed. Each match's score is worked out again from the query's terms as
+//! the matches are collected, and can be raised for having been modified
+//! recently.
+
+use crate::config::{LookrdConfig, Scoring};
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+use std::time::{Duration, SystemTime};
+use tantivy::collector::{ScoreSegmentTweaker, ScoreTweaker};
+use tantivy::fastfield::FastFieldReader;
+use tantivy::fieldnorm::FieldNormReader;
+use tantivy::postings::SegmentPostings;
+use tantivy::query::Query;
+use tantivy::schema::{Field, IndexRecordOption};
+use tantivy::{DocId, DocSet, Postings, Score, Searcher, SegmentReader, SkipResult, Term};
+
+/// The BM25 parameters tantivy uses, and those used if not configured.
+pub(crate) const DEFAULT_BM25_K1: f32 = 1.2;
+pub(crate) const DEFAULT_BM25_B: f32 = 0.75;
+
+/// How long it takes the boost for being modified recently to halve.
+const RECENCY_HALF_LIFE: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// How well a term in a field matches.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Similarity {
+    /// BM25, with `k1` deciding how quickly repeats of a term stop adding to
+    /// the score, and `b` how much a field's length counts against it.
+    Bm25 { k1: f32, b: f32 },
+    /// The square root of the term's frequency over that of the field's
+    /// length, weighted by how rare the term is.
+    TfIdf,
+}
+
+impl Similarity {
+    /// The similarity the config asks for, or `None` if it's tantivy's own,
+    /// which needs no rescoring.
+    pub(crate) fn from_config(config: &LookrdConfig) -> Option<Self> {
+        match config.scoring {
+            Scoring::Bm25 if config.bm25_k1.is_none() && config.bm25_b.is_none() => None,
+            Scoring::Bm25 => Some(Similarity::Bm25 {
+                k1: config.bm25_k1.unwrap_or(DEFAULT_BM25_K1),
+                b: config.bm25_b.unwrap_or(DEFAULT_BM25_B),
+            }),
+            Scoring::Tfidf => Some(Similarity::TfIdf),
+        }
+    }
+
+    /// How rare a term found in `doc_freq` of `doc_count` documents is.
+    fn idf(self, doc_freq: u64, doc_count: u64) -> f32 {
+        let (n, count) = (doc_freq as f32, doc_count as f32);
+        match self {
+            Similarity::Bm25 { .. } => (1.0 + (count - n + 0.5) / (n + 0.5)).ln(),
+            Similarity::TfIdf => 1.0 + (count / (n + 1.0)).ln(),
+        }
+    }
+
+    /// The score of a term found `tf` times in a field of `len` terms, where
+    /// fields average `avg_len`.
+    fn score(self, idf: f32, tf: u32, len: u32, avg_len: f32) -> f32 {
+        let tf = tf as f32;
+        match self {
+            Similarity::Bm25 { k1, b } => {
+                let norm = k1 * (1.0 - b + b * len as f32 / avg_len);
+                idf * tf * (k1 + 1.0) / (tf + norm)
+            }
+            Similarity::TfIdf => idf * tf.sqrt() / (len.max(1) as f32).sqrt(),
+        }
+    }
+}
+
+/// How much more a match scores for having been modified recently: `1 +
+/// weight` times as much if just now, `1 + weight / 2` a half-life ago, and
+/// so on. Matches without a modification time aren't raised.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct Recency {
+    weight: f32,
+    /// In nanoseconds since the epoch, as modification times are indexed.
+    now: u64,
+}
+
+impl Recency {
+    pub(crate) fn new(weight: f32, now: SystemTime) -> Self {
+        let now = now
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos() as u64);
+        Recency { weight, now }
+    }
+
+    fn boost(self, mtime: u64) -> f32 {
+        if mtime == 0 {
+            return 1.0;
+        }
+        let age = self.now.saturating_sub(mtime) as f64 / RECENCY_HALF_LIFE.as_nanos() as f64;
+        1.0 + self.weight * 0.5f64.powf(age) as f32
+    }
+}
+
+/// A term of the query, with what's needed to score it.
+struct WeightedTerm {
+    term: Term,
+    idf: f32,
+    avg_len: f32,
+    boost: f32,
+}
+
+/// Scores each match as the sum of the scores of the query's terms found in
+/// it, each multiplied by its field's boost. Queries without terms, like
+/// those of only ranges, keep tantivy's score, as do all without a
+/// similarity. Then, with a recency, raises it by the match's modification
+/// time in the field.
+pub(crate) struct Rescorer {
+    similarity: Similarity,
+    terms: Vec<WeightedTerm>,
+    recency: Option<(Field, Recency)>,
+}
+
+impl Rescorer {
+    pub(crate) fn new(
+        searcher: &Searcher,
+        query: &dyn Query,
+        similarity: Option<Similarity>,
+        boost: impl Fn(Field) -> f32,
+    ) -> Self {
+        let similarity = match similarity {
+            Some(s) => s,
+            None => {
+                return Rescorer {
+                    // Unused without terms.
+                    similarity: Similarity::TfIdf,
+                    terms: Vec::new(),
+                    recency: None,
+                };
+            }
+        };
+        let mut term_set = BTreeSet::new();
+        query.query_terms(&mut term_set);
+        let doc_count: u64 = searcher
+            .segment_readers()
+            .iter()
+            .map(|r| u64::from(r.max_doc()))
+            .sum();
+        let terms = term_set
+            .into_iter()
+            .map(|term| {
+                let field = term.field();
+                let num_tokens: u64 = searcher
+                    .segment_readers()
+                    .iter()
+                    .map(|r| r.inverted_index(field).total_num_tokens())
+                    .sum();
+                let avg_len = if doc_count == 0 || num_tokens == 0 {
+                    1.0
+                } else {
+                    num_tokens as f32 / doc_count as f32
+                };
+                WeightedTerm {
+                    idf: similarity.idf(searcher.doc_freq(&term), doc_count),
+                    avg_len,
+                    boost: boost(field),
+                    term,
+                }
+            })
+            .collect();
+        Rescorer {
+            similarity,
+            terms,
+            recency: None,
+        }
+    }
+
+    pub(crate) fn with_recency(mut self, mtime: Field, recency: Recency) -> Self {
+        self.recency = Some((mtime, recency));
+        self
+    }
+}
+
+impl ScoreTweaker<Score> for Rescorer {
+    type Child = SegmentRescorer;
+
+    fn segment_tweaker(&self, reader: &SegmentReader) -> tantivy::Result<SegmentRescorer> {
+        let terms = self
+            .terms
+            .iter()
+            .filter_map(|t| {
+                let postings = reader
+                    .inverted_index(t.term.field())
+                    .read_postings(&t.term, IndexRecordOption::WithFreqs)?;
+                Some(SegmentTerm {
+                    postings: RefCell::new(TermFreqs {
+                        postings,
+                        started: false,
+                        ended: false,
+                    }),
+                    fieldnorms: reader.get_fieldnorms_reader(t.term.field()),
+                    idf: t.idf,
+                    avg_len: t.avg_len,
+                    boost: t.boost,
+                })
+            })
+            .collect();
+        let recency = match self.recency {
+            Some((field, recency)) => reader.fast_fields().u64(field).map(|r| (r, recency)),
+            None => None,
+        };
+        Ok(SegmentRescorer {
+            similarity: self.similarity,
+            rescore: !self.terms.is_empty(),
+            terms,
+            recency,
+        })
+    }
+}
+
+pub(crate) struct SegmentRescorer {
+    similarity: Similarity,
+    rescore: bool,
+    /// The terms found in the segment.
+    terms: Vec<SegmentTerm>,
+    /// The segment's modification times, if raising recent matches.
+    recency: Option<(FastFieldReader<u64>, Recency)>,
+}
+
+struct SegmentTerm {
+    postings: RefCell<TermFreqs>,
+    fieldnorms: FieldNormReader,
+    idf: f32,
+    avg_len: f32,
+    boost: f32,
+}
+
+impl ScoreSegmentTweaker<Score> for SegmentRescorer {
+    fn score(&self, doc: DocId, score: Score) -> Score {
+        let score = if self.rescore {
+            self.terms
+                .iter()
+                .map(|t| match t.postings.borrow_mut().term_freq(doc) {
+                    0 => 0.0,
+                    tf => {
+                        let len = t.fieldnorms.fieldnorm(doc);
+                        t.boost * self.similarity.score(t.idf, tf, len, t.avg_len)
+                    }
+                })
+                .sum()
+        } else {
+            score
+        };
+        match &self.recency {
+            Some((mtimes, recency)) => score * recency.boost(mtimes.get(doc)),
+            None => score,
+        }
+    }
+}
+
+/// Walks a term's postings alongside the matches, which are collected in
+/// order of their ids.
+struct TermFreqs {
+    postings: SegmentPostings,
+    started: bool,
+    ended: bool,
+}
+
+impl TermFreqs {
+    /// How many times the term is in `doc`, which must be no lower than the
+    /// last one asked about.
+    fn term_freq(&mut self, doc: DocId) -> u32 {
+        if self.ended {
+            return 0;
+        }
+        if !self.started || self.postings.doc() < doc {
+            self.started = true;
+            if self.postings.skip_next(doc) == SkipResult::End {
+                self.ended = true;
+                return 0;
+            }
+        }
+        if self.postings.doc() == doc {
+            self.postings.term_freq()
+        } else {
+            0
+        }
+    }
+}
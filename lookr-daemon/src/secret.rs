@@ -1,15 +1,24 @@
 //! Manages the user secrets.
 
-use std::error;
-use std::io;
+use crate::users::{user_dir, UserAccess};
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
 use std::path::{Path, PathBuf};
 
-pub struct SecretManager<'a> {
-    data_dir: &'a PathBuf,
+/// The file in each user's directory holding their secret.
+static SECRET_FILE: &str = "secret";
+
+/// The number of random bytes in a secret.
+const SECRET_LEN: usize = 32;
+
+/// Creates a secret for each user, in a file only they can read, which they
+/// send with their requests to show who they are.
+pub struct SecretManager {
+    data_dir: PathBuf,
 }
 
-impl<'a> SecretManager<'a> {
-    pub fn new(data_dir: &'a PathBuf) -> io::Result<Self> {
+impl SecretManager {
+    pub fn new(data_dir: &Path) -> io::Result<Self> {
         if !data_dir.exists() {
             return Err(io::Error::new(
                 io::ErrorKind::NotFound,
@@ -23,18 +32,100 @@ impl<'a> SecretManager<'a> {
             ));
         }
 
-        Ok(SecretManager { data_dir })
+        Ok(SecretManager {
+            data_dir: data_dir.to_path_buf(),
+        })
+    }
+
+    /// The user's secret and the file it's kept in, creating them if there
+    /// isn't one yet. A new secret is made every time the daemon starts, so
+    /// one that has leaked stops working on restart.
+    pub fn create_for_user(&self, user: &UserAccess) -> io::Result<(String, PathBuf)> {
+        let dir = user_dir(&self.data_dir, &user.name);
+        fs::create_dir_all(&dir)?;
+        // Others can reach their own secret, but can't list the directory.
+        set_mode(&dir, 0o711)?;
+
+        let path = dir.join(SECRET_FILE);
+        let secret = random_secret()?;
+        // Replaced rather than rewritten, so it's never readable by the
+        // wrong user part way through.
+        let tmp = dir.join(format!(".{}.tmp", SECRET_FILE));
+        let _ = fs::remove_file(&tmp);
+        {
+            let mut file = create_private(&tmp)?;
+            file.write_all(secret.as_bytes())?;
+        }
+        set_owner(&tmp, user)?;
+        fs::rename(&tmp, &path)?;
+        Ok((secret, path))
+    }
+}
+
+/// A hex string of `SECRET_LEN` random bytes.
+fn random_secret() -> io::Result<String> {
+    let mut bytes = [0; SECRET_LEN];
+    File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+    Ok(bytes.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+#[cfg(unix)]
+fn create_private(path: &Path) -> io::Result<File> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o400)
+        .open(path)
+}
+
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: u32) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))
+}
+
+/// Give the file to the user. Only root can give files away, but a daemon
+/// that isn't root can still keep a secret for the user it runs as.
+#[cfg(unix)]
+fn set_owner(path: &Path, user: &UserAccess) -> io::Result<()> {
+    use nix::unistd::{chown, geteuid, Uid};
+
+    if geteuid().as_raw() == user.uid {
+        return Ok(());
     }
+    chown(path, Some(Uid::from_raw(user.uid)), None).map_err(io::Error::from)
+}
 
-    /// Returns the path to the users secret, this will create a secret for the
-    /// given user if the user exists.
-    pub fn get_path_for_user(user: &str) -> Result<Option<PathBuf>, Box<dyn error::Error>> {
-        // Check for an existing secret for the given user, and the user matches.
+#[cfg(test)]
+mod test {
+    use super::*;
 
-        // Determine if the user exists.
+    #[test]
+    fn test_create_for_user() {
+        let dir = tempfile::tempdir().unwrap();
+        let secrets = SecretManager::new(dir.path()).unwrap();
+        let user = UserAccess {
+            name: "me".to_string(),
+            uid: nix::unistd::geteuid().as_raw(),
+            gids: vec![],
+        };
 
-        // If it exists and there is no secret, then create one and write the secret.
+        let (secret, path) = secrets.create_for_user(&user).unwrap();
+        assert_eq!(path, dir.path().join("me").join("secret"));
+        assert_eq!(secret.len(), SECRET_LEN * 2);
+        assert_eq!(fs::read_to_string(&path).unwrap(), secret);
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o400);
+        }
 
-        todo!()
+        // A new one each time.
+        let (again, _) = secrets.create_for_user(&user).unwrap();
+        assert_ne!(again, secret);
+        assert_eq!(fs::read_to_string(&path).unwrap(), again);
     }
 }
@@ -0,0 +1,113 @@
+//! A check that indexing and querying work on this machine, without a
+//! config or the network, for after installing lookrd.
+
+use crate::config::LookrdConfig;
+use crate::indexer::{build_schema, Indexer};
+use crate::proto::rpc::lookr_server::Lookr;
+use crate::proto::rpc::{QueryReq, SortBy};
+use crate::rpc::LookrService;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+use tantivy::Index;
+use tonic::Request;
+
+/// The files the test indexes, relative to its directory.
+const FILES: [&str; 4] = [
+    "docs/readme.md",
+    "docs/notes/plan.txt",
+    "src/main.rs",
+    "src/lib.rs",
+];
+
+/// A directory removed when dropped.
+struct TempDir(PathBuf);
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Index a new directory of known files, in memory, and check queries
+/// through the service find what they should. Returns the number of checks
+/// that passed, or what went wrong with the first that didn't.
+pub async fn run() -> Result<usize, String> {
+    let dir = TempDir(env::temp_dir().join(format!("lookrd-selftest-{}", process::id())));
+    create_files(&dir.0).map_err(|e| format!("Could not create {:?}: {}", dir.0, e))?;
+
+    let config = LookrdConfig {
+        index_paths: vec![dir.0.to_string_lossy().to_string()],
+        data_dir: dir.0.to_string_lossy().to_string(),
+        ..Default::default()
+    };
+    let schema = build_schema();
+    let index = Index::create_in_ram(schema.clone());
+    let mut indexer = Indexer::new(index.clone(), schema.clone(), config.clone())
+        .map_err(|e| format!("Could not create the indexer: {}", e))?;
+    indexer
+        .index_once()
+        .map_err(|e| format!("Could not index {:?}: {}", dir.0, e))?;
+    let service = LookrService::new(index, schema, &config, indexer.handle());
+
+    let path = |file: &str| dir.0.join(file).to_string_lossy().to_string();
+    let checks = vec![
+        ("a file name", query("main", ""), vec![path("src/main.rs")]),
+        (
+            "an extension",
+            query("rs", ""),
+            vec![path("src/lib.rs"), path("src/main.rs")],
+        ),
+        (
+            "within a directory",
+            query("txt", &path("docs")),
+            vec![path("docs/notes/plan.txt")],
+        ),
+        ("no match", query("missing", ""), vec![]),
+    ];
+    for (name, req, expected) in &checks {
+        let results = match service.query(Request::new(req.clone())).await {
+            Ok(resp) => resp.into_inner().results,
+            Err(e) => return Err(format!("Querying {}: {}", name, e.message())),
+        };
+        if &results != expected {
+            return Err(format!(
+                "Querying {} for {:?} found {:?}, expected {:?}",
+                name, req.query, results, expected
+            ));
+        }
+    }
+    Ok(checks.len())
+}
+
+fn create_files(dir: &Path) -> io::Result<()> {
+    for file in &FILES {
+        let path = dir.join(file);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&path, "")?;
+    }
+    Ok(())
+}
+
+fn query(query: &str, within: &str) -> QueryReq {
+    QueryReq {
+        query: query.to_string(),
+        within: within.to_string(),
+        sort_by: SortBy::Alphabetical as i32,
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_selftest() {
+        assert_eq!(run().await, Ok(4));
+    }
+}
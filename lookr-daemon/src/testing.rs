@@ -0,0 +1,48 @@
+//! Building populated indexes for tests and benchmarks. Outside of this
+//! crate's tests it needs the `bench` feature.
+
+use crate::config::LookrdConfig;
+use crate::indexer::{build_schema, Fields, Indexer};
+use crate::rpc::LookrService;
+use std::path::Path;
+use std::sync::atomic::Ordering;
+use tantivy::Index;
+
+/// A service over an in-RAM index of exactly `paths`, committed in one
+/// segment, and as ready as if they'd been walked.
+pub fn service<S: AsRef<str>>(paths: &[S], config: &LookrdConfig) -> LookrService {
+    let schema = build_schema();
+    let index = Index::create_in_ram(schema.clone());
+    let fields = Fields::new(&schema);
+    // Registers the tokenizers the schema needs.
+    let indexer = Indexer::new(index.clone(), schema.clone(), config.clone()).unwrap();
+
+    let mut index_writer = index.writer_with_num_threads(1, 50_000_000).unwrap();
+    for p in paths {
+        let doc = indexer.document(&fields, p.as_ref(), Path::new(p.as_ref()), None);
+        index_writer.add_document(doc);
+    }
+    index_writer.commit().unwrap();
+
+    let handle = indexer.handle();
+    handle.stats.ready.store(true, Ordering::Relaxed);
+    LookrService::new(index, schema, config, handle)
+}
+
+/// `n` paths laid out the same way every time. Of them, `project<p>` matches
+/// a tenth, `module<m>` a hundredth, `file<i>` only one, and each extension
+/// a fifth.
+pub fn synthetic_paths(n: usize) -> Vec<String> {
+    const EXTENSIONS: [&str; 5] = ["rs", "md", "txt", "json", "toml"];
+    (0..n)
+        .map(|i| {
+            format!(
+                "/home/user/project{}/src/module{}/file{}.{}",
+                i % 10,
+                i % 100,
+                i,
+                EXTENSIONS[i % EXTENSIONS.len()]
+            )
+        })
+        .collect()
+}
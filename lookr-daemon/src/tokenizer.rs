@@ -0,0 +1,407 @@
+//! Tokenizers for the text fields, registered on the index according to the
+//! config.
+
+use crate::config::{LookrdConfig, TokenizerKind};
+use std::mem;
+use std::str::CharIndices;
+use tantivy::tokenizer::{
+    AsciiFoldingFilter, BoxTokenStream, LowerCaser, NgramTokenizer, RawTokenizer, RemoveLongFilter,
+    TextAnalyzer, Token, TokenFilter, TokenStream, Tokenizer,
+};
+use tantivy::Index;
+use unicode_normalization::char::is_combining_mark;
+use unicode_normalization::{is_nfc_quick, IsNormalized, UnicodeNormalization};
+
+/// The tokenizer used for the path field.
+pub static PATH_TOKENIZER: &str = "lookr_path";
+
+/// The tokenizer used for the filename field, which is the path tokenizer
+/// unless words are being split.
+pub static FILENAME_TOKENIZER: &str = "lookr_filename";
+
+/// The tokenizer used for the first line field.
+pub static FIRST_LINE_TOKENIZER: &str = "lookr_first_line";
+
+/// The tokenizer used for the filename ngram field.
+pub static NGRAM_TOKENIZER: &str = "lookr_ngram";
+
+/// The shortest and longest runs of characters indexed by `ngram`.
+const NGRAM_MIN: usize = 2;
+const NGRAM_MAX: usize = 3;
+
+/// Register the tokenizers the schema refers to. This has to be done for
+/// every `Index` opened, before anything is indexed or queried. The schema
+/// only names them, so which tokenizer each field uses is up to the config.
+pub(crate) fn register(index: &Index, config: &LookrdConfig) {
+    let kinds = &config.tokenizers;
+    index
+        .tokenizers()
+        .register(PATH_TOKENIZER, analyzer(config, kinds.path, false));
+    index.tokenizers().register(
+        FILENAME_TOKENIZER,
+        analyzer(config, kinds.filename, config.split_words),
+    );
+    index.tokenizers().register(
+        FIRST_LINE_TOKENIZER,
+        analyzer(config, kinds.first_line, false),
+    );
+    index.tokenizers().register(
+        NGRAM_TOKENIZER,
+        analyzer(config, TokenizerKind::Ngram, false),
+    );
+}
+
+fn analyzer(config: &LookrdConfig, kind: TokenizerKind, split_words: bool) -> TextAnalyzer {
+    let mut analyzer = match kind {
+        TokenizerKind::Default => {
+            TextAnalyzer::from(PathTokenizer).filter(RemoveLongFilter::limit(40))
+        }
+        TokenizerKind::Raw => TextAnalyzer::from(RawTokenizer),
+        TokenizerKind::Ngram => {
+            TextAnalyzer::from(NgramTokenizer::all_ngrams(NGRAM_MIN, NGRAM_MAX))
+        }
+        TokenizerKind::Whitespace => {
+            TextAnalyzer::from(WhitespaceTokenizer).filter(RemoveLongFilter::limit(40))
+        }
+    };
+    // Splitting has to see the case before it is lowered. Whole values and
+    // ngrams have no words to split.
+    if split_words && matches!(kind, TokenizerKind::Default | TokenizerKind::Whitespace) {
+        analyzer = analyzer.filter(CamelCaseFilter);
+    }
+    analyzer = analyzer.filter(LowerCaser).filter(NfcFilter);
+    if config.ascii_fold {
+        analyzer = analyzer.filter(AsciiFoldingFilter);
+    }
+    analyzer
+}
+
+/// Splits on whitespace and punctuation, like tantivy's `SimpleTokenizer`,
+/// but keeps combining marks with the letter before them, so decomposed
+/// names (as macOS stores them) stay in one piece.
+#[derive(Clone)]
+pub(crate) struct PathTokenizer;
+
+struct PathTokenStream<'a> {
+    text: &'a str,
+    chars: CharIndices<'a>,
+    token: Token,
+}
+
+fn is_token_char(c: char) -> bool {
+    c.is_alphanumeric() || is_combining_mark(c)
+}
+
+impl Tokenizer for PathTokenizer {
+    fn token_stream<'a>(&self, text: &'a str) -> BoxTokenStream<'a> {
+        BoxTokenStream::from(PathTokenStream {
+            text,
+            chars: text.char_indices(),
+            token: Token::default(),
+        })
+    }
+}
+
+impl<'a> TokenStream for PathTokenStream<'a> {
+    fn advance(&mut self) -> bool {
+        self.token.text.clear();
+        self.token.position = self.token.position.wrapping_add(1);
+        while let Some((offset_from, c)) = self.chars.next() {
+            if c.is_alphanumeric() {
+                let offset_to = self
+                    .chars
+                    .by_ref()
+                    .find(|&(_, c)| !is_token_char(c))
+                    .map(|(offset, _)| offset)
+                    .unwrap_or(self.text.len());
+                self.token.offset_from = offset_from;
+                self.token.offset_to = offset_to;
+                self.token.text.push_str(&self.text[offset_from..offset_to]);
+                return true;
+            }
+        }
+        false
+    }
+
+    fn token(&self) -> &Token {
+        &self.token
+    }
+
+    fn token_mut(&mut self) -> &mut Token {
+        &mut self.token
+    }
+}
+
+/// Splits on whitespace only.
+#[derive(Clone)]
+pub(crate) struct WhitespaceTokenizer;
+
+struct WhitespaceTokenStream<'a> {
+    text: &'a str,
+    chars: CharIndices<'a>,
+    token: Token,
+}
+
+impl Tokenizer for WhitespaceTokenizer {
+    fn token_stream<'a>(&self, text: &'a str) -> BoxTokenStream<'a> {
+        BoxTokenStream::from(WhitespaceTokenStream {
+            text,
+            chars: text.char_indices(),
+            token: Token::default(),
+        })
+    }
+}
+
+impl<'a> TokenStream for WhitespaceTokenStream<'a> {
+    fn advance(&mut self) -> bool {
+        self.token.text.clear();
+        self.token.position = self.token.position.wrapping_add(1);
+        while let Some((offset_from, c)) = self.chars.next() {
+            if !c.is_whitespace() {
+                let offset_to = self
+                    .chars
+                    .by_ref()
+                    .find(|&(_, c)| c.is_whitespace())
+                    .map(|(offset, _)| offset)
+                    .unwrap_or(self.text.len());
+                self.token.offset_from = offset_from;
+                self.token.offset_to = offset_to;
+                self.token.text.push_str(&self.text[offset_from..offset_to]);
+                return true;
+            }
+        }
+        false
+    }
+
+    fn token(&self) -> &Token {
+        &self.token
+    }
+
+    fn token_mut(&mut self) -> &mut Token {
+        &mut self.token
+    }
+}
+
+/// Normalizes each token to NFC, so composed and decomposed forms of the
+/// same name match. The offsets still point into the original text.
+#[derive(Clone)]
+pub(crate) struct NfcFilter;
+
+struct NfcTokenStream<'a> {
+    tail: BoxTokenStream<'a>,
+    buffer: String,
+}
+
+impl TokenFilter for NfcFilter {
+    fn transform<'a>(&self, token_stream: BoxTokenStream<'a>) -> BoxTokenStream<'a> {
+        BoxTokenStream::from(NfcTokenStream {
+            tail: token_stream,
+            buffer: String::with_capacity(100),
+        })
+    }
+}
+
+impl<'a> TokenStream for NfcTokenStream<'a> {
+    fn advance(&mut self) -> bool {
+        if !self.tail.advance() {
+            return false;
+        }
+        let text = &self.tail.token().text;
+        if is_nfc_quick(text.chars()) != IsNormalized::Yes {
+            self.buffer.clear();
+            self.buffer.extend(text.nfc());
+            mem::swap(&mut self.tail.token_mut().text, &mut self.buffer);
+        }
+        true
+    }
+
+    fn token(&self) -> &Token {
+        self.tail.token()
+    }
+
+    fn token_mut(&mut self) -> &mut Token {
+        self.tail.token_mut()
+    }
+}
+
+/// Adds the words of a camelCase token after it, at the same position, so
+/// `getUserName` can be found by `user` as well as by its whole name.
+/// snake_case and kebab-case are already split by `PathTokenizer`.
+#[derive(Clone)]
+pub(crate) struct CamelCaseFilter;
+
+struct CamelCaseTokenStream<'a> {
+    tail: BoxTokenStream<'a>,
+    token: Token,
+    // The words still to be returned, last first.
+    words: Vec<Token>,
+}
+
+impl TokenFilter for CamelCaseFilter {
+    fn transform<'a>(&self, token_stream: BoxTokenStream<'a>) -> BoxTokenStream<'a> {
+        BoxTokenStream::from(CamelCaseTokenStream {
+            tail: token_stream,
+            token: Token::default(),
+            words: Vec::new(),
+        })
+    }
+}
+
+impl<'a> TokenStream for CamelCaseTokenStream<'a> {
+    fn advance(&mut self) -> bool {
+        if let Some(word) = self.words.pop() {
+            self.token = word;
+            return true;
+        }
+        if !self.tail.advance() {
+            return false;
+        }
+        self.token = self.tail.token().clone();
+
+        let bounds = camel_case_words(&self.token.text);
+        if bounds.len() > 1 {
+            for &(from, to) in bounds.iter().rev() {
+                self.words.push(Token {
+                    offset_from: self.token.offset_from + from,
+                    offset_to: self.token.offset_from + to,
+                    position: self.token.position,
+                    text: self.token.text[from..to].to_string(),
+                    position_length: 1,
+                });
+            }
+        }
+        true
+    }
+
+    fn token(&self) -> &Token {
+        &self.token
+    }
+
+    fn token_mut(&mut self) -> &mut Token {
+        &mut self.token
+    }
+}
+
+/// The byte ranges of the words in `text`, which start at an uppercase letter
+/// following a lowercase letter or digit (`getUser`), or at the last of a run
+/// of uppercase letters followed by a lowercase one (`HTTPServer`).
+fn camel_case_words(text: &str) -> Vec<(usize, usize)> {
+    let mut words = Vec::new();
+    let mut start = 0;
+    // The previous letter, skipping over combining marks.
+    let mut prev: Option<char> = None;
+    let mut chars = text.char_indices().peekable();
+    while let Some((offset, c)) = chars.next() {
+        if is_combining_mark(c) {
+            continue;
+        }
+        if let Some(p) = prev {
+            let next_lower = chars.peek().is_some_and(|&(_, n)| n.is_lowercase());
+            let boundary = c.is_uppercase()
+                && (p.is_lowercase() || p.is_numeric() || (p.is_uppercase() && next_lower));
+            if boundary {
+                words.push((start, offset));
+                start = offset;
+            }
+        }
+        prev = Some(c);
+    }
+    if start < text.len() {
+        words.push((start, text.len()));
+    }
+    words
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn tokens(config: &LookrdConfig, text: &str) -> Vec<String> {
+        analyze(config, PATH_TOKENIZER, text)
+    }
+
+    fn analyze(config: &LookrdConfig, tokenizer: &str, text: &str) -> Vec<String> {
+        let index = Index::create_in_ram(crate::indexer::build_schema());
+        register(&index, config);
+        let analyzer = index.tokenizers().get(tokenizer).unwrap();
+        let mut stream = analyzer.token_stream(text);
+        let mut tokens = Vec::new();
+        while let Some(token) = stream.next() {
+            tokens.push(token.text.clone());
+        }
+        tokens
+    }
+
+    #[test]
+    fn test_path_tokenizer() {
+        let fold = LookrdConfig {
+            ascii_fold: true,
+            ..Default::default()
+        };
+        let composed = "/docs/Caf\u{e9}.txt";
+        let decomposed = "/docs/Cafe\u{301}.txt";
+
+        assert_eq!(tokens(&fold, composed), vec!["docs", "cafe", "txt"]);
+        assert_eq!(tokens(&fold, decomposed), vec!["docs", "cafe", "txt"]);
+
+        let no_fold = LookrdConfig::default();
+        assert_eq!(tokens(&no_fold, composed), vec!["docs", "caf\u{e9}", "txt"]);
+        assert_eq!(
+            tokens(&no_fold, decomposed),
+            vec!["docs", "caf\u{e9}", "txt"]
+        );
+    }
+
+    #[test]
+    fn test_split_words() {
+        let split = LookrdConfig {
+            split_words: true,
+            ..Default::default()
+        };
+        let filename = |text| analyze(&split, FILENAME_TOKENIZER, text);
+
+        assert_eq!(
+            filename("getUserName.rs"),
+            vec!["getusername", "get", "user", "name", "rs"]
+        );
+        assert_eq!(filename("my_module.py"), vec!["my", "module", "py"]);
+        assert_eq!(filename("kebab-case-name"), vec!["kebab", "case", "name"]);
+        assert_eq!(
+            filename("HTTPServer2Go"),
+            vec!["httpserver2go", "http", "server2", "go"]
+        );
+        assert_eq!(filename("README"), vec!["readme"]);
+
+        // Only the filename is split, and only when configured.
+        assert_eq!(
+            analyze(&split, PATH_TOKENIZER, "getUserName"),
+            vec!["getusername"]
+        );
+        assert_eq!(
+            analyze(&LookrdConfig::default(), FILENAME_TOKENIZER, "getUserName"),
+            vec!["getusername"]
+        );
+    }
+
+    #[test]
+    fn test_field_tokenizers() {
+        let mut config = LookrdConfig::default();
+        config.tokenizers.filename = TokenizerKind::Raw;
+        config.tokenizers.first_line = TokenizerKind::Whitespace;
+        assert_eq!(
+            analyze(&config, FILENAME_TOKENIZER, "My Report.txt"),
+            vec!["my report.txt"]
+        );
+        assert_eq!(
+            analyze(&config, FIRST_LINE_TOKENIZER, "#!/usr/bin/env  python3"),
+            vec!["#!/usr/bin/env", "python3"]
+        );
+
+        config.tokenizers.filename = TokenizerKind::Ngram;
+        assert_eq!(
+            analyze(&config, FILENAME_TOKENIZER, "Repo"),
+            vec!["re", "rep", "ep", "epo", "po"]
+        );
+        assert_eq!(tokens(&config, "/a/Repo"), vec!["a", "repo"]);
+    }
+}
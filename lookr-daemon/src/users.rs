@@ -0,0 +1,191 @@
+//! The users a multi-user daemon keeps an index for, and what each of them
+//! can see.
+
+use std::fs::Metadata;
+use std::path::{Path, PathBuf};
+
+/// The lowest uid of a login user, below which are system accounts.
+#[cfg(unix)]
+const MIN_LOGIN_UID: u32 = 1000;
+
+/// The uid of `nobody`, which isn't a login user either.
+#[cfg(unix)]
+const NOBODY_UID: u32 = 65534;
+
+/// A user, with the ids that decide which files they can see.
+#[derive(Clone, Debug, PartialEq)]
+pub struct UserAccess {
+    pub name: String,
+    pub uid: u32,
+    /// The user's primary and supplementary groups.
+    pub gids: Vec<u32>,
+}
+
+impl UserAccess {
+    /// Look the user and their groups up.
+    #[cfg(unix)]
+    pub fn lookup(name: &str) -> Result<Self, crate::privileges::PrivilegeError> {
+        use crate::privileges::PrivilegeError;
+        use nix::unistd::User;
+
+        let user = match User::from_name(name) {
+            Ok(Some(u)) => u,
+            Ok(None) => return Err(PrivilegeError::NoSuchUser(name.to_string())),
+            Err(e) => return Err(PrivilegeError::Lookup(name.to_string(), e)),
+        };
+        #[cfg(not(target_vendor = "apple"))]
+        let gids = {
+            let c_name = std::ffi::CString::new(name)
+                .map_err(|_| PrivilegeError::NoSuchUser(name.to_string()))?;
+            nix::unistd::getgrouplist(&c_name, user.gid)
+                .map_err(|e| PrivilegeError::Groups(name.to_string(), e))?
+                .into_iter()
+                .map(|g| g.as_raw())
+                .collect()
+        };
+        #[cfg(target_vendor = "apple")]
+        let gids = vec![user.gid.as_raw()];
+        Ok(UserAccess {
+            name: name.to_string(),
+            uid: user.uid.as_raw(),
+            gids,
+        })
+    }
+
+    /// Whether the user can list the directory, and so see what's in it.
+    /// Only the mode bits are checked, not ACLs.
+    #[cfg(unix)]
+    pub fn can_list(&self, dir: &Metadata) -> bool {
+        use std::os::unix::fs::MetadataExt;
+
+        // Listing a directory needs both read and execute.
+        self.allows(dir.uid(), dir.gid(), dir.mode(), 0o5)
+    }
+
+    #[cfg(not(unix))]
+    pub fn can_list(&self, _dir: &Metadata) -> bool {
+        true
+    }
+
+    /// Whether the user can get to what's in the directory by name, if not
+    /// list it. Only the mode bits are checked, not ACLs.
+    #[cfg(unix)]
+    pub fn can_search(&self, dir: &Metadata) -> bool {
+        use std::os::unix::fs::MetadataExt;
+
+        self.allows(dir.uid(), dir.gid(), dir.mode(), 0o1)
+    }
+
+    #[cfg(not(unix))]
+    pub fn can_search(&self, _dir: &Metadata) -> bool {
+        true
+    }
+
+    /// Whether the user can read the file. Only the mode bits are checked,
+    /// not ACLs.
+    #[cfg(unix)]
+    pub fn can_read(&self, file: &Metadata) -> bool {
+        use std::os::unix::fs::MetadataExt;
+
+        self.allows(file.uid(), file.gid(), file.mode(), 0o4)
+    }
+
+    #[cfg(not(unix))]
+    pub fn can_read(&self, _file: &Metadata) -> bool {
+        true
+    }
+
+    /// Whether the owner, group or other bits of `mode` that apply to the
+    /// user include all of `wanted`, given as other bits.
+    fn allows(&self, uid: u32, gid: u32, mode: u32, wanted: u32) -> bool {
+        if self.uid == 0 {
+            return true;
+        }
+        let bits = if uid == self.uid {
+            mode >> 6
+        } else if self.gids.contains(&gid) {
+            mode >> 3
+        } else {
+            mode
+        };
+        bits & wanted == wanted
+    }
+}
+
+/// The users named in the config, separated by commas, or every login user
+/// if none are.
+#[cfg(unix)]
+pub fn configured_users(users: Option<&str>) -> Vec<String> {
+    match users {
+        Some(users) => users
+            .split(',')
+            .map(str::trim)
+            .filter(|u| !u.is_empty())
+            .map(str::to_string)
+            .collect(),
+        None => login_users(),
+    }
+}
+
+/// Every user with a uid in the range given to people rather than services.
+#[cfg(unix)]
+fn login_users() -> Vec<String> {
+    let mut users = Vec::new();
+    // getpwent isn't thread safe, but this is only called once at startup.
+    unsafe {
+        libc::setpwent();
+        loop {
+            let entry = libc::getpwent();
+            if entry.is_null() {
+                break;
+            }
+            let uid = (*entry).pw_uid;
+            if uid >= MIN_LOGIN_UID && uid != NOBODY_UID {
+                let name = std::ffi::CStr::from_ptr((*entry).pw_name);
+                users.push(name.to_string_lossy().to_string());
+            }
+        }
+        libc::endpwent();
+    }
+    users.sort();
+    users.dedup();
+    users
+}
+
+/// Where everything the daemon keeps for the user goes.
+pub fn user_dir(data_dir: &Path, user: &str) -> PathBuf {
+    data_dir.join(user)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_allows() {
+        let user = UserAccess {
+            name: "me".to_string(),
+            uid: 1000,
+            gids: vec![1000, 27],
+        };
+        // Owned by the user.
+        assert!(user.allows(1000, 0, 0o700, 0o5));
+        assert!(!user.allows(1000, 0, 0o070, 0o5));
+        // Through a supplementary group.
+        assert!(user.allows(0, 27, 0o750, 0o5));
+        assert!(!user.allows(0, 27, 0o710, 0o5));
+        // Through other.
+        assert!(user.allows(0, 0, 0o755, 0o5));
+        assert!(!user.allows(0, 0, 0o750, 0o5));
+        assert!(!user.allows(0, 0, 0o751, 0o5));
+        assert!(user.allows(0, 0, 0o711, 0o1));
+        assert!(!user.allows(0, 0, 0o700, 0o1));
+
+        let root = UserAccess {
+            name: "root".to_string(),
+            uid: 0,
+            gids: vec![0],
+        };
+        assert!(root.allows(1000, 1000, 0o700, 0o5));
+    }
+}
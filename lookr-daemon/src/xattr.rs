@@ -0,0 +1,208 @@
+//! Reading the extended attributes of files, for `index_xattrs`. Only Linux
+//! and macOS have them here; elsewhere files are read as having none.
+
+use std::path::Path;
+
+/// The longest value indexed. Longer ones are indexed by name only, as are
+/// values that aren't UTF-8 text, like the binary plists macOS keeps Finder
+/// tags in.
+const MAX_VALUE_LEN: usize = 256;
+
+/// The attributes of `p` whose names start with one of `prefixes`, each
+/// with its value if it can be indexed. Symlinks' own attributes are read,
+/// not those of what they point to.
+pub(crate) fn read(p: &Path, prefixes: &[String]) -> Vec<(String, Option<String>)> {
+    if prefixes.is_empty() {
+        return Vec::new();
+    }
+    let names = match sys::list(p) {
+        Ok(names) => names,
+        Err(e) => {
+            debug!("Could not list the attributes of {:?}: {}", p, e);
+            return Vec::new();
+        }
+    };
+    names
+        .split(|&b| b == 0)
+        .filter(|name| !name.is_empty())
+        .filter_map(|name| {
+            let name_str = std::str::from_utf8(name).ok()?;
+            if !prefixes
+                .iter()
+                .any(|prefix| name_str.starts_with(prefix.as_str()))
+            {
+                return None;
+            }
+            // Gone since it was listed, if this fails.
+            let value = Some(sys::get(p, name).ok()?)
+                .filter(|v| v.len() <= MAX_VALUE_LEN)
+                .and_then(|v| String::from_utf8(v).ok())
+                .map(|v| v.trim_end_matches('\0').to_string());
+            Some((name_str.to_string(), value))
+        })
+        .collect()
+}
+
+/// The terms a file with the attribute is indexed under: its name, and its
+/// name and value as `name=value` if it has one.
+pub(crate) fn terms(name: &str, value: Option<&str>) -> Vec<String> {
+    let mut terms = vec![name.to_string()];
+    if let Some(value) = value {
+        terms.push(format!("{}={}", name, value));
+    }
+    terms
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+mod sys {
+    use std::ffi::CString;
+    use std::io;
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::Path;
+
+    /// The names of the attributes, each ended by a NUL.
+    pub(super) fn list(p: &Path) -> io::Result<Vec<u8>> {
+        let path = CString::new(p.as_os_str().as_bytes())?;
+        read_sized(|buf, size| unsafe { listxattr(&path, buf, size) })
+    }
+
+    pub(super) fn get(p: &Path, name: &[u8]) -> io::Result<Vec<u8>> {
+        let path = CString::new(p.as_os_str().as_bytes())?;
+        let name = CString::new(name)?;
+        read_sized(|buf, size| unsafe { getxattr(&path, &name, buf, size) })
+    }
+
+    /// Ask for the size, then for the contents, asking again if they grew
+    /// in between.
+    fn read_sized<F>(read: F) -> io::Result<Vec<u8>>
+    where
+        F: Fn(*mut libc::c_void, usize) -> libc::ssize_t,
+    {
+        loop {
+            let size = read(std::ptr::null_mut(), 0);
+            if size < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let mut buf = vec![0u8; size as usize];
+            let read_len = read(buf.as_mut_ptr() as *mut libc::c_void, buf.len());
+            if read_len >= 0 {
+                buf.truncate(read_len as usize);
+                return Ok(buf);
+            }
+            let e = io::Error::last_os_error();
+            if e.raw_os_error() != Some(libc::ERANGE) {
+                return Err(e);
+            }
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    unsafe fn listxattr(path: &CString, buf: *mut libc::c_void, size: usize) -> libc::ssize_t {
+        libc::llistxattr(path.as_ptr(), buf as *mut libc::c_char, size)
+    }
+
+    #[cfg(target_os = "linux")]
+    unsafe fn getxattr(
+        path: &CString,
+        name: &CString,
+        buf: *mut libc::c_void,
+        size: usize,
+    ) -> libc::ssize_t {
+        libc::lgetxattr(path.as_ptr(), name.as_ptr(), buf, size)
+    }
+
+    #[cfg(target_os = "macos")]
+    unsafe fn listxattr(path: &CString, buf: *mut libc::c_void, size: usize) -> libc::ssize_t {
+        libc::listxattr(
+            path.as_ptr(),
+            buf as *mut libc::c_char,
+            size,
+            libc::XATTR_NOFOLLOW,
+        )
+    }
+
+    #[cfg(target_os = "macos")]
+    unsafe fn getxattr(
+        path: &CString,
+        name: &CString,
+        buf: *mut libc::c_void,
+        size: usize,
+    ) -> libc::ssize_t {
+        libc::getxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            buf,
+            size,
+            0,
+            libc::XATTR_NOFOLLOW,
+        )
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+mod sys {
+    use std::io;
+    use std::path::Path;
+
+    pub(super) fn list(_p: &Path) -> io::Result<Vec<u8>> {
+        Ok(Vec::new())
+    }
+
+    pub(super) fn get(_p: &Path, _name: &[u8]) -> io::Result<Vec<u8>> {
+        Ok(Vec::new())
+    }
+}
+
+/// Set an attribute on `p`, for tests.
+#[cfg(all(test, target_os = "linux"))]
+pub(crate) fn set(p: &Path, name: &str, value: &[u8]) {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = CString::new(p.as_os_str().as_bytes()).unwrap();
+    let name = CString::new(name).unwrap();
+    let ret = unsafe {
+        libc::setxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            value.as_ptr() as *const libc::c_void,
+            value.len(),
+            0,
+        )
+    };
+    assert_eq!(ret, 0, "{}", std::io::Error::last_os_error());
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_read() {
+        let dir = tempfile::tempdir().unwrap();
+        let p = dir.path().join("a.txt");
+        std::fs::write(&p, "a").unwrap();
+        set(&p, "user.color", b"red");
+        set(&p, "user.binary", &[0xff, 0xfe]);
+        set(&p, "user.other.note", b"hi");
+
+        let mut attrs = read(&p, &["user.c".to_string(), "user.b".to_string()]);
+        attrs.sort();
+        assert_eq!(
+            attrs,
+            vec![
+                ("user.binary".to_string(), None),
+                ("user.color".to_string(), Some("red".to_string())),
+            ]
+        );
+        assert_eq!(read(&p, &[]), vec![]);
+        assert_eq!(
+            read(&dir.path().join("gone"), &["user.".to_string()]),
+            vec![]
+        );
+        assert_eq!(
+            terms("user.color", Some("red")),
+            vec!["user.color".to_string(), "user.color=red".to_string()]
+        );
+    }
+}